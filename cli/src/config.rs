@@ -0,0 +1,50 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+/// Defaults for command-line options, read from a TOML file.
+///
+/// The file is named by `$CHASSET_CONFIG` if set, and is otherwise `chasset/config.toml` under `$XDG_CONFIG_HOME`,
+/// which defaults to `~/.config`. A missing file is equivalent to an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Repository to operate on when none is named on the command line
+    pub repository: Option<PathBuf>,
+    /// Don't display progress bars
+    #[serde(default)]
+    pub quiet: bool,
+}
+
+impl Config {
+    /// Read the configuration file, if any.
+    pub fn load() -> Result<Self> {
+        let path = match path() {
+            Some(x) => x,
+            None => return Ok(Self::default()),
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        toml::from_str(&text).map_err(|e| Error::Config(path, e))
+    }
+}
+
+/// Location of the configuration file, if one can be determined.
+pub fn path() -> Option<PathBuf> {
+    if let Some(x) = env::var_os("CHASSET_CONFIG") {
+        return Some(x.into());
+    }
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(x) => PathBuf::from(x),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("chasset").join("config.toml"))
+}
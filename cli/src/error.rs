@@ -0,0 +1,42 @@
+use std::io;
+use std::path::PathBuf;
+
+use err_derive::Error;
+
+/// Reasons a subcommand can fail.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// An IO operation failed.
+    #[error(display = "{}", _0)]
+    Io(io::Error),
+    /// The subcommand can't operate on the repository as laid out.
+    #[error(display = "{}", _0)]
+    Unsupported(String),
+    /// No repository was named on the command line or in the configuration file.
+    #[error(display = "no repository specified, and none configured in {}", _0)]
+    NoRepository(String),
+    /// The configuration file couldn't be parsed.
+    #[error(display = "malformed configuration file {}: {}", _0, _1)]
+    Config(PathBuf, toml::de::Error),
+}
+
+impl Error {
+    /// Exit status to report the error with.
+    ///
+    /// Status 1 is reserved for subcommands such as `check` and `exists` whose answer is "no". Malformed command lines
+    /// are likewise reported with status 3.
+    pub fn code(&self) -> i32 {
+        match *self {
+            Error::Io(_) => 2,
+            Error::Unsupported(_) | Error::NoRepository(_) | Error::Config(..) => 3,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(x: io::Error) -> Self {
+        Error::Io(x)
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
@@ -0,0 +1,1459 @@
+mod config;
+mod error;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use structopt::{
+    clap::{self, Shell},
+    StructOpt,
+};
+
+use chasset::*;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+#[derive(StructOpt)]
+#[structopt(name = "chasset")]
+struct Opt {
+    #[structopt(short = "a")]
+    /// Path contains archives instead of loose files. Detected automatically if absent.
+    archives: bool,
+    #[structopt(short = "q", long = "quiet")]
+    /// Don't display progress bars
+    quiet: bool,
+    #[structopt(long = "json")]
+    /// Print the output of `ls`, `stat`, `du`, `fsck`, `diff`, and `archives` as line-delimited JSON
+    json: bool,
+    #[structopt(parse(from_os_str))]
+    /// Location of the chasset repository. Defaults to `repository` from the configuration file.
+    path: Option<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(name = "cat")]
+    /// Read or write assets
+    Cat {
+        /// Hashes of assets to write to stdout, concatenated. If absent, new data is inserted from stdin.
+        hashes: Vec<String>,
+        #[structopt(short = "o", parse(from_os_str))]
+        /// Write each asset to a file in this directory named by its hash instead
+        output: Option<PathBuf>,
+    },
+    #[structopt(name = "ls")]
+    /// List stored assets
+    Ls {
+        #[structopt(long = "kind")]
+        /// Only list assets whose hashes are of this kind
+        kind: Option<HashKind>,
+        #[structopt(long = "min-size")]
+        /// Only list assets of at least this many bytes
+        min_size: Option<u64>,
+        #[structopt(long = "max-size")]
+        /// Only list assets of at most this many bytes
+        max_size: Option<u64>,
+        #[structopt(long = "sort", possible_values = &["size", "hash"])]
+        /// Order assets by size (largest first) or by hash
+        sort: Option<String>,
+        #[structopt(long = "limit")]
+        /// List at most this many assets
+        limit: Option<usize>,
+        #[structopt(short = "l")]
+        /// Also print the size of each asset
+        long: bool,
+    },
+    #[structopt(name = "rm")]
+    /// Delete assets from a loose files repository
+    Rm {
+        /// Hashes of assets to delete
+        hashes: Vec<String>,
+        #[structopt(long = "stdin")]
+        /// Also delete assets whose hashes are read from stdin, one per line
+        stdin: bool,
+        #[structopt(long = "dry-run")]
+        /// Print the assets that would be deleted without deleting them
+        dry_run: bool,
+    },
+    #[structopt(name = "gc")]
+    /// Delete assets of a loose files repository that aren't reachable from a root
+    ///
+    /// Pinned and referenced assets are always roots. Assets referred to by reachable manifests are reachable.
+    Gc {
+        #[structopt(long = "root")]
+        /// Additional roots
+        roots: Vec<String>,
+        #[structopt(long = "roots-file", parse(from_os_str))]
+        /// File listing additional roots, one per line
+        roots_file: Option<PathBuf>,
+        #[structopt(long = "dry-run")]
+        /// Report what would be deleted without deleting it
+        dry_run: bool,
+    },
+    #[structopt(name = "fsck")]
+    /// Verify every asset of a loose files repository, exiting with status 1 if any problems are found
+    Fsck {
+        #[structopt(long = "quarantine")]
+        /// Move corrupt assets into the repository's quarantine directory
+        quarantine: bool,
+    },
+    #[structopt(name = "pack")]
+    /// Pack the assets of a loose files repository into archives
+    Pack {
+        #[structopt(long = "max-size")]
+        /// Greatest size of each archive in bytes. By default, a single archive is written.
+        max_size: Option<u64>,
+        #[structopt(long = "remove-loose")]
+        /// Delete the loose files once packed
+        remove_loose: bool,
+        #[structopt(parse(from_os_str))]
+        /// Directory to write archives into
+        dest: PathBuf,
+    },
+    #[structopt(name = "unpack")]
+    /// Copy every asset of the repository, e.g. an archive or directory of archives, into a loose files repository
+    ///
+    /// Each asset is verified against its hash as it is written.
+    Unpack {
+        #[structopt(parse(from_os_str))]
+        /// Location of the loose files repository, created if necessary
+        dest: PathBuf,
+    },
+    #[structopt(name = "import")]
+    /// Store every file under a directory, printing the hash and path of each
+    ///
+    /// Files are hashed in parallel if chasset is built with the `rayon` feature.
+    Import {
+        #[structopt(parse(from_os_str))]
+        /// Directory to import
+        dir: PathBuf,
+        #[structopt(long = "manifest")]
+        /// Also print the hash of a manifest describing the directory
+        manifest: bool,
+    },
+    #[structopt(name = "export")]
+    /// Write a single asset to a new file
+    Export {
+        #[structopt(required_unless = "stdin")]
+        /// Hash of the asset
+        hash: Option<String>,
+        #[structopt(parse(from_os_str), required_unless = "stdin")]
+        /// Path of the file to create
+        dest: Option<PathBuf>,
+        #[structopt(long = "stdin", parse(from_os_str), conflicts_with_all = &["hash", "dest"])]
+        /// Instead export assets whose hashes are read from stdin, one per line, to files in this directory named by
+        /// their hashes
+        stdin: Option<PathBuf>,
+        #[structopt(long = "hardlink", conflicts_with = "reflink")]
+        /// Hard link the file to the repository's copy, which must then never be modified
+        hardlink: bool,
+        #[structopt(long = "reflink")]
+        /// Clone the repository's copy where supported, copying otherwise
+        reflink: bool,
+    },
+    #[structopt(name = "export-tree")]
+    /// Recreate the directory tree described by a manifest
+    ExportTree {
+        /// Hash of the manifest
+        root: String,
+        #[structopt(parse(from_os_str))]
+        /// Directory to populate, created if necessary
+        dest: PathBuf,
+    },
+    #[structopt(name = "stat")]
+    /// Describe where and how an asset is stored
+    Stat {
+        /// Hashes of the assets
+        hashes: Vec<String>,
+        #[structopt(long = "stdin")]
+        /// Also describe assets whose hashes are read from stdin, one per line
+        stdin: bool,
+    },
+    #[structopt(name = "du")]
+    /// Summarize the space used by the repository
+    ///
+    /// Sizes of archived assets are as stored, i.e. after any compression or encryption.
+    Du {
+        #[structopt(long = "archives", parse(from_os_str))]
+        /// Directory of archives to include alongside a loose files repository
+        archives: Option<PathBuf>,
+        #[structopt(long = "top", default_value = "10")]
+        /// Number of largest assets to list
+        top: usize,
+    },
+    #[cfg(feature = "tiny_http")]
+    #[structopt(name = "serve")]
+    /// Serve assets over HTTP in the layout fetched by `HttpStore`
+    Serve {
+        #[structopt(long = "listen", default_value = "0.0.0.0:8080")]
+        /// Address to listen on
+        listen: String,
+        #[structopt(long = "threads", default_value = "4")]
+        /// Number of requests to handle concurrently
+        threads: usize,
+        #[structopt(long = "listing")]
+        /// Enumerate every asset in response to `GET /`
+        listing: bool,
+    },
+    #[structopt(name = "sync")]
+    /// Copy assets missing from one repository out of another
+    ///
+    /// Remote HTTP repositories must be served with listing enabled, and can only be pulled from.
+    Sync {
+        /// Path or HTTP URL of the remote repository
+        remote: String,
+        #[structopt(long = "push", conflicts_with = "pull")]
+        /// Copy assets from this repository into the remote one
+        push: bool,
+        #[structopt(long = "pull")]
+        /// Copy assets from the remote repository into this one. The default.
+        pull: bool,
+    },
+    #[structopt(name = "cp")]
+    /// Copy assets into another repository, verifying each
+    ///
+    /// Assets copied into a directory of archives are packed into a new archive.
+    Cp {
+        #[structopt(long = "from", parse(from_os_str))]
+        /// Repository to copy from. Defaults to this repository.
+        from: Option<PathBuf>,
+        #[structopt(long = "to", parse(from_os_str))]
+        /// Repository to copy into, created as loose files if absent
+        to: PathBuf,
+        /// Hashes of assets to copy
+        hashes: Vec<String>,
+        #[structopt(long = "stdin", conflicts_with = "all")]
+        /// Also copy assets whose hashes are read from stdin, one per line
+        stdin: bool,
+        #[structopt(long = "all", conflicts_with = "hashes")]
+        /// Copy every asset missing from the destination
+        all: bool,
+    },
+    #[structopt(name = "hash")]
+    /// Print the hashes files would be stored under, without storing them or accessing the repository
+    Hash {
+        #[structopt(parse(from_os_str))]
+        /// Files to hash. If absent, data is read from stdin.
+        files: Vec<PathBuf>,
+    },
+    #[structopt(name = "check")]
+    /// Verify that a file's contents match a hash
+    ///
+    /// Exits with status 0 if the file matches and 1 otherwise. No repository is accessed.
+    Check {
+        /// Expected hash of the file's contents
+        hash: chasset::Hash,
+        #[structopt(parse(from_os_str), required_unless = "stdin")]
+        /// File to verify
+        file: Option<PathBuf>,
+        #[structopt(long = "stdin", conflicts_with = "file")]
+        /// Verify data read from stdin instead of a file
+        stdin: bool,
+    },
+    #[structopt(name = "exists")]
+    /// Determine whether assets are present
+    ///
+    /// Exits with status 0 if every asset is present and 1 otherwise. Full hashes are required.
+    Exists {
+        /// Hashes of assets to look for
+        hashes: Vec<chasset::Hash>,
+        #[structopt(long = "stdin")]
+        /// Also look for assets whose hashes are read from stdin, one per line
+        stdin: bool,
+        #[structopt(long = "missing")]
+        /// Print the hashes of absent assets
+        missing: bool,
+    },
+    #[structopt(name = "diff")]
+    /// Compare the assets of the repository with those of another, or of two other repositories
+    ///
+    /// Assets only in the first repository are prefixed with "<", and those only in the second with ">".
+    Diff {
+        #[structopt(parse(from_os_str))]
+        /// Location of the other repository, or of the first of two repositories to compare
+        other: PathBuf,
+        #[structopt(parse(from_os_str))]
+        /// Location of the second repository to compare, in place of this repository and `other`
+        second: Option<PathBuf>,
+    },
+    #[structopt(name = "archives")]
+    /// List the archives of the repository with their metadata
+    Archives {
+        #[structopt(subcommand)]
+        cmd: Option<ArchivesCommand>,
+    },
+    #[structopt(name = "watch")]
+    /// Print the hashes of assets as they're added to the repository, until interrupted
+    ///
+    /// The repository is listed periodically, or for loose files repositories when built with the `notify` feature,
+    /// watched for new files. Assets already present when watching begins aren't printed.
+    Watch {
+        #[structopt(long = "interval", default_value = "1", parse(try_from_str = parse_interval))]
+        /// Seconds to wait between listings
+        interval: Duration,
+    },
+    #[structopt(name = "completions")]
+    /// Print a completion script for a shell to stdout
+    Completions {
+        #[structopt(possible_values = &Shell::variants())]
+        /// Shell to generate the script for
+        shell: Shell,
+    },
+    #[structopt(name = "resolve")]
+    /// Print the full hash of the only asset whose hash begins with a prefix
+    ///
+    /// Subcommands that look up existing assets also accept such prefixes in place of hashes. The hash kind may be
+    /// omitted.
+    Resolve {
+        /// Beginning of the hash
+        prefix: String,
+    },
+}
+
+#[derive(StructOpt)]
+enum ArchivesCommand {
+    #[structopt(name = "show")]
+    /// List the assets stored in a single archive
+    Show {
+        #[structopt(parse(from_os_str))]
+        /// Archive file to inspect
+        file: PathBuf,
+    },
+}
+
+fn main() {
+    let opt = match Opt::from_args_safe() {
+        Ok(x) => x,
+        Err(e) => match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            // clap exits with status 1 by default, which `check` and `exists` use to answer "no"
+            _ => {
+                eprintln!("{}", e.message);
+                process::exit(3);
+            }
+        },
+    };
+    if let Err(e) = run(opt) {
+        eprintln!("chasset: {}", e);
+        process::exit(e.code());
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    if let Command::Completions { shell } = opt.cmd {
+        Opt::clap().gen_completions_to("chasset", shell, &mut io::stdout());
+        return Ok(());
+    }
+    if let Command::Hash { ref files } = opt.cmd {
+        if files.is_empty() {
+            let stdin = io::stdin();
+            println!("{}", hash_of(&mut stdin.lock())?);
+        }
+        for path in files {
+            println!("{}  {}", hash_of(&mut File::open(path)?)?, path.display());
+        }
+        return Ok(());
+    }
+    if let Command::Check { hash, ref file, .. } = opt.cmd {
+        let actual = match *file {
+            Some(ref path) => hash_of(&mut File::open(path)?)?,
+            None => {
+                let stdin = io::stdin();
+                hash_of(&mut stdin.lock())?
+            }
+        };
+        if actual != hash {
+            if !opt.quiet {
+                eprintln!("mismatch: expected {}, got {}", hash, actual);
+            }
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Command::Diff {
+        ref other,
+        second: Some(ref second),
+    } = opt.cmd
+    {
+        let result = chasset::diff(&*chasset::open(other)?, &*chasset::open(second)?)?;
+        print_diff(&result, other, second, opt.json)?;
+        return Ok(());
+    }
+    let config = Config::load()?;
+    let path = match opt.path.or(config.repository) {
+        Some(x) => x,
+        None => {
+            let config = config::path().map_or_else(
+                || "the configuration file".into(),
+                |x| x.display().to_string(),
+            );
+            return Err(Error::NoRepository(config));
+        }
+    };
+    let quiet = opt.quiet || config.quiet;
+    let layout = if opt.archives {
+        Layout::Archives
+    } else {
+        chasset::detect(&path)?
+    };
+    match opt.cmd {
+        Command::Cat { ref hashes, .. } if hashes.is_empty() => {
+            if layout != Layout::LooseFiles {
+                return Err(Error::Unsupported("archives are read-only".into()));
+            }
+            let repo = LooseFiles::open(path.clone())?;
+            let bar = progress(quiet, None);
+            let mut stage = ProgressWriter::new(repo.make_writer()?, |n| bar.set_position(n));
+            let stdin = io::stdin();
+            io::copy(&mut stdin.lock(), &mut stage)?;
+            let (hash, _) = stage.store()?;
+            bar.finish_and_clear();
+            println!("{}", hash);
+        }
+        Command::Cat { hashes, output } => {
+            let repo = layout.open(&path)?;
+            let hashes = hashes
+                .iter()
+                .map(|x| chasset::resolve(&*repo, x))
+                .collect::<io::Result<Vec<_>>>()?;
+            if let Some(ref dir) = output {
+                fs::create_dir_all(dir)?;
+            }
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            for hash in hashes {
+                let asset = repo.get(&hash)?;
+                match output {
+                    Some(ref dir) => fs::write(dir.join(hash.to_string()), &asset)?,
+                    None => stdout.write_all(&asset)?,
+                }
+            }
+        }
+        Command::Ls {
+            kind,
+            min_size,
+            max_size,
+            sort,
+            limit,
+            long,
+        } => {
+            let repo = layout.open(&path)?;
+            let mut assets = repo
+                .list_sizes()
+                .filter(|x| match *x {
+                    Ok((hash, size)) => {
+                        kind.is_none_or(|k| hash.kind() == k)
+                            && min_size.is_none_or(|n| size >= n)
+                            && max_size.is_none_or(|n| size <= n)
+                    }
+                    Err(_) => true,
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            match sort.as_ref().map(|x| &x[..]) {
+                Some("size") => assets.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0))),
+                Some(_) => assets.sort(),
+                None => {}
+            }
+            assets.truncate(limit.unwrap_or(usize::MAX));
+            for (hash, size) in assets {
+                match (opt.json, long) {
+                    (true, true) => emit(&Entry { hash, size })?,
+                    (true, false) => emit(&hash)?,
+                    (false, true) => println!("{:>12} {}", size, hash),
+                    (false, false) => println!("{}", hash),
+                }
+            }
+        }
+        Command::Rm {
+            hashes,
+            stdin,
+            dry_run,
+        } => {
+            if layout != Layout::LooseFiles {
+                return Err(Error::Unsupported("archives are read-only".into()));
+            }
+            let repo = LooseFiles::open(path.clone())?;
+            let mut hashes = hashes
+                .iter()
+                .map(|x| chasset::resolve(&repo, x))
+                .collect::<io::Result<Vec<_>>>()?;
+            if stdin {
+                let stdin = io::stdin();
+                hashes.extend(read_hashes(stdin.lock())?);
+            }
+            for hash in hashes {
+                if !repo.contains(&hash) {
+                    eprintln!("{}: not found", hash);
+                    continue;
+                }
+                if !dry_run {
+                    repo.remove(&hash)?;
+                }
+                println!("{}", hash);
+            }
+        }
+        Command::Gc {
+            roots,
+            roots_file,
+            dry_run,
+        } => {
+            if layout != Layout::LooseFiles {
+                return Err(Error::Unsupported("archives are read-only".into()));
+            }
+            let repo = LooseFiles::open(path.clone())?;
+            let mut roots = roots
+                .iter()
+                .map(|x| chasset::resolve(&repo, x))
+                .collect::<io::Result<Vec<_>>>()?;
+            if let Some(path) = roots_file {
+                roots.extend(read_hashes(io::BufReader::new(File::open(path)?))?);
+            }
+            let plan = repo.plan_garbage_with(&roots)?;
+            if dry_run {
+                for hash in &plan.garbage {
+                    println!("{}", hash);
+                }
+                println!(
+                    "would reclaim {} assets, {} bytes; keeping {} assets",
+                    plan.garbage.len(),
+                    plan.bytes,
+                    plan.live
+                );
+            } else {
+                let removed = plan.apply(&repo)?;
+                println!(
+                    "reclaimed {} assets, {} bytes; kept {} assets",
+                    removed, plan.bytes, plan.live
+                );
+            }
+        }
+        Command::Fsck { quarantine } => {
+            if layout != Layout::LooseFiles {
+                return Err(Error::Unsupported(
+                    "only loose files repositories can be checked".into(),
+                ));
+            }
+            let report = LooseFiles::open(path.clone())?.fsck(quarantine)?;
+            if opt.json {
+                emit(&report)?;
+                process::exit(if report.is_clean() { 0 } else { 1 });
+            }
+            for hash in &report.corrupt {
+                println!("corrupt {}", hash);
+            }
+            for path in &report.stray {
+                println!("stray {}", path.display());
+            }
+            println!(
+                "checked {} assets, {} bytes; {} corrupt{}, {} stray",
+                report.checked,
+                report.bytes,
+                report.corrupt.len(),
+                if report.quarantined && !report.corrupt.is_empty() {
+                    " (quarantined)"
+                } else {
+                    ""
+                },
+                report.stray.len()
+            );
+            if !report.is_clean() {
+                process::exit(1);
+            }
+        }
+        Command::Pack {
+            max_size,
+            remove_loose,
+            dest,
+        } => {
+            if layout != Layout::LooseFiles {
+                return Err(Error::Unsupported(
+                    "only loose files repositories can be packed".into(),
+                ));
+            }
+            let repo = LooseFiles::open(path.clone())?;
+            let mut packer = archive::Packer::new(archive::Metadata {
+                created: Some(SystemTime::now()),
+                creator: Some("chasset".into()),
+                ..archive::Metadata::default()
+            });
+            if let Some(x) = max_size {
+                packer.max_size(x);
+            }
+            let hashes = repo.list().collect::<Vec<_>>();
+            let bar = progress(quiet, Some(hashes.len() as u64));
+            let report = packer.pack(&dest, hashes.iter().cloned(), |x| {
+                bar.inc(1);
+                repo.get(x)
+            })?;
+            bar.finish_and_clear();
+            for path in &report.volumes {
+                println!("{}", path.display());
+            }
+            println!(
+                "packed {} assets, {} bytes, into {} archives",
+                report.assets,
+                report.bytes,
+                report.volumes.len()
+            );
+            if remove_loose {
+                // Make the volumes' names durable before deleting the only other copy of their contents
+                File::open(&dest)?.sync_all()?;
+                let mut packed = HashSet::new();
+                for path in &report.volumes {
+                    packed.extend(archive::Archive::open(path)?.list());
+                }
+                for hash in hashes.iter().filter(|x| packed.contains(x)) {
+                    match repo.remove(hash) {
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                        x => x?,
+                    }
+                }
+            }
+        }
+        Command::Unpack { dest } => {
+            let source = layout.open(&path)?;
+            let dest = LooseFiles::open(dest)?;
+            let (mut written, mut present, mut bytes) = (0, 0, 0);
+            for hash in source.list() {
+                let asset = source.get(&hash)?;
+                if chasset::store_verified(&dest, &hash, &asset[..])? {
+                    written += 1;
+                    bytes += asset.len() as u64;
+                } else {
+                    present += 1;
+                }
+            }
+            println!(
+                "unpacked {} assets, {} bytes; {} already present",
+                written, bytes, present
+            );
+        }
+        Command::Import { dir, manifest } => {
+            if layout != Layout::LooseFiles {
+                return Err(Error::Unsupported("archives are read-only".into()));
+            }
+            let repo = LooseFiles::open(path.clone())?;
+            let bytes = AtomicU64::new(0);
+            let listing = Mutex::new(Vec::new());
+            let bar = progress(quiet, None);
+            let root = tree::import_tree_with(&repo, &dir, |path, entry| {
+                let bytes = bytes.fetch_add(entry.size, Ordering::Relaxed) + entry.size;
+                bar.inc(1);
+                bar.set_message(format!("{} bytes", bytes));
+                listing.lock().unwrap().push((path.to_owned(), entry.hash));
+            })?;
+            bar.finish_and_clear();
+            let mut listing = listing.into_inner().unwrap();
+            listing.sort();
+            for (path, hash) in &listing {
+                println!("{}  {}", hash, path.display());
+            }
+            if manifest {
+                println!("{}", root);
+            }
+        }
+        Command::Export {
+            hash,
+            dest,
+            stdin,
+            hardlink,
+            reflink,
+        } => {
+            let link = if hardlink {
+                Some(tree::Link::Hard)
+            } else if reflink {
+                Some(tree::Link::Reflink)
+            } else {
+                None
+            };
+            match link {
+                Some(link) => {
+                    if layout != Layout::LooseFiles {
+                        return Err(Error::Unsupported(
+                            "only assets of loose files repositories can be linked".into(),
+                        ));
+                    }
+                    let repo = LooseFiles::open(path.clone())?;
+                    for (hash, dest) in export_targets(&repo, hash, dest, stdin)? {
+                        tree::export_asset_linked(&repo, &hash, &dest, link)?;
+                    }
+                }
+                None => {
+                    let repo = layout.open(&path)?;
+                    for (hash, dest) in export_targets(&*repo, hash, dest, stdin)? {
+                        tree::export_asset(&*repo, &hash, &dest)?;
+                    }
+                }
+            }
+        }
+        Command::ExportTree { root, dest } => {
+            let repo = layout.open(&path)?;
+            tree::export_tree(&*repo, &chasset::resolve(&*repo, &root)?, &dest)?;
+        }
+        Command::Stat { hashes, stdin } => {
+            let mut resolved = Vec::with_capacity(hashes.len());
+            if !hashes.is_empty() {
+                let repo = layout.open(&path)?;
+                for x in &hashes {
+                    resolved.push(chasset::resolve(&*repo, x)?);
+                }
+            }
+            if stdin {
+                let stdin = io::stdin();
+                resolved.extend(read_hashes(stdin.lock())?);
+            }
+            let repo = Opened::open(&path, layout)?;
+            for (i, hash) in resolved.into_iter().enumerate() {
+                let stat = stat(&repo, hash)?;
+                if opt.json {
+                    emit(&stat)?;
+                    continue;
+                }
+                if i != 0 {
+                    println!();
+                }
+                println!("hash: {}", stat.hash);
+                println!("kind: {}", stat.kind);
+                println!("size: {}", stat.size);
+                if let Some(x) = stat.stored_size {
+                    println!("stored size: {}", x);
+                }
+                match stat.offset {
+                    Some(x) => println!("location: {} at offset {}", stat.location.display(), x),
+                    None => println!("location: {}", stat.location.display()),
+                }
+                println!("compressed: {}", stat.compressed);
+                println!("encrypted: {}", stat.encrypted);
+            }
+        }
+        Command::Du { archives, top } => du(&path, layout, archives.as_deref(), top, opt.json)?,
+        Command::Diff { other, .. } => {
+            let result = chasset::diff(&*layout.open(&path)?, &*chasset::open(&other)?)?;
+            print_diff(&result, &path, &other, opt.json)?;
+        }
+        Command::Sync { remote, push, .. } => sync(&path, layout, &remote, push, quiet)?,
+        Command::Hash { .. } | Command::Check { .. } | Command::Completions { .. } => {
+            unreachable!()
+        }
+        Command::Cp {
+            from,
+            to,
+            hashes,
+            stdin,
+            all,
+        } => {
+            let source = match from {
+                Some(ref x) => chasset::open(x)?,
+                None => layout.open(&path)?,
+            };
+            let dest_layout = match fs::metadata(&to) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Layout::LooseFiles,
+                _ => chasset::detect(&to)?,
+            };
+            let (loose, archives) = match dest_layout {
+                Layout::LooseFiles => (Some(LooseFiles::open(to.clone())?), None),
+                Layout::Archives => (None, Some(ArchiveSet::open(&to)?)),
+                Layout::Archive => {
+                    return Err(Error::Unsupported(format!(
+                        "{} is a single archive, which can't be added to",
+                        to.display()
+                    )));
+                }
+            };
+            let contains = |x: &chasset::Hash| match loose {
+                Some(ref loose) => loose.contains(x),
+                None => archives.as_ref().unwrap().contains(x),
+            };
+            let mut missing = Vec::new();
+            if all {
+                missing.extend(source.list().filter(|x| !contains(x)));
+            } else {
+                let mut resolved = hashes
+                    .iter()
+                    .map(|x| chasset::resolve(&*source, x))
+                    .collect::<io::Result<Vec<_>>>()?;
+                if stdin {
+                    let stdin = io::stdin();
+                    resolved.extend(read_hashes(stdin.lock())?);
+                }
+                missing.extend(resolved.into_iter().filter(|x| !contains(x)));
+            }
+            match loose {
+                Some(ref loose) => transfer(&*source, &missing, loose, quiet)?,
+                None => {
+                    let packer = archive::Packer::new(archive::Metadata {
+                        created: Some(SystemTime::now()),
+                        creator: Some("chasset".into()),
+                        ..archive::Metadata::default()
+                    });
+                    let bar = progress(quiet, Some(missing.len() as u64));
+                    let report = packer.pack(&to, missing.iter().cloned(), |x| {
+                        bar.inc(1);
+                        let asset = source.get(x)?;
+                        if hash_of(&mut &asset[..])? != *x {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("data for {} does not match its hash", x),
+                            ));
+                        }
+                        Ok(asset)
+                    })?;
+                    bar.finish_and_clear();
+                    for path in &report.volumes {
+                        println!("{}", path.display());
+                    }
+                    println!(
+                        "transferred {} assets, {} bytes",
+                        report.assets, report.bytes
+                    );
+                }
+            }
+        }
+        Command::Exists {
+            mut hashes,
+            stdin,
+            missing,
+        } => {
+            if stdin {
+                let stdin = io::stdin();
+                hashes.extend(read_hashes(stdin.lock())?);
+            }
+            let repo = layout.open(&path)?;
+            let present = repo.contains_many(&hashes);
+            let mut all = true;
+            for (hash, present) in hashes.iter().zip(present) {
+                if present {
+                    continue;
+                }
+                all = false;
+                if !missing {
+                    break;
+                }
+                if opt.json {
+                    emit(hash)?;
+                } else {
+                    println!("{}", hash);
+                }
+            }
+            if !all {
+                process::exit(1);
+            }
+        }
+        Command::Archives {
+            cmd: Some(ArchivesCommand::Show { file }),
+        } => {
+            let archive = Archive::open(&file)?;
+            for hash in archive.list() {
+                if opt.json {
+                    emit(&hash)?;
+                } else {
+                    println!("{}", hash);
+                }
+            }
+        }
+        Command::Archives { cmd: None } => {
+            let rows = match layout {
+                Layout::LooseFiles => {
+                    return Err(Error::Unsupported(format!(
+                        "{} holds no archives",
+                        path.display()
+                    )));
+                }
+                Layout::Archives => ArchiveSet::open(&path)?
+                    .archives()
+                    .iter()
+                    .map(|x| ArchiveRow {
+                        path: x.path.clone(),
+                        kind: x.kind.name(),
+                        assets: x.len,
+                        size: x.size,
+                        encrypted: x.encrypted,
+                        compressed: x.compressed,
+                        metadata: (&x.metadata).into(),
+                    })
+                    .collect::<Vec<_>>(),
+                Layout::Archive => {
+                    let x = Archive::open(&path)?;
+                    vec![ArchiveRow {
+                        path: x.path().into(),
+                        kind: x.kind().name(),
+                        assets: x.len(),
+                        size: x.size(),
+                        encrypted: x.is_encrypted(),
+                        compressed: x.is_compressed(),
+                        metadata: x.metadata().into(),
+                    }]
+                }
+            };
+            for row in &rows {
+                if opt.json {
+                    emit(row)?;
+                } else {
+                    println!("{}", row);
+                }
+            }
+        }
+        Command::Watch { interval } => {
+            #[cfg(feature = "notify")]
+            {
+                if layout == Layout::LooseFiles {
+                    return watch_loose(&path, opt.json);
+                }
+            }
+            let mut seen = layout.open(&path)?.list().collect::<HashSet<_>>();
+            loop {
+                thread::sleep(interval);
+                // Archives are indexed when opened, so reopen to find new ones
+                for hash in layout.open(&path)?.list() {
+                    if !seen.insert(hash) {
+                        continue;
+                    }
+                    if opt.json {
+                        emit(&hash)?;
+                    } else {
+                        println!("{}", hash);
+                    }
+                }
+                io::stdout().flush()?;
+            }
+        }
+        Command::Resolve { prefix } => {
+            println!("{}", chasset::resolve(&*layout.open(&path)?, &prefix)?);
+        }
+        #[cfg(feature = "tiny_http")]
+        Command::Serve {
+            listen,
+            threads,
+            listing,
+        } => {
+            use std::sync::Arc;
+
+            let mut server = serve::Server::bind(&listen, layout.open(&path)?)?;
+            server.listing(listing);
+            let server = Arc::new(server);
+            eprintln!("listening on {}", listen);
+            let workers = (1..threads.max(1))
+                .map(|_| {
+                    let server = server.clone();
+                    thread::spawn(move || server.run())
+                })
+                .collect::<Vec<_>>();
+            server.run()?;
+            for worker in workers {
+                worker.join().unwrap()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a positive, finite number of seconds.
+fn parse_interval(s: &str) -> std::result::Result<Duration, String> {
+    let secs = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if !(secs.is_finite() && secs > 0.0) {
+        return Err("interval must be a positive number of seconds".into());
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Print the hashes of assets as they're added to the loose files repository at `path`, until interrupted.
+#[cfg(feature = "notify")]
+fn watch_loose(path: &Path, json: bool) -> Result<()> {
+    let repo = LooseFiles::open(path.into())?;
+    // Only watch the directories holding assets, so that incomplete writes in "temp" aren't repeatedly hashed
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let is_kind = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|x| x.parse::<HashKind>().is_ok());
+        if is_kind && entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    if dirs.is_empty() {
+        let dir = path.join(HashKind::default().name());
+        fs::create_dir_all(&dir)?;
+        dirs.push(dir);
+    }
+    let (send, recv) = std::sync::mpsc::channel();
+    for dir in &dirs {
+        // Watching begins before listing, so that no asset added meanwhile is missed
+        let watcher = chasset::Watcher::new(NullStore, dir)?;
+        let send = send.clone();
+        thread::spawn(move || {
+            for event in watcher {
+                if send.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    drop(send);
+    let mut seen = repo.list().collect::<HashSet<_>>();
+    for event in recv {
+        let (_, hash) = event?;
+        if !seen.insert(hash) {
+            continue;
+        }
+        if json {
+            emit(&hash)?;
+        } else {
+            println!("{}", hash);
+        }
+        io::stdout().flush()?;
+    }
+    Ok(())
+}
+
+/// An asset and its size, as listed by `ls -l`.
+#[derive(Serialize)]
+struct Entry {
+    hash: chasset::Hash,
+    size: u64,
+}
+
+/// Where and how an asset is stored.
+#[derive(Serialize)]
+struct Stat {
+    hash: chasset::Hash,
+    kind: String,
+    size: u64,
+    stored_size: Option<u64>,
+    location: PathBuf,
+    offset: Option<u64>,
+    compressed: bool,
+    encrypted: bool,
+}
+
+/// A repository opened by its concrete type, for inspecting how assets are stored.
+enum Opened {
+    LooseFiles(LooseFiles),
+    Archives(ArchiveSet),
+    Archive(Archive),
+}
+
+impl Opened {
+    fn open(path: &Path, layout: Layout) -> io::Result<Self> {
+        Ok(match layout {
+            Layout::LooseFiles => Opened::LooseFiles(LooseFiles::open(path.into())?),
+            Layout::Archives => Opened::Archives(ArchiveSet::open(path)?),
+            Layout::Archive => Opened::Archive(Archive::open(path)?),
+        })
+    }
+}
+
+fn stat(repo: &Opened, hash: chasset::Hash) -> io::Result<Stat> {
+    let mut stat = Stat {
+        hash,
+        kind: hash.kind().name().into(),
+        size: 0,
+        stored_size: None,
+        location: PathBuf::new(),
+        offset: None,
+        compressed: false,
+        encrypted: false,
+    };
+    match *repo {
+        Opened::LooseFiles(ref repo) => {
+            stat.location = repo.path(&hash);
+            stat.size = fs::metadata(&stat.location)?.len();
+        }
+        Opened::Archives(ref repo) => {
+            let location = repo
+                .locate(&hash)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such asset"))?;
+            stat.size = repo.get(&hash)?.len() as u64;
+            stat.stored_size = Some(location.stored_len);
+            stat.location = location.archive.path.clone();
+            stat.offset = Some(location.offset);
+            stat.compressed = location.archive.compressed;
+            stat.encrypted = location.archive.encrypted;
+        }
+        Opened::Archive(ref archive) => {
+            stat.size = archive.get(&hash)?.len() as u64;
+            stat.location = archive.path().into();
+            stat.compressed = archive.is_compressed();
+            stat.encrypted = archive.is_encrypted();
+        }
+    }
+    Ok(stat)
+}
+
+/// An archive as listed by `archives`.
+#[derive(Serialize)]
+struct ArchiveRow {
+    path: PathBuf,
+    kind: &'static str,
+    assets: usize,
+    size: u64,
+    encrypted: bool,
+    compressed: bool,
+    metadata: ArchiveMetadata,
+}
+
+impl std::fmt::Display for ArchiveRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{} assets\t{} bytes",
+            self.path.display(),
+            self.kind,
+            self.assets,
+            self.size
+        )?;
+        if self.encrypted {
+            f.write_str("\tencrypted")?;
+        }
+        if self.compressed {
+            f.write_str("\tcompressed")?;
+        }
+        let meta = &self.metadata;
+        if let Some(x) = meta.created {
+            write!(f, "\tcreated={}", x)?;
+        }
+        if let Some(ref x) = meta.creator {
+            write!(f, "\tcreator={}", x)?;
+        }
+        if let Some(x) = meta.generation {
+            write!(f, "\tgeneration={}", x)?;
+        }
+        for (key, value) in &meta.extra {
+            write!(f, "\t{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// `archive::Metadata` with the creation time in seconds since the Unix epoch.
+#[derive(Serialize)]
+struct ArchiveMetadata {
+    created: Option<u64>,
+    creator: Option<String>,
+    generation: Option<u64>,
+    extra: BTreeMap<String, String>,
+}
+
+impl From<&archive::Metadata> for ArchiveMetadata {
+    fn from(x: &archive::Metadata) -> Self {
+        Self {
+            created: x
+                .created
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            creator: x.creator.clone(),
+            generation: x.generation,
+            extra: x.extra.clone(),
+        }
+    }
+}
+
+/// Space used by a set of assets.
+#[derive(Default, Serialize)]
+struct Usage {
+    assets: u64,
+    bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: u64) {
+        self.assets += 1;
+        self.bytes += bytes;
+    }
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} assets, {} bytes", self.assets, self.bytes)
+    }
+}
+
+fn print_diff(result: &RepoDiff, a: &Path, b: &Path, json: bool) -> io::Result<()> {
+    if json {
+        return emit(result);
+    }
+    for hash in &result.only_in_a {
+        println!("< {}", hash);
+    }
+    for hash in &result.only_in_b {
+        println!("> {}", hash);
+    }
+    println!(
+        "{} assets, {} bytes only in {}; {} assets, {} bytes only in {}; {} assets, {} bytes in both",
+        result.only_in_a.len(),
+        result.bytes_only_in_a,
+        a.display(),
+        result.only_in_b.len(),
+        result.bytes_only_in_b,
+        b.display(),
+        result.common.len(),
+        result.bytes_common
+    );
+    Ok(())
+}
+
+fn sync(path: &Path, layout: Layout, remote: &str, push: bool, quiet: bool) -> Result<()> {
+    if remote.starts_with("http://") || remote.starts_with("https://") {
+        if push {
+            return Err(Error::Unsupported("HTTP repositories are read-only".into()));
+        }
+        #[cfg(feature = "ureq")]
+        {
+            let remote = HttpStore::new(remote.into());
+            let local = open_loose(path, layout)?;
+            let missing = remote
+                .fetch_list()?
+                .into_iter()
+                .filter(|x| !local.contains(x))
+                .collect::<Vec<_>>();
+            return Ok(transfer(&remote, &missing, &local, quiet)?);
+        }
+        #[cfg(not(feature = "ureq"))]
+        {
+            return Err(Error::Unsupported("HTTP support is not enabled".into()));
+        }
+    }
+    let remote_path = Path::new(remote);
+    let remote_layout = chasset::detect(remote_path)?;
+    let (source, dest) = if push {
+        (layout.open(path)?, open_loose(remote_path, remote_layout)?)
+    } else {
+        (remote_layout.open(remote_path)?, open_loose(path, layout)?)
+    };
+    let mut missing = Vec::new();
+    diff::diff_each(&*source, &dest, |side, hash| {
+        if side == diff::Side::A {
+            missing.push(hash);
+        }
+    });
+    Ok(transfer(&*source, &missing, &dest, quiet)?)
+}
+
+fn open_loose(path: &Path, layout: Layout) -> Result<LooseFiles> {
+    if layout != Layout::LooseFiles {
+        return Err(Error::Unsupported(format!(
+            "{} is read-only",
+            path.display()
+        )));
+    }
+    Ok(LooseFiles::open(path.into())?)
+}
+
+/// Copy `hashes` from `source` into `dest`, verifying each and reporting progress.
+fn transfer<S: Store + ?Sized>(
+    source: &S,
+    hashes: &[chasset::Hash],
+    dest: &LooseFiles,
+    quiet: bool,
+) -> io::Result<()> {
+    let mut done = Usage::default();
+    let bar = progress(quiet, Some(hashes.len() as u64));
+    for hash in hashes {
+        let asset = match source.get(hash) {
+            Ok(x) => x,
+            // Removed concurrently
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        chasset::store_verified(dest, hash, &asset[..])?;
+        done.add(asset.len() as u64);
+        bar.inc(1);
+        bar.set_message(format!("{} bytes", done.bytes));
+    }
+    bar.finish_and_clear();
+    println!("transferred {}", done);
+    Ok(())
+}
+
+/// Summary of the space used by a repository.
+#[derive(Serialize)]
+struct Du<'a> {
+    total: Usage,
+    kinds: BTreeMap<&'a str, Usage>,
+    loose: Usage,
+    archived: Usage,
+    temp: Usage,
+    largest: Vec<(chasset::Hash, u64)>,
+}
+
+fn du(
+    path: &Path,
+    layout: Layout,
+    archives: Option<&Path>,
+    top: usize,
+    json: bool,
+) -> io::Result<()> {
+    let mut sizes = ContentMap::<u64>::default();
+    let mut loose = Usage::default();
+    let mut archived = Usage::default();
+    let mut temp = Usage::default();
+    let mut archive_dir = archives;
+    match layout {
+        Layout::LooseFiles => {
+            let repo = LooseFiles::open(path.into())?;
+            for hash in repo.list() {
+                let size = match fs::metadata(repo.path(&hash)) {
+                    Ok(x) => x.len(),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+                loose.add(size);
+                sizes.insert(hash, size);
+            }
+            // Suspended writes are also incomplete, until resumed or abandoned
+            for dir in &["temp", "suspended"] {
+                match fs::read_dir(path.join(dir)) {
+                    Ok(entries) => {
+                        for entry in entries {
+                            temp.add(entry?.metadata()?.len());
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Layout::Archives => archive_dir = Some(path),
+        Layout::Archive => {
+            let archive = Archive::open(path)?;
+            for entry in archive.list_sizes() {
+                let (hash, size) = entry?;
+                archived.add(size);
+                sizes.insert(hash, size);
+            }
+        }
+    }
+    if let Some(dir) = archive_dir {
+        let repo = ArchiveSet::open(dir)?;
+        for hash in repo.list() {
+            let size = repo.locate(&hash).map_or(0, |x| x.stored_len);
+            archived.add(size);
+            sizes.entry(hash).or_insert(size);
+        }
+    }
+
+    let mut total = Usage::default();
+    let mut kinds = BTreeMap::<&str, Usage>::new();
+    for (hash, &size) in &sizes {
+        total.add(size);
+        kinds.entry(hash.kind().name()).or_default().add(size);
+    }
+    let mut largest = sizes.into_iter().collect::<Vec<_>>();
+    largest.sort_unstable_by(|x, y| y.1.cmp(&x.1).then(x.0.cmp(&y.0)));
+    largest.truncate(top);
+
+    if json {
+        return emit(&Du {
+            total,
+            kinds,
+            loose,
+            archived,
+            temp,
+            largest,
+        });
+    }
+    println!("total: {}", total);
+    for (kind, usage) in &kinds {
+        println!("  {}: {}", kind, usage);
+    }
+    println!("loose: {}", loose);
+    println!("archived: {}", archived);
+    println!(
+        "temporary files: {} files, {} bytes",
+        temp.assets, temp.bytes
+    );
+    if !largest.is_empty() {
+        println!("largest:");
+        for (hash, size) in &largest {
+            println!("  {} {}", size, hash);
+        }
+    }
+    Ok(())
+}
+
+/// Display progress towards `len` items, or an unknown amount of work, on stderr unless `quiet`.
+fn progress(quiet: bool, len: Option<u64>) -> ProgressBar {
+    if quiet {
+        return ProgressBar::hidden();
+    }
+    match len {
+        Some(len) => {
+            let bar = ProgressBar::new(len);
+            bar.set_style(
+                ProgressStyle::with_template("{bar:40} {pos}/{len} {msg}").expect("valid template"),
+            );
+            bar
+        }
+        None => ProgressBar::new_spinner(),
+    }
+}
+
+/// Print `value` as a line of JSON.
+fn emit<T: Serialize>(value: &T) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer(&mut stdout, value)?;
+    writeln!(stdout)
+}
+
+fn hash_of<R: io::Read>(input: &mut R) -> io::Result<chasset::Hash> {
+    let mut hasher = Hasher::new();
+    io::copy(input, &mut hasher)?;
+    Ok(hasher.result())
+}
+
+/// Determine the assets to export and where, from either a single hash and destination or a directory to export
+/// assets whose hashes are read from stdin into.
+fn export_targets<S: Store + ?Sized>(
+    repo: &S,
+    hash: Option<String>,
+    dest: Option<PathBuf>,
+    stdin: Option<PathBuf>,
+) -> io::Result<Vec<(chasset::Hash, PathBuf)>> {
+    let dir = match stdin {
+        Some(x) => x,
+        None => {
+            let hash = chasset::resolve(repo, &hash.unwrap())?;
+            return Ok(vec![(hash, dest.unwrap())]);
+        }
+    };
+    fs::create_dir_all(&dir)?;
+    let stdin = io::stdin();
+    Ok(read_hashes(stdin.lock())?
+        .into_iter()
+        .map(|hash| (hash, dir.join(hash.to_string())))
+        .collect())
+}
+
+/// Parse one hash per line, ignoring blank lines.
+fn read_hashes<R: BufRead>(input: R) -> io::Result<Vec<chasset::Hash>> {
+    let mut result = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let hash = line.parse().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid hash {:?}: {}", line, e),
+            )
+        })?;
+        result.push(hash);
+    }
+    Ok(result)
+}
@@ -0,0 +1,145 @@
+//! Runs the `chasset` binary against temporary repositories.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, str};
+
+/// A fresh directory, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let path = std::env::temp_dir().join(format!(
+            "chasset-cli-{}-{}-{:08X}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed),
+            nanos
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run `chasset` with `args`, feeding it `stdin`, and ignoring any configuration file of the user running the tests.
+fn chasset(dir: &Path, args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_chasset"))
+        .args(args)
+        .env("CHASSET_CONFIG", dir.join("config.toml"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Run `chasset` as for `chasset`, requiring success, and return its standard output.
+fn stdout(dir: &Path, args: &[&str], stdin: &[u8]) -> String {
+    let output = chasset(dir, args, stdin);
+    assert!(
+        output.status.success(),
+        "chasset {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Store `data` in the loose files repository at `repo` with `cat`, returning its hash.
+fn put(dir: &Path, repo: &str, data: &[u8]) -> String {
+    stdout(dir, &[repo, "cat"], data).trim().to_owned()
+}
+
+#[test]
+fn cat() {
+    let dir = TempDir::new();
+    let repo = dir.0.join("repo");
+    fs::create_dir_all(&repo).unwrap();
+    let repo = repo.to_str().unwrap();
+    let hash = put(&dir.0, repo, b"hello");
+    assert_eq!(stdout(&dir.0, &[repo, "cat", &hash], b""), "hello");
+    let empty = put(&dir.0, repo, b"");
+    assert_ne!(empty, hash);
+    assert_eq!(stdout(&dir.0, &[repo, "cat", &empty], b""), "");
+    assert_eq!(
+        stdout(&dir.0, &[repo, "cat", &hash, &empty, &hash], b""),
+        "hellohello"
+    );
+    // Storing the same data again yields the same hash
+    assert_eq!(put(&dir.0, repo, b"hello"), hash);
+    let mut listed = stdout(&dir.0, &[repo, "ls"], b"")
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    listed.sort();
+    let mut expected = vec![hash, empty];
+    expected.sort();
+    assert_eq!(listed, expected);
+}
+
+#[test]
+fn pack() {
+    let dir = TempDir::new();
+    let repo = dir.0.join("repo");
+    fs::create_dir_all(&repo).unwrap();
+    let repo = repo.to_str().unwrap();
+    let archives = dir.0.join("archives");
+    let archives = archives.to_str().unwrap();
+    let hash = put(&dir.0, repo, b"hello");
+    let empty = put(&dir.0, repo, b"");
+    stdout(&dir.0, &[repo, "pack", "--remove-loose", archives], b"");
+    assert_eq!(stdout(&dir.0, &[repo, "ls"], b""), "");
+    assert_eq!(
+        stdout(&dir.0, &["-a", archives, "cat", &hash], b""),
+        "hello"
+    );
+    assert_eq!(stdout(&dir.0, &["-a", archives, "cat", &empty], b""), "");
+    assert_eq!(
+        stdout(&dir.0, &["-a", archives, "ls"], b"").lines().count(),
+        2
+    );
+}
+
+#[test]
+fn exit_codes() {
+    let dir = TempDir::new();
+    let repo = dir.0.join("repo");
+    fs::create_dir_all(&repo).unwrap();
+    let repo = repo.to_str().unwrap();
+    let hash = put(&dir.0, repo, b"hello");
+    let code = |args: &[&str], stdin: &[u8]| chasset(&dir.0, args, stdin).status.code();
+
+    // `check` and `exists` answer "no" with status 1
+    assert_eq!(code(&["check", &hash, "--stdin"], b"hello"), Some(0));
+    assert_eq!(code(&["check", &hash, "--stdin"], b"goodbye"), Some(1));
+    assert_eq!(code(&[repo, "exists", &hash], b""), Some(0));
+    let other = dir.0.join("other");
+    fs::create_dir_all(&other).unwrap();
+    let missing = put(&dir.0, other.to_str().unwrap(), b"missing");
+    let output = chasset(&dir.0, &[repo, "exists", "--missing", &hash, &missing], b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(str::from_utf8(&output.stdout).unwrap().trim(), missing);
+
+    // Malformed command lines are distinguished from "no"
+    assert_eq!(code(&[repo, "frobnicate"], b""), Some(3));
+    assert_eq!(code(&[repo, "ls", "--bogus"], b""), Some(3));
+    assert_eq!(code(&["check", "not-a-hash", "--stdin"], b""), Some(3));
+
+    // IO errors, e.g. from reading a missing asset
+    assert_eq!(code(&[repo, "cat", &missing], b""), Some(2));
+}
@@ -1,66 +1,686 @@
 //! Tools for a repository formed by a collection of archive files, each containing many assets.
 //!
-//! Uses `carchive` formatted files, with a 2-byte little-endian extension header identifying the hash kind.
+//! Uses `carchive` formatted files, with a 2-byte little-endian extension header identifying the hash kind. The hash
+//! kind may be followed by a 4-byte little-endian length and that many bytes of `Metadata`, encoded as a sequence of
+//! records each consisting of a 1-byte tag, a 4-byte little-endian length, and that many bytes of content. Records with
+//! unknown tags are ignored.
+//!
+//! An archive whose asset data is encrypted records the fingerprint of its `Key` in its metadata. Each encrypted asset
+//! is stored as a 24-byte random nonce followed by the XChaCha20-Poly1305 ciphertext of the asset, authenticated with
+//! the asset's hash as associated data. Hashes are always computed over the plaintext.
+//!
+//! An archive whose asset data is compressed records the hash of its zstd `Dictionary` in its metadata. Each compressed
+//! asset is stored as a zstd frame, and is compressed before being encrypted.
+//!
+//! Assets smaller than a threshold may be stored inline in a table following the `carchive` data, so that they're read
+//! from alongside the `carchive` index rather than the data region. The table consists of entries, each the asset's
+//! key, a 2-byte little-endian length, and that many bytes of asset data as it would otherwise be stored, followed by
+//! the 8-byte little-endian length of the entries and the 8-byte magic string `CHINLIN1`.
+//!
+//! An archive may carry an index table following the inline table, if any, so that it can be opened without reading the
+//! `carchive` data at all. The table consists of the 4-byte little-endian length and content of the `carchive`
+//! extension header, then for each asset stored in the `carchive` data its key, the 8-byte little-endian offset of its
+//! data from the start of the file, and the 8-byte little-endian length of its data, followed by the 8-byte
+//! little-endian length of the table and the 8-byte magic string `CHINDEX1`.
+//!
+//! An archive may end with a 33-byte checksum trailer: the `Hash` of every preceding byte of the file, followed by the
+//! 8-byte magic string `CHASSUM1`. None of these trailers are part of the `carchive` data.
+//!
+//! An archive may be accompanied by a detached signature stored alongside it with the added extension `.sig`,
+//! containing the 64-byte ed25519 signature of the archive's complete contents.
 
-use std::collections::HashMap;
+use std::collections::{hash_map, BTreeMap};
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io;
-use std::path::Path;
-use std::sync::Arc;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use byteorder::{ByteOrder, LittleEndian};
 use carchive;
-use memmap::Mmap;
+#[cfg(feature = "chacha20poly1305")]
+use chacha20poly1305::aead::{Aead, Payload};
+#[cfg(feature = "chacha20poly1305")]
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use data_encoding::BASE32_NOPAD;
+#[cfg(feature = "ed25519-dalek")]
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand;
 
-use crate::{Asset, Hash, HashKind};
+use crate::budget::{MapBudget, Slot};
+#[cfg(feature = "zstd")]
+use crate::compression::{self, Dictionary};
+#[cfg(feature = "chacha20poly1305")]
+pub use crate::encryption::Key;
+#[cfg(feature = "chacha20poly1305")]
+use crate::encryption::NONCE_LEN;
+use crate::store;
+#[cfg(feature = "metrics")]
+use crate::telemetry;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring;
+use crate::{
+    hash_of, map_file, not_found, Asset, ContentMap, ContentSet, Hash, HashKind, Hasher, Storage,
+    Store,
+};
 
 /// A repository formed by a collection of archive files, each containing many assets.
+///
+/// The indices of every archive are merged into a single in-memory table when the repository is opened, so each lookup
+/// costs one hash table probe regardless of how many archives are present. If multiple archives contain the same asset,
+/// the archive whose file name sorts first takes precedence.
 pub struct ArchiveSet {
-    archives: HashMap<HashKind, Vec<carchive::Reader<ArcMap>>>,
+    archives: Vec<Member>,
+    info: Vec<ArchiveInfo>,
+    index: ContentMap<Entry>,
 }
 
-impl ArchiveSet {
+/// Statistics describing one archive in an `ArchiveSet`.
+#[derive(Debug, Clone)]
+pub struct ArchiveInfo {
+    /// Location of the archive file
+    pub path: PathBuf,
+    /// Kind of hash identifying the assets in the archive
+    pub kind: HashKind,
+    /// Number of assets stored in the archive
+    pub len: usize,
+    /// Number of assets for which this archive takes precedence, i.e. which aren't also found in an earlier archive
+    pub live: usize,
+    /// Total size of all assets stored in the archive
+    pub data_bytes: u64,
+    /// Size of the archive file
+    pub size: u64,
+    /// Whether the archive's asset data is encrypted
+    pub encrypted: bool,
+    /// Whether the archive's asset data is compressed
+    pub compressed: bool,
+    /// Descriptive information recorded when the archive was written
+    pub metadata: Metadata,
+}
+
+/// Where an asset of an `ArchiveSet` is stored, as returned by `ArchiveSet::locate`.
+#[derive(Debug, Clone)]
+pub struct Location<'a> {
+    /// The archive that takes precedence for the asset
+    pub archive: &'a ArchiveInfo,
+    /// Offset of the asset's stored data from the start of the archive file
+    pub offset: u64,
+    /// Size of the asset's stored data, after any compression or encryption
+    pub stored_len: u64,
+}
+
+/// An archive within an `ArchiveSet`.
+struct Member {
+    source: Source,
+    #[cfg(feature = "chacha20poly1305")]
+    cipher: Option<XChaCha20Poly1305>,
+    #[cfg(feature = "zstd")]
+    dictionary: Option<Dictionary>,
+}
+
+impl Member {
+    /// Whether asset data must be decrypted or decompressed before use
+    fn is_encoded(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut encoded = false;
+        #[cfg(feature = "chacha20poly1305")]
+        {
+            encoded |= self.cipher.is_some();
+        }
+        #[cfg(feature = "zstd")]
+        {
+            encoded |= self.dictionary.is_some();
+        }
+        encoded
+    }
+}
+
+/// Where an archive's asset data is read from.
+enum Source {
+    Memory(Storage),
+    ReadAt(Box<dyn ReadAt + Send + Sync>),
+    /// Read on demand from a local file, which permits batching reads
+    File(File),
+    /// Mapped on demand, subject to a `MapBudget`
+    Managed {
+        file: File,
+        slot: Arc<Slot>,
+        budget: MapBudget,
+    },
+}
+
+impl Source {
+    fn read(&self, start: u64, len: u64) -> io::Result<Asset> {
+        match *self {
+            Source::Memory(ref data) => Ok(Asset {
+                storage: data.clone(),
+                start: start as usize,
+                len: len as usize,
+            }),
+            Source::ReadAt(ref source) => read_heap(&**source, start, len),
+            Source::File(ref file) => read_heap(file, start, len),
+            Source::Managed {
+                ref file,
+                ref slot,
+                ref budget,
+            } => match budget.get(slot, file)? {
+                Some(map) => Ok(Asset {
+                    storage: Storage::Map(map),
+                    start: start as usize,
+                    len: len as usize,
+                }),
+                None => read_heap(file, start, len),
+            },
+        }
+    }
+}
+
+impl ReadAt for Source {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match *self {
+            Source::Memory(ref data) => data.bytes().read_at(buf, offset),
+            Source::ReadAt(ref source) => source.read_at(buf, offset),
+            Source::File(ref file) | Source::Managed { ref file, .. } => file.read_at(buf, offset),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        match *self {
+            Source::Memory(ref data) => Ok(data.bytes().len() as u64),
+            Source::ReadAt(ref source) => source.size(),
+            Source::File(ref file) | Source::Managed { ref file, .. } => file.size(),
+        }
+    }
+}
+
+fn read_heap<R: ReadAt + ?Sized>(source: &R, start: u64, len: u64) -> io::Result<Asset> {
+    if len > usize::MAX as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "asset too large to load into memory",
+        ));
+    }
+    let mut buf = vec![0; len as usize];
+    read_exact_at(source, &mut buf, start)?;
+    Ok(Asset {
+        len: buf.len(),
+        storage: Storage::Heap(buf.into()),
+        start: 0,
+    })
+}
+
+/// Location of an asset within an `ArchiveSet`.
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    /// Index into `ArchiveSet::archives`
+    archive: usize,
+    /// Offset of the asset's data from the start of the archive
+    start: u64,
+    len: u64,
+}
+
+/// How asset data is read from archives.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Access {
+    /// Keep every archive memory-mapped, and return assets that refer directly into the mappings.
+    #[default]
+    Mapped,
+    /// Read only the trailers and index table of each archive when opening it, then read asset data into freshly
+    /// allocated buffers with positioned reads when requested.
+    ///
+    /// Suitable for archives on network mounts, where page faults on a mapping can stall for unacceptably long, and for
+    /// archives much larger than physical memory or the address space, since no asset data is ever mapped. Archives
+    /// lacking an index table, such as those written by earlier versions of this library, are mapped while their
+    /// `carchive` index is loaded, and must fit in the address space. Use `ArchiveSet::reader` to read large assets
+    /// without buffering them entirely.
+    Streaming,
+}
+
+/// Options for opening an `ArchiveSet`.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    access: Access,
+    checksums: bool,
+    budget: Option<MapBudget>,
+    #[cfg(feature = "chacha20poly1305")]
+    keys: Vec<Key>,
+    #[cfg(feature = "zstd")]
+    dictionaries: Vec<Dictionary>,
+    #[cfg(feature = "ed25519-dalek")]
+    trusted: Option<Vec<VerifyingKey>>,
+}
+
+impl OpenOptions {
+    /// Create options with the default settings, equivalent to those used by `ArchiveSet::open`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how asset data is read from archives. Defaults to `Access::Mapped`.
+    pub fn access(&mut self, access: Access) -> &mut Self {
+        self.access = access;
+        self
+    }
+
+    /// Under `Access::Mapped`, map archive files only while the mappings fit in `budget`, which may release them
+    /// again to make room for others. Asset data is read onto the heap when no mapping can be made.
+    ///
+    /// Archives written without an index table must be mapped in their entirety to be opened. Under either access mode,
+    /// those mappings are charged to `budget` for as long as the archive is open, and are never released early.
+    ///
+    /// Has no effect on archives opened with `open_sources`.
+    pub fn map_budget(&mut self, budget: MapBudget) -> &mut Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Require every archive to be signed by one of `keys`. Opening fails if any archive is unsigned or its contents
+    /// don't match its signature.
+    ///
+    /// Verification reads each archive in its entirety.
+    #[cfg(feature = "ed25519-dalek")]
+    pub fn trusted_keys(&mut self, keys: Vec<VerifyingKey>) -> &mut Self {
+        self.trusted = Some(keys);
+        self
+    }
+
+    /// Require every archive to end with a checksum trailer matching its contents, as written by
+    /// `Writer::finish_with_checksum`. Opening fails if any archive lacks a checksum or is corrupt.
+    ///
+    /// Verification reads each archive in its entirety, detecting damage incurred in transport before it can surface
+    /// from an arbitrary later `get`.
+    pub fn verify_checksums(&mut self, enabled: bool) -> &mut Self {
+        self.checksums = enabled;
+        self
+    }
+
+    /// Supply `key` for decrypting encrypted archives. May be called repeatedly to supply keys for different archives.
+    ///
+    /// Opening fails if an encrypted archive is encountered for which no key was supplied.
+    #[cfg(feature = "chacha20poly1305")]
+    pub fn key(&mut self, key: Key) -> &mut Self {
+        self.keys.push(key);
+        self
+    }
+
+    /// Supply `dictionary` for decompressing compressed archives. May be called repeatedly to supply dictionaries for
+    /// different archives.
+    ///
+    /// Opening fails if a compressed archive is encountered for which no dictionary was supplied.
+    #[cfg(feature = "zstd")]
+    pub fn dictionary(&mut self, dictionary: Dictionary) -> &mut Self {
+        self.dictionaries.push(dictionary);
+        self
+    }
+
     /// Open a repository located at `dir`, creating it if necessary.
-    pub fn open(dir: &Path) -> io::Result<Self> {
+    ///
+    /// Archives take precedence in lexicographic order of their file names. With the `rayon` feature enabled, archives
+    /// are opened and validated concurrently.
+    pub fn open(&self, dir: &Path) -> io::Result<ArchiveSet> {
         fs::create_dir_all(dir)?;
-        let mut archives = HashMap::new();
+        let mut paths = Vec::new();
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
-            let file = File::open(entry.path())?;
-            let map = ArcMap(Arc::new(unsafe { Mmap::map(&file) }?));
-            let archive = carchive::Reader::new(map)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            let kind = {
-                let x = archive
-                    .extensions(2)
-                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid archive"))?;
-                HashKind::from_id(x[0] as u16 | (x[1] as u16) << 8).ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::InvalidData, "archive uses unknown hash kind")
-                })?
-            };
-            if kind.len() != archive.key_len() as usize {
+            if is_hidden(&entry.file_name()) {
+                // Incomplete output of a `Packer`
+                continue;
+            }
+            let path = entry.path();
+            if path.extension().is_some_and(|x| x == SIGNATURE_EXTENSION) {
+                continue;
+            }
+            paths.push(path);
+        }
+        paths.sort_unstable();
+
+        #[cfg(feature = "rayon")]
+        let opened = {
+            use rayon::prelude::*;
+            paths
+                .into_par_iter()
+                .map(|path| self.open_archive(path))
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        #[cfg(not(feature = "rayon"))]
+        let opened = paths
+            .into_iter()
+            .map(|path| self.open_archive(path))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.assemble(opened)
+    }
+
+    /// Open a repository formed by archives read from arbitrary `sources`, each labeled with a name.
+    ///
+    /// Allows archives to be read through custom IO, such as a ranged HTTP client or a platform asset manager. Only
+    /// each source's trailers and index table are read when opened, and asset data is read on demand. Archives written
+    /// without an index table must be read in their entirety, since `carchive` requires contiguous access to its index;
+    /// under `Access::Streaming`, their asset data is subsequently read on demand from the source, and under
+    /// `Access::Mapped` the data read while opening is retained.
+    ///
+    /// Archives take precedence in the order given. Signatures are sought alongside the names.
+    pub fn open_sources<I>(&self, sources: I) -> io::Result<ArchiveSet>
+    where
+        I: IntoIterator<Item = (PathBuf, Box<dyn ReadAt + Send + Sync>)>,
+    {
+        let opened = sources
+            .into_iter()
+            .map(|(name, source)| {
+                let archive = match Tables::read(&*source)? {
+                    Some(tables) => Archive::from_tables(Source::ReadAt(source), name, tables),
+                    None => {
+                        let mut archive = Archive::read_all(&*source, name)?;
+                        if self.access == Access::Streaming {
+                            archive.source = Source::ReadAt(source);
+                        }
+                        archive
+                    }
+                };
+                self.prepare(archive)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.assemble(opened)
+    }
+
+    /// Open a repository formed by archives embedded in the executable, e.g. via `include_bytes!`, each labeled with
+    /// a name.
+    ///
+    /// Under `Access::Mapped`, assets refer directly into the embedded data.
+    /// Archives take precedence in the order given. Unless `trusted_keys` is set, no filesystem access is performed;
+    /// otherwise, signatures are sought alongside the names.
+    pub fn open_static<I>(&self, archives: I) -> io::Result<ArchiveSet>
+    where
+        I: IntoIterator<Item = (&'static str, &'static [u8])>,
+    {
+        let opened = archives
+            .into_iter()
+            .map(|(name, data)| {
+                let mut archive = Archive::from_static(data, name.into())?;
+                if self.access == Access::Streaming {
+                    archive.source = Source::ReadAt(Box::new(data));
+                }
+                self.prepare(archive)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.assemble(opened)
+    }
+
+    fn assemble(&self, opened: Vec<Archive>) -> io::Result<ArchiveSet> {
+        let mut archives = Vec::with_capacity(opened.len());
+        let mut info = Vec::with_capacity(opened.len());
+        let mut index = ContentMap::default();
+        for archive in opened {
+            let id = archives.len();
+            let mut data_bytes = 0;
+            let mut live = 0;
+            for (hash, start, len) in archive.entries() {
+                data_bytes += len;
+                if let hash_map::Entry::Vacant(e) = index.entry(hash) {
+                    e.insert(Entry {
+                        archive: id,
+                        start,
+                        len,
+                    });
+                    live += 1;
+                }
+            }
+            info.push(ArchiveInfo {
+                path: archive.path.clone(),
+                kind: archive.kind,
+                len: archive.len,
+                live,
+                data_bytes,
+                size: archive.size(),
+                encrypted: archive.is_encrypted(),
+                compressed: archive.is_compressed(),
+                metadata: archive.metadata.clone(),
+            });
+            if archive.fingerprint.is_some() && !archive.has_key() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no key supplied for {}", archive.path.display()),
+                ));
+            }
+            if archive.dictionary_hash.is_some() && !archive.has_dictionary() {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    "archive key length doesn't match hash type",
+                    format!("no dictionary supplied for {}", archive.path.display()),
                 ));
             }
-            archives.entry(kind).or_insert_with(Vec::new).push(archive);
+            archives.push(Member {
+                source: archive.source,
+                #[cfg(feature = "chacha20poly1305")]
+                cipher: archive.cipher,
+                #[cfg(feature = "zstd")]
+                dictionary: archive.dictionary,
+            });
+        }
+        Ok(ArchiveSet {
+            archives,
+            info,
+            index,
+        })
+    }
+
+    fn open_archive(&self, path: PathBuf) -> io::Result<Archive> {
+        let file = File::open(&path)?;
+        let archive = match (self.access, &self.budget) {
+            (Access::Mapped, None) => Archive::from_file(&file, path)?,
+            (Access::Mapped, Some(budget)) => match Tables::read(&file)? {
+                Some(tables) => {
+                    let source = Source::Managed {
+                        file,
+                        slot: budget.slot(),
+                        budget: budget.clone(),
+                    };
+                    Archive::from_tables(source, path, tables)
+                }
+                // The mapping backing the index also serves asset data, rather than mapping the file a second time
+                None => self.open_whole(&file, path)?,
+            },
+            (Access::Streaming, _) => match Tables::read(&file)? {
+                Some(tables) => Archive::from_tables(Source::File(file), path, tables),
+                None => {
+                    let mut archive = self.open_whole(&file, path)?;
+                    archive.source = Source::File(file);
+                    archive
+                }
+            },
+        };
+        self.prepare(archive)
+    }
+
+    /// Open an archive lacking an index table, which must be mapped in its entirety to load its `carchive` index.
+    ///
+    /// The mapping is charged to the `MapBudget`, if any, for as long as the archive is open. If the budget doesn't
+    /// allow it, the archive is read onto the heap instead.
+    fn open_whole(&self, file: &File, path: PathBuf) -> io::Result<Archive> {
+        let budget = match self.budget {
+            Some(ref x) => x,
+            None => return Archive::from_file(file, path),
+        };
+        match budget.map(file)? {
+            Some(map) => Archive::from_storage(Storage::Map(map), path),
+            None => Archive::read_all(file, path),
+        }
+    }
+
+    /// Apply decryption keys, dictionaries, and verification to a freshly opened archive.
+    fn prepare(&self, #[allow(unused_mut)] mut archive: Archive) -> io::Result<Archive> {
+        #[cfg(feature = "chacha20poly1305")]
+        {
+            if let Some(fingerprint) = archive.fingerprint {
+                if let Some(key) = self.keys.iter().find(|x| x.fingerprint() == fingerprint) {
+                    archive.cipher = Some(key.cipher());
+                }
+            }
         }
-        Ok(Self { archives })
+        #[cfg(feature = "zstd")]
+        {
+            if let Some(hash) = archive.dictionary_hash {
+                archive.dictionary = self
+                    .dictionaries
+                    .iter()
+                    .find(|x| *x.hash() == hash)
+                    .cloned();
+            }
+        }
+        if self.checksums {
+            archive.verify_checksum()?;
+        }
+        #[cfg(feature = "ed25519-dalek")]
+        {
+            if let Some(ref keys) = self.trusted {
+                archive.verify(keys)?;
+            }
+        }
+        Ok(archive)
+    }
+}
+
+impl ArchiveSet {
+    /// Open a repository located at `dir` with default options, creating it if necessary.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        OpenOptions::new().open(dir)
+    }
+
+    /// Open a repository located at `dir`, requiring every archive to be signed by one of `keys`.
+    ///
+    /// See `OpenOptions::trusted_keys`.
+    #[cfg(feature = "ed25519-dalek")]
+    pub fn open_verified(dir: &Path, keys: Vec<VerifyingKey>) -> io::Result<Self> {
+        OpenOptions::new().trusted_keys(keys).open(dir)
     }
 
     /// Access the asset identified by `hash`.
-    pub fn get(&self, hash: &Hash) -> Option<Asset> {
-        for archive in self.archives.get(&hash.kind())? {
-            if let Some(x) = archive.get(hash.bytes()) {
-                let base = x.as_ptr() as usize - archive.get_ref().0.as_ptr() as usize;
-                return Some(Asset {
-                    map: archive.get_ref().0.clone(),
-                    start: base,
-                    len: x.len(),
-                });
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let result = self.read(hash);
+        #[cfg(feature = "metrics")]
+        telemetry::read("archive_set", &result);
+        result
+    }
+
+    fn read(&self, hash: &Hash) -> io::Result<Asset> {
+        let entry = self.lookup(hash).ok_or_else(not_found)?;
+        let member = &self.archives[entry.archive];
+        let asset = member.source.read(entry.start, entry.len)?;
+        #[cfg(feature = "chacha20poly1305")]
+        let asset = match member.cipher {
+            Some(ref cipher) => decrypt(cipher, hash, &asset)?,
+            None => asset,
+        };
+        #[cfg(feature = "zstd")]
+        {
+            if let Some(ref dictionary) = member.dictionary {
+                return Ok(dictionary.decompress(&asset)?.into());
+            }
+        }
+        Ok(asset)
+    }
+
+    /// Access each asset identified by `hashes`, in the same order.
+    ///
+    /// Assets are read in order of their location within each archive, improving locality. With the `rayon` feature
+    /// enabled, assets are read concurrently. With the `io-uring` feature enabled on Linux, unencoded assets in
+    /// archives opened under `Access::Streaming` are read in batches through io_uring where the kernel supports it.
+    pub fn get_many(&self, hashes: &[Hash]) -> Vec<io::Result<Asset>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(assets) = self.get_many_uring(hashes) {
+                return assets;
+            }
+        }
+        store::get_ordered(
+            hashes,
+            |x| self.lookup(x).map(|entry| (entry.archive, entry.start)),
+            |x| self.get(x),
+        )
+    }
+
+    /// Read assets stored in plain files through io_uring, leaving the rest to `get`.
+    ///
+    /// Returns `None` if no archive is read from a plain file, or if io_uring is unavailable.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn get_many_uring(&self, hashes: &[Hash]) -> Option<Vec<io::Result<Asset>>> {
+        if !self
+            .archives
+            .iter()
+            .any(|x| matches!(x.source, Source::File(_)))
+        {
+            return None;
+        }
+        let mut result = hashes.iter().map(|_| None).collect::<Vec<_>>();
+        let mut batched = Vec::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            let entry = match self.lookup(hash) {
+                Some(x) => x,
+                None => {
+                    result[i] = Some(Err(not_found()));
+                    continue;
+                }
+            };
+            let member = &self.archives[entry.archive];
+            match member.source {
+                Source::File(ref file) if !member.is_encoded() && entry.len <= u32::MAX as u64 => {
+                    batched.push((i, entry.archive, entry.start, entry.len, file));
+                }
+                _ => result[i] = Some(self.get(hash)),
             }
         }
-        None
+        batched.sort_unstable_by_key(|&(_, archive, start, _, _)| (archive, start));
+        let reads = batched
+            .iter()
+            .map(|&(_, _, start, len, file)| uring::Read {
+                file,
+                offset: start,
+                len: len as u32,
+            })
+            .collect::<Vec<_>>();
+        for (&(i, _, _, len, _), data) in batched.iter().zip(uring::read_many(&reads)?) {
+            result[i] = Some(data.map(|data| Asset {
+                storage: Storage::Heap(data.into()),
+                start: 0,
+                len: len as usize,
+            }));
+        }
+        Some(result.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Incrementally read the asset identified by `hash`.
+    ///
+    /// Unlike `get`, this never holds more of the asset in memory than the caller asks for at once, making it suitable
+    /// for assets too large to buffer in their entirety under `Access::Streaming`. Encrypted and compressed assets must
+    /// be decoded in their entirety, and are therefore always buffered.
+    pub fn reader(&self, hash: &Hash) -> io::Result<AssetReader<'_>> {
+        let entry = self.lookup(hash).ok_or_else(not_found)?;
+        let member = &self.archives[entry.archive];
+        if member.is_encoded() {
+            let asset = self.get(hash)?;
+            return Ok(AssetReader {
+                len: asset.len() as u64,
+                source: ReadSource::Buffer(asset),
+                start: 0,
+                pos: 0,
+            });
+        }
+        Ok(AssetReader {
+            source: ReadSource::Archive(&member.source),
+            start: entry.start,
+            len: entry.len,
+            pos: 0,
+        })
+    }
+
+    fn lookup(&self, hash: &Hash) -> Option<&Entry> {
+        self.index.get(hash)
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.lookup(hash).is_some()
     }
 
     /// Enumerate assets stored in the repository.
@@ -68,21 +688,2251 @@ impl ArchiveSet {
     /// This should only be used for diagnostic purposes. It almost never makes sense to access an asset you don't
     /// already know the hash of.
     pub fn list<'a>(&'a self) -> impl Iterator<Item = Hash> + 'a {
-        self.archives.iter().flat_map(|(&kind, xs)| {
-            xs.iter().flat_map(move |archive| {
-                archive.iter().map(move |(key, _)| {
-                    Hash::from_bytes(kind, key)
-                        .expect("archives with invalid key lengths aren't opened")
-                })
-            })
+        self.index.keys().cloned()
+    }
+
+    /// Describe each archive in the repository, in order of precedence.
+    pub fn archives(&self) -> &[ArchiveInfo] {
+        &self.info
+    }
+
+    /// Enumerate assets stored in the repository along with their sizes in bytes.
+    ///
+    /// Only encrypted and compressed assets must be read to determine their size.
+    pub fn list_sizes(&self) -> impl Iterator<Item = io::Result<(Hash, u64)>> + '_ {
+        self.index.iter().map(move |(hash, entry)| {
+            let info = &self.info[entry.archive];
+            if info.encrypted || info.compressed {
+                return self.get(hash).map(|x| (*hash, x.len() as u64));
+            }
+            Ok((*hash, entry.len))
+        })
+    }
+
+    /// Find where the asset identified by `hash` is stored, if present.
+    pub fn locate(&self, hash: &Hash) -> Option<Location<'_>> {
+        let entry = self.lookup(hash)?;
+        Some(Location {
+            archive: &self.info[entry.archive],
+            offset: entry.start,
+            stored_len: entry.len,
         })
     }
 }
 
-struct ArcMap(Arc<Mmap>);
+impl Store for ArchiveSet {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
 
-impl AsRef<[u8]> for ArcMap {
-    fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+
+    fn list_sizes(&self) -> Box<dyn Iterator<Item = io::Result<(Hash, u64)>> + '_> {
+        Box::new(self.list_sizes())
+    }
+
+    fn get_many(&self, hashes: &[Hash]) -> Vec<io::Result<Asset>> {
+        self.get_many(hashes)
+    }
+}
+
+/// Reads a single asset from an `ArchiveSet` on demand.
+pub struct AssetReader<'a> {
+    source: ReadSource<'a>,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+enum ReadSource<'a> {
+    Archive(&'a Source),
+    Buffer(Asset),
+}
+
+impl<'a> AssetReader<'a> {
+    /// Size of the asset in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the asset is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'a> io::Read for AssetReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.len.saturating_sub(self.pos) as usize);
+        let offset = self.start + self.pos;
+        let n = match &self.source {
+            ReadSource::Archive(source) => source.read_at(&mut buf[..n], offset)?,
+            ReadSource::Buffer(asset) => {
+                let offset = offset as usize;
+                buf[..n].copy_from_slice(&asset[offset..offset + n]);
+                n
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> Seek for AssetReader<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            io::SeekFrom::Start(x) => Some(x),
+            io::SeekFrom::End(x) => offset(self.len, x),
+            io::SeekFrom::Current(x) => offset(self.pos, x),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+fn offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+/// A single archive file.
+pub struct Archive {
+    source: Source,
+    index: Index,
+    kind: HashKind,
+    len: usize,
+    path: PathBuf,
+    size: u64,
+    checksum: bool,
+    metadata: Metadata,
+    /// Fingerprint of the key this archive's asset data is encrypted with, if any
+    fingerprint: Option<Hash>,
+    #[cfg(feature = "chacha20poly1305")]
+    cipher: Option<XChaCha20Poly1305>,
+    /// Hash of the dictionary this archive's asset data is compressed with, if any
+    dictionary_hash: Option<Hash>,
+    #[cfg(feature = "zstd")]
+    dictionary: Option<Dictionary>,
+}
+
+/// How the assets of an `Archive` are found.
+enum Index {
+    /// The `carchive` index of contents held in memory, and the offset and length of each asset stored inline
+    Carchive {
+        reader: carchive::Reader<ArcMap>,
+        inline: ContentMap<(u64, u64)>,
+    },
+    /// The offset and length of every asset, read from the index and inline tables
+    Table(ContentMap<(u64, u64)>),
+}
+
+/// Information recorded in an archive's `carchive` extension header.
+struct Header {
+    kind: HashKind,
+    metadata: Metadata,
+    fingerprint: Option<Hash>,
+    dictionary_hash: Option<Hash>,
+}
+
+impl Header {
+    fn parse(x: &[u8]) -> io::Result<Self> {
+        if x.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid archive",
+            ));
+        }
+        let kind = HashKind::from_id(x[0] as u16 | (x[1] as u16) << 8).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "archive uses unknown hash kind")
+        })?;
+        if x.len() < 6 {
+            return Ok(Self {
+                kind,
+                metadata: Metadata::default(),
+                fingerprint: None,
+                dictionary_hash: None,
+            });
+        }
+        let len = LittleEndian::read_u32(&x[2..6]) as usize;
+        if x.len() - 6 < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated archive metadata",
+            ));
+        }
+        let records = records(&x[6..6 + len])?;
+        let fingerprint = match records.iter().find(|&&(tag, _)| tag == TAG_KEY) {
+            None => None,
+            Some(&(_, x)) => {
+                Some(Hash::from_bytes(HashKind::Blake2b, x).map_err(|_| invalid_metadata())?)
+            }
+        };
+        let dictionary_hash = match records.iter().find(|&&(tag, _)| tag == TAG_DICTIONARY) {
+            None => None,
+            Some(&(_, x)) => {
+                Some(Hash::from_bytes(HashKind::Blake2b, x).map_err(|_| invalid_metadata())?)
+            }
+        };
+        Ok(Self {
+            kind,
+            metadata: Metadata::from_records(&records)?,
+            fingerprint,
+            dictionary_hash,
+        })
+    }
+
+    /// The extension header of `reader`, consisting of the hash kind and any metadata.
+    fn bytes<T: AsRef<[u8]>>(reader: &carchive::Reader<T>) -> io::Result<&[u8]> {
+        let len = match reader.extensions(6) {
+            None => {
+                return reader
+                    .extensions(2)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid archive"));
+            }
+            Some(x) => LittleEndian::read_u32(&x[2..6]) as usize,
+        };
+        reader
+            .extensions(6 + len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated archive metadata"))
+    }
+}
+
+/// The index and inline tables of an archive, read without touching its `carchive` data.
+struct Tables {
+    header: Header,
+    /// Offset and length of every asset
+    entries: ContentMap<(u64, u64)>,
+    trailers: Trailers,
+}
+
+impl Tables {
+    /// Read the tables of the archive in `source`, or return `None` if it has no index table.
+    fn read<R: ReadAt + ?Sized>(source: &R) -> io::Result<Option<Self>> {
+        let trailers = Trailers::read(source)?;
+        let range = match trailers.index {
+            Some(ref x) => x.clone(),
+            None => return Ok(None),
+        };
+        let mut table = vec![0; (range.end - range.start) as usize];
+        read_exact_at(source, &mut table, range.start)?;
+        if table.len() < 4 {
+            return Err(invalid_index());
+        }
+        let header_len = LittleEndian::read_u32(&table) as usize;
+        if table.len() - 4 < header_len {
+            return Err(invalid_index());
+        }
+        let header = Header::parse(&table[4..4 + header_len])?;
+        let stride = header.kind.len() + 16;
+        let records = &table[4 + header_len..];
+        if !records.len().is_multiple_of(stride) {
+            return Err(invalid_index());
+        }
+        let mut entries = ContentMap::default();
+        for x in records.chunks(stride) {
+            let hash = Hash::from_bytes(header.kind, &x[..header.kind.len()]).unwrap();
+            let start = LittleEndian::read_u64(&x[header.kind.len()..]);
+            let len = LittleEndian::read_u64(&x[header.kind.len() + 8..]);
+            if start
+                .checked_add(len)
+                .is_none_or(|end| end > trailers.contents)
+            {
+                return Err(invalid_index());
+            }
+            entries.insert(hash, (start, len));
+        }
+        if let Some(ref range) = trailers.inline {
+            let mut table = vec![0; (range.end - range.start) as usize];
+            read_exact_at(source, &mut table, range.start)?;
+            parse_inline(&table, range.start, header.kind, &mut entries)?;
+        }
+        Ok(Some(Self {
+            header,
+            entries,
+            trailers,
+        }))
+    }
+}
+
+/// Record the offset and length of each entry of the inline table `table`, which starts `base` bytes into the archive.
+fn parse_inline(
+    mut table: &[u8],
+    base: u64,
+    kind: HashKind,
+    out: &mut ContentMap<(u64, u64)>,
+) -> io::Result<()> {
+    let end = base + table.len() as u64;
+    while !table.is_empty() {
+        if table.len() < kind.len() + 2 {
+            return Err(invalid_inline());
+        }
+        let hash = Hash::from_bytes(kind, &table[..kind.len()]).unwrap();
+        let len = LittleEndian::read_u16(&table[kind.len()..]) as usize;
+        let rest = &table[kind.len() + 2..];
+        if rest.len() < len {
+            return Err(invalid_inline());
+        }
+        out.insert(hash, (end - rest.len() as u64, len as u64));
+        table = &rest[len..];
+    }
+    Ok(())
+}
+
+impl Archive {
+    /// Open the archive at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::from_file(&File::open(path)?, path.into())
+    }
+
+    /// Open an archive read from `source`, labeled with `name`.
+    ///
+    /// Only the archive's trailers and index table are read up front, with asset data read from `source` on demand.
+    /// Archives written without an index table are read into memory in their entirety.
+    pub fn from_read_at(source: Box<dyn ReadAt + Send + Sync>, name: PathBuf) -> io::Result<Self> {
+        match Tables::read(&*source)? {
+            Some(tables) => Ok(Self::from_tables(Source::ReadAt(source), name, tables)),
+            None => Self::read_all(&*source, name),
+        }
+    }
+
+    /// Open an archive by reading the entirety of `source` into memory.
+    fn read_all(source: &dyn ReadAt, name: PathBuf) -> io::Result<Self> {
+        let mut data = vec![0; source.size()? as usize];
+        read_exact_at(source, &mut data, 0)?;
+        Self::from_storage(Storage::Heap(data.into()), name)
+    }
+
+    /// Open an archive embedded in the executable, e.g. via `include_bytes!`, labeled with `name`.
+    ///
+    /// The data is used in place, without copying.
+    pub fn from_static(data: &'static [u8], name: PathBuf) -> io::Result<Self> {
+        Self::from_storage(Storage::Static(data), name)
+    }
+
+    fn from_file(file: &File, path: PathBuf) -> io::Result<Self> {
+        let map = map_file(file)?;
+        Self::from_storage(Storage::Map(map), path)
+    }
+
+    /// Open an archive whose asset data is read from `source`, given its previously read `tables`.
+    fn from_tables(source: Source, path: PathBuf, tables: Tables) -> Self {
+        let len = tables.entries.len();
+        Self::new(
+            source,
+            Index::Table(tables.entries),
+            tables.header,
+            len,
+            path,
+            &tables.trailers,
+        )
+    }
+
+    fn new(
+        source: Source,
+        index: Index,
+        header: Header,
+        len: usize,
+        path: PathBuf,
+        trailers: &Trailers,
+    ) -> Self {
+        Self {
+            source,
+            index,
+            kind: header.kind,
+            len,
+            path,
+            size: trailers.size,
+            checksum: trailers.checksum,
+            metadata: header.metadata,
+            fingerprint: header.fingerprint,
+            #[cfg(feature = "chacha20poly1305")]
+            cipher: None,
+            dictionary_hash: header.dictionary_hash,
+            #[cfg(feature = "zstd")]
+            dictionary: None,
+        }
+    }
+
+    fn from_storage(data: Storage, path: PathBuf) -> io::Result<Self> {
+        let trailers = Trailers::read(data.bytes())?;
+        let map = ArcMap {
+            len: trailers.contents as usize,
+            data: data.clone(),
+        };
+        let reader = carchive::Reader::new(map)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let header = Header::parse(Header::bytes(&reader)?)?;
+        if header.kind.len() != reader.key_len() as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive key length doesn't match hash type",
+            ));
+        }
+        let mut inline = ContentMap::default();
+        if let Some(ref range) = trailers.inline {
+            let table = &data.bytes()[range.start as usize..range.end as usize];
+            parse_inline(table, range.start, header.kind, &mut inline)?;
+        }
+        let len = reader.iter().count() + inline.len();
+        Ok(Self::new(
+            Source::Memory(data),
+            Index::Carchive { reader, inline },
+            header,
+            len,
+            path,
+            &trailers,
+        ))
+    }
+
+    /// Whether the archive's asset data is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.fingerprint.is_some()
+    }
+
+    /// Supply the key needed to decrypt an encrypted archive.
+    ///
+    /// Fails if the archive isn't encrypted with `key`.
+    #[cfg(feature = "chacha20poly1305")]
+    pub fn set_key(&mut self, key: &Key) -> io::Result<()> {
+        if self.fingerprint != Some(key.fingerprint()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archive is not encrypted with this key",
+            ));
+        }
+        self.cipher = Some(key.cipher());
+        Ok(())
+    }
+
+    fn has_key(&self) -> bool {
+        #[cfg(feature = "chacha20poly1305")]
+        {
+            self.cipher.is_some()
+        }
+        #[cfg(not(feature = "chacha20poly1305"))]
+        {
+            false
+        }
+    }
+
+    /// Whether the archive's asset data is compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.dictionary_hash.is_some()
+    }
+
+    /// Supply the dictionary needed to decompress a compressed archive.
+    ///
+    /// Fails if the archive isn't compressed with `dictionary`.
+    #[cfg(feature = "zstd")]
+    pub fn set_dictionary(&mut self, dictionary: &Dictionary) -> io::Result<()> {
+        if self.dictionary_hash != Some(*dictionary.hash()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archive is not compressed with this dictionary",
+            ));
+        }
+        self.dictionary = Some(dictionary.clone());
+        Ok(())
+    }
+
+    fn has_dictionary(&self) -> bool {
+        #[cfg(feature = "zstd")]
+        {
+            self.dictionary.is_some()
+        }
+        #[cfg(not(feature = "zstd"))]
+        {
+            false
+        }
+    }
+
+    /// Access the asset identified by `hash`.
+    ///
+    /// Fails for encrypted archives unless the key has been supplied with `set_key`, and compressed archives unless
+    /// the dictionary has been supplied with `set_dictionary`.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let (start, len) = self.lookup(hash).ok_or_else(not_found)?;
+        let asset = self.source.read(start, len)?;
+        let asset = self.decrypt(hash, asset)?;
+        if self.dictionary_hash.is_none() {
+            return Ok(asset);
+        }
+        #[cfg(feature = "zstd")]
+        {
+            if let Some(ref dictionary) = self.dictionary {
+                return Ok(dictionary.decompress(&asset)?.into());
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "archive is compressed",
+        ))
+    }
+
+    /// Decrypt `asset` if the archive is encrypted.
+    fn decrypt(&self, #[allow(unused_variables)] hash: &Hash, asset: Asset) -> io::Result<Asset> {
+        if self.fingerprint.is_none() {
+            return Ok(asset);
+        }
+        #[cfg(feature = "chacha20poly1305")]
+        {
+            if let Some(ref cipher) = self.cipher {
+                return decrypt(cipher, hash, &asset);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "archive is encrypted",
+        ))
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the archive.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.lookup(hash).is_some()
+    }
+
+    /// Find the offset and length of the stored data of the asset identified by `hash`.
+    fn lookup(&self, hash: &Hash) -> Option<(u64, u64)> {
+        if hash.kind() != self.kind {
+            return None;
+        }
+        match self.index {
+            Index::Carchive {
+                ref reader,
+                ref inline,
+            } => {
+                if let Some(&x) = inline.get(hash) {
+                    return Some(x);
+                }
+                let data = reader.get(hash.bytes())?;
+                Some((offset_of(reader, data), data.len() as u64))
+            }
+            Index::Table(ref entries) => entries.get(hash).cloned(),
+        }
+    }
+
+    /// Enumerate assets stored in the archive.
+    pub fn list<'a>(&'a self) -> impl Iterator<Item = Hash> + 'a {
+        self.entries().map(|(hash, _, _)| hash)
+    }
+
+    /// Enumerate assets stored in the archive along with their sizes in bytes.
+    ///
+    /// Only encrypted and compressed assets must be read to determine their size.
+    pub fn list_sizes(&self) -> impl Iterator<Item = io::Result<(Hash, u64)>> + '_ {
+        let encoded = self.is_encrypted() || self.is_compressed();
+        self.entries().map(move |(hash, _, len)| {
+            if encoded {
+                return self.get(&hash).map(|x| (hash, x.len() as u64));
+            }
+            Ok((hash, len))
+        })
+    }
+
+    /// Number of assets stored in the archive.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the archive contains no assets.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Kind of hash identifying the assets in this archive.
+    pub fn kind(&self) -> HashKind {
+        self.kind
+    }
+
+    /// Location of the archive file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Size of the archive file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the archive ends with a checksum trailer.
+    pub fn has_checksum(&self) -> bool {
+        self.checksum
+    }
+
+    /// Check that the archive ends with a checksum trailer matching its contents.
+    ///
+    /// Reads the archive in its entirety.
+    pub fn verify_checksum(&self) -> io::Result<()> {
+        if !self.checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has no checksum", self.path.display()),
+            ));
+        }
+        let len = self.size - CHECKSUM_LEN as u64;
+        let mut checksum = [0; crate::BLAKE2B_LEN];
+        read_exact_at(&self.source, &mut checksum, len)?;
+        let mut hasher = Hasher::new();
+        match self.source {
+            Source::Memory(ref data) => hasher.process(&data.bytes()[..len as usize]),
+            ref source => {
+                let mut buf = vec![0; 64 * 1024];
+                let mut offset = 0;
+                while offset < len {
+                    let n = (len - offset).min(buf.len() as u64) as usize;
+                    read_exact_at(source, &mut buf[..n], offset)?;
+                    hasher.process(&buf[..n]);
+                    offset += n as u64;
+                }
+            }
+        }
+        if hasher.result().bytes() != checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupt", self.path.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Descriptive information recorded when the archive was written.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Check that the archive's detached signature was made by one of `keys`.
+    #[cfg(feature = "ed25519-dalek")]
+    pub fn verify(&self, keys: &[VerifyingKey]) -> io::Result<()> {
+        let signature = match fs::read(signature_path(&self.path)) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "archive is not signed",
+                ));
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        };
+        let signature = Signature::from_slice(&signature).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed archive signature")
+        })?;
+        let data = self.source.read(0, self.size)?;
+        if keys.iter().any(|key| key.verify(&data, &signature).is_ok()) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive is not signed by a trusted key",
+            ))
+        }
+    }
+
+    /// Enumerate the hash, offset, and length of each asset.
+    fn entries<'a>(&'a self) -> Box<dyn Iterator<Item = (Hash, u64, u64)> + 'a> {
+        let kind = self.kind;
+        match self.index {
+            Index::Carchive {
+                ref reader,
+                ref inline,
+            } => Box::new(
+                reader
+                    .iter()
+                    .map(move |(key, value)| {
+                        let hash = Hash::from_bytes(kind, key)
+                            .expect("archive key length was checked against the hash kind");
+                        (hash, offset_of(reader, value), value.len() as u64)
+                    })
+                    .chain(
+                        inline
+                            .iter()
+                            .map(|(&hash, &(start, len))| (hash, start, len)),
+                    ),
+            ),
+            Index::Table(ref entries) => Box::new(
+                entries
+                    .iter()
+                    .map(|(&hash, &(start, len))| (hash, start, len)),
+            ),
+        }
+    }
+}
+
+/// Offset of `data` from the start of the contents of `reader`.
+fn offset_of(reader: &carchive::Reader<ArcMap>, data: &[u8]) -> u64 {
+    (data.as_ptr() as usize - reader.get_ref().data.bytes().as_ptr() as usize) as u64
+}
+
+impl Store for Archive {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+
+    fn list_sizes(&self) -> Box<dyn Iterator<Item = io::Result<(Hash, u64)>> + '_> {
+        Box::new(self.list_sizes())
+    }
+}
+
+/// Writes a new archive.
+pub struct Writer<W: Write + Seek> {
+    inner: carchive::Writer<Tracked<W>>,
+    /// Where the data of each asset was written, shared with `inner`
+    tracking: Arc<Mutex<Tracking>>,
+    /// The `carchive` extension header, repeated in the index table
+    header: Vec<u8>,
+    added: ContentSet,
+    #[cfg(feature = "chacha20poly1305")]
+    cipher: Option<XChaCha20Poly1305>,
+    #[cfg(feature = "zstd")]
+    compression: Option<(Dictionary, i32)>,
+    inline_below: usize,
+    /// Entries of the inline table
+    inline: Vec<u8>,
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Begin writing an archive into `inner`, labeled with `metadata`.
+    pub fn new(inner: W, metadata: &Metadata) -> io::Result<Self> {
+        Self::begin(inner, metadata, None, None)
+    }
+
+    /// Begin writing an archive into `inner` whose asset data is encrypted with `key`, labeled with `metadata`.
+    ///
+    /// `metadata` is not encrypted.
+    #[cfg(feature = "chacha20poly1305")]
+    pub fn new_encrypted(inner: W, metadata: &Metadata, key: &Key) -> io::Result<Self> {
+        let mut writer = Self::begin(inner, metadata, Some(&key.fingerprint()), None)?;
+        writer.cipher = Some(key.cipher());
+        Ok(writer)
+    }
+
+    /// Begin writing an archive into `inner` whose asset data is compressed with `dictionary` at `level`, labeled with
+    /// `metadata`.
+    #[cfg(feature = "zstd")]
+    pub fn new_compressed(
+        inner: W,
+        metadata: &Metadata,
+        dictionary: &Dictionary,
+        level: i32,
+    ) -> io::Result<Self> {
+        let mut writer = Self::begin(inner, metadata, None, Some(dictionary.hash()))?;
+        writer.compression = Some((dictionary.clone(), level));
+        Ok(writer)
+    }
+
+    /// Begin writing an archive with the settings of `packer`.
+    fn for_packer(inner: W, packer: &Packer) -> io::Result<Self> {
+        #[cfg(feature = "chacha20poly1305")]
+        let fingerprint = packer.key.as_ref().map(Key::fingerprint);
+        #[cfg(not(feature = "chacha20poly1305"))]
+        let fingerprint = None;
+        #[cfg(feature = "zstd")]
+        let dictionary = packer.compression.as_ref().map(|x| *x.0.hash());
+        #[cfg(not(feature = "zstd"))]
+        let dictionary = None;
+        #[allow(unused_mut)]
+        let mut writer = Self::begin(
+            inner,
+            &packer.metadata,
+            fingerprint.as_ref(),
+            dictionary.as_ref(),
+        )?;
+        #[cfg(feature = "chacha20poly1305")]
+        {
+            writer.cipher = packer.key.as_ref().map(Key::cipher);
+        }
+        #[cfg(feature = "zstd")]
+        {
+            writer.compression = packer.compression.clone();
+        }
+        writer.inline_below = packer.inline_below;
+        Ok(writer)
+    }
+
+    /// Store assets whose stored form is smaller than `bytes`, at most 65536, inline alongside the index.
+    ///
+    /// Reading an inline asset needn't touch the data region of the archive, reducing seeks when many small assets are
+    /// read. Archives with inline assets can't be read by versions of this library predating the feature. Defaults to
+    /// 0, storing nothing inline.
+    pub fn inline_below(&mut self, bytes: usize) -> &mut Self {
+        assert!(bytes <= 1 << 16, "inline threshold must be at most 65536");
+        self.inline_below = bytes;
+        self
+    }
+
+    fn begin(
+        mut inner: W,
+        metadata: &Metadata,
+        fingerprint: Option<&Hash>,
+        dictionary: Option<&Hash>,
+    ) -> io::Result<Self> {
+        let kind = HashKind::default();
+        let mut ext = vec![0; 6];
+        LittleEndian::write_u16(&mut ext[0..2], kind.id());
+        metadata.encode(&mut ext);
+        if let Some(x) = fingerprint {
+            write_record(&mut ext, TAG_KEY, x.bytes());
+        }
+        if let Some(x) = dictionary {
+            write_record(&mut ext, TAG_DICTIONARY, x.bytes());
+        }
+        let len = ext.len() - 6;
+        if len > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archive metadata too large",
+            ));
+        }
+        LittleEndian::write_u32(&mut ext[2..6], len as u32);
+        let base = inner.stream_position()?;
+        let tracking = Arc::new(Mutex::new(Tracking {
+            base,
+            pos: base,
+            entries: Some(Vec::new()),
+            ..Tracking::default()
+        }));
+        let inner = Tracked {
+            inner,
+            tracking: tracking.clone(),
+        };
+        Ok(Self {
+            inner: carchive::Writer::new(inner, kind.len() as u32, &ext)?,
+            tracking,
+            header: ext,
+            added: ContentSet::default(),
+            #[cfg(feature = "chacha20poly1305")]
+            cipher: None,
+            #[cfg(feature = "zstd")]
+            compression: None,
+            inline_below: 0,
+            inline: Vec::new(),
+        })
+    }
+
+    /// Store `data` in the archive, returning its hash.
+    ///
+    /// Data that has already been added is not stored again.
+    pub fn add(&mut self, data: &[u8]) -> io::Result<Hash> {
+        let hash = hash_of(data);
+        if !self.added.insert(hash) {
+            return Ok(hash);
+        }
+        #[cfg(feature = "zstd")]
+        let compressed = match self.compression {
+            Some((ref dictionary, level)) => Some(dictionary.compress(data, level)?),
+            None => None,
+        };
+        #[cfg(feature = "zstd")]
+        let data = compressed.as_ref().map_or(data, |x| &x[..]);
+        #[cfg(feature = "chacha20poly1305")]
+        {
+            if let Some(ref cipher) = self.cipher {
+                let nonce = rand::random::<[u8; NONCE_LEN]>();
+                let mut stored = nonce.to_vec();
+                stored.extend(
+                    cipher
+                        .encrypt(
+                            XNonce::from_slice(&nonce),
+                            Payload {
+                                msg: data,
+                                aad: hash.bytes(),
+                            },
+                        )
+                        .map_err(|_| io::Error::other("failed to encrypt asset"))?,
+                );
+                self.store(&hash, &stored)?;
+                return Ok(hash);
+            }
+        }
+        self.store(&hash, data)?;
+        Ok(hash)
+    }
+
+    /// Write the stored form of the asset identified by `hash`.
+    fn store(&mut self, hash: &Hash, data: &[u8]) -> io::Result<()> {
+        if data.len() >= self.inline_below {
+            self.tracking.lock().unwrap().begin();
+            self.inner.add(hash.bytes(), data)?;
+            self.tracking.lock().unwrap().end(hash, data.len() as u64);
+            return Ok(());
+        }
+        let mut len = [0; 2];
+        LittleEndian::write_u16(&mut len, data.len() as u16);
+        self.inline.extend_from_slice(hash.bytes());
+        self.inline.extend_from_slice(&len);
+        self.inline.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Store every regular file in the tar stream `input`, returning the hash of each file's contents keyed by its path
+    /// within the stream.
+    #[cfg(feature = "tar")]
+    pub fn import_tar<R: Read>(&mut self, input: R) -> io::Result<BTreeMap<PathBuf, Hash>> {
+        let mut result = BTreeMap::new();
+        let mut buf = Vec::new();
+        for entry in tar::Archive::new(input).entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.into_owned();
+            buf.clear();
+            entry.read_to_end(&mut buf)?;
+            result.insert(path, self.add(&buf)?);
+        }
+        Ok(result)
+    }
+
+    /// Store every file in the zip archive `input`, returning the hash of each file's contents keyed by its path within
+    /// the zip archive.
+    #[cfg(feature = "zip")]
+    pub fn import_zip<R: Read + Seek>(&mut self, input: R) -> io::Result<BTreeMap<PathBuf, Hash>> {
+        let mut result = BTreeMap::new();
+        let mut buf = Vec::new();
+        let mut zip = zip::ZipArchive::new(input).map_err(zip_error)?;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).map_err(zip_error)?;
+            if file.is_dir() {
+                continue;
+            }
+            let path = PathBuf::from(file.name());
+            buf.clear();
+            file.read_to_end(&mut buf)?;
+            result.insert(path, self.add(&buf)?);
+        }
+        Ok(result)
+    }
+
+    /// Complete the archive, returning the underlying writer.
+    ///
+    /// An index table is written unless the `carchive` writer stored asset data in a way that couldn't be tracked.
+    pub fn finish(self) -> io::Result<W> {
+        let mut inner = self.inner.finish()?.inner;
+        let entries = self.tracking.lock().unwrap().entries.take();
+        inner.seek(SeekFrom::End(0))?;
+        let mut len = [0; 8];
+        if !self.inline.is_empty() {
+            LittleEndian::write_u64(&mut len, self.inline.len() as u64);
+            inner.write_all(&self.inline)?;
+            inner.write_all(&len)?;
+            inner.write_all(INLINE_MAGIC)?;
+        }
+        if let Some(entries) = entries {
+            let mut header_len = [0; 4];
+            LittleEndian::write_u32(&mut header_len, self.header.len() as u32);
+            inner.write_all(&header_len)?;
+            inner.write_all(&self.header)?;
+            let mut buf = [0; 16];
+            for &(ref hash, start, n) in &entries {
+                LittleEndian::write_u64(&mut buf[..8], start);
+                LittleEndian::write_u64(&mut buf[8..], n);
+                inner.write_all(hash.bytes())?;
+                inner.write_all(&buf)?;
+            }
+            let table_len =
+                4 + self.header.len() + entries.len() * (HashKind::default().len() + 16);
+            LittleEndian::write_u64(&mut len, table_len as u64);
+            inner.write_all(&len)?;
+            inner.write_all(INDEX_MAGIC)?;
+        }
+        Ok(inner)
+    }
+
+    /// Complete the archive and append a checksum trailer, returning the underlying writer.
+    ///
+    /// Reads back the entire archive from `inner`.
+    pub fn finish_with_checksum(self) -> io::Result<W>
+    where
+        W: Read,
+    {
+        let mut inner = self.finish()?;
+        write_checksum(&mut inner)?;
+        Ok(inner)
+    }
+}
+
+/// Output of a `carchive::Writer`, observed to learn where the data of each asset is written so that an index table
+/// can be written without reading the archive back.
+struct Tracked<W> {
+    inner: W,
+    tracking: Arc<Mutex<Tracking>>,
+}
+
+#[derive(Default)]
+struct Tracking {
+    /// Position of the start of the archive in the underlying writer
+    base: u64,
+    /// Current position in the underlying writer
+    pos: u64,
+    /// Whether an asset is being added
+    adding: bool,
+    /// Range written while adding the current asset, if contiguous
+    written: Option<Range<u64>>,
+    /// Whether anything was written outside `written` while adding the current asset
+    scattered: bool,
+    /// Range spanned by the data of every asset recorded so far
+    extent: Option<Range<u64>>,
+    /// Hash, offset, and length of each asset's data, or `None` if the location of some asset's data is unknown
+    entries: Option<Vec<(Hash, u64, u64)>>,
+}
+
+impl Tracking {
+    fn begin(&mut self) {
+        self.adding = true;
+        self.written = None;
+        self.scattered = false;
+    }
+
+    /// Record that the data of the asset identified by `hash`, `len` bytes long, has been added.
+    fn end(&mut self, hash: &Hash, len: u64) {
+        self.adding = false;
+        let range = match self.written.take() {
+            None if len == 0 => self.pos..self.pos,
+            Some(x) if !self.scattered && x.end - x.start == len => x,
+            _ => {
+                // The data wasn't written as-is while it was being added
+                self.entries = None;
+                return;
+            }
+        };
+        if let Some(ref mut entries) = self.entries {
+            entries.push((*hash, range.start - self.base, len));
+        }
+        if len == 0 {
+            return;
+        }
+        self.extent = Some(match self.extent.take() {
+            None => range.clone(),
+            Some(x) => x.start.min(range.start)..x.end.max(range.end),
+        });
+    }
+
+    fn wrote(&mut self, n: u64) {
+        let range = self.pos..self.pos + n;
+        self.pos = range.end;
+        if n == 0 {
+            return;
+        }
+        if let Some(ref x) = self.extent {
+            if range.start < x.end && x.start < range.end {
+                // Overwrote data previously recorded
+                self.entries = None;
+            }
+        }
+        if !self.adding {
+            return;
+        }
+        match self.written {
+            None => self.written = Some(range),
+            Some(ref mut x) if x.end == range.start => x.end = range.end,
+            Some(_) => self.scattered = true,
+        }
+    }
+}
+
+impl<W: Write> Write for Tracked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.tracking.lock().unwrap().wrote(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for Tracked<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = self.inner.seek(pos)?;
+        self.tracking.lock().unwrap().pos = pos;
+        Ok(pos)
+    }
+}
+
+/// Write a detached signature for the archive at `path` using `key`.
+///
+/// The signature is written under a temporary hidden name and renamed into place, so an interrupted call never leaves a
+/// truncated signature behind.
+#[cfg(feature = "ed25519-dalek")]
+pub fn sign(path: &Path, key: &SigningKey) -> io::Result<()> {
+    let map = map_file(&File::open(path)?)?;
+    let signature = key.sign(&map);
+    let dest = signature_path(path);
+    let temp = dest.with_file_name(format!(".{:016X}.tmp", rand::random::<u64>()));
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(&signature.to_bytes()[..])?;
+        file.sync_data()?;
+        fs::rename(&temp, &dest)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Extension of detached archive signatures
+const SIGNATURE_EXTENSION: &str = "sig";
+
+#[cfg(feature = "ed25519-dalek")]
+fn signature_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".");
+    path.push(SIGNATURE_EXTENSION);
+    path.into()
+}
+
+/// Writes collections of assets into one or more new archives, or "volumes".
+///
+/// Each volume is named after the hash of the keys it contains, followed by a random suffix so that packing the same
+/// assets again, e.g. with different settings, never replaces an existing volume. Assets are assigned to volumes in
+/// order of their hashes, so the same collection of assets packed with the same settings is always split the same way.
+#[derive(Debug, Clone)]
+pub struct Packer {
+    metadata: Metadata,
+    max_size: Option<u64>,
+    /// Assets to omit, and their sizes
+    exclude: ContentMap<u64>,
+    checksum: bool,
+    #[cfg(feature = "chacha20poly1305")]
+    key: Option<Key>,
+    #[cfg(feature = "zstd")]
+    compression: Option<(Dictionary, i32)>,
+    inline_below: usize,
+}
+
+/// Summary of the output of `Packer::pack`.
+#[derive(Debug, Clone, Default)]
+pub struct PackReport {
+    /// Paths of the newly written volumes
+    pub volumes: Vec<PathBuf>,
+    /// Number of assets written
+    pub assets: usize,
+    /// Total size of assets written
+    pub bytes: u64,
+    /// Number of assets omitted due to `Packer::exclude`
+    pub excluded: usize,
+    /// Total size of assets omitted due to `Packer::exclude`
+    pub bytes_saved: u64,
+}
+
+/// Space taken in a volume by archive structure rather than asset data
+#[derive(Debug, Copy, Clone)]
+struct Reserves {
+    /// Fixed cost of the archive's header, metadata, trailers, and checksum
+    header: u64,
+    /// Cost of each asset, in addition to its stored data
+    entry: u64,
+}
+
+impl Reserves {
+    /// Measure the structure of archives written with the settings of `packer` by writing small samples in memory.
+    fn measure(packer: &Packer) -> io::Result<Self> {
+        let empty = Writer::for_packer(io::Cursor::new(Vec::new()), packer)?
+            .finish()?
+            .into_inner()
+            .len() as u64;
+        let mut header = 0;
+        let mut entry = 0;
+        // Inline and regular assets are indexed differently, so measure both and take the worst case. One asset shows
+        // the cost of tables written only once there's something to put in them; a second shows the cost per asset.
+        for &inline_below in &[0, 2] {
+            let lens = (0..3)
+                .map(|n| Self::sample(inline_below, n))
+                .collect::<io::Result<Vec<u64>>>()?;
+            let per = (lens[2] - lens[1]).saturating_sub(1);
+            entry = entry.max(per);
+            header = header.max((lens[1] - lens[0]).saturating_sub(1 + per));
+        }
+        if packer.checksum {
+            header += (crate::BLAKE2B_LEN + CHECKSUM_MAGIC.len()) as u64;
+        }
+        Ok(Self {
+            header: empty + header,
+            entry,
+        })
+    }
+
+    /// Length of a plain archive holding `n` distinct single-byte assets.
+    fn sample(inline_below: usize, n: u8) -> io::Result<u64> {
+        let mut writer = Writer::new(io::Cursor::new(Vec::new()), &Metadata::default())?;
+        writer.inline_below(inline_below);
+        for i in 0..n {
+            writer.add(&[i])?;
+        }
+        Ok(writer.finish()?.into_inner().len() as u64)
+    }
+}
+
+impl Packer {
+    /// Create a packer that labels its volumes with `metadata`.
+    pub fn new(metadata: Metadata) -> Self {
+        Self {
+            metadata,
+            max_size: None,
+            exclude: ContentMap::default(),
+            checksum: false,
+            #[cfg(feature = "chacha20poly1305")]
+            key: None,
+            #[cfg(feature = "zstd")]
+            compression: None,
+            inline_below: 0,
+        }
+    }
+
+    /// Limit the size of each volume to at most `bytes`, splitting the output across as many volumes as necessary.
+    ///
+    /// By default, all assets are written to a single volume.
+    pub fn max_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Omit the assets identified by `hashes` from future output. Each hash is paired with the asset's size, which is
+    /// counted towards `PackReport::bytes_saved` instead of reading the asset.
+    ///
+    /// Pass the contents of existing archives, e.g. from `ArchiveSet::list_sizes`, to write only the assets that are
+    /// new relative to them, as when building an incremental patch.
+    pub fn exclude<I: IntoIterator<Item = (Hash, u64)>>(&mut self, hashes: I) -> &mut Self {
+        self.exclude.extend(hashes);
+        self
+    }
+
+    /// Encrypt the asset data of future volumes with `key`.
+    #[cfg(feature = "chacha20poly1305")]
+    pub fn encrypt(&mut self, key: Key) -> &mut Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Compress the asset data of future volumes with `dictionary` at `level`.
+    #[cfg(feature = "zstd")]
+    pub fn compress(&mut self, dictionary: Dictionary, level: i32) -> &mut Self {
+        self.compression = Some((dictionary, level));
+        self
+    }
+
+    /// Store assets smaller than `bytes` inline in future volumes. See `Writer::inline_below`.
+    pub fn inline_below(&mut self, bytes: usize) -> &mut Self {
+        assert!(bytes <= 1 << 16, "inline threshold must be at most 65536");
+        self.inline_below = bytes;
+        self
+    }
+
+    /// Append a checksum trailer to future volumes, for verification with `OpenOptions::verify_checksums`.
+    pub fn checksum(&mut self, enabled: bool) -> &mut Self {
+        self.checksum = enabled;
+        self
+    }
+
+    /// Write the assets identified by `hashes`, retrieved using `get`, into new volumes in `dir`.
+    ///
+    /// Volumes are written under a temporary hidden name, which `ArchiveSet` ignores, and renamed only once complete.
+    /// If packing is interrupted, hidden files left in `dir` may be safely deleted.
+    pub fn pack<I, F>(&self, dir: &Path, hashes: I, mut get: F) -> io::Result<PackReport>
+    where
+        I: IntoIterator<Item = Hash>,
+        F: FnMut(&Hash) -> io::Result<Asset>,
+    {
+        let mut hashes = hashes.into_iter().collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes.dedup();
+        fs::create_dir_all(dir)?;
+        let mut report = PackReport::default();
+        let reserves = Reserves::measure(self)?;
+        let mut volume: Option<Volume> = None;
+        for hash in &hashes {
+            if let Some(&len) = self.exclude.get(hash) {
+                report.excluded += 1;
+                report.bytes_saved += len;
+                continue;
+            }
+            let asset = get(hash)?;
+            #[allow(unused_mut)]
+            let mut len = asset.len() as u64;
+            #[cfg(feature = "zstd")]
+            {
+                if self.compression.is_some() {
+                    len = compression::compress_bound(len);
+                }
+            }
+            #[allow(unused_mut)]
+            let mut cost = len + reserves.entry;
+            #[cfg(feature = "chacha20poly1305")]
+            {
+                if self.key.is_some() {
+                    cost += ENCRYPTION_OVERHEAD;
+                }
+            }
+            if let Some(max) = self.max_size {
+                if reserves.header + cost > max {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is too large to fit in a volume", hash),
+                    ));
+                }
+                if volume.as_ref().is_some_and(|x| x.size + cost > max) {
+                    report.volumes.push(volume.take().unwrap().finish(dir)?);
+                }
+            }
+            if volume.is_none() {
+                volume = Some(Volume::new(dir, self, reserves.header)?);
+            }
+            volume.as_mut().unwrap().add(hash, &asset, cost)?;
+            report.assets += 1;
+            report.bytes += asset.len() as u64;
+        }
+        if let Some(volume) = volume {
+            report.volumes.push(volume.finish(dir)?);
+        }
+        Ok(report)
+    }
+}
+
+/// A volume being written by a `Packer`.
+struct Volume {
+    writer: Writer<io::BufWriter<File>>,
+    path: PathBuf,
+    /// Random component of the temporary name, reused in the final one
+    nonce: u64,
+    size: u64,
+    keys: Hasher,
+    checksum: bool,
+}
+
+impl Volume {
+    fn new(dir: &Path, packer: &Packer, header: u64) -> io::Result<Self> {
+        loop {
+            let nonce = rand::random::<u64>();
+            let path = dir.join(format!(".{:016X}.tmp", nonce));
+            // Readable so that a checksum can be computed over the finished volume
+            match fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => {
+                    let file = io::BufWriter::new(file);
+                    let writer = Writer::for_packer(file, packer)?;
+                    return Ok(Self {
+                        writer,
+                        path,
+                        nonce,
+                        size: header,
+                        keys: Hasher::new(),
+                        checksum: packer.checksum,
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, hash: &Hash, data: &[u8], cost: u64) -> io::Result<()> {
+        if self.writer.add(data)? != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data for {} does not match its hash", hash),
+            ));
+        }
+        self.keys.process(hash.bytes());
+        self.size += cost;
+        Ok(())
+    }
+
+    fn finish(self, dir: &Path) -> io::Result<PathBuf> {
+        let mut file = self.writer.finish()?.into_inner()?;
+        if self.checksum {
+            write_checksum(&mut file)?;
+        }
+        file.sync_data()?;
+        let dest = dir.join(format!(
+            "{}-{:016X}",
+            BASE32_NOPAD.encode(self.keys.result().bytes()),
+            self.nonce
+        ));
+        fs::rename(&self.path, &dest)?;
+        Ok(dest)
+    }
+}
+
+/// Space taken by an encrypted asset in addition to its plaintext: the nonce and the authentication tag
+#[cfg(feature = "chacha20poly1305")]
+const ENCRYPTION_OVERHEAD: u64 = NONCE_LEN as u64 + 16;
+
+#[cfg(feature = "chacha20poly1305")]
+fn decrypt(cipher: &XChaCha20Poly1305, hash: &Hash, data: &[u8]) -> io::Result<Asset> {
+    if data.len() < NONCE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated encrypted asset",
+        ));
+    }
+    let plaintext = cipher
+        .decrypt(
+            XNonce::from_slice(&data[..NONCE_LEN]),
+            Payload {
+                msg: &data[NONCE_LEN..],
+                aad: hash.bytes(),
+            },
+        )
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt asset"))?;
+    Ok(Asset {
+        len: plaintext.len(),
+        storage: Storage::Heap(plaintext.into()),
+        start: 0,
+    })
+}
+
+/// Descriptive information about an archive.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Metadata {
+    /// When the archive was written
+    pub created: Option<SystemTime>,
+    /// The tool that wrote the archive
+    pub creator: Option<String>,
+    /// Application-defined version number of the archive, e.g. for ordering successive releases
+    pub generation: Option<u64>,
+    /// Free-form key/value pairs
+    pub extra: BTreeMap<String, String>,
+}
+
+const TAG_CREATED: u8 = 1;
+const TAG_CREATOR: u8 = 2;
+const TAG_GENERATION: u8 = 3;
+const TAG_EXTRA: u8 = 4;
+/// Fingerprint of the key the archive is encrypted with. Not part of `Metadata`.
+const TAG_KEY: u8 = 5;
+/// Hash of the dictionary the archive is compressed with. Not part of `Metadata`.
+const TAG_DICTIONARY: u8 = 6;
+
+impl Metadata {
+    /// Create metadata recording the current time and the name of the tool writing the archive.
+    pub fn new(creator: String) -> Self {
+        Self {
+            created: Some(SystemTime::now()),
+            creator: Some(creator),
+            ..Self::default()
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        if let Some(x) = self.created {
+            let secs = x.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs());
+            let mut buf = [0; 8];
+            LittleEndian::write_u64(&mut buf, secs);
+            write_record(out, TAG_CREATED, &buf);
+        }
+        if let Some(ref x) = self.creator {
+            write_record(out, TAG_CREATOR, x.as_bytes());
+        }
+        if let Some(x) = self.generation {
+            let mut buf = [0; 8];
+            LittleEndian::write_u64(&mut buf, x);
+            write_record(out, TAG_GENERATION, &buf);
+        }
+        for (key, value) in &self.extra {
+            let mut buf = vec![0; 4];
+            LittleEndian::write_u32(&mut buf, key.len() as u32);
+            buf.extend_from_slice(key.as_bytes());
+            buf.extend_from_slice(value.as_bytes());
+            write_record(out, TAG_EXTRA, &buf);
+        }
+    }
+
+    #[cfg(test)]
+    fn decode(data: &[u8]) -> io::Result<Self> {
+        Self::from_records(&records(data)?)
+    }
+
+    fn from_records(records: &[(u8, &[u8])]) -> io::Result<Self> {
+        let mut result = Self::default();
+        for &(tag, content) in records {
+            match tag {
+                TAG_CREATED => {
+                    if content.len() != 8 {
+                        return Err(invalid_metadata());
+                    }
+                    let secs = LittleEndian::read_u64(content);
+                    result.created = Some(UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                TAG_CREATOR => {
+                    result.creator = Some(utf8(content)?.into());
+                }
+                TAG_GENERATION => {
+                    if content.len() != 8 {
+                        return Err(invalid_metadata());
+                    }
+                    result.generation = Some(LittleEndian::read_u64(content));
+                }
+                TAG_EXTRA => {
+                    if content.len() < 4 {
+                        return Err(invalid_metadata());
+                    }
+                    let key_len = LittleEndian::read_u32(content) as usize;
+                    if content.len() - 4 < key_len {
+                        return Err(invalid_metadata());
+                    }
+                    let key = utf8(&content[4..4 + key_len])?;
+                    let value = utf8(&content[4 + key_len..])?;
+                    result.extra.insert(key.into(), value.into());
+                }
+                _ => {}
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Split an encoded metadata block into its tagged records.
+fn records(mut data: &[u8]) -> io::Result<Vec<(u8, &[u8])>> {
+    let mut result = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 5 {
+            return Err(invalid_metadata());
+        }
+        let tag = data[0];
+        let len = LittleEndian::read_u32(&data[1..5]) as usize;
+        if data.len() - 5 < len {
+            return Err(invalid_metadata());
+        }
+        result.push((tag, &data[5..5 + len]));
+        data = &data[5 + len..];
+    }
+    Ok(result)
+}
+
+fn write_record(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    let mut header = [0; 5];
+    header[0] = tag;
+    LittleEndian::write_u32(&mut header[1..], content.len() as u32);
+    out.extend_from_slice(&header);
+    out.extend_from_slice(content);
+}
+
+fn utf8(x: &[u8]) -> io::Result<&str> {
+    str::from_utf8(x).map_err(|_| invalid_metadata())
+}
+
+fn invalid_metadata() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed archive metadata")
+}
+
+#[cfg(feature = "zip")]
+fn zip_error(e: zip::result::ZipError) -> io::Error {
+    match e {
+        zip::result::ZipError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+fn is_hidden(name: &OsStr) -> bool {
+    name.to_str().is_some_and(|x| x.starts_with('.'))
+}
+
+/// Magic string ending an archive's checksum trailer
+const CHECKSUM_MAGIC: &[u8; 8] = b"CHASSUM1";
+/// Length of an archive's checksum trailer
+const CHECKSUM_LEN: usize = crate::BLAKE2B_LEN + CHECKSUM_MAGIC.len();
+
+/// Magic string ending an inline table
+const INLINE_MAGIC: &[u8; 8] = b"CHINLIN1";
+/// Magic string ending an index table
+const INDEX_MAGIC: &[u8; 8] = b"CHINDEX1";
+
+/// Extents of the trailers at the end of an archive.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Trailers {
+    /// Size of the entire archive
+    size: u64,
+    /// Whether the archive ends with a checksum trailer
+    checksum: bool,
+    /// Range of the index table's entries, if any
+    index: Option<Range<u64>>,
+    /// Range of the inline table's entries, if any
+    inline: Option<Range<u64>>,
+    /// Length of the `carchive` data
+    contents: u64,
+}
+
+impl Trailers {
+    /// Find the trailers of the archive in `source`, reading only its end.
+    fn read<R: ReadAt + ?Sized>(source: &R) -> io::Result<Self> {
+        let size = source.size()?;
+        let mut end = size;
+        let mut checksum = false;
+        if end >= CHECKSUM_LEN as u64 {
+            let mut magic = [0; 8];
+            read_exact_at(source, &mut magic, end - 8)?;
+            if magic == *CHECKSUM_MAGIC {
+                checksum = true;
+                end -= CHECKSUM_LEN as u64;
+            }
+        }
+        let index = read_table(source, &mut end, INDEX_MAGIC, invalid_index)?;
+        let inline = read_table(source, &mut end, INLINE_MAGIC, invalid_inline)?;
+        Ok(Self {
+            size,
+            checksum,
+            index,
+            inline,
+            contents: end,
+        })
+    }
+}
+
+/// Find the table ending at `end` with `magic`, if any, and move `end` to its start.
+fn read_table<R: ReadAt + ?Sized>(
+    source: &R,
+    end: &mut u64,
+    magic: &[u8; 8],
+    invalid: fn() -> io::Error,
+) -> io::Result<Option<Range<u64>>> {
+    if *end < 16 {
+        return Ok(None);
+    }
+    let mut footer = [0; 16];
+    read_exact_at(source, &mut footer, *end - 16)?;
+    if footer[8..] != magic[..] {
+        return Ok(None);
+    }
+    let len = LittleEndian::read_u64(&footer);
+    if len > *end - 16 {
+        return Err(invalid());
+    }
+    let start = *end - 16 - len;
+    let range = start..*end - 16;
+    *end = start;
+    Ok(Some(range))
+}
+
+fn invalid_inline() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed inline table")
+}
+
+fn invalid_index() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed index table")
+}
+
+/// Append a checksum trailer covering the entire contents of `file`.
+fn write_checksum<F: Read + Write + Seek>(file: &mut F) -> io::Result<()> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = Hasher::new();
+    io::copy(&mut (&mut *file).take(len), &mut hasher)?;
+    file.seek(SeekFrom::Start(len))?;
+    file.write_all(hasher.result().bytes())?;
+    file.write_all(CHECKSUM_MAGIC)?;
+    Ok(())
+}
+
+/// Shared archive contents, excluding any trailers.
+struct ArcMap {
+    data: Storage,
+    len: usize,
+}
+
+impl AsRef<[u8]> for ArcMap {
+    fn as_ref(&self) -> &[u8] {
+        &self.data.bytes()[..self.len]
+    }
+}
+
+/// A source of archive data supporting reads at arbitrary offsets.
+///
+/// Implement this to read archives through custom IO with `OpenOptions::open_sources`.
+pub trait ReadAt {
+    /// Read bytes starting at `offset` into `buf`, returning how many were read. Returns 0 only at or past the end.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Total size of the data in bytes.
+    fn size(&self) -> io::Result<u64>;
+}
+
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = (offset.min(self.len() as u64)) as usize;
+        let n = buf.len().min(self.len() - start);
+        buf[..n].copy_from_slice(&self[start..start + n]);
+        Ok(n)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self[..].read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self[..].size()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Box<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+fn read_exact_at<R: ReadAt + ?Sized>(
+    source: &R,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        match source.read_at(buf, offset) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ));
+            }
+            Ok(n) => {
+                let rest = buf;
+                buf = &mut rest[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metadata_roundtrip() {
+        let mut metadata = Metadata::new("test".into());
+        metadata.generation = Some(42);
+        metadata.extra.insert("branch".into(), "release".into());
+        let mut buf = Vec::new();
+        metadata.encode(&mut buf);
+        let decoded = Metadata::decode(&buf).unwrap();
+        assert_eq!(decoded.creator, metadata.creator);
+        assert_eq!(decoded.generation, metadata.generation);
+        assert_eq!(decoded.extra, metadata.extra);
+        assert!(decoded.created.is_some());
+    }
+
+    #[test]
+    fn metadata_truncated() {
+        let mut buf = Vec::new();
+        Metadata::new("test".into()).encode(&mut buf);
+        assert!(Metadata::decode(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn read_at_slice() {
+        let data = &b"0123456789"[..];
+        let mut buf = [0; 4];
+        read_exact_at(data, &mut buf, 6).unwrap();
+        assert_eq!(&buf, b"6789");
+        assert_eq!(data.read_at(&mut buf, 8).unwrap(), 2);
+        assert_eq!(data.read_at(&mut buf, 20).unwrap(), 0);
+        assert!(read_exact_at(data, &mut buf, 7).is_err());
+    }
+
+    #[test]
+    fn checksum_roundtrip() {
+        let mut file = io::Cursor::new(b"archive contents".to_vec());
+        write_checksum(&mut file).unwrap();
+        let data = file.into_inner();
+        let trailers = Trailers::read(&data).unwrap();
+        assert!(trailers.checksum);
+        assert_eq!(trailers.contents, 16);
+        let mut hasher = Hasher::new();
+        hasher.process(&data[..16]);
+        assert_eq!(&data[16..16 + crate::BLAKE2B_LEN], hasher.result().bytes());
+        assert!(!Trailers::read(&data[..16]).unwrap().checksum);
+    }
+
+    #[test]
+    fn split_tables() {
+        let mut data = b"archive contents".to_vec();
+        let trailers = Trailers::read(&data).unwrap();
+        assert_eq!(
+            (trailers.inline, trailers.index, trailers.contents),
+            (None, None, 16)
+        );
+        data.extend_from_slice(b"table");
+        data.extend_from_slice(&5u64.to_le_bytes());
+        data.extend_from_slice(INLINE_MAGIC);
+        data.extend_from_slice(b"index");
+        data.extend_from_slice(&5u64.to_le_bytes());
+        data.extend_from_slice(INDEX_MAGIC);
+        let trailers = Trailers::read(&data).unwrap();
+        assert_eq!(trailers.contents, 16);
+        assert_eq!(trailers.inline, Some(16..21));
+        assert_eq!(trailers.index, Some(37..42));
+        let len = data.len();
+        data[len - 16..len - 8].copy_from_slice(&100u64.to_le_bytes());
+        assert!(Trailers::read(&data).is_err());
+    }
+
+    #[test]
+    fn streaming_uses_index_table() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-streaming-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(dir.join("a"))
+            .unwrap();
+        let mut writer = Writer::new(file, &Metadata::new("test".into())).unwrap();
+        let small = writer.add(b"small").unwrap();
+        let large = writer.add(&[0xAB; 4096]).unwrap();
+        writer.finish_with_checksum().unwrap();
+
+        let tables = Tables::read(&File::open(dir.join("a")).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(tables.entries.len(), 2);
+        assert!(tables.trailers.checksum);
+
+        let set = OpenOptions::new()
+            .access(Access::Streaming)
+            .open(&dir)
+            .unwrap();
+        assert_eq!(&*set.get(&small).unwrap(), b"small");
+        let mut buf = Vec::new();
+        set.reader(&large).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, vec![0xAB; 4096]);
+        assert_eq!(set.locate(&large).unwrap().stored_len, 4096);
+        drop(set);
+
+        // Nothing is mapped, either while opening or while reading
+        let budget = MapBudget::new(u64::MAX, usize::MAX);
+        let set = OpenOptions::new()
+            .access(Access::Streaming)
+            .map_budget(budget.clone())
+            .open(&dir)
+            .unwrap();
+        assert_eq!(budget.usage(), (0, 0));
+        let asset = set.get(&large).unwrap();
+        assert_eq!(asset.len(), 4096);
+        assert_eq!(budget.usage(), (0, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn uring() {
+        if io_uring::IoUring::new(1).is_err() {
+            // Unsupported by this kernel
+            return;
+        }
+        let dir =
+            std::env::temp_dir().join(format!("chasset-uring-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let assets = (0..100u32)
+            .map(|i| i.to_le_bytes().repeat(i as usize % 7 + 1))
+            .collect::<Vec<_>>();
+        let mut hashes = Vec::new();
+        for (name, assets) in [("a", &assets[..50]), ("b", &assets[50..])].iter() {
+            let file = File::create(dir.join(name)).unwrap();
+            let mut writer = Writer::new(file, &Metadata::new("test".into())).unwrap();
+            for data in assets.iter() {
+                hashes.push(writer.add(data).unwrap());
+            }
+            writer.finish().unwrap();
+        }
+        let missing = hash_of(b"missing");
+        hashes.push(missing);
+        hashes.reverse();
+
+        let set = OpenOptions::new()
+            .access(Access::Streaming)
+            .open(&dir)
+            .unwrap();
+        let result = set.get_many_uring(&hashes).unwrap();
+        assert_eq!(result.len(), hashes.len());
+        assert_eq!(
+            result[0].as_ref().err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        for (asset, data) in result[1..].iter().zip(assets.iter().rev()) {
+            assert_eq!(&**asset.as_ref().unwrap(), &data[..]);
+        }
+        // Mapped archives have nothing to read through io_uring
+        drop(set);
+        let set = OpenOptions::new()
+            .access(Access::Mapped)
+            .open(&dir)
+            .unwrap();
+        assert!(set.get_many_uring(&hashes).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pack_volumes() {
+        let dir = std::env::temp_dir().join(format!("chasset-pack-{:016X}", rand::random::<u64>()));
+        let assets = (0..16u8).map(|i| vec![i; 100]).collect::<Vec<_>>();
+        let hashes = assets.iter().map(|x| hash_of(x)).collect::<Vec<_>>();
+        let get = |hash: &Hash| {
+            let i = hashes.iter().position(|x| x == hash).unwrap();
+            Ok(Asset::from(assets[i].clone()))
+        };
+        let mut packer = Packer::new(Metadata::new("test".into()));
+        packer.max_size(Reserves::measure(&packer).unwrap().header + 512);
+        let report = packer.pack(&dir, hashes.iter().cloned(), get).unwrap();
+        assert_eq!(report.assets, 16);
+        assert!(report.volumes.len() > 1);
+        for volume in &report.volumes {
+            assert!(fs::metadata(volume).unwrap().len() <= packer.max_size.unwrap());
+        }
+
+        // Packing the same assets again must not replace the first volumes
+        let again = packer.pack(&dir, hashes.iter().cloned(), get).unwrap();
+        for volume in &again.volumes {
+            assert!(!report.volumes.contains(volume));
+        }
+        for volume in &report.volumes {
+            assert!(volume.exists());
+        }
+        let set = ArchiveSet::open(&dir).unwrap();
+        for (hash, data) in hashes.iter().zip(&assets) {
+            assert_eq!(&*set.get(hash).unwrap(), &data[..]);
+        }
+
+        // Excluded assets are never read
+        let mut packer = Packer::new(Metadata::new("test".into()));
+        packer.exclude(set.list_sizes().skip(1).map(Result::unwrap));
+        let report = packer
+            .pack(&dir.join("patch"), hashes.iter().cloned(), |hash| {
+                assert!(!packer.exclude.contains_key(hash));
+                get(hash)
+            })
+            .unwrap();
+        assert_eq!((report.assets, report.excluded), (1, 15));
+        assert_eq!(report.bytes_saved, 1500);
+
+        let mut packer = Packer::new(Metadata::new("test".into()));
+        packer.checksum(true);
+        let report = packer
+            .pack(&dir.join("checksummed"), hashes.iter().cloned(), get)
+            .unwrap();
+        let set = OpenOptions::new()
+            .verify_checksums(true)
+            .open(&dir.join("checksummed"))
+            .unwrap();
+        assert_eq!(&*set.get(&hashes[3]).unwrap(), &assets[3][..]);
+        let mut data = fs::read(&report.volumes[0]).unwrap();
+        data[20] ^= 1;
+        fs::write(&report.volumes[0], &data).unwrap();
+        assert!(OpenOptions::new()
+            .verify_checksums(true)
+            .open(&dir.join("checksummed"))
+            .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pack_inline() {
+        let dir = std::env::temp_dir().join(format!(
+            "chasset-pack-inline-{:016X}",
+            rand::random::<u64>()
+        ));
+        let assets = [&b"tiny"[..], &[7; 100][..], &b""[..]];
+        let hashes = assets.iter().map(|x| hash_of(x)).collect::<Vec<_>>();
+        let get = |hash: &Hash| {
+            let i = hashes.iter().position(|x| x == hash).unwrap();
+            Ok(Asset::from(assets[i].to_vec()))
+        };
+        let mut packer = Packer::new(Metadata::new("test".into()));
+        packer.inline_below(16).checksum(true);
+        let report = packer.pack(&dir, hashes.iter().cloned(), get).unwrap();
+        assert_eq!(report.volumes.len(), 1);
+        let path = &report.volumes[0];
+        let data = fs::read(path).unwrap();
+        let inline = Trailers::read(&data).unwrap().inline.unwrap();
+
+        let archive = Archive::open(path).unwrap();
+        archive.verify_checksum().unwrap();
+        let mut sizes = archive.list_sizes().map(Result::unwrap).collect::<Vec<_>>();
+        sizes.sort_unstable();
+        let mut expected = hashes
+            .iter()
+            .zip(&assets)
+            .map(|(&hash, x)| (hash, x.len() as u64))
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(sizes, expected);
+        for access in &[Access::Mapped, Access::Streaming] {
+            let set = OpenOptions::new()
+                .access(*access)
+                .verify_checksums(true)
+                .open(&dir)
+                .unwrap();
+            for (hash, &asset) in hashes.iter().zip(&assets) {
+                assert_eq!(&*archive.get(hash).unwrap(), asset);
+                assert_eq!(&*set.get(hash).unwrap(), asset);
+                let mut buf = Vec::new();
+                set.reader(hash).unwrap().read_to_end(&mut buf).unwrap();
+                assert_eq!(buf, asset);
+                let location = set.locate(hash).unwrap();
+                assert_eq!(location.stored_len, asset.len() as u64);
+                let range = location.offset..location.offset + location.stored_len;
+                assert_eq!(&data[range.start as usize..range.end as usize], asset);
+                // Only assets below the threshold are stored inline
+                assert_eq!(
+                    inline.start <= range.start && range.end <= inline.end,
+                    asset.len() < 16
+                );
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "ed25519-dalek")]
+    #[test]
+    fn signatures() {
+        let dir = std::env::temp_dir().join(format!("chasset-sign-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a");
+        let mut writer =
+            Writer::new(File::create(&path).unwrap(), &Metadata::new("test".into())).unwrap();
+        let hash = writer.add(b"signed").unwrap();
+        writer.finish().unwrap();
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let open = |keys: Vec<VerifyingKey>| OpenOptions::new().trusted_keys(keys).open(&dir);
+        assert!(open(vec![key.verifying_key()]).is_err());
+        sign(&path, &key).unwrap();
+        let set = open(vec![key.verifying_key()]).unwrap();
+        assert_eq!(&*set.get(&hash).unwrap(), b"signed");
+        assert!(open(vec![other.verifying_key()]).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // Flip a byte of the archive after signing
+        let mut data = fs::read(&path).unwrap();
+        let i = data.len() / 2;
+        data[i] ^= 1;
+        fs::write(&path, &data).unwrap();
+        let err = open(vec![key.verifying_key()]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "chacha20poly1305")]
+    #[test]
+    fn encrypted_roundtrip() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-encrypted-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a");
+        let key = Key::generate();
+        let plaintext = b"a secret asset that must not appear in the archive";
+        let mut writer = Writer::new_encrypted(
+            File::create(&path).unwrap(),
+            &Metadata::new("test".into()),
+            &key,
+        )
+        .unwrap();
+        let hash = writer.add(plaintext).unwrap();
+        writer.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        assert!(!data.windows(plaintext.len()).any(|x| x == &plaintext[..]));
+
+        let set = OpenOptions::new().key(key.clone()).open(&dir).unwrap();
+        assert_eq!(&*set.get(&hash).unwrap(), &plaintext[..]);
+        let mut buf = Vec::new();
+        set.reader(&hash).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, &plaintext[..]);
+        let offset = set.locate(&hash).unwrap().offset;
+        drop(set);
+
+        // Missing and wrong keys are refused
+        assert!(ArchiveSet::open(&dir).is_err());
+        assert!(OpenOptions::new().key(Key::generate()).open(&dir).is_err());
+        let mut archive = Archive::open(&path).unwrap();
+        assert!(archive.set_key(&Key::generate()).is_err());
+        archive.set_key(&key).unwrap();
+        assert_eq!(&*archive.get(&hash).unwrap(), &plaintext[..]);
+
+        // Flip a byte of the ciphertext, past the nonce
+        let mut data = data;
+        data[offset as usize + NONCE_LEN + 1] ^= 1;
+        fs::write(&path, &data).unwrap();
+        let set = OpenOptions::new().key(key).open(&dir).unwrap();
+        assert_eq!(
+            set.get(&hash).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sources_read_on_demand() {
+        struct Counted {
+            data: Vec<u8>,
+            read: Arc<std::sync::atomic::AtomicUsize>,
+        }
+        impl ReadAt for Counted {
+            fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+                let n = self.data.read_at(buf, offset)?;
+                self.read.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+                Ok(n)
+            }
+            fn size(&self) -> io::Result<u64> {
+                self.data.size()
+            }
+        }
+
+        let mut writer =
+            Writer::new(io::Cursor::new(Vec::new()), &Metadata::new("test".into())).unwrap();
+        let large = writer.add(&[0xAB; 1 << 16]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+        let read = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let source = Counted {
+            data,
+            read: read.clone(),
+        };
+        let set = OpenOptions::new()
+            .open_sources(vec![(
+                PathBuf::from("a"),
+                Box::new(source) as Box<dyn ReadAt + Send + Sync>,
+            )])
+            .unwrap();
+        assert!(read.load(std::sync::atomic::Ordering::Relaxed) < 1 << 12);
+        assert_eq!(&*set.get(&large).unwrap(), &[0xAB; 1 << 16][..]);
+        assert!(read.load(std::sync::atomic::Ordering::Relaxed) >= 1 << 16);
+    }
+
+    #[test]
+    fn budgeted_open() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-budgeted-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut writer =
+            Writer::new(io::Cursor::new(Vec::new()), &Metadata::new("test".into())).unwrap();
+        let hash = writer.add(&[0xAB; 4096]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+        fs::write(dir.join("a"), &data).unwrap();
+        let open = |budget: &MapBudget| {
+            OpenOptions::new()
+                .map_budget(budget.clone())
+                .open(&dir)
+                .unwrap()
+        };
+
+        // Archives with an index table aren't mapped until their data is read
+        let budget = MapBudget::new(u64::MAX, usize::MAX);
+        let set = open(&budget);
+        assert_eq!(budget.usage(), (0, 0));
+        assert_eq!(set.get(&hash).unwrap().len(), 4096);
+        assert_eq!(budget.usage(), (1, data.len() as u64));
+        drop(set);
+
+        // Archives without one are mapped while open, and the mapping is charged
+        let contents = Trailers::read(&data).unwrap().contents as usize;
+        fs::write(dir.join("a"), &data[..contents]).unwrap();
+        let set = open(&budget);
+        assert_eq!(budget.usage(), (1, contents as u64));
+        assert_eq!(set.get(&hash).unwrap().len(), 4096);
+        assert_eq!(budget.usage(), (1, contents as u64));
+        drop(set);
+        assert_eq!(budget.usage(), (0, 0));
+
+        // ...unless the budget refuses, in which case it's read onto the heap
+        let budget = MapBudget::new(0, 0);
+        let set = open(&budget);
+        assert_eq!(&*set.get(&hash).unwrap(), &[0xAB; 4096][..]);
+        assert_eq!(budget.usage(), (0, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_ignores_key() {
+        let metadata = Metadata {
+            creator: Some("test".into()),
+            ..Metadata::default()
+        };
+        let mut buf = Vec::new();
+        metadata.encode(&mut buf);
+        write_record(&mut buf, TAG_KEY, &[0xAB; 25]);
+        let records = records(&buf).unwrap();
+        assert_eq!(records.last(), Some(&(TAG_KEY, &[0xAB; 25][..])));
+        assert_eq!(Metadata::from_records(&records).unwrap(), metadata);
     }
 }
@@ -0,0 +1,208 @@
+//! Append-only records of when and by what each asset was written.
+//!
+//! An `AuditLog` is a text file with one line per write: seconds since the Unix epoch to nanosecond precision, the
+//! asset's hash, its size in bytes, `new` or `dup` according to whether the asset was already present, and the
+//! identity of the tool that wrote it, separated by spaces. Lines are only ever appended. Malformed lines, such as the
+//! remains of writes interrupted by a crash, are skipped when reading.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Hash, StoreObserver};
+
+/// A log of asset writes, kept by wrapping a store in an `ObservedStore` with this as its observer.
+pub struct AuditLog {
+    path: PathBuf,
+    tool: String,
+    /// Whether the log didn't end in a complete line when opened, so the next line written must first end it
+    file: Mutex<(File, bool)>,
+    error: Mutex<Option<io::Error>>,
+}
+
+/// A write recorded by an `AuditLog`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    /// When the write completed
+    pub time: SystemTime,
+    /// The asset written
+    pub hash: Hash,
+    /// Size of the asset in bytes
+    pub size: u64,
+    /// Whether the asset was not already present
+    pub new: bool,
+    /// Identity of the tool responsible
+    pub tool: String,
+}
+
+impl AuditLog {
+    /// Open the log at `path`, creating it if necessary, attributing subsequently recorded writes to `tool`.
+    ///
+    /// `tool` should identify the program and, where relevant, its user and host, e.g. "packer/1.2 ci@build3". Fails
+    /// with `io::ErrorKind::InvalidInput` if `tool` contains control characters.
+    ///
+    /// A trailing partial line, as left by an interrupted write, is terminated by the next write rather than removed,
+    /// since it may instead be a line that another process is still appending.
+    pub fn open(path: PathBuf, tool: String) -> io::Result<Self> {
+        if tool.chars().any(char::is_control) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tool identity contains control characters",
+            ));
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let len = file.metadata()?.len();
+        let torn = len > 0 && {
+            let mut last = [0];
+            file.seek(SeekFrom::Start(len - 1))?;
+            file.read_exact(&mut last)?;
+            last[0] != b'\n'
+        };
+        Ok(Self {
+            path,
+            tool,
+            file: Mutex::new((file, torn)),
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Record a write of the asset identified by `hash`, `size` bytes long, at the current time.
+    pub fn record(&self, hash: &Hash, size: u64, new: bool) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut file = self.file.lock().unwrap();
+        let line = format!(
+            "{}{}.{:09} {} {} {} {}\n",
+            if file.1 { "\n" } else { "" },
+            time.as_secs(),
+            time.subsec_nanos(),
+            hash,
+            size,
+            if new { "new" } else { "dup" },
+            self.tool
+        );
+        // A single write of the whole line keeps concurrent appends by other processes from interleaving
+        file.0.write_all(line.as_bytes())?;
+        file.1 = false;
+        Ok(())
+    }
+
+    /// Take the error, if any, that prevented the most recent failed attempt to record a write made through
+    /// `StoreObserver`.
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.error.lock().unwrap().take()
+    }
+
+    /// Read every recorded write, oldest first.
+    pub fn history(&self) -> io::Result<Vec<Record>> {
+        let text = fs::read_to_string(&self.path)?;
+        let mut lines = text.split('\n').collect::<Vec<_>>();
+        // Either empty, or a partial line that's still being written or was left by an interrupted write
+        lines.pop();
+        Ok(lines.into_iter().filter_map(|x| parse(x).ok()).collect())
+    }
+
+    /// Read every recorded write of the asset identified by `hash`, oldest first.
+    pub fn history_of(&self, hash: &Hash) -> io::Result<Vec<Record>> {
+        let mut result = self.history()?;
+        result.retain(|x| x.hash == *hash);
+        Ok(result)
+    }
+}
+
+impl StoreObserver for AuditLog {
+    fn put(&self, hash: &Hash, size: u64, new: bool) {
+        if let Err(e) = self.record(hash, size, new) {
+            *self.error.lock().unwrap() = Some(e);
+        }
+    }
+}
+
+fn parse(line: &str) -> io::Result<Record> {
+    let mut fields = line.splitn(5, ' ');
+    let mut next = || fields.next().ok_or_else(invalid_log);
+    let time = next()?;
+    let (secs, nanos) = match time.find('.') {
+        Some(i) => (&time[..i], &time[i + 1..]),
+        None => return Err(invalid_log()),
+    };
+    let time = UNIX_EPOCH
+        + Duration::new(
+            secs.parse().map_err(|_| invalid_log())?,
+            nanos.parse().map_err(|_| invalid_log())?,
+        );
+    let hash = next()?.parse().map_err(|_| invalid_log())?;
+    let size = next()?.parse().map_err(|_| invalid_log())?;
+    let new = match next()? {
+        "new" => true,
+        "dup" => false,
+        _ => return Err(invalid_log()),
+    };
+    let tool = next()?.to_owned();
+    Ok(Record {
+        time,
+        hash,
+        size,
+        new,
+        tool,
+    })
+}
+
+fn invalid_log() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed audit log")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::{MemoryStore, ObservedStore, WritableStore};
+
+    #[test]
+    fn history() {
+        let path =
+            std::env::temp_dir().join(format!("chasset-audit-{:016X}", rand::random::<u64>()));
+        let log = Arc::new(AuditLog::open(path.clone(), "test tool/1.0".into()).unwrap());
+        let store = ObservedStore::new(MemoryStore::new(), log.clone());
+        let a = WritableStore::put(&store, b"a").unwrap();
+        let b = WritableStore::put(&store, b"bb").unwrap();
+        WritableStore::put(&store, b"a").unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"123 partial")
+            .unwrap();
+
+        let history = log.history().unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].hash, b);
+        assert_eq!(history[1].size, 2);
+        assert_eq!(history[1].tool, "test tool/1.0");
+        let of_a = log.history_of(&a).unwrap();
+        assert_eq!(
+            of_a.iter().map(|x| x.new).collect::<Vec<_>>(),
+            [true, false]
+        );
+        assert!(log.take_error().is_none());
+        drop(store);
+        let log = AuditLog::open(path.clone(), "other".into()).unwrap();
+        // The partial line is left for its writer to finish
+        assert!(fs::read(&path).unwrap().ends_with(b"123 partial"));
+        log.record(&a, 1, false).unwrap();
+        log.record(&b, 2, false).unwrap();
+        let history = log.history().unwrap();
+        assert_eq!(history.len(), 5);
+        assert_eq!(history[3].hash, a);
+        assert_eq!(history[3].tool, "other");
+        fs::remove_file(&path).unwrap();
+    }
+}
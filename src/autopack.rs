@@ -0,0 +1,340 @@
+//! Tools for a repository that migrates loose files into archives as they accumulate.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use crate::archive::{ArchiveSet, Packer};
+use crate::{loose_files, Asset, ContentSet, Hash, LooseFiles, Store, StoreWriter, WritableStore};
+
+/// Configuration for an `AutoPackStore`.
+#[derive(Debug, Clone)]
+pub struct AutoPackOptions {
+    packer: Packer,
+    max_count: Option<usize>,
+    max_bytes: Option<u64>,
+}
+
+impl AutoPackOptions {
+    /// Pack loose assets into archives using `packer`.
+    ///
+    /// By default, packing only happens when explicitly requested with `AutoPackStore::pack`.
+    pub fn new(packer: Packer) -> Self {
+        Self {
+            packer,
+            max_count: None,
+            max_bytes: None,
+        }
+    }
+
+    /// Begin packing in the background once more than `n` loose assets have accumulated.
+    pub fn max_count(&mut self, n: usize) -> &mut Self {
+        self.max_count = Some(n);
+        self
+    }
+
+    /// Begin packing in the background once loose assets total more than `bytes`.
+    pub fn max_bytes(&mut self, bytes: u64) -> &mut Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Accept writes into `loose`, packing them into archives in `dir`.
+    pub fn open(&self, loose: LooseFiles, dir: PathBuf) -> io::Result<AutoPackStore> {
+        let archives = ArchiveSet::open(&dir)?;
+        let mut state = State::default();
+        for x in loose.list_sizes() {
+            state.count += 1;
+            state.bytes += x?.1;
+        }
+        Ok(AutoPackStore {
+            inner: Arc::new(Inner {
+                options: self.clone(),
+                loose,
+                dir,
+                archives: RwLock::new(Arc::new(archives)),
+                state: Mutex::new(state),
+            }),
+        })
+    }
+}
+
+/// A store that accepts writes into `LooseFiles` and packs them into an `ArchiveSet` once they accumulate.
+///
+/// Reads consult the loose files first, then the archives. Packing writes new archives, reopens the archive set, and
+/// only then deletes the packed loose files, so every asset remains readable throughout.
+pub struct AutoPackStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    options: AutoPackOptions,
+    loose: LooseFiles,
+    dir: PathBuf,
+    archives: RwLock<Arc<ArchiveSet>>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Number of loose assets
+    count: usize,
+    /// Total size of loose assets
+    bytes: u64,
+    /// Whether a pack is in progress
+    packing: bool,
+    /// Failure of the most recent background pack
+    error: Option<io::Error>,
+}
+
+impl AutoPackStore {
+    /// The loose files that new assets are written into.
+    pub fn loose(&self) -> &LooseFiles {
+        &self.inner.loose
+    }
+
+    /// A snapshot of the archives that loose assets are packed into.
+    pub fn archives(&self) -> Arc<ArchiveSet> {
+        self.inner.archives.read().unwrap().clone()
+    }
+
+    /// Location of the archives.
+    pub fn dir(&self) -> &Path {
+        &self.inner.dir
+    }
+
+    /// Pack all loose assets into a new archive now, blocking until complete.
+    ///
+    /// Does nothing if a pack is already in progress, or if any process holds a lock on the loose repository.
+    pub fn pack(&self) -> io::Result<()> {
+        if !self.inner.begin() {
+            return Ok(());
+        }
+        self.inner.pack()
+    }
+
+    /// Take the error, if any, that caused the most recent background pack to fail.
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.inner.state.lock().unwrap().error.take()
+    }
+
+    /// Account for a newly written loose asset, starting a background pack if a threshold is exceeded.
+    fn added(&self, len: u64) {
+        let start = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.count += 1;
+            state.bytes += len;
+            let options = &self.inner.options;
+            let over = options.max_count.is_some_and(|x| state.count > x)
+                || options.max_bytes.is_some_and(|x| state.bytes > x);
+            if over && !state.packing {
+                state.packing = true;
+                true
+            } else {
+                false
+            }
+        };
+        if start {
+            let inner = self.inner.clone();
+            thread::spawn(move || {
+                if let Err(e) = inner.pack() {
+                    inner.state.lock().unwrap().error = Some(e);
+                }
+            });
+        }
+    }
+}
+
+impl Inner {
+    /// Claim the right to pack, returning `false` if a pack is already in progress.
+    fn begin(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        !std::mem::replace(&mut state.packing, true)
+    }
+
+    fn pack(&self) -> io::Result<()> {
+        let _packing = Packing(self);
+        self.pack_inner()
+    }
+
+    fn pack_inner(&self) -> io::Result<()> {
+        // Another process is maintaining or relying on the loose files; leave them be
+        let _lock = match self.loose.try_lock_exclusive()? {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let sizes = self.loose.list_sizes().collect::<io::Result<Vec<_>>>()?;
+        if sizes.is_empty() {
+            return Ok(());
+        }
+        self.options
+            .packer
+            .pack(&self.dir, sizes.iter().map(|x| x.0), |x| self.loose.get(x))?;
+        let archives = Arc::new(ArchiveSet::open(&self.dir)?);
+        *self.archives.write().unwrap() = archives.clone();
+        // Assets the packer excluded weren't written, so must stay loose
+        let (mut count, mut bytes) = (0, 0);
+        for &(ref hash, len) in &sizes {
+            if archives.contains(hash) {
+                self.loose.remove(hash)?;
+                count += 1;
+                bytes += len;
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.count = state.count.saturating_sub(count);
+        state.bytes = state.bytes.saturating_sub(bytes);
+        Ok(())
+    }
+}
+
+/// Releases the right to pack when dropped, even if packing panicked, so that later packs aren't blocked forever.
+struct Packing<'a>(&'a Inner);
+
+impl Drop for Packing<'_> {
+    fn drop(&mut self) {
+        // Tolerate poisoning, since this may run while unwinding
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.packing = false;
+    }
+}
+
+impl Store for AutoPackStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        match self.inner.loose.get(hash) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            x => {
+                return x;
+            }
+        }
+        self.archives().get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.inner.loose.contains(hash) || self.inner.archives.read().unwrap().contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        let archives = self.archives();
+        let loose = self.inner.loose.list().collect::<ContentSet>();
+        let packed = archives
+            .list()
+            .filter(|x| !loose.contains(x))
+            .collect::<Vec<_>>();
+        Box::new(loose.into_iter().chain(packed))
+    }
+}
+
+impl WritableStore for AutoPackStore {
+    type Writer = Writer;
+
+    fn make_writer(&self) -> io::Result<Writer> {
+        Ok(Writer {
+            inner: self.inner.loose.make_writer()?,
+            store: AutoPackStore {
+                inner: self.inner.clone(),
+            },
+            len: 0,
+        })
+    }
+}
+
+/// A staging area for streaming data into an `AutoPackStore`.
+pub struct Writer {
+    inner: loose_files::Writer,
+    store: AutoPackStore,
+    len: u64,
+}
+
+impl StoreWriter for Writer {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        let (hash, new) = self.inner.store()?;
+        if new {
+            self.store.added(self.len);
+        }
+        Ok((hash, new))
+    }
+}
+
+impl io::Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::archive::Metadata;
+    use std::time::{Duration, Instant};
+
+    fn open(options: &mut AutoPackOptions) -> (PathBuf, AutoPackStore) {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-autopack-{:016X}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join("archives")).unwrap();
+        let loose = LooseFiles::open(dir.join("loose")).unwrap();
+        let store = options.open(loose, dir.join("archives")).unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn explicit() {
+        let (dir, store) = open(&mut AutoPackOptions::new(Packer::new(Metadata::new(
+            "test".into(),
+        ))));
+        let hash = store.put(b"data").unwrap();
+        assert!(store.loose().contains(&hash));
+        store.pack().unwrap();
+        assert!(!store.loose().contains(&hash));
+        assert!(store.archives().contains(&hash));
+        assert_eq!(&Store::get(&store, &hash).unwrap()[..], b"data");
+        assert_eq!(Store::list(&store).collect::<Vec<_>>(), vec![hash]);
+        // Nothing left to pack
+        store.pack().unwrap();
+        assert_eq!(store.archives().archives().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exclude() {
+        let mut packer = Packer::new(Metadata::new("test".into()));
+        packer.exclude(vec![(crate::hash_of(b"excluded"), 8)]);
+        let (dir, store) = open(&mut AutoPackOptions::new(packer));
+        let excluded = store.put(b"excluded").unwrap();
+        let packed = store.put(b"packed").unwrap();
+        store.pack().unwrap();
+        assert!(store.archives().contains(&packed));
+        assert!(!store.loose().contains(&packed));
+        // Not written to any archive, so still loose
+        assert!(!store.archives().contains(&excluded));
+        assert_eq!(&Store::get(&store, &excluded).unwrap()[..], b"excluded");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn threshold() {
+        let (dir, store) =
+            open(AutoPackOptions::new(Packer::new(Metadata::new("test".into()))).max_count(2));
+        let hashes = (0..3u8)
+            .map(|i| store.put(&[i]).unwrap())
+            .collect::<Vec<_>>();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while store.loose().list().next().is_some() {
+            assert!(Instant::now() < deadline, "background pack never finished");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(store.take_error().is_none());
+        let archives = store.archives();
+        for hash in &hashes {
+            assert!(archives.contains(hash));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! Limits on the memory mappings held across repositories.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use memmap::Mmap;
+
+use crate::map_file;
+
+/// A shared cap on the number and total size of memory mappings made by the repositories using it.
+///
+/// Mappings still referenced by an `Asset` always remain valid and count against the budget until dropped. When a new
+/// mapping would exceed the budget, mappings cached by an `ArchiveSet` are released in least recently used order; if
+/// that doesn't make enough room, data is read onto the heap instead of being mapped.
+///
+/// Useful where address space is scarce, such as in 32-bit processes or those with many repositories open.
+#[derive(Clone)]
+pub struct MapBudget {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    max_bytes: u64,
+    max_maps: usize,
+    #[cfg_attr(not(feature = "carchive"), allow(dead_code))]
+    clock: AtomicU64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Every mapping made, and its size
+    live: Vec<(Weak<Mmap>, u64)>,
+    /// Mappings cached for reuse, which may be released
+    cached: Vec<Weak<Slot>>,
+}
+
+/// A mapping cached for reuse, subject to release by a `MapBudget`.
+#[derive(Default)]
+pub(crate) struct Slot {
+    state: Mutex<SlotState>,
+}
+
+#[derive(Default)]
+struct SlotState {
+    map: Option<Arc<Mmap>>,
+    used: u64,
+}
+
+impl MapBudget {
+    /// Allow at most `max_maps` mappings totaling at most `max_bytes` at once.
+    pub fn new(max_bytes: u64, max_maps: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                max_bytes,
+                max_maps,
+                clock: AtomicU64::new(0),
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    /// Number and total size of the mappings currently alive.
+    pub fn usage(&self) -> (usize, u64) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.prune();
+        (state.live.len(), state.bytes())
+    }
+
+    /// Map `file` if the budget allows, releasing cached mappings to make room if necessary.
+    pub(crate) fn map(&self, file: &File) -> io::Result<Option<Arc<Mmap>>> {
+        let len = file.metadata()?.len();
+        if len == 0 {
+            // Empty files cannot be mapped
+            return Ok(None);
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        state.prune();
+        while state.live.len() + 1 > self.inner.max_maps
+            || state.bytes() + len > self.inner.max_bytes
+        {
+            if !state.release_coldest() {
+                return Ok(None);
+            }
+            state.prune();
+        }
+        let map = map_file(file)?;
+        state.live.push((Arc::downgrade(&map), len));
+        Ok(Some(map))
+    }
+
+    /// Create a slot for caching a mapping.
+    #[cfg_attr(not(feature = "carchive"), allow(dead_code))]
+    pub(crate) fn slot(&self) -> Arc<Slot> {
+        let slot = Arc::new(Slot::default());
+        let mut state = self.inner.state.lock().unwrap();
+        state.cached.retain(|x| x.strong_count() > 0);
+        state.cached.push(Arc::downgrade(&slot));
+        slot
+    }
+
+    /// Get the mapping of `file` cached in `slot`, mapping it anew if necessary and the budget allows.
+    #[cfg_attr(not(feature = "carchive"), allow(dead_code))]
+    pub(crate) fn get(&self, slot: &Slot, file: &File) -> io::Result<Option<Arc<Mmap>>> {
+        let now = self.inner.clock.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut cached = slot.state.lock().unwrap();
+            if let Some(map) = cached.map.clone() {
+                cached.used = now;
+                return Ok(Some(map));
+            }
+        }
+        // The slot must not be locked while mapping, since making room may lock other slots
+        let map = self.map(file)?;
+        if let Some(ref map) = map {
+            let mut cached = slot.state.lock().unwrap();
+            if cached.map.is_none() {
+                cached.map = Some(map.clone());
+            }
+            cached.used = now;
+        }
+        Ok(map)
+    }
+}
+
+impl fmt::Debug for MapBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MapBudget")
+            .field("max_bytes", &self.inner.max_bytes)
+            .field("max_maps", &self.inner.max_maps)
+            .finish()
+    }
+}
+
+impl State {
+    /// Forget mappings that no longer exist.
+    fn prune(&mut self) {
+        self.live.retain(|(x, _)| x.strong_count() > 0);
+    }
+
+    fn bytes(&self) -> u64 {
+        self.live.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Release the least recently used cached mapping, returning `false` if none could be released.
+    fn release_coldest(&mut self) -> bool {
+        let mut coldest: Option<(u64, Arc<Slot>)> = None;
+        for slot in self.cached.iter().filter_map(|x| x.upgrade()) {
+            // Slots in use are skipped rather than waited on, preventing deadlock
+            let used = match slot.state.try_lock() {
+                Ok(ref x) if x.map.is_some() => x.used,
+                _ => continue,
+            };
+            if coldest.as_ref().is_none_or(|&(x, _)| used < x) {
+                coldest = Some((used, slot));
+            }
+        }
+        let slot = match coldest {
+            Some((_, slot)) => slot,
+            None => return false,
+        };
+        let released = match slot.state.try_lock() {
+            Ok(mut x) => x.map.take().is_some(),
+            Err(_) => false,
+        };
+        released
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn release() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-budget-{:016X}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut files = Vec::new();
+        for i in 0..3 {
+            let path = dir.join(i.to_string());
+            File::create(&path).unwrap().write_all(b"data").unwrap();
+            files.push(File::open(&path).unwrap());
+        }
+        let budget = MapBudget::new(8, 10);
+        let slots = (0..3).map(|_| budget.slot()).collect::<Vec<_>>();
+        assert!(budget.get(&slots[0], &files[0]).unwrap().is_some());
+        let held = budget.get(&slots[1], &files[1]).unwrap().unwrap();
+        assert_eq!(budget.usage(), (2, 8));
+        // Releases slot 0, the least recently used
+        assert!(budget.get(&slots[2], &files[2]).unwrap().is_some());
+        assert!(slots[0].state.lock().unwrap().map.is_none());
+        // Releasing slot 1 doesn't make room, since its mapping is kept alive by `held`, so slot 2 is released too
+        let remapped = budget.get(&slots[0], &files[0]).unwrap().unwrap();
+        assert!(slots[2].state.lock().unwrap().map.is_none());
+        // Every mapping is in use
+        assert!(budget.map(&files[2]).unwrap().is_none());
+        drop((held, remapped));
+        assert_eq!(budget.usage(), (0, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
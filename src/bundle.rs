@@ -0,0 +1,147 @@
+//! A portable stream format for transferring assets between repositories.
+//!
+//! A bundle is self-describing, so it can be carried between air-gapped machines and read into any `WritableStore`.
+//! It consists of the magic `CHBUNDL1` followed by any number of entries, each:
+//!
+//! - the byte 1
+//! - a little-endian `u16` hash kind ID, `u16` length, and bytes of the asset's hash
+//! - a `u64` length, followed by that many bytes of asset data
+//!
+//! and finally the byte 0. Each asset is checked against its hash as it's read, so corruption in transit is detected.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{Hash, HashKind, Hasher, Store, StoreWriter, WritableStore};
+
+const MAGIC: &[u8; 8] = b"CHBUNDL1";
+
+/// Write the assets identified by `hashes` from `store` into `out` as a bundle.
+pub fn write<S, I, W>(store: &S, hashes: I, mut out: W) -> io::Result<()>
+where
+    S: Store + ?Sized,
+    I: IntoIterator<Item = Hash>,
+    W: Write,
+{
+    out.write_all(MAGIC)?;
+    for hash in hashes {
+        let data = store.get(&hash)?;
+        let mut header = [0; 5];
+        header[0] = 1;
+        LittleEndian::write_u16(&mut header[1..3], hash.kind().id());
+        LittleEndian::write_u16(&mut header[3..5], hash.bytes().len() as u16);
+        out.write_all(&header)?;
+        out.write_all(hash.bytes())?;
+        let mut len = [0; 8];
+        LittleEndian::write_u64(&mut len, data.len() as u64);
+        out.write_all(&len)?;
+        out.write_all(&data)?;
+    }
+    out.write_all(&[0])?;
+    out.flush()
+}
+
+/// Read every asset in the bundle `input` into `dest`, returning their hashes.
+///
+/// Assets are streamed into `dest` in constant memory. If an asset doesn't match its hash, reading stops with an error
+/// and that asset is discarded; earlier assets remain in `dest`.
+pub fn read<R, S>(mut input: R, dest: &S) -> io::Result<Vec<Hash>>
+where
+    R: Read,
+    S: WritableStore + ?Sized,
+{
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a bundle"));
+    }
+    let mut hashes = Vec::new();
+    loop {
+        let mut header = [0; 5];
+        input.read_exact(&mut header[..1])?;
+        match header[0] {
+            0 => return Ok(hashes),
+            1 => {}
+            _ => return Err(malformed()),
+        }
+        input.read_exact(&mut header[1..])?;
+        let kind = HashKind::from_id(LittleEndian::read_u16(&header[1..3])).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unknown hash kind in bundle")
+        })?;
+        let mut bytes = vec![0; LittleEndian::read_u16(&header[3..5]) as usize];
+        input.read_exact(&mut bytes)?;
+        let hash = Hash::from_bytes(kind, &bytes).map_err(|_| malformed())?;
+        let mut len = [0; 8];
+        input.read_exact(&mut len)?;
+        let len = LittleEndian::read_u64(&len);
+
+        let mut writer = Verifier {
+            inner: dest.make_writer()?,
+            hasher: Hasher::new(),
+        };
+        if io::copy(&mut (&mut input).take(len), &mut writer)? != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated bundle",
+            ));
+        }
+        if writer.hasher.result() != hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data for {} does not match its hash", hash),
+            ));
+        }
+        writer.inner.store()?;
+        hashes.push(hash);
+    }
+}
+
+/// Hashes data on its way into a store, so it can be checked before being committed
+struct Verifier<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for Verifier<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.process(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed bundle")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MemoryStore;
+
+    #[test]
+    fn roundtrip() {
+        let source = MemoryStore::new();
+        let hashes = vec![source.put(b"foo"), source.put(b"bar")];
+        let mut bundle = Vec::new();
+        write(&source, hashes.iter().cloned(), &mut bundle).unwrap();
+
+        let dest = MemoryStore::new();
+        assert_eq!(read(&bundle[..], &dest).unwrap(), hashes);
+        assert_eq!(&dest.get(&hashes[1]).unwrap()[..], b"bar");
+
+        let corrupt = bundle.len() - 2;
+        bundle[corrupt] ^= 1;
+        let dest = MemoryStore::new();
+        assert_eq!(
+            read(&bundle[..], &dest).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(!dest.contains(&hashes[1]));
+    }
+}
@@ -0,0 +1,279 @@
+//! Interoperability with IPFS content identifiers.
+//!
+//! A CIDv1 names a block by a multihash of its contents, so chasset hashes translate directly: a blake2b `Hash` is
+//! the multihash `blake2b-200`. `BlockStore` exposes any repository in those terms, allowing it to back an IPFS or
+//! bitswap node.
+
+use std::str::FromStr;
+use std::{fmt, io};
+
+use data_encoding::{DecodeError, BASE32_NOPAD};
+use err_derive::Error;
+
+use crate::{Asset, Hash, HashKind, Hasher, Store, WritableStore};
+
+/// Multicodec of blocks holding opaque data.
+pub const RAW: u64 = 0x55;
+
+/// Multicodec of CID version 1.
+const VERSION: u64 = 1;
+
+/// Multihash function code of `HashKind::Blake2b`.
+const BLAKE2B_200: u64 = 0xb219;
+
+/// A version 1 content identifier.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Cid {
+    codec: u64,
+    hash: Hash,
+}
+
+/// Errors in the encoding of a CID.
+#[derive(Debug, Error)]
+pub enum CidError {
+    /// Ended before the CID was complete.
+    #[error(display = "truncated CID")]
+    Truncated,
+    /// Data follows the CID.
+    #[error(display = "trailing data after CID")]
+    Trailing,
+    /// A CID version other than 1.
+    #[error(display = "unsupported CID version: {}", _0)]
+    UnsupportedVersion(u64),
+    /// A multihash function with no corresponding `HashKind`.
+    #[error(display = "unsupported multihash function: {:#x}", _0)]
+    UnsupportedHash(u64),
+    /// A digest whose length doesn't match its multihash function.
+    #[error(display = "invalid digest length")]
+    InvalidLength,
+    /// A multibase other than base32.
+    #[error(display = "unsupported multibase: {:?}", _0)]
+    UnsupportedBase(Option<char>),
+    /// Malformed base32.
+    #[error(display = "malformed CID: {}", _0)]
+    Malformed(DecodeError),
+}
+
+impl Cid {
+    /// Identify data of the form given by the multicodec `codec` by its `hash`.
+    pub fn new(codec: u64, hash: Hash) -> Self {
+        Self { codec, hash }
+    }
+
+    /// Identify opaque data by its `hash`.
+    pub fn raw(hash: Hash) -> Self {
+        Self::new(RAW, hash)
+    }
+
+    /// The multicodec describing the form of the identified data.
+    pub fn codec(&self) -> u64 {
+        self.codec
+    }
+
+    /// The hash of the identified data.
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// Encode in binary form.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let digest = self.hash.bytes();
+        let mut out = Vec::with_capacity(8 + digest.len());
+        write_varint(&mut out, VERSION);
+        write_varint(&mut out, self.codec);
+        write_varint(&mut out, multihash_code(self.hash.kind()));
+        write_varint(&mut out, digest.len() as u64);
+        out.extend_from_slice(digest);
+        out
+    }
+
+    /// Decode from binary form.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, CidError> {
+        let version = read_varint(&mut bytes)?;
+        if version != VERSION {
+            return Err(CidError::UnsupportedVersion(version));
+        }
+        let codec = read_varint(&mut bytes)?;
+        let code = read_varint(&mut bytes)?;
+        let kind = match code {
+            BLAKE2B_200 => HashKind::Blake2b,
+            _ => return Err(CidError::UnsupportedHash(code)),
+        };
+        let len = read_varint(&mut bytes)?;
+        if len != kind.len() as u64 {
+            return Err(CidError::InvalidLength);
+        }
+        if bytes.len() < kind.len() {
+            return Err(CidError::Truncated);
+        }
+        if bytes.len() > kind.len() {
+            return Err(CidError::Trailing);
+        }
+        let hash = Hash::from_bytes(kind, bytes).map_err(|_| CidError::InvalidLength)?;
+        Ok(Self { codec, hash })
+    }
+}
+
+impl From<Hash> for Cid {
+    fn from(hash: Hash) -> Self {
+        Self::raw(hash)
+    }
+}
+
+/// Multibase base32, as conventionally used for CIDv1.
+impl fmt::Display for Cid {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "b{}",
+            BASE32_NOPAD.encode(&self.to_bytes()).to_ascii_lowercase()
+        )
+    }
+}
+
+impl FromStr for Cid {
+    type Err = CidError;
+    fn from_str(s: &str) -> Result<Self, CidError> {
+        let mut chars = s.chars();
+        match chars.next() {
+            Some('b') | Some('B') => {}
+            x => return Err(CidError::UnsupportedBase(x)),
+        }
+        let bytes = BASE32_NOPAD
+            .decode(chars.as_str().to_ascii_uppercase().as_bytes())
+            .map_err(CidError::Malformed)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+fn multihash_code(kind: HashKind) -> u64 {
+    match kind {
+        HashKind::Blake2b => BLAKE2B_200,
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+/// Read an unsigned varint, which the multiformats spec limits to 9 bytes.
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, CidError> {
+    let mut x = 0;
+    for i in 0..9 {
+        let (&byte, rest) = bytes.split_first().ok_or(CidError::Truncated)?;
+        *bytes = rest;
+        x |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+    Err(CidError::Truncated)
+}
+
+/// Addresses a repository by CID, for use as the block store of an IPFS node.
+///
+/// Blocks are stored as plain assets, so the codec of a CID has no bearing on which block it retrieves.
+#[derive(Debug, Clone)]
+pub struct BlockStore<S> {
+    store: S,
+}
+
+impl<S> BlockStore<S> {
+    /// Serve the blocks of `store`.
+    pub fn new(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Access the underlying repository.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Recover the underlying repository.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S: Store> BlockStore<S> {
+    /// Get the block identified by `cid`.
+    pub fn get(&self, cid: &Cid) -> io::Result<Asset> {
+        self.store.get(&cid.hash)
+    }
+
+    /// Whether the block identified by `cid` is present.
+    pub fn has(&self, cid: &Cid) -> bool {
+        self.store.contains(&cid.hash)
+    }
+
+    /// Get the block identified by the binary CID `cid`.
+    ///
+    /// CIDs using hash functions chasset doesn't implement can never be present, and so are reported as not found.
+    pub fn get_bytes(&self, cid: &[u8]) -> io::Result<Asset> {
+        match Cid::from_bytes(cid) {
+            Ok(cid) => self.get(&cid),
+            Err(CidError::UnsupportedHash(_)) | Err(CidError::UnsupportedVersion(_)) => {
+                Err(io::Error::new(io::ErrorKind::NotFound, "no such block"))
+            }
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        }
+    }
+
+    /// Enumerate the raw CIDs of every block present.
+    pub fn list(&self) -> impl Iterator<Item = Cid> + '_ {
+        self.store.list().map(Cid::raw)
+    }
+}
+
+impl<S: WritableStore> BlockStore<S> {
+    /// Store `data` as a raw block.
+    pub fn put(&self, data: &[u8]) -> io::Result<Cid> {
+        Ok(Cid::raw(self.store.put(data)?))
+    }
+
+    /// Store a block received from a peer, verifying that it's identified by `cid`.
+    pub fn put_block(&self, cid: &Cid, data: &[u8]) -> io::Result<()> {
+        let mut hasher = Hasher::new();
+        hasher.process(data);
+        if hasher.result() != cid.hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "block does not match CID",
+            ));
+        }
+        self.store.put(data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MemoryStore;
+
+    #[test]
+    fn roundtrip() {
+        let cid = Cid::raw(Hash::Blake2b([0xAB; 25]));
+        let bytes = cid.to_bytes();
+        assert_eq!(&bytes[..6], &[0x01, 0x55, 0x99, 0xe4, 0x02, 25]);
+        assert_eq!(Cid::from_bytes(&bytes).unwrap(), cid);
+        let text = cid.to_string();
+        assert!(text.starts_with("bafk"));
+        assert_eq!(text.parse::<Cid>().unwrap(), cid);
+        assert!(Cid::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn blocks() {
+        let blocks = BlockStore::new(MemoryStore::new());
+        let cid = blocks.put(b"hello").unwrap();
+        assert!(blocks.has(&cid));
+        assert_eq!(&*blocks.get_bytes(&cid.to_bytes()).unwrap(), b"hello");
+        assert!(blocks.put_block(&cid, b"goodbye").is_err());
+        assert_eq!(blocks.list().collect::<Vec<_>>(), vec![cid]);
+    }
+}
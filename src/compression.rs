@@ -0,0 +1,287 @@
+//! Compression of asset data at rest, using zstd dictionaries shared between assets.
+//!
+//! Small assets compress poorly on their own, since each must independently establish the statistics a compressor
+//! relies on. A `Dictionary` trained over a sample of similar assets supplies those statistics up front, often
+//! improving ratios severalfold for collections of many small, similar assets such as JSON documents or shaders.
+//! Dictionaries are identified by their hash, and may themselves be stored as assets.
+//!
+//! Each compressed asset is stored as a single zstd frame.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::index::Index;
+use crate::{hash_of, not_found, Asset, Buffer, Hash, Store, StoreWriter, WritableStore};
+
+/// Compression level used where none is specified.
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// A zstd dictionary for compressing and decompressing assets similar to those it was trained on.
+#[derive(Clone)]
+pub struct Dictionary {
+    hash: Hash,
+    data: Arc<Vec<u8>>,
+}
+
+impl Dictionary {
+    /// Train a dictionary of at most `max_size` bytes over the assets of `store` identified by `samples`.
+    ///
+    /// A few thousand samples and a `max_size` of around 100KiB are typical.
+    pub fn train<S, I>(store: &S, samples: I, max_size: usize) -> io::Result<Self>
+    where
+        S: Store + ?Sized,
+        I: IntoIterator<Item = Hash>,
+    {
+        let samples = samples
+            .into_iter()
+            .map(|x| store.get(&x))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self::from_bytes(zstd::dict::from_samples(
+            &samples, max_size,
+        )?))
+    }
+
+    /// Use `data`, e.g. as previously returned by `as_bytes`, as a dictionary.
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self {
+            hash: hash_of(&data),
+            data: Arc::new(data),
+        }
+    }
+
+    /// Load the dictionary stored in `store` as the asset identified by `hash`.
+    pub fn load<S: Store + ?Sized>(store: &S, hash: &Hash) -> io::Result<Self> {
+        let dictionary = Self::from_bytes(store.get(hash)?.to_vec());
+        if dictionary.hash != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data for {} does not match its hash", hash),
+            ));
+        }
+        Ok(dictionary)
+    }
+
+    /// The hash identifying this dictionary, equal to that of `as_bytes`.
+    pub fn hash(&self) -> &Hash {
+        &self.hash
+    }
+
+    /// The serialized dictionary.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Compress `data` at `level`, from 1 to 22.
+    pub fn compress(&self, data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+        zstd::bulk::Compressor::with_dictionary(level, &self.data)?.compress(data)
+    }
+
+    /// Decompress `data` compressed with this dictionary.
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut result = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(data, &self.data)?.read_to_end(&mut result)?;
+        Ok(result)
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dictionary({})", self.hash)
+    }
+}
+
+/// Upper bound on the size of `len` bytes once compressed, following zstd's `ZSTD_COMPRESSBOUND`.
+#[cfg_attr(not(feature = "carchive"), allow(dead_code))]
+pub(crate) fn compress_bound(len: u64) -> u64 {
+    const SMALL: u64 = 128 * 1024;
+    len + (len >> 8) + if len < SMALL { (SMALL - len) >> 11 } else { 0 }
+}
+
+/// A store that compresses asset data with a `Dictionary` before writing it into another store, and decompresses it on
+/// read.
+///
+/// Assets are addressed by the hash of their uncompressed data, recorded in a local index mapping it to the hash of the
+/// compressed data in the inner store. The index uses the same format as that of an `EncryptedStore`.
+pub struct CompressedStore<S> {
+    shared: Arc<Shared<S>>,
+}
+
+struct Shared<S> {
+    inner: S,
+    dictionary: Dictionary,
+    level: i32,
+    index: Index,
+}
+
+impl<S> CompressedStore<S> {
+    /// Compress assets stored in `inner` with `dictionary` at `level`, addressing them via the index at `index`, which
+    /// is created if necessary.
+    pub fn open(inner: S, dictionary: Dictionary, level: i32, index: &Path) -> io::Result<Self> {
+        Ok(Self {
+            shared: Arc::new(Shared {
+                inner,
+                dictionary,
+                level,
+                index: Index::open(index)?,
+            }),
+        })
+    }
+
+    /// The store holding compressed data.
+    pub fn inner(&self) -> &S {
+        &self.shared.inner
+    }
+
+    /// The dictionary assets are compressed with.
+    pub fn dictionary(&self) -> &Dictionary {
+        &self.shared.dictionary
+    }
+}
+
+impl<S: Store> CompressedStore<S> {
+    /// Access and decompress the asset identified by `hash`.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let shared = &*self.shared;
+        let stored = shared.index.get(hash).ok_or_else(not_found)?;
+        let data = shared.dictionary.decompress(&shared.inner.get(&stored)?)?;
+        if hash_of(&data) != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data for {} does not match its hash", hash),
+            ));
+        }
+        Ok(data.into())
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.shared
+            .index
+            .get(hash)
+            .is_some_and(|x| self.shared.inner.contains(&x))
+    }
+
+    /// Enumerate assets stored in the repository.
+    ///
+    /// This should only be used for diagnostic purposes. It almost never makes sense to access an asset you don't
+    /// already know the hash of.
+    pub fn list(&self) -> impl Iterator<Item = Hash> {
+        self.shared.index.keys().into_iter()
+    }
+}
+
+impl<S: WritableStore> CompressedStore<S> {
+    /// Create a `Writer` for streaming data into the repository.
+    ///
+    /// Data is buffered in memory until it's stored.
+    pub fn make_writer(&self) -> Writer<S> {
+        Writer {
+            shared: self.shared.clone(),
+            buffer: Buffer::default(),
+        }
+    }
+
+    /// Compress and write `data` into the repository.
+    pub fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.shared.put(hash_of(data), data).map(|(hash, _)| hash)
+    }
+}
+
+impl<S: WritableStore> Shared<S> {
+    fn put(&self, hash: Hash, data: &[u8]) -> io::Result<(Hash, bool)> {
+        if self.index.contains(&hash) {
+            return Ok((hash, false));
+        }
+        let compressed = self.dictionary.compress(data, self.level)?;
+        let mut writer = self.inner.make_writer()?;
+        writer.write_all(&compressed)?;
+        let (stored, _) = writer.store()?;
+        Ok((hash, self.index.insert(hash, stored)?))
+    }
+}
+
+impl<S: Store> Store for CompressedStore<S> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+}
+
+impl<S: WritableStore> WritableStore for CompressedStore<S> {
+    type Writer = Writer<S>;
+
+    fn make_writer(&self) -> io::Result<Writer<S>> {
+        Ok(self.make_writer())
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+/// A staging area for streaming data into a `CompressedStore`.
+///
+/// `store` must be called to commit data to the repository. Otherwise, it will be discarded when the `Writer` is
+/// dropped.
+pub struct Writer<S> {
+    shared: Arc<Shared<S>>,
+    buffer: Buffer,
+}
+
+impl<S: WritableStore> StoreWriter for Writer<S> {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        let (hash, data) = self.buffer.finish();
+        self.shared.put(hash, &data)
+    }
+}
+
+impl<S> io::Write for Writer<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    use crate::MemoryStore;
+
+    #[test]
+    fn roundtrip() {
+        let samples = MemoryStore::new();
+        let hashes = (0..100)
+            .map(|i| samples.put(format!("{{\"id\": {}, \"kind\": \"shader\"}}", i).as_bytes()))
+            .collect::<Vec<_>>();
+        let dictionary = Dictionary::train(&samples, hashes, 4096).unwrap();
+        let dict_hash = samples.put(dictionary.as_bytes());
+        assert_eq!(dict_hash, *dictionary.hash());
+        let dictionary = Dictionary::load(&samples, &dict_hash).unwrap();
+
+        let index =
+            std::env::temp_dir().join(format!("chasset-zindex-{:016X}", rand::random::<u64>()));
+        let store =
+            CompressedStore::open(MemoryStore::new(), dictionary, DEFAULT_LEVEL, &index).unwrap();
+        let data = b"{\"id\": 1000, \"kind\": \"shader\"}";
+        let hash = store.put(data).unwrap();
+        assert_eq!(hash, hash_of(data));
+        assert!(!store.inner().contains(&hash));
+        assert_eq!(&store.get(&hash).unwrap()[..], &data[..]);
+        assert_eq!(store.list().collect::<Vec<_>>(), [hash]);
+        fs::remove_file(&index).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+//! Comparison of the contents of two repositories, e.g. to monitor replication or estimate the size of a transfer.
+
+use std::cmp::Ordering;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Hash, Store};
+
+/// Greatest number of hashes from each repository held in memory at once, give or take random variation.
+const PARTITION_SIZE: usize = 1 << 16;
+
+/// Which of two repositories contain an asset.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Side {
+    /// Only the first
+    A,
+    /// Only the second
+    B,
+    /// Both
+    Both,
+}
+
+/// The differences between two repositories, computed by `diff`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RepoDiff {
+    /// Assets only in the first repository, sorted
+    pub only_in_a: Vec<Hash>,
+    /// Assets only in the second repository, sorted
+    pub only_in_b: Vec<Hash>,
+    /// Assets in both repositories, sorted
+    pub common: Vec<Hash>,
+    /// Total size of `only_in_a` in bytes
+    pub bytes_only_in_a: u64,
+    /// Total size of `only_in_b` in bytes
+    pub bytes_only_in_b: u64,
+    /// Total size of `common` in bytes
+    pub bytes_common: u64,
+}
+
+/// Compare the assets of `a` and `b`.
+///
+/// Sizes are taken from `Store::list_sizes`, so repositories that record them aren't read. Assets removed during the
+/// comparison may be omitted.
+pub fn diff<A, B>(a: &A, b: &B) -> io::Result<RepoDiff>
+where
+    A: Store + ?Sized,
+    B: Store + ?Sized,
+{
+    let mut result = RepoDiff::default();
+    let (partitions, partition) = partitioning(a, b);
+    for i in 0..partitions {
+        let xs = sizes_in(a, &partition, i)?;
+        let ys = sizes_in(b, &partition, i)?;
+        merge(&xs, &ys, &mut |side, hash, size| {
+            let (hashes, bytes) = match side {
+                Side::A => (&mut result.only_in_a, &mut result.bytes_only_in_a),
+                Side::B => (&mut result.only_in_b, &mut result.bytes_only_in_b),
+                Side::Both => (&mut result.common, &mut result.bytes_common),
+            };
+            hashes.push(hash);
+            *bytes += size;
+        });
+    }
+    Ok(result)
+}
+
+/// Call `f` on every asset in `a` or `b`, in order of hash, along with which contain it.
+///
+/// Unlike `diff`, memory use is bounded regardless of the size of the repositories. Each repository is therefore
+/// enumerated several times if large.
+pub fn diff_each<A, B, F>(a: &A, b: &B, mut f: F)
+where
+    A: Store + ?Sized,
+    B: Store + ?Sized,
+    F: FnMut(Side, Hash),
+{
+    let (partitions, partition) = partitioning(a, b);
+    for i in 0..partitions {
+        let mut xs = a
+            .list()
+            .filter(|x| partition(x) == i)
+            .map(|x| (x, ()))
+            .collect::<Vec<_>>();
+        let mut ys = b
+            .list()
+            .filter(|x| partition(x) == i)
+            .map(|x| (x, ()))
+            .collect::<Vec<_>>();
+        xs.sort_unstable();
+        xs.dedup();
+        ys.sort_unstable();
+        ys.dedup();
+        merge(&xs, &ys, &mut |side, hash, ()| f(side, hash));
+    }
+}
+
+/// Collect the hashes and sizes of the assets of `store` in partition `i`, sorted by hash.
+fn sizes_in<S, P>(store: &S, partition: &P, i: usize) -> io::Result<Vec<(Hash, u64)>>
+where
+    S: Store + ?Sized,
+    P: Fn(&Hash) -> usize,
+{
+    let mut xs = Vec::new();
+    for x in store.list_sizes() {
+        let x = x?;
+        if partition(&x.0) == i {
+            xs.push(x);
+        }
+    }
+    xs.sort_unstable_by_key(|x| x.0);
+    xs.dedup_by_key(|x| x.0);
+    Ok(xs)
+}
+
+/// Choose a number of partitions for comparing `a` and `b`, and a function selecting each hash's partition.
+fn partitioning<A, B>(a: &A, b: &B) -> (usize, impl Fn(&Hash) -> usize)
+where
+    A: Store + ?Sized,
+    B: Store + ?Sized,
+{
+    let count = a.list().count().max(b.list().count());
+    // A power of two up to 256, so that partitions can be selected by the leading bits of the first byte of each hash
+    let partitions = (count / PARTITION_SIZE + 1).next_power_of_two().min(256);
+    let shift = 8 - partitions.trailing_zeros();
+    (partitions, move |hash: &Hash| {
+        usize::from(hash.bytes()[0]) >> shift
+    })
+}
+
+/// Visit the union of `xs` and `ys`, sorted by hash, in order, along with the value attached to each hash, taken from
+/// `xs` for those in both.
+fn merge<T: Copy, F: FnMut(Side, Hash, T)>(mut xs: &[(Hash, T)], mut ys: &[(Hash, T)], f: &mut F) {
+    loop {
+        match (xs.first(), ys.first()) {
+            (None, None) => return,
+            (Some(&x), None) => {
+                f(Side::A, x.0, x.1);
+                xs = &xs[1..];
+            }
+            (None, Some(&y)) => {
+                f(Side::B, y.0, y.1);
+                ys = &ys[1..];
+            }
+            (Some(&x), Some(&y)) => match x.0.cmp(&y.0) {
+                Ordering::Less => {
+                    f(Side::A, x.0, x.1);
+                    xs = &xs[1..];
+                }
+                Ordering::Greater => {
+                    f(Side::B, y.0, y.1);
+                    ys = &ys[1..];
+                }
+                Ordering::Equal => {
+                    f(Side::Both, x.0, x.1);
+                    xs = &xs[1..];
+                    ys = &ys[1..];
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Asset, MemoryStore};
+
+    #[test]
+    fn sides() {
+        let a = MemoryStore::new();
+        let b = MemoryStore::new();
+        let common = a.put(b"common");
+        b.put(b"common");
+        let only_a = a.put(b"a");
+        let only_b = b.put(b"bb");
+        let result = diff(&a, &b).unwrap();
+        assert_eq!(result.common, [common]);
+        assert_eq!(result.only_in_a, [only_a]);
+        assert_eq!(result.only_in_b, [only_b]);
+        assert_eq!(
+            (
+                result.bytes_only_in_a,
+                result.bytes_only_in_b,
+                result.bytes_common
+            ),
+            (1, 2, 6)
+        );
+        assert_eq!(diff(&Sizes(&a), &Sizes(&b)).unwrap(), result);
+    }
+
+    /// Forwards to a store, but refuses to read assets, so sizes must come from `list_sizes`
+    struct Sizes<'a>(&'a MemoryStore);
+
+    impl Store for Sizes<'_> {
+        fn get(&self, _: &Hash) -> io::Result<Asset> {
+            panic!("asset read")
+        }
+
+        fn contains(&self, hash: &Hash) -> bool {
+            self.0.contains(hash)
+        }
+
+        fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+            Store::list(self.0)
+        }
+
+        fn list_sizes(&self) -> Box<dyn Iterator<Item = io::Result<(Hash, u64)>> + '_> {
+            Box::new(
+                Store::list(self.0).map(move |x| Ok((x, self.0.get(&x).unwrap().len() as u64))),
+            )
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Encryption of asset data at rest.
+//!
+//! Each encrypted asset is stored as a random or derived 24-byte nonce followed by the XChaCha20-Poly1305 ciphertext.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand;
+
+use crate::index::Index;
+use crate::{hash_of, not_found, Asset, Buffer, Hash, Hasher, Store, StoreWriter, WritableStore};
+
+/// Length of the nonce prefixed to each encrypted asset
+pub(crate) const NONCE_LEN: usize = 24;
+
+/// A secret key for encrypting and decrypting archives and `EncryptedStore`s.
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Generate a new random key.
+    pub fn generate() -> Self {
+        Key(rand::random())
+    }
+
+    /// Construct a key from its raw bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Key(bytes)
+    }
+
+    /// The raw bytes of the key. Keep them secret.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// A publicly shareable identifier for this key, recorded in archives it encrypts.
+    pub(crate) fn fingerprint(&self) -> Hash {
+        let mut hasher = Hasher::new();
+        hasher.process(b"chasset archive key fingerprint");
+        hasher.process(&self.0);
+        hasher.result()
+    }
+
+    pub(crate) fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&self.0))
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.pad("Key(..)")
+    }
+}
+
+/// How nonces are chosen when an `EncryptedStore` encrypts an asset.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Scheme {
+    /// Choose each nonce at random, so that encrypting the same data twice yields unrelated ciphertexts.
+    Keyed,
+    /// Derive each nonce from the key and the plaintext's hash, so that encrypting the same data twice yields the same
+    /// ciphertext and the inner store deduplicates it. Reveals which stored assets are identical to anyone who can see
+    /// the ciphertexts, but nothing else.
+    Convergent,
+}
+
+/// A store that encrypts asset data before writing it into another store, and decrypts it on read.
+///
+/// Assets are addressed either by the hash of their plaintext, recorded in a local index mapping it to the hash of its
+/// ciphertext in the inner store, or directly by the hash of their ciphertext. The latter requires no index and allows
+/// an untrusted host to verify and deduplicate what it stores without learning anything about plaintexts, but the
+/// resulting hashes depend on the key and, under `Scheme::Keyed`, vary from one write to the next.
+pub struct EncryptedStore<S> {
+    shared: Arc<Shared<S>>,
+}
+
+struct Shared<S> {
+    inner: S,
+    key: Key,
+    cipher: XChaCha20Poly1305,
+    scheme: Scheme,
+    /// Absent when addressing by ciphertext hash
+    index: Option<Index>,
+}
+
+impl<S> EncryptedStore<S> {
+    /// Encrypt assets stored in `inner` with `key`, addressing them by plaintext hash via the index at `index`, which
+    /// is created if necessary.
+    pub fn open(inner: S, key: Key, scheme: Scheme, index: &Path) -> io::Result<Self> {
+        Ok(Self::new(inner, key, scheme, Some(Index::open(index)?)))
+    }
+
+    /// Encrypt assets stored in `inner` with `key`, addressing them by the hash of their ciphertext.
+    pub fn by_ciphertext(inner: S, key: Key, scheme: Scheme) -> Self {
+        Self::new(inner, key, scheme, None)
+    }
+
+    fn new(inner: S, key: Key, scheme: Scheme, index: Option<Index>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                cipher: key.cipher(),
+                key,
+                scheme,
+                index,
+            }),
+        }
+    }
+
+    /// The store holding encrypted data.
+    pub fn inner(&self) -> &S {
+        &self.shared.inner
+    }
+}
+
+impl<S: Store> EncryptedStore<S> {
+    /// Access and decrypt the asset identified by `hash`.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let shared = &*self.shared;
+        let stored = match shared.index {
+            None => *hash,
+            Some(ref index) => index.get(hash).ok_or_else(not_found)?,
+        };
+        let data = shared.inner.get(&stored)?;
+        if data.len() < NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated encrypted asset",
+            ));
+        }
+        let plaintext = shared
+            .cipher
+            .decrypt(
+                XNonce::from_slice(&data[..NONCE_LEN]),
+                Payload {
+                    msg: &data[NONCE_LEN..],
+                    aad: &[],
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt asset"))?;
+        if shared.index.is_some() && hash_of(&plaintext) != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data for {} does not match its hash", hash),
+            ));
+        }
+        Ok(plaintext.into())
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        match self.shared.index {
+            None => self.shared.inner.contains(hash),
+            Some(ref index) => index
+                .get(hash)
+                .is_some_and(|x| self.shared.inner.contains(&x)),
+        }
+    }
+
+    /// Enumerate assets stored in the repository.
+    ///
+    /// This should only be used for diagnostic purposes. It almost never makes sense to access an asset you don't
+    /// already know the hash of.
+    pub fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        match self.shared.index {
+            None => self.shared.inner.list(),
+            Some(ref index) => Box::new(index.keys().into_iter()),
+        }
+    }
+}
+
+impl<S: WritableStore> EncryptedStore<S> {
+    /// Create a `Writer` for streaming data into the repository.
+    ///
+    /// Data is buffered in memory until it's stored.
+    pub fn make_writer(&self) -> Writer<S> {
+        Writer {
+            shared: self.shared.clone(),
+            buffer: Buffer::default(),
+        }
+    }
+
+    /// Encrypt and write `data` into the repository.
+    pub fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.shared.put(hash_of(data), data).map(|(hash, _)| hash)
+    }
+}
+
+impl<S: WritableStore> Shared<S> {
+    fn put(&self, hash: Hash, data: &[u8]) -> io::Result<(Hash, bool)> {
+        if let Some(ref index) = self.index {
+            if index.contains(&hash) {
+                return Ok((hash, false));
+            }
+        }
+        let nonce = match self.scheme {
+            Scheme::Keyed => rand::random::<[u8; NONCE_LEN]>(),
+            Scheme::Convergent => {
+                let mut hasher = Hasher::new();
+                hasher.process(b"chasset convergent nonce");
+                hasher.process(self.key.as_bytes());
+                hasher.process(hash.bytes());
+                let mut nonce = [0; NONCE_LEN];
+                nonce.copy_from_slice(&hasher.result().bytes()[..NONCE_LEN]);
+                nonce
+            }
+        };
+        let mut stored = nonce.to_vec();
+        stored.extend(
+            self.cipher
+                .encrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: data,
+                        aad: &[],
+                    },
+                )
+                .map_err(|_| io::Error::other("failed to encrypt asset"))?,
+        );
+        let mut writer = self.inner.make_writer()?;
+        writer.write_all(&stored)?;
+        let (stored, new) = writer.store()?;
+        let index = match self.index {
+            None => return Ok((stored, new)),
+            Some(ref x) => x,
+        };
+        Ok((hash, index.insert(hash, stored)?))
+    }
+}
+
+impl<S: Store> Store for EncryptedStore<S> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        self.list()
+    }
+}
+
+impl<S: WritableStore> WritableStore for EncryptedStore<S> {
+    type Writer = Writer<S>;
+
+    fn make_writer(&self) -> io::Result<Writer<S>> {
+        Ok(self.make_writer())
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+/// A staging area for streaming data into an `EncryptedStore`.
+///
+/// `store` must be called to commit data to the repository. Otherwise, it will be discarded when the `Writer` is
+/// dropped.
+pub struct Writer<S> {
+    shared: Arc<Shared<S>>,
+    buffer: Buffer,
+}
+
+impl<S: WritableStore> Writer<S> {
+    /// Encrypts and commits the written data to the repository. The `bool` is true iff the data was not already there.
+    pub fn store(self) -> io::Result<(Hash, bool)> {
+        let (hash, data) = self.buffer.finish();
+        self.shared.put(hash, &data)
+    }
+}
+
+impl<S: WritableStore> StoreWriter for Writer<S> {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        self.store()
+    }
+}
+
+impl<S> io::Write for Writer<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    use crate::MemoryStore;
+
+    #[test]
+    fn roundtrip() {
+        let index =
+            std::env::temp_dir().join(format!("chasset-index-{:016X}", rand::random::<u64>()));
+        let key = Key::generate();
+        let hash = {
+            let store =
+                EncryptedStore::open(MemoryStore::new(), key.clone(), Scheme::Keyed, &index)
+                    .unwrap();
+            let hash = store.put(b"secret").unwrap();
+            assert_eq!(&store.get(&hash).unwrap()[..], b"secret");
+            assert!(!store.inner().contains(&hash));
+            assert_eq!(store.list().collect::<Vec<_>>(), vec![hash]);
+            hash
+        };
+        assert_eq!(Index::open(&index).unwrap().keys().len(), 1);
+        fs::remove_file(&index).unwrap();
+
+        let store = EncryptedStore::by_ciphertext(MemoryStore::new(), key, Scheme::Convergent);
+        let stored = store.put(b"secret").unwrap();
+        assert_ne!(stored, hash);
+        assert_eq!(store.put(b"secret").unwrap(), stored);
+        assert_eq!(&store.get(&stored).unwrap()[..], b"secret");
+    }
+}
@@ -0,0 +1,347 @@
+//! Tools for keeping frequently used assets in a small, fast tier.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    loose_files, Asset, ContentMap, ContentSet, Hash, HashKind, LooseFiles, Store, StoreWriter,
+    WritableStore,
+};
+
+/// A repository split between a `LooseFiles` holding frequently used assets and a slower `cold` store holding the
+/// rest, with assets moved between them by `migrate_cold`.
+///
+/// New assets are written to the hot tier. Reads and writes are counted as uses. With the `carchive` feature, an
+/// `AutoPackStore` makes a cold tier that packs demoted assets into archives.
+///
+/// Use statistics are kept in memory and, if a path is given, in a sidecar file written by `save` and when the store is
+/// dropped.
+pub struct HotColdStore<C> {
+    inner: Arc<Inner<C>>,
+}
+
+struct Inner<C> {
+    hot: LooseFiles,
+    cold: C,
+    stats_path: Option<PathBuf>,
+    stats: Mutex<ContentMap<Stats>>,
+}
+
+/// How an asset has been used.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Stats {
+    /// Number of uses since the last migration
+    pub hits: u64,
+    /// Time of the most recent use
+    pub last_used: SystemTime,
+}
+
+/// The boundary between hot and cold assets.
+///
+/// An asset is cold if it was used fewer than `min_hits` times since the last migration, or not at all within
+/// `max_idle`. Assets that have never been used since statistics began are cold.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Threshold {
+    /// Uses required for an asset to be hot
+    pub min_hits: u64,
+    /// Time since last use after which an asset is cold
+    pub max_idle: Duration,
+}
+
+/// The outcome of a migration.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Migration {
+    /// Number of assets moved from the hot tier to the cold tier
+    pub demoted: usize,
+    /// Total size of demoted assets in bytes
+    pub bytes_demoted: u64,
+    /// Number of assets copied from the cold tier to the hot tier
+    pub promoted: usize,
+    /// Total size of promoted assets in bytes
+    pub bytes_promoted: u64,
+}
+
+impl<C: WritableStore> HotColdStore<C> {
+    /// Combine a `hot` and `cold` tier, recording use statistics in the file at `stats` if supplied.
+    pub fn open(hot: LooseFiles, cold: C, stats: Option<PathBuf>) -> io::Result<Self> {
+        let recorded = match stats {
+            Some(ref path) => read_stats(path)?,
+            None => ContentMap::default(),
+        };
+        Ok(Self {
+            inner: Arc::new(Inner {
+                hot,
+                cold,
+                stats_path: stats,
+                stats: Mutex::new(recorded),
+            }),
+        })
+    }
+
+    /// The fast tier.
+    pub fn hot(&self) -> &LooseFiles {
+        &self.inner.hot
+    }
+
+    /// The slow tier.
+    pub fn cold(&self) -> &C {
+        &self.inner.cold
+    }
+
+    /// Use statistics of the asset identified by `hash`, if it's been used since statistics began.
+    pub fn stats(&self, hash: &Hash) -> Option<Stats> {
+        self.inner.stats.lock().unwrap().get(hash).cloned()
+    }
+
+    /// Write the statistics sidecar, if any.
+    pub fn save(&self) -> io::Result<()> {
+        self.inner.save()
+    }
+
+    /// Access the asset identified by `hash` from whichever tier holds it, counting a use.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let asset = match self.inner.hot.get(hash) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => self.inner.cold.get(hash)?,
+            x => x?,
+        };
+        self.inner.used(hash);
+        Ok(asset)
+    }
+
+    /// Determine whether either tier holds the asset identified by `hash`.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.inner.hot.contains(hash) || self.inner.cold.contains(hash)
+    }
+
+    /// Write `data` into the hot tier, counting a use.
+    pub fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        let hash = self.inner.hot.put(data)?;
+        self.inner.used(&hash);
+        Ok(hash)
+    }
+
+    /// Enumerate assets in either tier.
+    pub fn list(&self) -> impl Iterator<Item = Hash> + '_ {
+        let hot = self.inner.hot.list().collect::<ContentSet>();
+        let cold = self
+            .inner
+            .cold
+            .list()
+            .filter(|x| !hot.contains(x))
+            .collect::<Vec<_>>();
+        hot.into_iter().chain(cold)
+    }
+
+    /// Move cold assets out of the hot tier, and copy hot assets into it, according to `threshold`. Hit counts are
+    /// then reset.
+    ///
+    /// Promoted assets remain in the cold tier, since it needn't support removal.
+    pub fn migrate_cold(&self, threshold: &Threshold) -> io::Result<Migration> {
+        let now = SystemTime::now();
+        let stats = self.inner.stats.lock().unwrap().clone();
+        let is_hot = |hash: &Hash| {
+            stats.get(hash).is_some_and(|x| {
+                x.hits >= threshold.min_hits
+                    && now.duration_since(x.last_used).unwrap_or_default() <= threshold.max_idle
+            })
+        };
+        let mut result = Migration::default();
+        let hot = self.inner.hot.list().collect::<ContentSet>();
+        for hash in &hot {
+            if is_hot(hash) {
+                continue;
+            }
+            let data = self.inner.hot.get(hash)?;
+            self.inner.cold.put(&data)?;
+            self.inner.hot.remove(hash)?;
+            result.demoted += 1;
+            result.bytes_demoted += data.len() as u64;
+        }
+        for hash in self.inner.cold.list() {
+            if hot.contains(&hash) || !is_hot(&hash) {
+                continue;
+            }
+            let data = self.inner.cold.get(&hash)?;
+            self.inner.hot.put(&data)?;
+            result.promoted += 1;
+            result.bytes_promoted += data.len() as u64;
+        }
+        for x in self.inner.stats.lock().unwrap().values_mut() {
+            x.hits = 0;
+        }
+        Ok(result)
+    }
+}
+
+impl<C> Inner<C> {
+    fn used(&self, hash: &Hash) {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(*hash).or_insert(Stats {
+            hits: 0,
+            last_used: UNIX_EPOCH,
+        });
+        entry.hits += 1;
+        entry.last_used = SystemTime::now();
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match self.stats_path {
+            Some(ref x) => x,
+            None => return Ok(()),
+        };
+        let mut buf = Vec::new();
+        for (hash, stats) in self.stats.lock().unwrap().iter() {
+            let secs = stats
+                .last_used
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let mut header = [0; 18];
+            LittleEndian::write_u16(&mut header[0..2], hash.kind().id());
+            LittleEndian::write_u64(&mut header[2..10], stats.hits);
+            LittleEndian::write_u64(&mut header[10..18], secs);
+            buf.extend_from_slice(&header);
+            buf.extend_from_slice(hash.bytes());
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp, path)
+    }
+}
+
+impl<C> Drop for Inner<C> {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+/// Read a statistics sidecar, consisting of a `u16` hash kind, `u64` hit count, `u64` seconds since the Unix epoch of
+/// last use, and hash bytes per asset.
+fn read_stats(path: &Path) -> io::Result<ContentMap<Stats>> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut data)?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e);
+        }
+    }
+    let mut result = ContentMap::default();
+    let mut rest = &data[..];
+    while rest.len() >= 18 {
+        let kind = match HashKind::from_id(LittleEndian::read_u16(&rest[0..2])) {
+            Some(x) if rest.len() - 18 >= x.len() => x,
+            // Unknown or truncated; statistics are advisory, so ignore the remainder
+            _ => break,
+        };
+        let stats = Stats {
+            hits: LittleEndian::read_u64(&rest[2..10]),
+            last_used: UNIX_EPOCH + Duration::from_secs(LittleEndian::read_u64(&rest[10..18])),
+        };
+        let hash = Hash::from_bytes(kind, &rest[18..18 + kind.len()]).unwrap();
+        result.insert(hash, stats);
+        rest = &rest[18 + kind.len()..];
+    }
+    Ok(result)
+}
+
+impl<C: WritableStore> Store for HotColdStore<C> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+}
+
+impl<C: WritableStore> WritableStore for HotColdStore<C> {
+    type Writer = Writer<C>;
+
+    fn make_writer(&self) -> io::Result<Writer<C>> {
+        Ok(Writer {
+            inner: self.inner.hot.make_writer()?,
+            store: self.inner.clone(),
+        })
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+/// A staging area for streaming data into the hot tier of a `HotColdStore`.
+pub struct Writer<C> {
+    inner: loose_files::Writer,
+    store: Arc<Inner<C>>,
+}
+
+impl<C> StoreWriter for Writer<C> {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        let (hash, new) = self.inner.store()?;
+        self.store.used(&hash);
+        Ok((hash, new))
+    }
+}
+
+impl<C> io::Write for Writer<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MemoryStore;
+
+    #[test]
+    fn migrate() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-hotcold-{:016X}", rand::random::<u64>()));
+        let stats = dir.join("stats");
+        let store = HotColdStore::open(
+            LooseFiles::open(dir.join("hot")).unwrap(),
+            MemoryStore::new(),
+            Some(stats.clone()),
+        )
+        .unwrap();
+        let rare = store.put(b"rare").unwrap();
+        let frequent = store.put(b"frequent").unwrap();
+        store.get(&frequent).unwrap();
+        let threshold = Threshold {
+            min_hits: 2,
+            max_idle: Duration::from_secs(3600),
+        };
+        let result = store.migrate_cold(&threshold).unwrap();
+        assert_eq!((result.demoted, result.promoted), (1, 0));
+        assert!(!store.hot().contains(&rare) && store.cold().contains(&rare));
+        assert!(store.hot().contains(&frequent));
+
+        store.get(&rare).unwrap();
+        store.get(&rare).unwrap();
+        let result = store.migrate_cold(&threshold).unwrap();
+        assert_eq!((result.demoted, result.promoted), (1, 1));
+        assert!(store.hot().contains(&rare) && !store.hot().contains(&frequent));
+        drop(store);
+        assert!(read_stats(&stats).unwrap().contains_key(&rare));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,382 @@
+//! Tools for a read-only repository served over HTTP.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use data_encoding::BASE32_NOPAD;
+use rand;
+
+use crate::{map_file, not_found, Asset, CachingStore, Hash, Hasher, LooseFiles, Storage, Store};
+
+/// A read-only repository fetched from an HTTP server, such as a CDN.
+///
+/// The asset identified by a hash is fetched from `{base}/{kind}/{base32}`, where `kind` is the name of the hash kind
+/// and `base32` is the unpadded base32 encoding of the hash. Downloaded data is checked against its hash before being
+/// returned.
+///
+/// Servers cannot generally be enumerated, so `list` yields nothing. See `fetch_list` for servers that support it.
+#[derive(Debug, Clone)]
+pub struct HttpStore {
+    base: String,
+    agent: ureq::Agent,
+    spool: Option<PathBuf>,
+}
+
+impl HttpStore {
+    /// Fetch assets from under the URL `base`.
+    pub fn new(base: String) -> Self {
+        Self::with_agent(base, ureq::Agent::new())
+    }
+
+    /// Fetch assets from under the URL `base` using `agent`, e.g. to configure timeouts or proxies.
+    pub fn with_agent(mut base: String, agent: ureq::Agent) -> Self {
+        while base.ends_with('/') {
+            base.pop();
+        }
+        Self {
+            base,
+            agent,
+            spool: None,
+        }
+    }
+
+    /// Download assets into temporary files in `dir` rather than onto the heap.
+    ///
+    /// Each file is memory-mapped, then deleted where the platform permits deleting mapped files.
+    pub fn spool_to(&mut self, dir: PathBuf) -> &mut Self {
+        self.spool = Some(dir);
+        self
+    }
+
+    /// URL from which the asset identified by `hash` is fetched.
+    pub fn url(&self, hash: &Hash) -> String {
+        format!(
+            "{}/{}/{}",
+            self.base,
+            hash.kind().name(),
+            BASE32_NOPAD.encode(hash.bytes())
+        )
+    }
+
+    /// Fetch the asset identified by `hash`.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let response = self.agent.get(&self.url(hash)).call().map_err(http_error)?;
+        let mut body = response.into_reader();
+        let mut hasher = Hasher::new();
+        let asset = match self.spool {
+            None => {
+                let mut data = Vec::new();
+                body.read_to_end(&mut data)?;
+                hasher.process(&data);
+                data.into()
+            }
+            Some(ref dir) => spool(dir, &mut body, &mut hasher)?,
+        };
+        if hasher.result() != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data for {} does not match its hash", hash),
+            ));
+        }
+        Ok(asset)
+    }
+
+    /// Determine whether the server has the asset identified by `hash`.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.agent.head(&self.url(hash)).call().is_ok()
+    }
+
+    /// Fetch the hash of every asset from `{base}/`, one per line, as served by a `serve::Server` with listing
+    /// enabled.
+    pub fn fetch_list(&self) -> io::Result<Vec<Hash>> {
+        let response = self
+            .agent
+            .get(&format!("{}/", self.base))
+            .call()
+            .map_err(http_error)?;
+        // Streamed line by line, since a large listing may exceed the limit on buffered response bodies
+        let mut hashes = Vec::new();
+        for line in io::BufReader::new(response.into_reader()).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            hashes.push(line.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "malformed asset listing")
+            })?);
+        }
+        Ok(hashes)
+    }
+}
+
+impl Store for HttpStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(iter::empty())
+    }
+}
+
+/// An `HttpStore` whose downloads are kept in a local `LooseFiles` repository, from which subsequent reads are served.
+///
+/// Concurrent requests for the same uncached asset share a single download. Use `CachingStore` directly to combine
+/// other stores.
+pub struct RemoteCachedStore {
+    inner: CachingStore<HttpStore, LooseFiles>,
+}
+
+impl RemoteCachedStore {
+    /// Fetch assets from under the URL `base`, caching them in the repository at `local`, which is created if
+    /// necessary.
+    pub fn new(base: String, local: PathBuf) -> io::Result<Self> {
+        Ok(Self::from_parts(
+            HttpStore::new(base),
+            LooseFiles::open(local)?,
+        ))
+    }
+
+    /// Fetch assets from `remote`, caching them in `local`.
+    pub fn from_parts(remote: HttpStore, local: LooseFiles) -> Self {
+        Self {
+            inner: CachingStore::new(remote, local),
+        }
+    }
+
+    /// The server assets are fetched from.
+    pub fn remote(&self) -> &HttpStore {
+        self.inner.remote()
+    }
+
+    /// The local cache.
+    pub fn local(&self) -> &LooseFiles {
+        self.inner.local()
+    }
+
+    /// Access the asset identified by `hash`, downloading it if it's not cached.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.inner.get(hash)
+    }
+
+    /// Determine whether the asset identified by `hash` is cached or available from the server.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.inner.contains(hash)
+    }
+
+    /// Enumerate the cached assets.
+    pub fn list(&self) -> impl Iterator<Item = Hash> {
+        self.local().list()
+    }
+}
+
+impl Store for RemoteCachedStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+}
+
+/// Copy `body` into a new memory-mapped temporary file in `dir`, updating `hasher`.
+fn spool(dir: &Path, body: &mut dyn Read, hasher: &mut Hasher) -> io::Result<Asset> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!(".{:016X}.tmp", rand::random::<u64>()));
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut buf = [0; 64 * 1024];
+        loop {
+            let n = match body.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hasher.process(&buf[..n]);
+            file.write_all(&buf[..n])?;
+        }
+        map(&file)
+    })();
+    let _ = fs::remove_file(&path);
+    result
+}
+
+fn map(file: &File) -> io::Result<Asset> {
+    if file.metadata()?.len() == 0 {
+        // Empty files cannot be mapped
+        return Ok(Vec::new().into());
+    }
+    let map = map_file(file)?;
+    Ok(Asset {
+        start: 0,
+        len: map.len(),
+        storage: Storage::Map(map),
+    })
+}
+
+fn http_error(e: ureq::Error) -> io::Error {
+    match e {
+        ureq::Error::Status(404, _) => not_found(),
+        ureq::Error::Status(code, _) => io::Error::other(format!("HTTP status {}", code)),
+        ureq::Error::Transport(e) => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hash_of;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// A minimal HTTP server answering each request for a path in `files`, counting `GET`s.
+    struct Files {
+        base: String,
+        files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        gets: Arc<AtomicUsize>,
+    }
+
+    impl Files {
+        fn serve() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            let files = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+            let gets = Arc::new(AtomicUsize::new(0));
+            let (f, g) = (files.clone(), gets.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut request = Vec::new();
+                    let mut byte = [0];
+                    while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                        request.push(byte[0]);
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    let mut parts = request.split(' ');
+                    let method = parts.next().unwrap().to_string();
+                    let path = parts.next().unwrap().to_string();
+                    if method == "GET" {
+                        g.fetch_add(1, Ordering::Relaxed);
+                    }
+                    let response = match f.lock().unwrap().get(&path) {
+                        Some(body) => {
+                            let mut x = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                                body.len()
+                            )
+                            .into_bytes();
+                            if method == "GET" {
+                                x.extend_from_slice(body);
+                            }
+                            x
+                        }
+                        None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                    };
+                    let _ = stream.write_all(&response);
+                }
+            });
+            Self { base, files, gets }
+        }
+
+        fn insert(&self, store: &HttpStore, data: &[u8]) -> Hash {
+            let hash = hash_of(data);
+            let url = store.url(&hash);
+            self.files
+                .lock()
+                .unwrap()
+                .insert(url[self.base.len()..].to_string(), data.to_vec());
+            hash
+        }
+    }
+
+    #[test]
+    fn fetch() {
+        let files = Files::serve();
+        let mut store = HttpStore::new(format!("{}/", files.base));
+        let hash = files.insert(&store, b"remote data");
+        assert!(store
+            .url(&hash)
+            .starts_with(&format!("{}/blake2b/", files.base)));
+        assert_eq!(&store.get(&hash).unwrap()[..], b"remote data");
+        assert!(store.contains(&hash));
+        let missing = hash_of(b"missing");
+        assert!(!store.contains(&missing));
+        assert_eq!(
+            store.get(&missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(Store::list(&store).count(), 0);
+
+        files
+            .files
+            .lock()
+            .unwrap()
+            .insert("/".into(), format!("{}\n", hash).into_bytes());
+        assert_eq!(store.fetch_list().unwrap(), vec![hash]);
+
+        // Data that doesn't match its hash is rejected
+        let corrupt = files.insert(&store, b"original");
+        files.files.lock().unwrap().insert(
+            store.url(&corrupt)[files.base.len()..].to_string(),
+            b"tampered".to_vec(),
+        );
+        assert_eq!(
+            store.get(&corrupt).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let spool =
+            std::env::temp_dir().join(format!("chasset-spool-{:016X}", rand::random::<u64>()));
+        store.spool_to(spool.clone());
+        assert_eq!(&store.get(&hash).unwrap()[..], b"remote data");
+        assert!(store.get(&corrupt).is_err());
+        assert_eq!(fs::read_dir(&spool).unwrap().count(), 0);
+        fs::remove_dir_all(&spool).unwrap();
+    }
+
+    #[test]
+    fn remote_cached() {
+        let files = Files::serve();
+        let local =
+            std::env::temp_dir().join(format!("chasset-remote-{:016X}", rand::random::<u64>()));
+        let store = RemoteCachedStore::new(files.base.clone(), local.clone()).unwrap();
+        let hash = files.insert(store.remote(), b"cached data");
+        assert!(store.contains(&hash));
+        assert_eq!(store.list().count(), 0);
+        assert_eq!(&store.get(&hash).unwrap()[..], b"cached data");
+        assert_eq!(&store.get(&hash).unwrap()[..], b"cached data");
+        assert_eq!(files.gets.load(Ordering::Relaxed), 1);
+        assert!(store.local().contains(&hash));
+        assert_eq!(store.list().collect::<Vec<_>>(), vec![hash]);
+
+        // Cached assets remain available even if the server loses them
+        files.files.lock().unwrap().clear();
+        assert_eq!(&Store::get(&store, &hash).unwrap()[..], b"cached data");
+        let missing = hash_of(b"missing");
+        assert_eq!(
+            store.get(&missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(!store.contains(&missing));
+        fs::remove_dir_all(&local).unwrap();
+    }
+}
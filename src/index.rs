@@ -0,0 +1,145 @@
+//! Persistent maps from the hashes of assets to the hashes they're stored under after transformation.
+//!
+//! An index is an append-only log of records, each consisting of two hashes: that of the asset, then that of its
+//! stored form. Each hash is encoded as a 2-byte little-endian hash kind ID, a 1-byte length, and that many bytes.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+use crate::{ContentMap, Hash, HashKind};
+
+pub(crate) struct Index {
+    file: Mutex<File>,
+    map: RwLock<ContentMap<Hash>>,
+}
+
+impl Index {
+    /// Open the index at `path`, creating it if necessary.
+    ///
+    /// A trailing partial record, as left by an interrupted write, is truncated away so that records appended later
+    /// start on a record boundary.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let (map, complete) = parse(&data);
+        if complete != data.len() {
+            file.set_len(complete as u64)?;
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            map: RwLock::new(map),
+        })
+    }
+
+    /// Hash of the stored form of the asset identified by `hash`.
+    pub(crate) fn get(&self, hash: &Hash) -> Option<Hash> {
+        self.map.read().unwrap().get(hash).cloned()
+    }
+
+    pub(crate) fn contains(&self, hash: &Hash) -> bool {
+        self.map.read().unwrap().contains_key(hash)
+    }
+
+    /// Durably record that the asset identified by `hash` is stored under `stored`. Returns whether `hash` was not
+    /// already present.
+    pub(crate) fn insert(&self, hash: Hash, stored: Hash) -> io::Result<bool> {
+        {
+            let mut file = self.file.lock().unwrap();
+            let mut record = Vec::new();
+            write_hash(&mut record, &hash);
+            write_hash(&mut record, &stored);
+            file.write_all(&record)?;
+            file.sync_data()?;
+        }
+        Ok(self.map.write().unwrap().insert(hash, stored).is_none())
+    }
+
+    /// Every asset recorded.
+    pub(crate) fn keys(&self) -> Vec<Hash> {
+        self.map.read().unwrap().keys().cloned().collect()
+    }
+}
+
+fn write_hash(out: &mut Vec<u8>, hash: &Hash) {
+    let id = hash.kind().id();
+    out.extend_from_slice(&[id as u8, (id >> 8) as u8, hash.bytes().len() as u8]);
+    out.extend_from_slice(hash.bytes());
+}
+
+/// Decode the hash at the start of `data`, returning it, or `None` if its kind is unknown, and the data following it.
+///
+/// Returns `None` if `data` holds only part of a hash.
+fn read_hash(data: &[u8]) -> Option<(Option<Hash>, &[u8])> {
+    if data.len() < 3 || data.len() - 3 < data[2] as usize {
+        return None;
+    }
+    let kind = HashKind::from_id(data[0] as u16 | (data[1] as u16) << 8);
+    let (bytes, rest) = data[3..].split_at(data[2] as usize);
+    Some((
+        kind.and_then(|kind| Hash::from_bytes(kind, bytes).ok()),
+        rest,
+    ))
+}
+
+/// Decode the records of an index, returning them and the length of the complete records.
+///
+/// Records of unknown hash kinds are ignored.
+fn parse(data: &[u8]) -> (ContentMap<Hash>, usize) {
+    let mut map = ContentMap::default();
+    let mut rest = data;
+    while let Some((plaintext, tail)) = read_hash(rest) {
+        let (stored, tail) = match read_hash(tail) {
+            Some(x) => x,
+            None => break,
+        };
+        if let (Some(plaintext), Some(stored)) = (plaintext, stored) {
+            map.insert(plaintext, stored);
+        }
+        rest = tail;
+    }
+    (map, data.len() - rest.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hash_of;
+
+    #[test]
+    fn torn_write() {
+        let path =
+            std::env::temp_dir().join(format!("chasset-index-{:016X}", rand::random::<u64>()));
+        let index = Index::open(&path).unwrap();
+        let (a, b, c) = (hash_of(b"a"), hash_of(b"b"), hash_of(b"c"));
+        assert!(index.insert(a, b).unwrap());
+        assert!(!index.insert(a, b).unwrap());
+        drop(index);
+
+        // Simulate a write interrupted partway through the second hash of a record
+        let mut record = Vec::new();
+        write_hash(&mut record, &b);
+        write_hash(&mut record, &c);
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..record.len() - 5]).unwrap();
+        drop(file);
+
+        let index = Index::open(&path).unwrap();
+        assert_eq!(index.get(&a), Some(b));
+        assert!(!index.contains(&b));
+        assert!(index.insert(c, a).unwrap());
+        drop(index);
+
+        let index = Index::open(&path).unwrap();
+        assert_eq!(index.get(&a), Some(b));
+        assert_eq!(index.get(&c), Some(a));
+        assert_eq!(index.keys().len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+}
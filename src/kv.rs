@@ -0,0 +1,224 @@
+//! Tools for a repository stored in an embedded key-value database.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use redb::{Database, ReadableTable, TableDefinition};
+
+use crate::{hash_of, not_found, Asset, Buffer, Hash, HashKind, Store, StoreWriter, WritableStore};
+
+/// Maps a hash kind's little-endian ID followed by the hash bytes to asset data
+const ASSETS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("assets");
+
+/// A repository that stores every asset in one redb database file.
+///
+/// Suited to workloads with very large numbers of tiny assets, where the per-file space overhead and directory
+/// traversal of `LooseFiles` dominate. Every write is transactional, and concurrent reads proceed without blocking each
+/// other or writers. Assets are read into memory in their entirety.
+#[derive(Clone)]
+pub struct KvStore {
+    db: Arc<Database>,
+}
+
+impl KvStore {
+    /// Open a repository stored in the database at `path`, creating it if necessary.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let db = Database::create(path).map_err(db_error)?;
+        // Ensure the table exists, so read transactions can always open it
+        let tx = db.begin_write().map_err(db_error)?;
+        tx.open_table(ASSETS).map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Access the asset identified by `hash`.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let tx = self.db.begin_read().map_err(db_error)?;
+        let table = tx.open_table(ASSETS).map_err(db_error)?;
+        let data = table
+            .get(&key(hash)[..])
+            .map_err(db_error)?
+            .ok_or_else(not_found)?;
+        Ok(data.value().to_vec().into())
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        let tx = match self.db.begin_read() {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        let table = match tx.open_table(ASSETS) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        let result = table.get(&key(hash)[..]).is_ok_and(|x| x.is_some());
+        result
+    }
+
+    /// Create a `Writer` for streaming data into the repository.
+    ///
+    /// Data is buffered in memory until it's stored.
+    pub fn make_writer(&self) -> Writer {
+        Writer {
+            db: self.db.clone(),
+            buffer: Buffer::default(),
+        }
+    }
+
+    /// Write `data` directly into the repository.
+    pub fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        let hash = hash_of(data);
+        insert(&self.db, &[(hash, data)])?;
+        Ok(hash)
+    }
+
+    /// Write each of `assets` into the repository in a single transaction, so either all or none are stored.
+    pub fn put_all<'a, I>(&self, assets: I) -> io::Result<Vec<Hash>>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let assets = assets
+            .into_iter()
+            .map(|data| (hash_of(data), data))
+            .collect::<Vec<_>>();
+        insert(&self.db, &assets)?;
+        Ok(assets.into_iter().map(|(hash, _)| hash).collect())
+    }
+
+    /// Enumerate assets stored in the repository.
+    ///
+    /// This should only be used for diagnostic purposes. It almost never makes sense to access an asset you don't
+    /// already know the hash of.
+    pub fn list(&self) -> impl Iterator<Item = Hash> {
+        let mut hashes = Vec::new();
+        if let Ok(tx) = self.db.begin_read() {
+            if let Ok(table) = tx.open_table(ASSETS) {
+                if let Ok(entries) = table.iter() {
+                    hashes.extend(entries.filter_map(|entry| {
+                        let (key, _) = entry.ok()?;
+                        let key = key.value();
+                        if key.len() < 2 {
+                            return None;
+                        }
+                        let kind = HashKind::from_id(key[0] as u16 | (key[1] as u16) << 8)?;
+                        Hash::from_bytes(kind, &key[2..]).ok()
+                    }));
+                }
+            }
+        }
+        hashes.into_iter()
+    }
+}
+
+impl Store for KvStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+}
+
+impl WritableStore for KvStore {
+    type Writer = Writer;
+
+    fn make_writer(&self) -> io::Result<Writer> {
+        Ok(self.make_writer())
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+/// A staging area for streaming data into a `KvStore`.
+///
+/// `store` must be called to commit data to the repository. Otherwise, it will be discarded when the `Writer` is
+/// dropped.
+pub struct Writer {
+    db: Arc<Database>,
+    buffer: Buffer,
+}
+
+impl Writer {
+    /// Commits the written data to the repository. The `bool` is true iff the data was not already there.
+    pub fn store(self) -> io::Result<(Hash, bool)> {
+        let (hash, data) = self.buffer.finish();
+        let inserted = insert(&self.db, &[(hash, &data)])?;
+        Ok((hash, inserted))
+    }
+}
+
+impl StoreWriter for Writer {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        self.store()
+    }
+}
+
+impl io::Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn key(hash: &Hash) -> Vec<u8> {
+    let id = hash.kind().id();
+    let mut key = vec![id as u8, (id >> 8) as u8];
+    key.extend_from_slice(hash.bytes());
+    key
+}
+
+/// Store each of `assets` in a single transaction, returning whether any were not already present.
+fn insert(db: &Database, assets: &[(Hash, &[u8])]) -> io::Result<bool> {
+    let tx = db.begin_write().map_err(db_error)?;
+    let mut inserted = false;
+    {
+        let mut table = tx.open_table(ASSETS).map_err(db_error)?;
+        for &(ref hash, data) in assets {
+            let key = key(hash);
+            if table.get(&key[..]).map_err(db_error)?.is_some() {
+                continue;
+            }
+            table.insert(&key[..], data).map_err(db_error)?;
+            inserted = true;
+        }
+    }
+    tx.commit().map_err(db_error)?;
+    Ok(inserted)
+}
+
+fn db_error<E: Into<redb::Error>>(e: E) -> io::Error {
+    io::Error::other(e.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let path = std::env::temp_dir().join(format!("chasset-kv-{:016X}", rand::random::<u64>()));
+        {
+            let store = KvStore::open(&path).unwrap();
+            let hash = store.put(b"hello").unwrap();
+            assert!(store.contains(&hash));
+            assert_eq!(&store.get(&hash).unwrap()[..], b"hello");
+            assert_eq!(store.list().collect::<Vec<_>>(), vec![hash]);
+            let mut writer = store.make_writer();
+            io::Write::write_all(&mut writer, b"hello").unwrap();
+            assert_eq!(writer.store().unwrap(), (hash, false));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
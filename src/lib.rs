@@ -2,17 +2,98 @@
 
 #![warn(missing_docs)]
 
+pub mod audit;
+pub use audit::AuditLog;
+pub mod budget;
+pub use budget::MapBudget;
+pub mod bundle;
+pub mod cid;
+pub use cid::Cid;
+#[cfg(feature = "zstd")]
+pub mod compression;
+#[cfg(feature = "zstd")]
+pub use compression::{CompressedStore, Dictionary};
+pub mod diff;
+pub use diff::{diff, RepoDiff};
+#[cfg(feature = "chacha20poly1305")]
+pub mod encryption;
+#[cfg(feature = "chacha20poly1305")]
+pub use encryption::EncryptedStore;
+pub mod hotcold;
+pub use hotcold::HotColdStore;
+#[cfg(feature = "ureq")]
+pub mod http;
+#[cfg(feature = "ureq")]
+pub use http::{HttpStore, RemoteCachedStore};
+mod index;
+#[cfg(feature = "redb")]
+pub mod kv;
+#[cfg(feature = "redb")]
+pub use kv::KvStore;
+pub mod lock;
+pub use lock::RepoLock;
 pub mod loose_files;
 pub use loose_files::LooseFiles;
+pub mod lru;
+pub use lru::LruStore;
+pub mod manifest;
+pub use manifest::Manifest;
+pub mod memory;
+pub use memory::MemoryStore;
+pub mod parity;
+pub mod pins;
+pub use pins::Pins;
+pub mod refs;
+pub use refs::Refs;
+pub mod retention;
+pub use retention::RetentionPolicy;
+pub mod scrub;
+pub use scrub::Scrubber;
+#[cfg(feature = "tiny_http")]
+pub mod serve;
+pub mod sidecar;
+pub use sidecar::MetadataLog;
+#[cfg(feature = "rusqlite")]
+pub mod sqlite;
+#[cfg(feature = "rusqlite")]
+pub use sqlite::SqliteStore;
+pub mod store;
+pub mod sync;
+pub mod tags;
+pub use tags::Tags;
+#[cfg(feature = "metrics")]
+pub mod telemetry;
+pub mod transform;
+pub use transform::{Pipeline, Transform, TransformStore};
+pub mod tree;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+#[cfg(feature = "notify")]
+pub mod watch;
+pub use store::{
+    detect, open, resolve, store_verified, store_verified_sized, BoxedStore, BoxedWritableStore,
+    BoxedWriter, CachingStore, CountingStore, CountingWriter, Counts, DynWritableStore, Layout,
+    MaxSize, MirroredStore, MirroredWriter, NullStore, NullWriter, ObservedStore, ObservedWriter,
+    Overlay, ProgressWriter, Store, StoreObserver, StoreWriter, Tiered, UnionStore, ValidatedStore,
+    ValidatedWriter, Validation, Validator, WritableStore,
+};
+#[cfg(feature = "notify")]
+pub use watch::Watcher;
 
 #[cfg(feature = "carchive")]
 pub mod archive;
 #[cfg(feature = "carchive")]
-pub use archive::ArchiveSet;
+pub use archive::{Archive, ArchiveSet};
+#[cfg(feature = "carchive")]
+pub mod autopack;
+#[cfg(feature = "carchive")]
+pub use autopack::AutoPackStore;
 
 use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::{fmt, hash, io};
 
 use blake2::digest::{Input, VariableOutput};
@@ -306,6 +387,8 @@ impl Hasher {
     }
     /// Incrementally hash `bytes`.
     pub fn process(&mut self, bytes: &[u8]) {
+        #[cfg(feature = "metrics")]
+        telemetry::hashed(bytes.len());
         self.0.input(bytes);
     }
     /// Get the hash of all `process`ed bytes.
@@ -316,24 +399,260 @@ impl Hasher {
     }
 }
 
-/// A refcounted, memory-mapped asset from disk.
+/// A refcounted asset, usually memory-mapped from disk or held on the heap.
 #[derive(Debug, Clone)]
 pub struct Asset {
-    map: Arc<Mmap>,
+    storage: Storage,
     start: usize,
     len: usize,
 }
 
 impl AsRef<[u8]> for Asset {
     fn as_ref(&self) -> &[u8] {
-        &self.map.as_ref()[self.start..self.start + self.len]
+        &self.storage.bytes()[self.start..self.start + self.len]
     }
 }
 
 impl ::std::ops::Deref for Asset {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
-        &self.map[self.start..self.start + self.len]
+        &self.storage.bytes()[self.start..self.start + self.len]
+    }
+}
+
+impl Asset {
+    /// Get a view of the `range` bytes of this asset, sharing the same underlying memory.
+    ///
+    /// Useful for handing out sub-resources packed within a single asset without copying them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is decreasing or extends past the end of the asset.
+    pub fn slice(&self, range: Range<usize>) -> Asset {
+        assert!(
+            range.start <= range.end && range.end <= self.len,
+            "range out of bounds"
+        );
+        Asset {
+            storage: self.storage.clone(),
+            start: self.start + range.start,
+            len: range.end - range.start,
+        }
+    }
+
+    /// Tell the operating system how this asset's memory will be accessed, so that it can tune readahead and caching.
+    ///
+    /// Only has an effect on memory-mapped assets on Unix. After `Advice::DontNeed`, the pages are read back from disk
+    /// when next accessed.
+    pub fn advise(&self, advice: Advice) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            if let Storage::Map(ref map) = self.storage {
+                if self.len == 0 {
+                    return Ok(());
+                }
+                let advice = match advice {
+                    Advice::Normal => libc::MADV_NORMAL,
+                    Advice::Sequential => libc::MADV_SEQUENTIAL,
+                    Advice::Random => libc::MADV_RANDOM,
+                    Advice::WillNeed => libc::MADV_WILLNEED,
+                    Advice::DontNeed => libc::MADV_DONTNEED,
+                };
+                // madvise requires a page-aligned address
+                let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+                let start = map.as_ptr() as usize + self.start;
+                let aligned = start & !(page - 1);
+                let result = unsafe {
+                    libc::madvise(
+                        aligned as *mut libc::c_void,
+                        self.len + (start - aligned),
+                        advice,
+                    )
+                };
+                if result != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = advice;
+        Ok(())
+    }
+
+    /// Begin reading this asset's memory in on a background thread, so that accessing it later doesn't stall on page
+    /// faults, e.g. while rendering a frame.
+    ///
+    /// Returns `None` if the asset isn't memory-mapped, and is therefore already in memory. Prefetches are carried out
+    /// in order by a single thread shared by the whole process, which holds a reference to the asset until it's done.
+    pub fn prefetch(&self) -> Option<Prefetch> {
+        if let Storage::Heap(_) | Storage::Static(_) = self.storage {
+            return None;
+        }
+        let (send, recv) = mpsc::channel();
+        let mut job = (self.clone(), send);
+        let mut worker = PREFETCH_WORKER.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(ref worker) = *worker {
+                match worker.send(job) {
+                    Ok(()) => break,
+                    // The worker has exited; start another
+                    Err(mpsc::SendError(x)) => job = x,
+                }
+            }
+            let (send, jobs) = mpsc::channel::<PrefetchJob>();
+            let spawned = thread::Builder::new()
+                .name("chasset prefetch".into())
+                .spawn(move || {
+                    for (asset, done) in jobs {
+                        asset.touch();
+                        let _ = done.send(());
+                    }
+                });
+            if spawned.is_err() {
+                // Better late than never
+                job.0.touch();
+                break;
+            }
+            *worker = Some(send);
+        }
+        Some(Prefetch(recv))
+    }
+
+    /// Fault in every page of this asset.
+    fn touch(&self) {
+        let _ = self.advise(Advice::WillNeed);
+        // Touch one byte per page, which may be smaller than the actual page size but never larger
+        for i in (0..self.len).step_by(4096) {
+            unsafe {
+                std::ptr::read_volatile(&self[i]);
+            }
+        }
+    }
+}
+
+/// Queue of assets for the thread that carries out `Asset::prefetch`, started on first use
+static PREFETCH_WORKER: Mutex<Option<mpsc::Sender<PrefetchJob>>> = Mutex::new(None);
+
+/// An asset to prefetch, and where to report its completion
+type PrefetchJob = (Asset, mpsc::Sender<()>);
+
+/// A prefetch begun by `Asset::prefetch`, which continues regardless of whether this is dropped.
+#[derive(Debug)]
+pub struct Prefetch(mpsc::Receiver<()>);
+
+impl Prefetch {
+    /// Wait for the prefetch to complete.
+    pub fn wait(self) {
+        // An error means the prefetch was carried out synchronously, or the worker panicked
+        let _ = self.0.recv();
+    }
+}
+
+/// How an `Asset`'s memory is expected to be accessed, as passed to `Asset::advise`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Advice {
+    /// No particular pattern; the default
+    Normal,
+    /// In order from start to end, so pages ahead should be read early and pages behind may be dropped
+    Sequential,
+    /// In no particular order, so readahead is wasted
+    Random,
+    /// Soon, so pages should be read in now
+    WillNeed,
+    /// Not soon, so pages may be dropped from memory
+    DontNeed,
+}
+
+impl From<Arc<[u8]>> for Asset {
+    fn from(x: Arc<[u8]>) -> Self {
+        Asset {
+            len: x.len(),
+            storage: Storage::Heap(x),
+            start: 0,
+        }
+    }
+}
+
+impl From<&'static [u8]> for Asset {
+    fn from(x: &'static [u8]) -> Self {
+        Asset {
+            len: x.len(),
+            storage: Storage::Static(x),
+            start: 0,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Asset {
+    fn from(x: Vec<u8>) -> Self {
+        Arc::<[u8]>::from(x).into()
+    }
+}
+
+/// Memory backing an `Asset`.
+#[derive(Debug, Clone)]
+enum Storage {
+    /// A memory-mapped file
+    Map(Arc<Mmap>),
+    /// A buffer on the heap
+    Heap(Arc<[u8]>),
+    /// Data embedded in the executable
+    Static(&'static [u8]),
+}
+
+impl Storage {
+    fn bytes(&self) -> &[u8] {
+        match *self {
+            Storage::Map(ref x) => &x[..],
+            Storage::Heap(ref x) => &x[..],
+            Storage::Static(x) => x,
+        }
+    }
+}
+
+/// Memory-map `file`, which must not be empty.
+fn map_file(file: &std::fs::File) -> io::Result<Arc<Mmap>> {
+    let map = unsafe { Mmap::map(file) }?;
+    #[cfg(feature = "metrics")]
+    telemetry::mapped(map.len());
+    Ok(Arc::new(map))
+}
+
+/// Compute the hash of `data`.
+fn hash_of(data: &[u8]) -> Hash {
+    let mut hasher = Hasher::new();
+    hasher.process(data);
+    hasher.result()
+}
+
+/// The error reported for a request for an asset that isn't present.
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no such asset")
+}
+
+/// Data streamed into a store's writer, buffered in memory and hashed as it arrives.
+#[derive(Debug, Default)]
+struct Buffer {
+    hasher: Hasher,
+    data: Vec<u8>,
+}
+
+impl Buffer {
+    /// The hash of everything written, and the data itself.
+    fn finish(self) -> (Hash, Vec<u8>) {
+        (self.hasher.result(), self.data)
+    }
+}
+
+impl io::Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        self.hasher.process(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
@@ -389,6 +708,77 @@ mod test {
         assert!(Hash::from_str("notarealhash:42").is_err());
     }
 
+    #[test]
+    fn asset_slice() {
+        let asset = Asset::from((0..10).collect::<Vec<u8>>());
+        let x = asset.slice(2..8);
+        assert_eq!(&x[..], &[2, 3, 4, 5, 6, 7]);
+        assert_eq!(&x.slice(1..3)[..], &[3, 4]);
+    }
+
+    /// A memory-mapped asset spanning several pages, and the data it should hold
+    fn map_temp(name: &str) -> (Asset, Vec<u8>) {
+        let path =
+            std::env::temp_dir().join(format!("chasset-{}-{:016X}", name, rand::random::<u64>()));
+        let data = (0..3 * 4096 + 123).map(|x| x as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+        let map = map_file(&std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let asset = Asset {
+            start: 0,
+            len: map.len(),
+            storage: Storage::Map(map),
+        };
+        (asset, data)
+    }
+
+    #[test]
+    fn advise() {
+        let (mapped, data) = map_temp("advise");
+        let assets = [
+            mapped.clone(),
+            // Neither end is page-aligned
+            mapped.slice(4097..9000),
+            mapped.slice(5..5),
+            Asset::from(data.clone()),
+            Asset::from(data.clone()).slice(3..7),
+            Asset::from(Vec::new()),
+            Asset::from(&b"static"[..]),
+        ];
+        for &advice in &[
+            Advice::Normal,
+            Advice::Sequential,
+            Advice::Random,
+            Advice::WillNeed,
+            Advice::DontNeed,
+        ] {
+            for asset in &assets {
+                asset.advise(advice).unwrap();
+            }
+        }
+        // Dropped pages are read back in
+        assert_eq!(&mapped[..], &data[..]);
+        assert_eq!(&assets[1][..], &data[4097..9000]);
+        assert_eq!(&assets[4][..], &data[3..7]);
+    }
+
+    #[test]
+    fn prefetch() {
+        let (mapped, data) = map_temp("prefetch");
+        assert!(Asset::from(data.clone()).prefetch().is_none());
+        assert!(Asset::from(&b"static"[..]).prefetch().is_none());
+        let pending = (0..8)
+            .map(|i| mapped.slice(i..mapped.len() - i).prefetch().unwrap())
+            .collect::<Vec<_>>();
+        for x in pending {
+            x.wait();
+        }
+        // Keeps the asset alive until done
+        let last = mapped.slice(4000..5000).prefetch().unwrap();
+        drop(mapped);
+        last.wait();
+    }
+
     #[test]
     fn collection() {
         let hash = Hash::Blake2b([0xAB; 25]);
@@ -0,0 +1,121 @@
+//! Advisory locks for coordinating access to a repository between processes.
+//!
+//! Maintenance operations that delete assets, such as `LooseFiles::collect_garbage`, hold an exclusive lock while they
+//! run. Applications that can't tolerate assets disappearing, e.g. a game client reading from a repository that a
+//! packer is maintaining, may hold a shared lock for as long as they need a stable view.
+//!
+//! Locks are advisory: they only exclude other users of this module. Locks are held by open files, so a process that
+//! takes a second, conflicting lock on the same path will wait for itself.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+// Called by path to avoid ambiguity with the equivalent methods of `File` in newer standard libraries
+use fs2::FileExt;
+
+/// A held lock, released when dropped.
+#[derive(Debug)]
+pub struct RepoLock {
+    file: File,
+    exclusive: bool,
+}
+
+impl RepoLock {
+    /// Wait for a lock on `path`, shared with other shared locks, creating the lock file if necessary.
+    pub fn shared(path: &Path) -> io::Result<Self> {
+        let file = open(path)?;
+        FileExt::lock_shared(&file)?;
+        Ok(Self {
+            file,
+            exclusive: false,
+        })
+    }
+
+    /// Wait for an exclusive lock on `path`, creating the lock file if necessary.
+    pub fn exclusive(path: &Path) -> io::Result<Self> {
+        let file = open(path)?;
+        FileExt::lock_exclusive(&file)?;
+        Ok(Self {
+            file,
+            exclusive: true,
+        })
+    }
+
+    /// Take a shared lock on `path` if that can be done without waiting.
+    pub fn try_shared(path: &Path) -> io::Result<Option<Self>> {
+        let file = open(path)?;
+        match contended(FileExt::try_lock_shared(&file))? {
+            false => Ok(None),
+            true => Ok(Some(Self {
+                file,
+                exclusive: false,
+            })),
+        }
+    }
+
+    /// Take an exclusive lock on `path` if that can be done without waiting.
+    pub fn try_exclusive(path: &Path) -> io::Result<Option<Self>> {
+        let file = open(path)?;
+        match contended(FileExt::try_lock_exclusive(&file))? {
+            false => Ok(None),
+            true => Ok(Some(Self {
+                file,
+                exclusive: true,
+            })),
+        }
+    }
+
+    /// Whether this lock excludes all others.
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        // Closing the file would release the lock regardless
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// Map the outcome of a lock attempt to whether the lock was taken.
+fn contended(result: io::Result<()>) -> io::Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exclusion() {
+        let dir = std::env::temp_dir().join(format!("chasset-lock-{:016X}", rand::random::<u64>()));
+        let path = dir.join("lock");
+        let a = RepoLock::try_shared(&path).unwrap().unwrap();
+        let b = RepoLock::try_shared(&path).unwrap().unwrap();
+        assert!(RepoLock::try_exclusive(&path).unwrap().is_none());
+        drop((a, b));
+        let c = RepoLock::try_exclusive(&path).unwrap().unwrap();
+        assert!(c.is_exclusive());
+        assert!(RepoLock::try_shared(&path).unwrap().is_none());
+        drop(c);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
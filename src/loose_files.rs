@@ -1,15 +1,33 @@
 //! Tools for a repository that stores one file per asset.
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::{self, File};
-use std::io;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use data_encoding::BASE32_NOPAD;
-use memmap::Mmap;
 use rand;
+use serde::{Deserialize, Serialize};
 
-use crate::{Asset, Hash, HashKind, Hasher};
+use crate::audit::AuditLog;
+use crate::budget::MapBudget;
+use crate::lock::RepoLock;
+use crate::manifest::{EntryKind, Manifest};
+use crate::pins::Pins;
+use crate::refs::{self, Refs};
+use crate::retention::RetentionPolicy;
+use crate::scrub::{self, FsckReport, ScrubReport};
+use crate::sidecar::MetadataLog;
+use crate::store;
+use crate::tags::Tags;
+#[cfg(feature = "metrics")]
+use crate::telemetry;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring;
+use crate::{
+    map_file, Asset, ContentSet, Hash, HashKind, Hasher, Storage, Store, StoreWriter, WritableStore,
+};
 
 /// A repository that stores each asset as a separate file.
 ///
@@ -24,40 +42,293 @@ use crate::{Asset, Hash, HashKind, Hasher};
 ///
 /// Unexpected interruptions (such as power loss) may cause incomplete writes to be left in the "temp" directory. Any
 /// file in the "temp" directory which is not currently open by any process arose from such an event, and may be safely
-/// deleted.
+/// deleted. Likewise, a "journal" directory holds the data of `Batch`es in progress; `open` deletes any batch directory
+/// within it which lacks a "COMMIT" file and is not in use by any process. Writes suspended with
+/// `Writer::suspend` are kept in a "suspended" directory until resumed, and may be deleted once they're abandoned.
+///
+/// `scrub` moves assets found to be corrupt into a "quarantine" directory, named by their expected hash, for
+/// inspection or deletion.
+///
+/// Processes coordinate maintenance through advisory locks on a "lock" file; see `lock_shared` and `lock_exclusive`.
 pub struct LooseFiles {
     prefix: PathBuf,
+    budget: Option<MapBudget>,
+    cache: Option<Arc<ContainsCache>>,
 }
 
 impl LooseFiles {
     /// Open a repository located at `prefix`, creating it if necessary.
+    ///
+    /// Completes any `Batch` whose commit was interrupted, and discards any abandoned before being committed.
     pub fn open(prefix: PathBuf) -> io::Result<Self> {
         fs::create_dir_all(&prefix)?;
-        Ok(Self { prefix })
+        recover(&prefix)?;
+        Ok(Self {
+            prefix,
+            budget: None,
+            cache: None,
+        })
+    }
+
+    /// Count mappings of assets against `budget`, reading assets onto the heap when it's exhausted.
+    pub fn set_map_budget(&mut self, budget: MapBudget) {
+        self.budget = Some(budget);
+    }
+
+    /// Remember the results of up to `capacity` recent `contains` calls, answering repeated queries without touching
+    /// the filesystem.
+    ///
+    /// Results are updated by puts and removals through this `LooseFiles`, but not by other processes or instances:
+    /// an asset they add may be reported absent until its entry is evicted or `clear_contains_cache` is called.
+    pub fn set_contains_cache(&mut self, capacity: usize) {
+        self.cache = Some(Arc::new(ContainsCache::new(capacity)));
+    }
+
+    /// Forget every result remembered by the cache enabled with `set_contains_cache`, e.g. after another process has
+    /// modified the repository.
+    pub fn clear_contains_cache(&self) {
+        if let Some(ref cache) = self.cache {
+            cache.clear();
+        }
     }
 
     /// Access the asset identified by `hash`.
     ///
-    /// The returned `File` is in read-only mode.
+    /// Non-empty assets are memory-mapped, unless a budget set with `set_map_budget` is exhausted, in which case they
+    /// are read onto the heap.
     pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let result = self.read(hash);
+        #[cfg(feature = "metrics")]
+        telemetry::read("loose_files", &result);
+        result
+    }
+
+    fn read(&self, hash: &Hash) -> io::Result<Asset> {
         let path = path_for(&self.prefix, hash);
-        let map = Arc::new(unsafe { Mmap::map(&File::open(path)?) }?);
+        let mut file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            // Empty files cannot be mapped
+            return Ok(Vec::new().into());
+        }
+        let map = match self.budget {
+            None => map_file(&file)?,
+            Some(ref budget) => match budget.map(&file)? {
+                Some(map) => map,
+                None => {
+                    let mut data = Vec::new();
+                    file.read_to_end(&mut data)?;
+                    return Ok(data.into());
+                }
+            },
+        };
         Ok(Asset {
             start: 0,
             len: map.len(),
-            map,
+            storage: Storage::Map(map),
         })
     }
 
     /// Determine whether the asset identified by `hash` exists in the repository.
     pub fn contains(&self, hash: &Hash) -> bool {
-        let path = path_for(&self.prefix, hash);
-        path.exists()
+        if let Some(x) = self.cache.as_ref().and_then(|x| x.get(hash)) {
+            return x;
+        }
+        let present = path_for(&self.prefix, hash).exists();
+        self.cached(hash, Some(present));
+        present
+    }
+
+    /// Record whether the asset identified by `hash` is present, or forget it if unknown.
+    fn cached(&self, hash: &Hash, present: Option<bool>) {
+        if let Some(ref cache) = self.cache {
+            cache.set(hash, present);
+        }
+    }
+
+    /// Get the path of the file that holds, or would hold, the asset identified by `hash`.
+    ///
+    /// The file must not be modified.
+    pub fn path(&self, hash: &Hash) -> PathBuf {
+        path_for(&self.prefix, hash)
+    }
+
+    /// Delete the asset identified by `hash` from the repository.
+    ///
+    /// Previously returned `Asset`s remain valid.
+    pub fn remove(&self, hash: &Hash) -> io::Result<()> {
+        let result = fs::remove_file(path_for(&self.prefix, hash));
+        match result {
+            Ok(()) => {
+                #[cfg(feature = "metrics")]
+                telemetry::removed("loose_files");
+                self.cached(hash, Some(false))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => self.cached(hash, Some(false)),
+            Err(_) => self.cached(hash, None),
+        }
+        result
+    }
+
+    /// Access the named references stored in the repository's "refs" directory.
+    pub fn refs(&self) -> io::Result<Refs> {
+        Refs::open(self.prefix.join("refs"))
+    }
+
+    /// Open the log of writes stored in the repository's "audit" file, attributing writes recorded through it to
+    /// `tool`.
+    pub fn audit_log(&self, tool: String) -> io::Result<AuditLog> {
+        AuditLog::open(self.prefix.join("audit"), tool)
+    }
+
+    /// Wait for a shared lock on the repository, excluding maintenance operations in any process until it's dropped.
+    pub fn lock_shared(&self) -> io::Result<RepoLock> {
+        RepoLock::shared(&self.prefix.join("lock"))
+    }
+
+    /// Wait for an exclusive lock on the repository, as held by maintenance operations.
+    pub fn lock_exclusive(&self) -> io::Result<RepoLock> {
+        RepoLock::exclusive(&self.prefix.join("lock"))
+    }
+
+    /// Take an exclusive lock on the repository if that can be done without waiting.
+    pub fn try_lock_exclusive(&self) -> io::Result<Option<RepoLock>> {
+        RepoLock::try_exclusive(&self.prefix.join("lock"))
+    }
+
+    /// Access the set of pinned assets stored in the repository's "pins" directory.
+    pub fn pins(&self) -> io::Result<Pins> {
+        Pins::open(self.prefix.join("pins"))
+    }
+
+    /// Access the tags stored in the repository's "tags" directory.
+    pub fn tags(&self) -> io::Result<Tags> {
+        Tags::open(self.prefix.join("tags"))
+    }
+
+    /// Access the metadata records stored in the repository's "metadata" file.
+    pub fn metadata(&self) -> io::Result<MetadataLog> {
+        MetadataLog::open(self.prefix.join("metadata"))
+    }
+
+    /// Delete every asset that isn't pinned, referred to by a reference, or reachable from such an asset that's a
+    /// `Manifest`. Returns the number of assets deleted.
+    ///
+    /// Assets written while collection is in progress may be deleted before they can be pinned or referred to, so
+    /// writers should be paused for the duration. Waits for an exclusive lock on the repository.
+    pub fn collect_garbage(&self) -> io::Result<usize> {
+        let _lock = self.lock_exclusive()?;
+        self.plan_garbage()?.remove(self)
+    }
+
+    /// Determine what `collect_garbage` would delete, without deleting anything.
+    pub fn plan_garbage(&self) -> io::Result<GcPlan> {
+        self.plan_garbage_with(&[])
+    }
+
+    /// Like `plan_garbage`, but additionally keep `roots` and every asset reachable from them.
+    ///
+    /// Useful for protecting assets that applications refer to from outside the repository. Apply the plan with
+    /// `GcPlan::apply`.
+    pub fn plan_garbage_with(&self, roots: &[Hash]) -> io::Result<GcPlan> {
+        let (roots, live) = self.trace(roots)?;
+        let mut plan = GcPlan {
+            roots,
+            live: 0,
+            garbage: Vec::new(),
+            bytes: 0,
+        };
+        for hash in self.list() {
+            if live.contains(&hash) {
+                plan.live += 1;
+                continue;
+            }
+            match fs::metadata(path_for(&self.prefix, &hash)) {
+                Ok(x) => plan.bytes += x.len(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+            plan.garbage.push(hash);
+        }
+        Ok(plan)
+    }
+
+    /// Delete assets according to `policy`, returning the number of assets deleted.
+    ///
+    /// Waits for an exclusive lock on the repository.
+    pub fn apply_retention(&self, policy: &RetentionPolicy) -> io::Result<usize> {
+        let _lock = self.lock_exclusive()?;
+        policy.plan(self)?.remove(self)
+    }
+
+    /// Find the roots, including `extra`, and every asset reachable from them.
+    pub(crate) fn trace(&self, extra: &[Hash]) -> io::Result<(Vec<Hash>, ContentSet)> {
+        let mut roots = self.pins()?.pins()?;
+        roots.extend_from_slice(extra);
+        roots.extend(self.refs()?.list().map(|(_, x)| x));
+        roots.sort_unstable();
+        roots.dedup();
+        // Roots might be manifests; entries are known to be or not to be
+        let mut queue = roots.iter().map(|&x| (x, true)).collect::<Vec<_>>();
+        let mut live = ContentSet::default();
+        while let Some((hash, tree)) = queue.pop() {
+            if !live.insert(hash) || !tree {
+                continue;
+            }
+            let data = match fs::read(path_for(&self.prefix, &hash)) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if let Ok(manifest) = Manifest::decode(&data) {
+                queue.extend(
+                    manifest
+                        .entries
+                        .values()
+                        .map(|x| (x.hash, x.kind == EntryKind::Tree)),
+                );
+            }
+        }
+        Ok((roots, live))
+    }
+
+    /// Begin a set of assets and reference updates to be published together.
+    pub fn batch(&self) -> io::Result<Batch<'_>> {
+        let journal = self.prefix.join("journal");
+        fs::create_dir_all(&journal)?;
+        loop {
+            let dir = journal.join(format!("{:016X}", rand::random::<u64>()));
+            match fs::create_dir(&dir) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+            let lock = match RepoLock::exclusive(&dir.join("lock")) {
+                Ok(x) => x,
+                // Discarded by a concurrent `open` before we could lock it
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            return Ok(Batch {
+                repo: self,
+                dir,
+                _lock: lock,
+                assets: Vec::new(),
+                refs: Vec::new(),
+                committed: false,
+            });
+        }
     }
 
     /// Create a `Writer` for streaming data into the repository in constant memory.
     pub fn make_writer(&self) -> io::Result<Writer> {
-        let mut path = self.prefix.join("temp");
+        self.make_writer_in(self.temp_dir()?)
+    }
+
+    /// Ensure the directory writes are staged in exists, and return its path.
+    fn temp_dir(&self) -> io::Result<PathBuf> {
+        let path = self.prefix.join("temp");
         match fs::create_dir(&path) {
             Ok(()) => {}
             Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
@@ -65,6 +336,11 @@ impl LooseFiles {
                 return Err(e);
             }
         }
+        Ok(path)
+    }
+
+    /// Create a `Writer` staging its data in the existing directory `path`.
+    fn make_writer_in(&self, mut path: PathBuf) -> io::Result<Writer> {
         loop {
             path.push(format!("{:08X}", rand::random::<u64>()));
             match fs::OpenOptions::new()
@@ -74,7 +350,7 @@ impl LooseFiles {
                 .open(&path)
             {
                 Ok(file) => {
-                    return Writer::new(file, path);
+                    return Writer::new(file, path, self.cache.clone());
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
                     path.pop();
@@ -87,13 +363,271 @@ impl LooseFiles {
         }
     }
 
+    /// Continue the write suspended as `token`.
+    ///
+    /// Rebuilds the hash computation by reading back the data already written, which is much faster than writing it
+    /// again from a remote source. The hasher's state can't be saved in the token instead, as the BLAKE2b
+    /// implementation doesn't expose it. Fails with `io::ErrorKind::NotFound` if the write has already been resumed,
+    /// and with `io::ErrorKind::InvalidData`, leaving the write suspended, if its data has been modified.
+    pub fn resume(&self, token: &ResumeToken) -> io::Result<Writer> {
+        if token.name.is_empty() || !token.name.bytes().all(|x| x.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "malformed resume token",
+            ));
+        }
+        let temp = self.prefix.join("temp");
+        fs::create_dir_all(&temp)?;
+        let path = temp.join(&token.name);
+        let suspended = self.prefix.join("suspended").join(&token.name);
+        let modified = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "suspended write has been modified",
+            )
+        };
+        if fs::metadata(&suspended)?.len() != token.len {
+            return Err(modified());
+        }
+        // Renaming claims the write, so that it can't be resumed twice
+        fs::rename(&suspended, &path)?;
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+        if file.metadata()?.len() != token.len {
+            // Modified since we checked; put it back for inspection
+            fs::rename(&path, &suspended)?;
+            return Err(modified());
+        }
+        let mut hasher = Hasher::new();
+        io::copy(&mut file, &mut hasher)?;
+        Ok(Writer {
+            hasher: Some(hasher),
+            path,
+            file,
+            cache: self.cache.clone(),
+        })
+    }
+
     /// Write `data` directly into the repository.
+    ///
+    /// With the `rayon` feature enabled, large assets are hashed while they're written.
     pub fn put(&self, mut data: &[u8]) -> io::Result<Hash> {
         let mut writer = self.make_writer()?;
+        #[cfg(feature = "rayon")]
+        {
+            if data.len() >= PARALLEL_PUT_THRESHOLD {
+                let (hash, written) = rayon::join(
+                    || {
+                        let mut hasher = Hasher::new();
+                        hasher.process(data);
+                        hasher.result()
+                    },
+                    || writer.file.write_all(data),
+                );
+                written?;
+                return writer.publish(hash).map(|(hash, _)| hash);
+            }
+        }
         io::copy(&mut data, &mut writer)?;
         writer.store().map(|(hash, _)| hash)
     }
 
+    /// Write the data read from each of `sources` into the repository, returning their hashes in the same order.
+    ///
+    /// Faster than `put` for many small assets: the staging directory is only checked once, directories for new assets
+    /// are only created once each, the temporary file of an asset that's already present is reused for the next, and
+    /// assets are made durable in groups so that the filesystem can write them back concurrently. Assets are published
+    /// as each group completes, so on failure those from earlier groups remain stored.
+    pub fn put_many<I, R>(&self, sources: I) -> io::Result<Vec<Hash>>
+    where
+        I: IntoIterator<Item = R>,
+        R: Read,
+    {
+        let temp = self.temp_dir()?;
+        let mut dirs = HashSet::new();
+        let mut hashes = Vec::new();
+        let mut batch = Vec::with_capacity(PUT_MANY_BATCH);
+        let mut pending = HashSet::new();
+        // Temporary file left over from an asset that was already present, reused for the next
+        let mut spare = None;
+        for mut source in sources {
+            let mut writer = match spare.take() {
+                Some(x) => x,
+                None => self.make_writer_in(temp.clone())?,
+            };
+            let len = io::copy(&mut source, &mut writer)?;
+            let hash = writer.hasher.clone().unwrap().result();
+            hashes.push(hash);
+            // Only the first of several sources with the same contents needs to be kept
+            let present = pending.contains(&hash) || path_for(&self.prefix, &hash).exists();
+            #[cfg(feature = "metrics")]
+            telemetry::stored("loose_files", len, !present);
+            #[cfg(not(feature = "metrics"))]
+            let _ = len;
+            if present {
+                self.cached(&hash, Some(true));
+                writer.reset()?;
+                spare = Some(writer);
+                continue;
+            }
+            pending.insert(hash);
+            batch.push((writer, hash));
+            if batch.len() == PUT_MANY_BATCH {
+                self.publish_many(&mut batch, &mut dirs)?;
+                pending.clear();
+            }
+        }
+        self.publish_many(&mut batch, &mut dirs)?;
+        Ok(hashes)
+    }
+
+    /// Publish every writer in `batch`, each holding a distinct asset that isn't yet present, syncing all of their data
+    /// before moving any into place.
+    ///
+    /// `dirs` records the asset directories known to exist.
+    fn publish_many(
+        &self,
+        batch: &mut Vec<(Writer, Hash)>,
+        dirs: &mut HashSet<PathBuf>,
+    ) -> io::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let synced = uring::sync_many(&batch.iter().map(|x| &x.0.file).collect::<Vec<_>>());
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        let synced: Option<io::Result<()>> = None;
+        match synced {
+            Some(result) => result?,
+            None => {
+                for (writer, _) in batch.iter() {
+                    writer.file.sync_data()?;
+                }
+            }
+        }
+        for (mut writer, hash) in batch.drain(..) {
+            let dest = path_for(&self.prefix, &hash);
+            let dir = dest.parent().unwrap();
+            if !dirs.contains(dir) {
+                fs::create_dir_all(dir)?;
+                dirs.insert(dir.to_owned());
+            }
+            fs::rename(&writer.path, &dest)?;
+            writer.hasher = None;
+            self.cached(&hash, Some(true));
+        }
+        Ok(())
+    }
+
+    /// Store the data read from `data` as the asset identified by `hash` without computing its hash, trusting the
+    /// caller that they match. Returns true iff the asset was not already present. If it was, `data` is not read.
+    ///
+    /// Intended for replicating content that was already verified, e.g. by `store_verified` on another host. If `hash`
+    /// is wrong, the repository will serve corrupt data under it until `fsck` or `scrub` notices. To catch a faulty
+    /// upstream early, a random `spot_check` fraction of calls, from 0 to 1, hash the data anyway and fail with
+    /// `io::ErrorKind::InvalidData` on a mismatch.
+    pub fn put_unchecked<R: Read>(
+        &self,
+        hash: Hash,
+        mut data: R,
+        spot_check: f64,
+    ) -> io::Result<bool> {
+        if self.contains(&hash) {
+            return Ok(false);
+        }
+        let mut writer = self.make_writer()?;
+        if spot_check > 0.0 && rand::random::<f64>() < spot_check {
+            io::copy(&mut data, &mut writer)?;
+            if writer.hasher.clone().unwrap().result() != hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("data doesn't match {}", hash),
+                ));
+            }
+        } else {
+            io::copy(&mut data, &mut writer.file)?;
+        }
+        writer.publish(hash).map(|(_, new)| new)
+    }
+
+    /// Store the contents of the file at `path`, streaming it in constant memory.
+    pub fn put_file(&self, path: &Path) -> io::Result<Hash> {
+        let mut writer = self.make_writer()?;
+        io::copy(&mut File::open(path)?, &mut writer)?;
+        writer.store().map(|(hash, _)| hash)
+    }
+
+    /// Store the contents of each file in `paths`, returning their hashes in the same order.
+    ///
+    /// With the `rayon` feature enabled, files are stored concurrently on the current rayon thread pool, which bounds
+    /// the number of files open at once; use `rayon::ThreadPool::install` to choose a different bound.
+    pub fn put_files<P: AsRef<Path> + Sync>(&self, paths: &[P]) -> Vec<io::Result<Hash>> {
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            paths
+                .par_iter()
+                .map(|x| self.put_file(x.as_ref()))
+                .collect()
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            paths.iter().map(|x| self.put_file(x.as_ref())).collect()
+        }
+    }
+
+    /// Verify the contents of up to `limit` assets against their hashes, continuing from where the previous call left
+    /// off, and quarantine any that are corrupt.
+    ///
+    /// Progress is recorded in a "scrub" file, so that calling this periodically eventually verifies every asset
+    /// without ever pausing for long. See `Scrubber` to do so in the background.
+    pub fn scrub(&self, limit: usize) -> io::Result<ScrubReport> {
+        scrub::run(
+            self,
+            &self.prefix.join("scrub"),
+            &self.prefix.join("quarantine"),
+            limit,
+        )
+    }
+
+    /// Move the file holding `hash` into the directory `quarantine`, named by its hash. Returns whether it was still
+    /// present.
+    pub(crate) fn quarantine(&self, hash: &Hash, quarantine: &Path) -> io::Result<bool> {
+        fs::create_dir_all(quarantine)?;
+        let result = fs::rename(
+            path_for(&self.prefix, hash),
+            quarantine.join(hash.to_string()),
+        );
+        match result {
+            Ok(()) => {
+                self.cached(hash, Some(false));
+                Ok(true)
+            }
+            // Removed, or replaced with an intact copy, concurrently
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.cached(hash, None);
+                Ok(false)
+            }
+            Err(e) => {
+                self.cached(hash, None);
+                Err(e)
+            }
+        }
+    }
+
+    /// Verify the contents of every asset against its hash, and find files among them that don't belong, such as those
+    /// left by other tools. Corrupt assets are moved into quarantine if `quarantine` is set.
+    ///
+    /// Takes time proportional to the total size of the repository; see `scrub` to spread the work out.
+    pub fn fsck(&self, quarantine: bool) -> io::Result<FsckReport> {
+        let quarantine_dir = self.prefix.join("quarantine");
+        scrub::fsck(
+            self,
+            &self.prefix,
+            if quarantine {
+                Some(&quarantine_dir)
+            } else {
+                None
+            },
+        )
+    }
+
     /// Enumerate assets stored in the repository.
     ///
     /// This should only be used for diagnostic purposes. It almost never makes sense to access an asset you don't
@@ -113,6 +647,295 @@ impl LooseFiles {
             })
             .flat_map(|x| x)
     }
+
+    /// Access each asset identified by `hashes`, in the same order.
+    ///
+    /// Assets are opened in hash order, so that each directory is visited once. With the `rayon` feature enabled,
+    /// assets are opened concurrently. With the `io-uring` feature enabled on Linux, small assets are instead read onto
+    /// the heap in batches through io_uring where the kernel supports it.
+    pub fn get_many(&self, hashes: &[Hash]) -> Vec<io::Result<Asset>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(x) = self.get_many_uring(hashes) {
+                return x;
+            }
+        }
+        store::get_ordered(hashes, |&x| x, |x| self.get(x))
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn get_many_uring(&self, hashes: &[Hash]) -> Option<Vec<io::Result<Asset>>> {
+        let mut order = (0..hashes.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| hashes[i]);
+        let mut result = hashes.iter().map(|_| None).collect::<Vec<_>>();
+        // Bound the number of files open at once
+        for chunk in order.chunks(URING_BATCH) {
+            let mut small = Vec::with_capacity(chunk.len());
+            for &i in chunk {
+                let file = match File::open(path_for(&self.prefix, &hashes[i])) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        result[i] = Some(Err(e));
+                        continue;
+                    }
+                };
+                match file.metadata() {
+                    Ok(ref x) if x.len() <= URING_READ_MAX => small.push((i, file, x.len() as u32)),
+                    Ok(_) => result[i] = Some(self.get(&hashes[i])),
+                    Err(e) => result[i] = Some(Err(e)),
+                }
+            }
+            let reads = small
+                .iter()
+                .map(|&(_, ref file, len)| uring::Read {
+                    file,
+                    offset: 0,
+                    len,
+                })
+                .collect::<Vec<_>>();
+            for (&(i, _, _), data) in small.iter().zip(uring::read_many(&reads)?) {
+                result[i] = Some(data.map(Asset::from));
+            }
+        }
+        Some(result.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Determine which of `hashes` exist in the repository, in the same order.
+    ///
+    /// With the `rayon` feature enabled, hashes are looked up concurrently.
+    pub fn contains_many(&self, hashes: &[Hash]) -> Vec<bool> {
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            hashes.par_iter().map(|x| self.contains(x)).collect()
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            hashes.iter().map(|x| self.contains(x)).collect()
+        }
+    }
+
+    /// Enumerate assets stored in the repository along with their sizes in bytes, without reading them.
+    ///
+    /// Assets removed during enumeration are skipped.
+    pub fn list_sizes(&self) -> impl Iterator<Item = io::Result<(Hash, u64)>> + '_ {
+        self.list().filter_map(
+            move |hash| match fs::metadata(path_for(&self.prefix, &hash)) {
+                Ok(x) => Some(Ok((hash, x.len()))),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => Some(Err(e)),
+            },
+        )
+    }
+}
+
+impl Store for LooseFiles {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn get_many(&self, hashes: &[Hash]) -> Vec<io::Result<Asset>> {
+        self.get_many(hashes)
+    }
+
+    fn contains_many(&self, hashes: &[Hash]) -> Vec<bool> {
+        self.contains_many(hashes)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+
+    fn list_sizes(&self) -> Box<dyn Iterator<Item = io::Result<(Hash, u64)>> + '_> {
+        Box::new(self.list_sizes())
+    }
+}
+
+impl WritableStore for LooseFiles {
+    type Writer = Writer;
+
+    fn make_writer(&self) -> io::Result<Writer> {
+        self.make_writer()
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+/// A set of assets and reference updates to be published together, created by `LooseFiles::batch`.
+///
+/// Assets are staged in a directory under "journal" until `commit` is called. Committing first durably records the
+/// contents of the batch in a "COMMIT" file, then publishes each asset and applies each reference update. If the commit
+/// is interrupted, e.g. by power loss, the next `LooseFiles::open` completes it, so a batch is never left half-applied.
+/// Assets are published before references are updated, so a reference never points to an asset that isn't present.
+///
+/// Staged data is deleted if the `Batch` is dropped without being committed. A lock on a "lock" file in the batch's
+/// directory marks it as in use, so that `LooseFiles::open` can discard the data of batches abandoned by a crash.
+pub struct Batch<'a> {
+    repo: &'a LooseFiles,
+    dir: PathBuf,
+    _lock: RepoLock,
+    assets: Vec<Hash>,
+    refs: Vec<(String, Option<Hash>)>,
+    committed: bool,
+}
+
+impl<'a> Batch<'a> {
+    /// Stage `data` for publication, returning its hash.
+    pub fn put(&mut self, mut data: &[u8]) -> io::Result<Hash> {
+        let path = self.dir.join(self.assets.len().to_string());
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut writer = Writer::new(file, path, None)?;
+        io::copy(&mut data, &mut writer)?;
+        let hash = writer.finish()?;
+        self.assets.push(hash);
+        Ok(hash)
+    }
+
+    /// Point the reference `name` at `hash` when the batch is committed.
+    pub fn set_ref(&mut self, name: &str, hash: &Hash) -> io::Result<()> {
+        refs::check_name(name)?;
+        self.refs.push((name.into(), Some(*hash)));
+        Ok(())
+    }
+
+    /// Delete the reference `name` when the batch is committed.
+    pub fn remove_ref(&mut self, name: &str) -> io::Result<()> {
+        refs::check_name(name)?;
+        self.refs.push((name.into(), None));
+        Ok(())
+    }
+
+    /// Publish every staged asset and apply every reference update.
+    pub fn commit(mut self) -> io::Result<()> {
+        let mut record = String::new();
+        for (i, hash) in self.assets.iter().enumerate() {
+            record.push_str(&format!("asset {} {}\n", i, hash));
+        }
+        for &(ref name, hash) in &self.refs {
+            match hash {
+                Some(hash) => record.push_str(&format!("ref {} {}\n", hash, name)),
+                None => record.push_str(&format!("unref {}\n", name)),
+            }
+        }
+        let temp = self.dir.join("COMMIT.tmp");
+        {
+            let mut file = File::create(&temp)?;
+            file.write_all(record.as_bytes())?;
+            file.sync_data()?;
+        }
+        fs::rename(&temp, self.dir.join("COMMIT"))?;
+        // Make the rename durable before publishing anything, so the commit can't be lost after partly applying
+        File::open(&self.dir)?.sync_all()?;
+        self.committed = true;
+        let result = apply(&self.repo.prefix, &self.dir);
+        for hash in &self.assets {
+            self.repo.cached(hash, None);
+        }
+        result
+    }
+}
+
+impl<'a> Drop for Batch<'a> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Complete every committed batch in the repository at `prefix`, and delete every uncommitted batch not in use.
+fn recover(prefix: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(prefix.join("journal")) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let dir = entry?.path();
+        if dir.join("COMMIT").exists() {
+            apply(prefix, &dir)?;
+            continue;
+        }
+        let _lock = match RepoLock::try_exclusive(&dir.join("lock")) {
+            Ok(Some(x)) => x,
+            // In use
+            Ok(None) => continue,
+            // Completed or discarded by someone else
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        // Committed between our check and taking the lock
+        if dir.join("COMMIT").exists() {
+            apply(prefix, &dir)?;
+            continue;
+        }
+        match fs::remove_dir_all(&dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            x => x?,
+        }
+    }
+    Ok(())
+}
+
+/// Carry out the committed batch staged in `dir`, then delete it.
+///
+/// Idempotent, so that interrupted or concurrent applications are harmless.
+fn apply(prefix: &Path, dir: &Path) -> io::Result<()> {
+    let record = match fs::read_to_string(dir.join("COMMIT")) {
+        Ok(x) => x,
+        // Already completed by someone else
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed batch journal");
+    let mut refs = None;
+    for line in record.lines() {
+        let mut words = line.splitn(3, ' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some("asset"), Some(file), Some(hash)) => {
+                let hash = hash.parse::<Hash>().map_err(|_| malformed())?;
+                let source = dir.join(file);
+                let dest = path_for(prefix, &hash);
+                if dest.exists() {
+                    continue;
+                }
+                fs::create_dir_all(dest.parent().unwrap())?;
+                match fs::rename(&source, &dest) {
+                    Ok(()) => {}
+                    // Already published
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            (Some("ref"), Some(hash), Some(name)) => {
+                let hash = hash.parse::<Hash>().map_err(|_| malformed())?;
+                if refs.is_none() {
+                    refs = Some(Refs::open(prefix.join("refs"))?);
+                }
+                refs.as_ref().unwrap().set(name, &hash)?;
+            }
+            (Some("unref"), Some(name), None) => {
+                if refs.is_none() {
+                    refs = Some(Refs::open(prefix.join("refs"))?);
+                }
+                refs.as_ref().unwrap().remove(name)?;
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    match fs::remove_dir_all(dir) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        x => x,
+    }
 }
 
 fn list_hash(hash_dir: PathBuf) -> impl Iterator<Item = Hash> {
@@ -158,6 +981,75 @@ fn list_leaf(kind: HashKind, leaf_dir: PathBuf) -> impl Iterator<Item = Hash> {
         })
 }
 
+/// Bounded record of which assets a `LooseFiles` contains, evicting the oldest entries first.
+#[derive(Debug)]
+struct ContainsCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    present: HashMap<Hash, bool>,
+    /// Hashes in `present`, oldest first
+    order: VecDeque<Hash>,
+}
+
+impl ContainsCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    fn get(&self, hash: &Hash) -> Option<bool> {
+        self.state.lock().unwrap().present.get(hash).cloned()
+    }
+
+    fn set(&self, hash: &Hash, present: Option<bool>) {
+        let mut state = self.state.lock().unwrap();
+        let present = match present {
+            Some(x) => x,
+            None => {
+                if state.present.remove(hash).is_some() {
+                    state.order.retain(|x| x != hash);
+                }
+                return;
+            }
+        };
+        if state.present.insert(*hash, present).is_some() {
+            return;
+        }
+        state.order.push_back(*hash);
+        while state.order.len() > self.capacity {
+            let oldest = state.order.pop_front().unwrap();
+            state.present.remove(&oldest);
+        }
+    }
+
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.present.clear();
+        state.order.clear();
+    }
+}
+
+/// Size from which `LooseFiles::put` hashes and writes concurrently, below which the overhead isn't worthwhile.
+#[cfg(feature = "rayon")]
+const PARALLEL_PUT_THRESHOLD: usize = 1 << 20;
+
+/// Number of assets `LooseFiles::put_many` writes before making them durable, bounding the files open at once.
+const PUT_MANY_BATCH: usize = 128;
+
+/// Number of assets `LooseFiles::get_many` opens at once when reading through io_uring.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const URING_BATCH: usize = 256;
+
+/// Size of the largest asset `LooseFiles::get_many` reads through io_uring rather than mapping.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const URING_READ_MAX: u64 = 1 << 20;
+
 fn path_for(prefix: &Path, hash: &Hash) -> PathBuf {
     let s = BASE32_NOPAD.encode(hash.bytes());
     let dir = &s[0..2];
@@ -165,6 +1057,44 @@ fn path_for(prefix: &Path, hash: &Hash) -> PathBuf {
     prefix.join(hash.kind().name()).join(dir).join(file)
 }
 
+/// The outcome of tracing live assets in a `LooseFiles`, for review before any are deleted.
+///
+/// A plan only remains accurate while no new roots are added, so writers should be paused between planning and
+/// applying.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GcPlan {
+    /// Pinned and referenced assets from which liveness was traced, in order
+    pub roots: Vec<Hash>,
+    /// Number of assets present that will be kept
+    pub live: usize,
+    /// Assets to be deleted
+    pub garbage: Vec<Hash>,
+    /// Total size of `garbage` in bytes
+    pub bytes: u64,
+}
+
+impl GcPlan {
+    /// Delete the garbage from `store`, returning the number of assets deleted.
+    ///
+    /// Assets that have already been deleted are skipped. Waits for an exclusive lock on the repository.
+    pub fn apply(&self, store: &LooseFiles) -> io::Result<usize> {
+        let _lock = store.lock_exclusive()?;
+        self.remove(store)
+    }
+
+    pub(crate) fn remove(&self, store: &LooseFiles) -> io::Result<usize> {
+        let mut removed = 0;
+        for hash in &self.garbage {
+            match store.remove(hash) {
+                Ok(()) => removed += 1,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+}
+
 /// A staging area for streaming data into the repository in constant memory.
 ///
 /// Data written into a `Writer` is used to update a hash computation and buffered in a temporary file on disk.
@@ -175,6 +1105,7 @@ pub struct Writer {
     hasher: Option<Hasher>,
     path: PathBuf,
     file: File,
+    cache: Option<Arc<ContainsCache>>,
 }
 
 impl Drop for Writer {
@@ -186,28 +1117,95 @@ impl Drop for Writer {
 }
 
 impl Writer {
-    fn new(file: File, path: PathBuf) -> io::Result<Self> {
+    fn new(file: File, path: PathBuf, cache: Option<Arc<ContainsCache>>) -> io::Result<Self> {
         Ok(Writer {
             hasher: Some(Hasher::default()),
             path,
             file,
+            cache,
         })
     }
 
+    /// Discard the data written so far, so the temporary file can hold another asset.
+    fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.hasher = Some(Hasher::default());
+        Ok(())
+    }
+
     /// Commits the written data to the repository. The `bool` is true iff the data was not already there.
     pub fn store(mut self) -> io::Result<(Hash, bool)> {
         let hash = self.hasher.take().unwrap().result();
+        self.publish(hash)
+    }
+
+    /// Move the written data into place as the asset identified by `hash`.
+    fn publish(mut self, hash: Hash) -> io::Result<(Hash, bool)> {
+        self.hasher = None;
+        #[cfg(feature = "metrics")]
+        let len = self.file.metadata()?.len();
         let prefix = self.path.parent().unwrap().parent().unwrap();
         let dest = path_for(prefix, &hash);
-        if dest.exists() {
+        let new = if dest.exists() {
             let _ = fs::remove_file(&self.path);
-            Ok((hash, false))
+            false
         } else {
             fs::create_dir_all(dest.parent().unwrap())?;
             self.file.sync_data()?;
             fs::rename(&self.path, &dest)?;
-            Ok((hash, true))
+            true
+        };
+        if let Some(ref cache) = self.cache {
+            cache.set(&hash, Some(true));
         }
+        #[cfg(feature = "metrics")]
+        telemetry::stored("loose_files", len, new);
+        Ok((hash, new))
+    }
+
+    /// Set the write aside to be continued later with `LooseFiles::resume`, possibly by another process.
+    pub fn suspend(mut self) -> io::Result<ResumeToken> {
+        self.file.sync_data()?;
+        let len = self.file.metadata()?.len();
+        let name = self.path.file_name().unwrap().to_str().unwrap().to_owned();
+        let dir = self
+            .path
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("suspended");
+        fs::create_dir_all(&dir)?;
+        fs::rename(&self.path, dir.join(&name))?;
+        self.hasher = None;
+        Ok(ResumeToken { name, len })
+    }
+
+    /// Make the written data durable without publishing it, returning its hash.
+    fn finish(mut self) -> io::Result<Hash> {
+        self.file.sync_data()?;
+        Ok(self.hasher.take().unwrap().result())
+    }
+}
+
+/// Identifies a suspended `Writer`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ResumeToken {
+    name: String,
+    len: u64,
+}
+
+impl ResumeToken {
+    /// Number of bytes written before suspension, from which the source should be resumed.
+    pub fn written(&self) -> u64 {
+        self.len
+    }
+}
+
+impl StoreWriter for Writer {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        self.store()
     }
 }
 
@@ -222,3 +1220,251 @@ impl io::Write for Writer {
         self.file.flush()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hash_of;
+
+    #[test]
+    fn collect_garbage() {
+        let dir = std::env::temp_dir().join(format!("chasset-gc-{:016X}", rand::random::<u64>()));
+        let store = LooseFiles::open(dir.clone()).unwrap();
+        let pinned = store.put(b"pinned").unwrap();
+        let garbage = store.put(b"garbage").unwrap();
+        let mut manifest = Manifest::new();
+        let child = manifest.put(&store, "child".into(), b"child").unwrap();
+        let root = manifest.store(&store).unwrap();
+        store.pins().unwrap().pin(&pinned).unwrap();
+        store.refs().unwrap().set("latest", &root).unwrap();
+
+        let plan = store.plan_garbage().unwrap();
+        assert_eq!(plan.garbage, [garbage]);
+        assert_eq!(plan.bytes, 7);
+        assert_eq!(plan.live, 3);
+        assert!(store.contains(&garbage));
+        assert!(store
+            .plan_garbage_with(&[garbage])
+            .unwrap()
+            .garbage
+            .is_empty());
+        assert_eq!(store.collect_garbage().unwrap(), 1);
+        assert!(!store.contains(&garbage));
+        assert_eq!(store.contains_many(&[pinned, garbage]), [true, false]);
+        for hash in &[pinned, child, root] {
+            assert!(store.contains(hash));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resume() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-resume-{:016X}", rand::random::<u64>()));
+        let store = LooseFiles::open(dir.clone()).unwrap();
+        let mut writer = store.make_writer().unwrap();
+        writer.write_all(b"hello, ").unwrap();
+        let token = writer.suspend().unwrap();
+        assert_eq!(token.written(), 7);
+
+        // A modified write is refused and left suspended
+        let suspended = dir.join("suspended").join(&token.name);
+        fs::write(&suspended, b"hello").unwrap();
+        assert_eq!(
+            store.resume(&token).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(suspended.exists());
+        fs::write(&suspended, b"hello, ").unwrap();
+
+        let mut writer = store.resume(&token).unwrap();
+        assert!(store.resume(&token).is_err());
+        writer.write_all(b"world").unwrap();
+        let (hash, _) = writer.store().unwrap();
+        assert_eq!(&*store.get(&hash).unwrap(), b"hello, world");
+        assert_eq!(hash, store.put(b"hello, world").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn put_many() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-put-many-{:016X}", rand::random::<u64>()));
+        let store = LooseFiles::open(dir.clone()).unwrap();
+        let existing = store.put(b"b").unwrap();
+        let sources = (0..300).map(|i| if i % 2 == 0 { &b"a"[..] } else { &b"b"[..] });
+        let hashes = store.put_many(sources).unwrap();
+        assert_eq!(hashes.len(), 300);
+        assert_eq!(hashes[1], existing);
+        assert_eq!(&*store.get(&hashes[298]).unwrap(), b"a");
+        assert_eq!(store.list().count(), 2);
+        let assets = store.get_many(&[hashes[1], hashes[0]]);
+        assert_eq!(&**assets[0].as_ref().unwrap(), b"b");
+        assert_eq!(&**assets[1].as_ref().unwrap(), b"a");
+        // A temporary file reused after a duplicate holds nothing of the duplicate's data
+        let hashes = store
+            .put_many(vec![&b"longer"[..], b"longer", b"c"])
+            .unwrap();
+        assert_eq!(hashes[0], hashes[1]);
+        assert_eq!(&*store.get(&hashes[2]).unwrap(), b"c");
+        assert_eq!(hashes[2], hash_of(b"c"));
+        assert_eq!(fs::read_dir(dir.join("temp")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn uring() {
+        if io_uring::IoUring::new(1).is_err() {
+            // Unsupported by this kernel
+            return;
+        }
+        let dir =
+            std::env::temp_dir().join(format!("chasset-uring-{:016X}", rand::random::<u64>()));
+        let store = LooseFiles::open(dir.clone()).unwrap();
+        // More than one batch of flushes and of reads
+        let assets = (0..URING_BATCH as u32 + 10)
+            .map(|i| i.to_le_bytes().repeat(i as usize % 7 + 1))
+            .collect::<Vec<_>>();
+        let mut hashes = store.put_many(assets.iter().map(|x| &x[..])).unwrap();
+        assert_eq!(fs::read_dir(dir.join("temp")).unwrap().count(), 0);
+        for (hash, data) in hashes.iter().zip(&assets) {
+            assert_eq!(&*store.get(hash).unwrap(), &data[..]);
+        }
+        let large = vec![0xAB; URING_READ_MAX as usize + 1];
+        hashes.push(store.put(&large).unwrap());
+        let missing = hash_of(b"missing");
+        hashes.push(missing);
+        hashes.reverse();
+
+        let result = store.get_many_uring(&hashes).unwrap();
+        assert_eq!(result.len(), hashes.len());
+        assert_eq!(
+            result[0].as_ref().err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(&**result[1].as_ref().unwrap(), &large[..]);
+        for (asset, data) in result[2..].iter().zip(assets.iter().rev()) {
+            assert_eq!(&**asset.as_ref().unwrap(), &data[..]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-empty-{:016X}", rand::random::<u64>()));
+        let mut store = LooseFiles::open(dir.clone()).unwrap();
+        let hash = store.put(b"").unwrap();
+        assert!(store.get(&hash).unwrap().is_empty());
+        store.set_map_budget(MapBudget::new(u64::MAX, usize::MAX));
+        assert!(store.get(&hash).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn put_unchecked() {
+        let dir = std::env::temp_dir().join(format!(
+            "chasset-put-unchecked-{:016X}",
+            rand::random::<u64>()
+        ));
+        let store = LooseFiles::open(dir.clone()).unwrap();
+        let hash = Hash::Blake2b([0; crate::BLAKE2B_LEN]);
+        assert_eq!(
+            store
+                .put_unchecked(hash, &b"a"[..], 1.0)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(!store.contains(&hash));
+        assert!(store.put_unchecked(hash, &b"a"[..], 0.0).unwrap());
+        assert_eq!(&*store.get(&hash).unwrap(), b"a");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn contains_cache() {
+        let dir = std::env::temp_dir().join(format!(
+            "chasset-contains-cache-{:016X}",
+            rand::random::<u64>()
+        ));
+        let mut store = LooseFiles::open(dir.clone()).unwrap();
+        store.set_contains_cache(1);
+        let other = LooseFiles::open(dir.clone()).unwrap();
+        let a = other.put(b"a").unwrap();
+        other.remove(&a).unwrap();
+        assert!(!store.contains(&a));
+        other.put(b"a").unwrap();
+        assert!(!store.contains(&a), "negative result should be cached");
+        store.clear_contains_cache();
+        assert!(store.contains(&a));
+        store.remove(&a).unwrap();
+        assert!(!store.contains(&a));
+        store.put(b"a").unwrap();
+        assert!(store.contains(&a));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn put_files() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-put-files-{:016X}", rand::random::<u64>()));
+        let store = LooseFiles::open(dir.join("repo")).unwrap();
+        fs::write(dir.join("a"), b"a").unwrap();
+        let hashes = store.put_files(&[dir.join("a"), dir.join("missing")]);
+        assert_eq!(hashes[0].as_ref().unwrap(), &store.put(b"a").unwrap());
+        assert_eq!(
+            hashes[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batch() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-batch-{:016X}", rand::random::<u64>()));
+        let store = LooseFiles::open(dir.clone()).unwrap();
+
+        let mut batch = store.batch().unwrap();
+        let abandoned = batch.put(b"abandoned").unwrap();
+        drop(batch);
+        assert!(!store.contains(&abandoned));
+
+        let mut batch = store.batch().unwrap();
+        let hash = batch.put(b"data").unwrap();
+        batch.set_ref("latest", &hash).unwrap();
+        // Simulate an interruption immediately after the commit record is written
+        let journal = batch.dir.clone();
+        fs::write(
+            journal.join("COMMIT"),
+            format!("asset 0 {}\nref {} latest\n", hash, hash),
+        )
+        .unwrap();
+        std::mem::forget(batch);
+        assert!(!store.contains(&hash));
+
+        let store = LooseFiles::open(dir.clone()).unwrap();
+        assert!(store.contains(&hash));
+        assert_eq!(store.refs().unwrap().get("latest").unwrap(), Some(hash));
+        assert!(!journal.exists());
+
+        // A batch in use survives another instance being opened
+        let mut batch = store.batch().unwrap();
+        let live = batch.put(b"live").unwrap();
+        let store2 = LooseFiles::open(dir.clone()).unwrap();
+        assert!(batch.dir.exists());
+        batch.commit().unwrap();
+        assert!(store2.contains(&live));
+
+        // Simulate a crash while staging an uncommitted batch
+        let abandoned = dir.join("journal").join("0123456789ABCDEF");
+        fs::create_dir(&abandoned).unwrap();
+        fs::write(abandoned.join("0"), b"abandoned").unwrap();
+        fs::write(abandoned.join("lock"), b"").unwrap();
+        LooseFiles::open(dir.clone()).unwrap();
+        assert!(!abandoned.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+//! Tools for a size-limited cache of loose files.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    hash_of, loose_files, Asset, ContentMap, Hash, HashKind, LooseFiles, Store, StoreWriter,
+    WritableStore,
+};
+
+/// A `LooseFiles` limited to a fixed number of bytes, evicting the least recently used assets to make room for new
+/// ones.
+///
+/// Recency is recorded in a sidecar index file, written by `save` and when the store is dropped. Assets found in the
+/// repository but missing from the index are treated as least recently used.
+pub struct LruStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    loose: LooseFiles,
+    index_path: PathBuf,
+    budget: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: ContentMap<Entry>,
+    /// Assets by time of last access
+    order: BTreeMap<u64, Hash>,
+    /// Source of access times
+    clock: u64,
+    /// Total size of all assets
+    bytes: u64,
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    len: u64,
+    accessed: u64,
+}
+
+impl State {
+    fn touch(&mut self, hash: &Hash) {
+        let clock = self.clock + 1;
+        if let Some(entry) = self.entries.get_mut(hash) {
+            self.order.remove(&entry.accessed);
+            entry.accessed = clock;
+            self.order.insert(clock, *hash);
+            self.clock = clock;
+        }
+    }
+
+    fn insert(&mut self, hash: Hash, len: u64, accessed: u64) {
+        if self.entries.contains_key(&hash) {
+            return;
+        }
+        self.entries.insert(hash, Entry { len, accessed });
+        self.order.insert(accessed, hash);
+        self.clock = self.clock.max(accessed);
+        self.bytes += len;
+    }
+
+    /// Evict least recently used assets until at most `budget` bytes remain, not counting `keep`.
+    fn evict(&mut self, loose: &LooseFiles, budget: u64, keep: Option<&Hash>) -> io::Result<()> {
+        let mut skipped = Vec::new();
+        while self.bytes > budget {
+            let (accessed, hash) = match self.order.iter().next() {
+                Some((&accessed, &hash)) => (accessed, hash),
+                None => break,
+            };
+            self.order.remove(&accessed);
+            if Some(&hash) == keep {
+                skipped.push((accessed, hash));
+                continue;
+            }
+            match loose.remove(&hash) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    self.order.insert(accessed, hash);
+                    return Err(e);
+                }
+            }
+            let entry = self.entries.remove(&hash).unwrap();
+            self.bytes -= entry.len;
+        }
+        self.order.extend(skipped);
+        Ok(())
+    }
+}
+
+impl LruStore {
+    /// Limit `loose` to `budget` bytes, recording recency in the file at `index`.
+    ///
+    /// Evicts assets immediately if `loose` already exceeds `budget`.
+    pub fn open(loose: LooseFiles, index: PathBuf, budget: u64) -> io::Result<Self> {
+        let mut recorded = read_index(&index)?;
+        // Index entries for assets that no longer exist are discarded by only considering listed assets
+        let mut known = Vec::new();
+        let mut unknown = Vec::new();
+        for x in loose.list_sizes() {
+            let (hash, len) = x?;
+            match recorded.remove(&hash) {
+                Some(accessed) => known.push((hash, len, accessed)),
+                None => unknown.push((hash, len)),
+            }
+        }
+        // Unrecorded assets are considered older than any recorded access
+        let mut state = State::default();
+        for (i, (hash, len)) in unknown.iter().enumerate() {
+            state.insert(*hash, *len, i as u64);
+        }
+        for (hash, len, accessed) in known {
+            state.insert(hash, len, accessed + unknown.len() as u64);
+        }
+        state.evict(&loose, budget, None)?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                loose,
+                index_path: index,
+                budget,
+                state: Mutex::new(state),
+            }),
+        })
+    }
+
+    /// The underlying loose files.
+    pub fn loose(&self) -> &LooseFiles {
+        &self.inner.loose
+    }
+
+    /// Maximum total size of stored assets.
+    pub fn budget(&self) -> u64 {
+        self.inner.budget
+    }
+
+    /// Total size of stored assets.
+    pub fn bytes(&self) -> u64 {
+        self.inner.state.lock().unwrap().bytes
+    }
+
+    /// Write the recency index.
+    pub fn save(&self) -> io::Result<()> {
+        self.inner.save()
+    }
+
+    /// Access the asset identified by `hash`, marking it as recently used.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let asset = self.inner.loose.get(hash)?;
+        self.inner.state.lock().unwrap().touch(hash);
+        Ok(asset)
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.inner.state.lock().unwrap().entries.contains_key(hash)
+    }
+
+    /// Write `data` into the repository, first evicting assets as necessary to make room.
+    ///
+    /// Data that's already present is only marked as recently used.
+    pub fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        let len = data.len() as u64;
+        if len > self.inner.budget {
+            return Err(too_large());
+        }
+        let hash = hash_of(data);
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.entries.contains_key(&hash) {
+                state.touch(&hash);
+                return Ok(hash);
+            }
+            state.evict(&self.inner.loose, self.inner.budget - len, None)?;
+        }
+        self.inner.loose.put(data)?;
+        self.inner.added(hash, len)?;
+        Ok(hash)
+    }
+
+    /// Enumerate assets stored in the repository.
+    pub fn list(&self) -> impl Iterator<Item = Hash> {
+        let state = self.inner.state.lock().unwrap();
+        state
+            .entries
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+impl Inner {
+    /// Record a newly written asset as most recently used, evicting others to stay within budget.
+    fn added(&self, hash: Hash, len: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.contains_key(&hash) {
+            state.touch(&hash);
+        } else {
+            let clock = state.clock + 1;
+            state.insert(hash, len, clock);
+        }
+        state.evict(&self.loose, self.budget, Some(&hash))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut buf = Vec::new();
+        {
+            let state = self.state.lock().unwrap();
+            for (&accessed, hash) in &state.order {
+                let mut header = [0; 10];
+                LittleEndian::write_u16(&mut header[0..2], hash.kind().id());
+                LittleEndian::write_u64(&mut header[2..10], accessed);
+                buf.extend_from_slice(&header);
+                buf.extend_from_slice(hash.bytes());
+            }
+        }
+        let tmp = self.index_path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.index_path)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+/// Read a recency index, yielding the last access time of each asset.
+fn read_index(path: &Path) -> io::Result<ContentMap<u64>> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut data)?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e);
+        }
+    }
+    let mut result = ContentMap::default();
+    let mut rest = &data[..];
+    while rest.len() >= 10 {
+        let kind = HashKind::from_id(LittleEndian::read_u16(&rest[0..2]));
+        let accessed = LittleEndian::read_u64(&rest[2..10]);
+        let kind = match kind {
+            Some(x) if rest.len() - 10 >= x.len() => x,
+            // Unknown or truncated; recency is advisory, so ignore the remainder
+            _ => break,
+        };
+        let hash = Hash::from_bytes(kind, &rest[10..10 + kind.len()]).unwrap();
+        result.insert(hash, accessed);
+        rest = &rest[10 + kind.len()..];
+    }
+    Ok(result)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "asset exceeds cache budget")
+}
+
+impl Store for LruStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+}
+
+impl WritableStore for LruStore {
+    type Writer = Writer;
+
+    fn make_writer(&self) -> io::Result<Writer> {
+        Ok(Writer {
+            inner: self.inner.loose.make_writer()?,
+            store: self.inner.clone(),
+            len: 0,
+        })
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+/// A staging area for streaming data into an `LruStore`.
+///
+/// Since the size of the data isn't known in advance, room is made for it only once it's stored.
+pub struct Writer {
+    inner: loose_files::Writer,
+    store: Arc<Inner>,
+    len: u64,
+}
+
+impl StoreWriter for Writer {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        if self.len > self.store.budget {
+            return Err(too_large());
+        }
+        let (hash, new) = self.inner.store()?;
+        if new {
+            self.store.added(hash, self.len)?;
+        } else {
+            self.store.state.lock().unwrap().touch(&hash);
+        }
+        Ok((hash, new))
+    }
+}
+
+impl io::Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evict() {
+        let dir = std::env::temp_dir().join(format!("chasset-lru-{:016X}", rand::random::<u64>()));
+        let loose = LooseFiles::open(dir.join("loose")).unwrap();
+        let store = LruStore::open(loose, dir.join("index"), 10).unwrap();
+        let a = store.put(b"aaaa").unwrap();
+        let b = store.put(b"bbbb").unwrap();
+        store.get(&a).unwrap();
+        let c = store.put(b"cccc").unwrap();
+        assert!(store.contains(&a) && !store.contains(&b) && store.contains(&c));
+        assert!(!store.loose().contains(&b));
+        assert_eq!(store.bytes(), 8);
+        // Writing a present asset evicts nothing, even when full
+        assert_eq!(store.put(b"cccc").unwrap(), c);
+        assert!(store.contains(&a) && store.contains(&c));
+        assert_eq!(store.bytes(), 8);
+        let empty = store.put(b"").unwrap();
+        assert_eq!(store.bytes(), 8);
+        drop(store);
+
+        let loose = LooseFiles::open(dir.join("loose")).unwrap();
+        let store = LruStore::open(loose, dir.join("index"), 4).unwrap();
+        assert!(store.contains(&c) && !store.contains(&a));
+        assert!(store.contains(&empty));
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,457 @@
+//! Descriptions of whole asset sets.
+//!
+//! A `Manifest` maps names to assets. Entries may refer to further manifests, forming a tree; since every manifest is
+//! itself stored as an asset, a single root hash identifies an entire versioned asset set, and subtrees shared between
+//! versions are stored once. Point a `Refs` name at the root to track the current version.
+//!
+//! Manifests have a canonical binary encoding, so equal manifests are always stored under the same hash:
+//!
+//! - the magic `CHMANIF1`
+//! - a little-endian `u32` count of entries, followed by that many entries in lexicographic order of name, each:
+//!   - `u32` length and UTF-8 bytes of the name
+//!   - `u8` entry kind: 0 for an asset, 1 for a manifest
+//!   - `u16` hash kind ID, `u16` length, and bytes of the hash
+//!   - `u64` size of the referenced asset
+//!   - `u32` count of metadata pairs, each a `u32` length and UTF-8 bytes of key then value, in order of key
+//!
+//! With the `ed25519-dalek` feature, a publisher can sign a `SignedRelease` associating a release name with a root
+//! manifest. A client that trusts the publisher's key then verifies the release with `SignedRelease::verify` and
+//! everything it refers to with `verify_tree`, establishing that each asset is exactly what was published.
+
+use std::collections::{btree_map, BTreeMap};
+use std::io;
+use std::str;
+
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(feature = "ed25519-dalek")]
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{Hash, HashKind, Hasher, Store, WritableStore};
+
+const MAGIC: &[u8; 8] = b"CHMANIF1";
+#[cfg(feature = "ed25519-dalek")]
+const RELEASE_MAGIC: &[u8; 8] = b"CHRELEA1";
+
+/// A set of named assets, possibly including nested manifests.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Entries by name
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// A named reference to an asset within a `Manifest`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The referenced asset
+    pub hash: Hash,
+    /// How the referenced asset should be interpreted
+    pub kind: EntryKind,
+    /// Size of the referenced asset in bytes
+    pub size: u64,
+    /// Free-form key/value pairs, e.g. a media type
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// The type of asset referred to by an `Entry`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    /// Opaque data
+    Blob,
+    /// An encoded `Manifest`
+    Tree,
+}
+
+impl Entry {
+    /// Refer to the opaque asset `hash`, `size` bytes long.
+    pub fn blob(hash: Hash, size: u64) -> Self {
+        Self {
+            hash,
+            kind: EntryKind::Blob,
+            size,
+            metadata: BTreeMap::new(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Create an empty manifest.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `data` in `store` and add an entry referring to it as `name`, replacing any existing entry.
+    pub fn put<S: WritableStore + ?Sized>(
+        &mut self,
+        store: &S,
+        name: String,
+        data: &[u8],
+    ) -> io::Result<Hash> {
+        let hash = store.put(data)?;
+        self.entries
+            .insert(name, Entry::blob(hash, data.len() as u64));
+        Ok(hash)
+    }
+
+    /// Store `manifest` in `store` and add an entry referring to it as `name`, replacing any existing entry.
+    pub fn put_tree<S: WritableStore + ?Sized>(
+        &mut self,
+        store: &S,
+        name: String,
+        manifest: &Manifest,
+    ) -> io::Result<Hash> {
+        let data = manifest.encode();
+        let hash = store.put(&data)?;
+        self.entries.insert(
+            name,
+            Entry {
+                hash,
+                kind: EntryKind::Tree,
+                size: data.len() as u64,
+                metadata: BTreeMap::new(),
+            },
+        );
+        Ok(hash)
+    }
+
+    /// Write this manifest into `store`, returning its hash.
+    pub fn store<S: WritableStore + ?Sized>(&self, store: &S) -> io::Result<Hash> {
+        store.put(&self.encode())
+    }
+
+    /// Read the manifest identified by `hash` from `store`.
+    pub fn load<S: Store + ?Sized>(store: &S, hash: &Hash) -> io::Result<Self> {
+        Self::decode(&store.get(hash)?)
+    }
+
+    /// Encode in the canonical binary format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        write_u32(&mut out, self.entries.len() as u32);
+        for (name, entry) in &self.entries {
+            write_str(&mut out, name);
+            out.push(match entry.kind {
+                EntryKind::Blob => 0,
+                EntryKind::Tree => 1,
+            });
+            write_hash(&mut out, &entry.hash);
+            let mut size = [0; 8];
+            LittleEndian::write_u64(&mut size, entry.size);
+            out.extend_from_slice(&size);
+            write_u32(&mut out, entry.metadata.len() as u32);
+            for (key, value) in &entry.metadata {
+                write_str(&mut out, key);
+                write_str(&mut out, value);
+            }
+        }
+        out
+    }
+
+    /// Decode from the canonical binary format.
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(invalid_manifest());
+        }
+        let mut data = &data[MAGIC.len()..];
+        let mut entries = BTreeMap::new();
+        for _ in 0..read_u32(&mut data)? {
+            let name = read_str(&mut data)?;
+            let kind = match take(&mut data, 1)?[0] {
+                0 => EntryKind::Blob,
+                1 => EntryKind::Tree,
+                _ => return Err(invalid_manifest()),
+            };
+            let hash = read_hash(&mut data)?;
+            let size = LittleEndian::read_u64(take(&mut data, 8)?);
+            let mut metadata = BTreeMap::new();
+            for _ in 0..read_u32(&mut data)? {
+                let key = read_str(&mut data)?;
+                metadata.insert(key, read_str(&mut data)?);
+            }
+            entries.insert(
+                name,
+                Entry {
+                    hash,
+                    kind,
+                    size,
+                    metadata,
+                },
+            );
+        }
+        if !data.is_empty() {
+            return Err(invalid_manifest());
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Visit every entry reachable from the manifest identified by `root`, depth-first.
+///
+/// Yields each entry's path, formed by joining the names leading to it with "/". Subtrees shared by multiple paths
+/// are visited once for each. Iteration stops after the first error.
+pub fn walk<'a, S: Store + ?Sized>(store: &'a S, root: &Hash) -> Walk<'a, S> {
+    let (stack, error) = match Manifest::load(store, root) {
+        Ok(x) => (vec![(String::new(), x.entries.into_iter())], None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
+    Walk {
+        store,
+        stack,
+        error,
+    }
+}
+
+/// Iterator over the entries reachable from a manifest, returned by `walk`.
+pub struct Walk<'a, S: ?Sized> {
+    store: &'a S,
+    /// Path prefix and remaining entries of each manifest being visited
+    stack: Vec<(String, btree_map::IntoIter<String, Entry>)>,
+    error: Option<io::Error>,
+}
+
+impl<'a, S: Store + ?Sized> Iterator for Walk<'a, S> {
+    type Item = io::Result<(String, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.stack.clear();
+            return Some(Err(e));
+        }
+        loop {
+            let (name, entry) = {
+                let &mut (_, ref mut entries) = self.stack.last_mut()?;
+                match entries.next() {
+                    Some(x) => x,
+                    None => {
+                        self.stack.pop();
+                        continue;
+                    }
+                }
+            };
+            let path = self.stack.last().unwrap().0.clone() + &name;
+            if entry.kind == EntryKind::Tree {
+                match Manifest::load(self.store, &entry.hash) {
+                    Ok(x) => self.stack.push((path.clone() + "/", x.entries.into_iter())),
+                    Err(e) => self.error = Some(e),
+                }
+            }
+            return Some(Ok((path, entry)));
+        }
+    }
+}
+
+/// Check that the manifest identified by `root` and every asset reachable from it are present in `store` and match
+/// their hashes and recorded sizes.
+///
+/// Reads every asset in its entirety.
+pub fn verify_tree<S: Store + ?Sized>(store: &S, root: &Hash) -> io::Result<()> {
+    verify_asset(store, root, None)?;
+    for entry in walk(store, root) {
+        let (_, entry) = entry?;
+        verify_asset(store, &entry.hash, Some(entry.size))?;
+    }
+    Ok(())
+}
+
+fn verify_asset<S: Store + ?Sized>(store: &S, hash: &Hash, size: Option<u64>) -> io::Result<()> {
+    let data = store.get(hash)?;
+    let mut hasher = Hasher::new();
+    hasher.process(&data);
+    if hasher.result() != *hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("data for {} does not match its hash", hash),
+        ));
+    }
+    if size.is_some_and(|x| x != data.len() as u64) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("size of {} does not match its manifest entry", hash),
+        ));
+    }
+    Ok(())
+}
+
+/// A publisher's signed statement that the release `name`, e.g. a version number, is described by the manifest
+/// identified by `root`.
+///
+/// Encoded as the magic `CHRELEA1`, the `u32` length and UTF-8 bytes of the name, the `u16` hash kind ID, `u16`
+/// length, and bytes of the root hash, and finally the 64-byte ed25519 signature of everything preceding it.
+#[cfg(feature = "ed25519-dalek")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SignedRelease {
+    name: String,
+    root: Hash,
+    signature: Signature,
+}
+
+#[cfg(feature = "ed25519-dalek")]
+impl SignedRelease {
+    /// Sign a statement that the release `name` is described by the manifest `root`.
+    pub fn sign(name: String, root: Hash, key: &SigningKey) -> Self {
+        let signature = key.sign(&release_message(&name, &root));
+        Self {
+            name,
+            root,
+            signature,
+        }
+    }
+
+    /// The name of the release.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The hash of the manifest describing the release.
+    pub fn root(&self) -> &Hash {
+        &self.root
+    }
+
+    /// Check that the release was signed by one of `keys`.
+    pub fn verify(&self, keys: &[VerifyingKey]) -> io::Result<()> {
+        let message = release_message(&self.name, &self.root);
+        if keys
+            .iter()
+            .any(|key| key.verify(&message, &self.signature).is_ok())
+        {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "release is not signed by a trusted key",
+            ))
+        }
+    }
+
+    /// Encode in the binary format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = release_message(&self.name, &self.root);
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    /// Decode from the binary format. The signature is not checked.
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        if data.len() < RELEASE_MAGIC.len() || &data[..RELEASE_MAGIC.len()] != RELEASE_MAGIC {
+            return Err(invalid_manifest());
+        }
+        let mut data = &data[RELEASE_MAGIC.len()..];
+        let name = read_str(&mut data)?;
+        let root = read_hash(&mut data)?;
+        let signature =
+            Signature::from_slice(take(&mut data, 64)?).map_err(|_| invalid_manifest())?;
+        if !data.is_empty() {
+            return Err(invalid_manifest());
+        }
+        Ok(Self {
+            name,
+            root,
+            signature,
+        })
+    }
+}
+
+/// The signed portion of an encoded `SignedRelease`
+#[cfg(feature = "ed25519-dalek")]
+fn release_message(name: &str, root: &Hash) -> Vec<u8> {
+    let mut out = RELEASE_MAGIC.to_vec();
+    write_str(&mut out, name);
+    write_hash(&mut out, root);
+    out
+}
+
+fn write_u32(out: &mut Vec<u8>, x: u32) {
+    let mut buf = [0; 4];
+    LittleEndian::write_u32(&mut buf, x);
+    out.extend_from_slice(&buf);
+}
+
+fn write_str(out: &mut Vec<u8>, x: &str) {
+    write_u32(out, x.len() as u32);
+    out.extend_from_slice(x.as_bytes());
+}
+
+fn write_hash(out: &mut Vec<u8>, hash: &Hash) {
+    let mut header = [0; 4];
+    LittleEndian::write_u16(&mut header[0..2], hash.kind().id());
+    LittleEndian::write_u16(&mut header[2..4], hash.bytes().len() as u16);
+    out.extend_from_slice(&header);
+    out.extend_from_slice(hash.bytes());
+}
+
+fn read_hash(data: &mut &[u8]) -> io::Result<Hash> {
+    let header = take(data, 4)?;
+    let kind = HashKind::from_id(LittleEndian::read_u16(&header[0..2])).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "unknown hash kind in manifest")
+    })?;
+    let len = LittleEndian::read_u16(&header[2..4]) as usize;
+    Hash::from_bytes(kind, take(data, len)?).map_err(|_| invalid_manifest())
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(invalid_manifest());
+    }
+    let (x, rest) = data.split_at(n);
+    *data = rest;
+    Ok(x)
+}
+
+fn read_u32(data: &mut &[u8]) -> io::Result<u32> {
+    Ok(LittleEndian::read_u32(take(data, 4)?))
+}
+
+fn read_str(data: &mut &[u8]) -> io::Result<String> {
+    let len = read_u32(data)? as usize;
+    str::from_utf8(take(data, len)?)
+        .map(|x| x.into())
+        .map_err(|_| invalid_manifest())
+}
+
+fn invalid_manifest() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed manifest")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MemoryStore;
+
+    #[test]
+    fn walk_tree() {
+        let store = MemoryStore::new();
+        let mut inner = Manifest::new();
+        inner.put(&store, "b".into(), b"bee").unwrap();
+        let mut root = Manifest::new();
+        root.put(&store, "a".into(), b"ay").unwrap();
+        root.put_tree(&store, "dir".into(), &inner).unwrap();
+        root.entries
+            .get_mut("a")
+            .unwrap()
+            .metadata
+            .insert("type".into(), "text/plain".into());
+        let hash = root.store(&store).unwrap();
+
+        assert_eq!(Manifest::load(&store, &hash).unwrap(), root);
+        assert!(Manifest::decode(&root.encode()[..20]).is_err());
+        let paths = walk(&store, &hash)
+            .map(|x| x.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["a", "dir", "dir/b"]);
+        verify_tree(&store, &hash).unwrap();
+    }
+
+    #[cfg(feature = "ed25519-dalek")]
+    #[test]
+    fn signed_release() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let hash = Hasher::new().result();
+        let release = SignedRelease::sign("1.2.3".into(), hash, &key);
+        let decoded = SignedRelease::decode(&release.encode()).unwrap();
+        assert_eq!(decoded, release);
+        decoded.verify(&[key.verifying_key()]).unwrap();
+        let other = SigningKey::from_bytes(&[2; 32]);
+        assert!(decoded.verify(&[other.verifying_key()]).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Tools for a repository held entirely in memory.
+
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use crate::{not_found, Asset, ContentMap, Hash, Hasher, Store, StoreWriter, WritableStore};
+
+/// A repository that keeps every asset on the heap.
+///
+/// Useful for tests, for tools that never touch the disk, and as a staging area for assets that will later be written
+/// elsewhere with `flush_to`.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    assets: Arc<RwLock<ContentMap<Arc<[u8]>>>>,
+}
+
+impl MemoryStore {
+    /// Create an empty repository.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Access the asset identified by `hash`.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let assets = self.assets.read().unwrap();
+        let data = assets.get(hash).ok_or_else(not_found)?;
+        Ok(data.clone().into())
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.assets.read().unwrap().contains_key(hash)
+    }
+
+    /// Create a `Writer` for streaming data into the repository.
+    pub fn make_writer(&self) -> Writer {
+        Writer {
+            assets: self.assets.clone(),
+            hasher: Hasher::new(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Write `data` directly into the repository.
+    pub fn put(&self, data: &[u8]) -> Hash {
+        let mut hasher = Hasher::new();
+        hasher.process(data);
+        let hash = hasher.result();
+        self.assets
+            .write()
+            .unwrap()
+            .entry(hash)
+            .or_insert_with(|| data.into());
+        hash
+    }
+
+    /// Enumerate assets stored in the repository.
+    pub fn list(&self) -> impl Iterator<Item = Hash> {
+        self.assets
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Number of assets stored.
+    pub fn len(&self) -> usize {
+        self.assets.read().unwrap().len()
+    }
+
+    /// Whether no assets are stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write every asset into `dest`, removing each from memory once it has been written.
+    pub fn flush_to<S: WritableStore + ?Sized>(&self, dest: &S) -> io::Result<()> {
+        for hash in self.list() {
+            let data = match self.assets.read().unwrap().get(&hash) {
+                Some(x) => x.clone(),
+                None => continue,
+            };
+            dest.put(&data)?;
+            self.assets.write().unwrap().remove(&hash);
+        }
+        Ok(())
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+}
+
+impl WritableStore for MemoryStore {
+    type Writer = Writer;
+
+    fn make_writer(&self) -> io::Result<Writer> {
+        Ok(self.make_writer())
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        Ok(self.put(data))
+    }
+}
+
+/// A staging area for streaming data into a `MemoryStore`.
+///
+/// `store` must be called to commit data to the repository. Otherwise, it will be discarded when the `Writer` is
+/// dropped.
+#[derive(Debug)]
+pub struct Writer {
+    assets: Arc<RwLock<ContentMap<Arc<[u8]>>>>,
+    hasher: Hasher,
+    data: Vec<u8>,
+}
+
+impl Writer {
+    /// Commits the written data to the repository. The `bool` is true iff the data was not already there.
+    pub fn store(self) -> (Hash, bool) {
+        let hash = self.hasher.result();
+        let mut assets = self.assets.write().unwrap();
+        if assets.contains_key(&hash) {
+            return (hash, false);
+        }
+        assets.insert(hash, self.data.into());
+        (hash, true)
+    }
+}
+
+impl StoreWriter for Writer {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        Ok(self.store())
+    }
+}
+
+impl io::Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        self.hasher.process(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn roundtrip() {
+        let store = MemoryStore::new();
+        let hash = store.put(b"hello");
+        assert!(store.contains(&hash));
+        assert_eq!(&store.get(&hash).unwrap()[..], b"hello");
+        assert_eq!(store.list().collect::<Vec<_>>(), vec![hash]);
+    }
+
+    #[test]
+    fn writer() {
+        let store = MemoryStore::new();
+        let hash = store.put(b"hello");
+        let mut writer = store.make_writer();
+        writer.write_all(b"hel").unwrap();
+        writer.write_all(b"lo").unwrap();
+        assert_eq!(writer.store(), (hash, false));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn flush() {
+        let staging = MemoryStore::new();
+        let dest = MemoryStore::new();
+        let hash = staging.put(b"hello");
+        staging.flush_to(&dest).unwrap();
+        assert!(staging.is_empty());
+        assert!(dest.contains(&hash));
+    }
+}
@@ -0,0 +1,576 @@
+//! Repair data for repositories that are the only copy of their contents.
+//!
+//! A parity file protects a group of member files, such as the volumes of an `ArchiveSet` or the assets of a
+//! `LooseFiles`. Members are divided into fixed-size blocks, and the blocks at the same offset in every member form a
+//! stripe. The parity file records the hash of every block and the XOR of every stripe, allowing `repair` to
+//! reconstruct any one damaged or missing block per stripe. Split large sets of files into several groups so that
+//! damage is unlikely to strike one stripe twice.
+//!
+//! A parity file consists of:
+//!
+//! - the magic `CHPARIT1`
+//! - little-endian `u32` block size and `u32` member count
+//! - for each member, a `u32` length and UTF-8 bytes of its path relative to the group's base directory, then its `u64`
+//!   length
+//! - for each member, the hash of each of its blocks, then the hash of each parity block, each a `u16` hash kind ID
+//!   followed by the hash bytes
+//! - the parity blocks, one per stripe, each a full block in size
+//!
+//! Members are padded with zeroes for the purpose of computing parity.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{hash_of, Hash, HashKind};
+
+const MAGIC: &[u8; 8] = b"CHPARIT1";
+
+/// Block size used by `generate`.
+pub const DEFAULT_BLOCK_SIZE: u32 = 64 * 1024;
+
+/// Number of members `generate_with` reads at once.
+pub const MAX_OPEN_MEMBERS: usize = 64;
+
+/// Number of damaged stripes `scan` and `repair` reconstruct at once.
+pub const REPAIR_WINDOW: usize = 64;
+
+/// Damage found by `scan` or `repair`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Report {
+    /// Members with at least one damaged or missing block
+    pub damaged: Vec<PathBuf>,
+    /// Number of damaged blocks, including parity blocks
+    pub damaged_blocks: u64,
+    /// Number of damaged blocks that were, or could be, reconstructed
+    pub repairable_blocks: u64,
+}
+
+impl Report {
+    /// Whether every damaged block was, or could be, reconstructed.
+    pub fn is_repairable(&self) -> bool {
+        self.damaged_blocks == self.repairable_blocks
+    }
+}
+
+/// Write a parity file to `parity` protecting the files at `members`, relative to `base`, using blocks of
+/// `DEFAULT_BLOCK_SIZE`.
+pub fn generate(base: &Path, members: &[PathBuf], parity: &Path) -> io::Result<()> {
+    generate_with(base, members, parity, DEFAULT_BLOCK_SIZE)
+}
+
+/// Like `generate`, with blocks `block_size` bytes long.
+///
+/// Smaller blocks make repairs more likely to succeed, at the cost of a larger table of hashes. At most
+/// `MAX_OPEN_MEMBERS` members are open at once. The parity file is written under a temporary hidden name and renamed
+/// into place, so an interrupted call never leaves a partial one behind.
+pub fn generate_with(
+    base: &Path,
+    members: &[PathBuf],
+    parity: &Path,
+    block_size: u32,
+) -> io::Result<()> {
+    assert!(block_size > 0, "block size must be nonzero");
+    let mut names = Vec::with_capacity(members.len());
+    let mut lens = Vec::with_capacity(members.len());
+    for path in members {
+        let name = path
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "non-UTF-8 member path"))?;
+        names.push(name);
+        lens.push(fs::metadata(base.join(path))?.len());
+    }
+    let layout = Layout {
+        block_size: u64::from(block_size),
+        lens,
+    };
+
+    // The header's size is known in advance, since every hash is of the same kind
+    let hash_len = 2 + hash_of(&[]).kind().len() as u64;
+    let blocks = (0..members.len()).map(|x| layout.blocks(x)).sum::<u64>();
+    let data_start = 16
+        + names.iter().map(|x| 12 + x.len() as u64).sum::<u64>()
+        + (blocks + layout.stripes()) * hash_len;
+
+    let temp = parity.with_file_name(format!(".{:016X}.tmp", rand::random::<u64>()));
+    let result = (|| {
+        let mut out = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        // Accumulate parity a group of members at a time, bounding the number of files open at once
+        let mut member_hashes = Vec::with_capacity(members.len());
+        let mut block = vec![0; block_size as usize];
+        let mut stripe = vec![0; block_size as usize];
+        for (group, paths) in members.chunks(MAX_OPEN_MEMBERS).enumerate() {
+            let first = group * MAX_OPEN_MEMBERS;
+            let mut files = paths
+                .iter()
+                .map(|x| File::open(base.join(x)).map(io::BufReader::new))
+                .collect::<io::Result<Vec<_>>>()?;
+            member_hashes.extend(paths.iter().map(|_| Vec::new()));
+            for i in 0..layout.stripes() {
+                let offset = data_start + i * layout.block_size;
+                if group == 0 {
+                    stripe.fill(0);
+                } else {
+                    out.seek(SeekFrom::Start(offset))?;
+                    out.read_exact(&mut stripe)?;
+                }
+                for (j, file) in files.iter_mut().enumerate() {
+                    let member = first + j;
+                    let n = layout.block_len(member, i);
+                    if n == 0 {
+                        continue;
+                    }
+                    file.read_exact(&mut block[..n])?;
+                    member_hashes[member].push(hash_of(&block[..n]));
+                    xor(&mut stripe, &block[..n]);
+                }
+                out.seek(SeekFrom::Start(offset))?;
+                out.write_all(&stripe)?;
+            }
+        }
+
+        let mut parity_hashes = Vec::new();
+        out.seek(SeekFrom::Start(data_start))?;
+        for _ in 0..layout.stripes() {
+            out.read_exact(&mut stripe)?;
+            parity_hashes.push(hash_of(&stripe));
+        }
+
+        let mut header = MAGIC.to_vec();
+        let mut buf = [0; 8];
+        LittleEndian::write_u32(&mut buf[0..4], block_size);
+        LittleEndian::write_u32(&mut buf[4..8], members.len() as u32);
+        header.extend_from_slice(&buf);
+        for (name, &len) in names.iter().zip(&layout.lens) {
+            LittleEndian::write_u32(&mut buf[0..4], name.len() as u32);
+            header.extend_from_slice(&buf[0..4]);
+            header.extend_from_slice(name.as_bytes());
+            LittleEndian::write_u64(&mut buf, len);
+            header.extend_from_slice(&buf);
+        }
+        for hash in member_hashes.iter().flatten().chain(&parity_hashes) {
+            write_hash(&mut header, hash);
+        }
+        debug_assert_eq!(header.len() as u64, data_start);
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(&header)?;
+        out.sync_data()?;
+        fs::rename(&temp, parity)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Check the members protected by `parity`, relative to `base`, for damage, without modifying anything.
+///
+/// Members are opened one at a time, and damaged stripes are reconstructed `REPAIR_WINDOW` at a time, so neither open
+/// files nor memory use grow with the number of members.
+pub fn scan(base: &Path, parity: &Path) -> io::Result<Report> {
+    process(base, parity, false)
+}
+
+/// Reconstruct damaged and missing blocks of the members protected by `parity`, relative to `base`, and of the parity
+/// file itself, where possible.
+///
+/// Resources are bounded as for `scan`.
+pub fn repair(base: &Path, parity: &Path) -> io::Result<Report> {
+    process(base, parity, true)
+}
+
+fn process(base: &Path, parity: &Path, fix: bool) -> io::Result<Report> {
+    let mut parity_file = fs::OpenOptions::new().read(true).write(fix).open(parity)?;
+    let table = Table::read(&mut io::BufReader::new(&parity_file))?;
+    let layout = &table.layout;
+    let block_size = layout.block_size as usize;
+
+    // Find the damaged member blocks of each stripe
+    let mut bad = vec![Vec::new(); layout.stripes() as usize];
+    let mut damaged_members = vec![false; table.names.len()];
+    let mut block = vec![0; block_size];
+    for (member, name) in table.names.iter().enumerate() {
+        let mut file = match File::open(base.join(name)) {
+            Ok(x) => Some(x),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        match file {
+            // Trailing garbage doesn't affect any block's hash
+            Some(ref file) if file.metadata()?.len() == layout.lens[member] => {}
+            _ => damaged_members[member] = true,
+        }
+        for i in 0..layout.blocks(member) {
+            let block = &mut block[..layout.block_len(member, i)];
+            let intact = match file {
+                Some(ref mut file) => read_block(file, i * layout.block_size, block)?,
+                None => false,
+            };
+            if !intact || hash_of(block) != table.member_hash(member, i) {
+                bad[i as usize].push(member);
+                damaged_members[member] = true;
+            }
+        }
+    }
+
+    // Identify the stripes with exactly one damaged block, and which block that is: a member's, or the parity block
+    let mut report = Report::default();
+    let mut repairs = Vec::new();
+    for (i, bad) in (0..layout.stripes()).zip(&bad) {
+        let parity_offset = table.data_start + i * layout.block_size;
+        let parity_intact = read_block(&mut parity_file, parity_offset, &mut block)?
+            && hash_of(&block) == table.parity_hashes[i as usize];
+        report.damaged_blocks += bad.len() as u64 + u64::from(!parity_intact);
+        match (bad.len(), parity_intact) {
+            (0, false) => repairs.push((i, None)),
+            (1, true) => repairs.push((i, Some(bad[0]))),
+            _ => {}
+        }
+    }
+    report.repairable_blocks = repairs.len() as u64;
+
+    // Reconstruct a window of stripes at a time, reading each member once per window
+    let mut stripes = vec![vec![0; block_size]; REPAIR_WINDOW.min(repairs.len())];
+    let mut target_file: Option<(usize, File)> = None;
+    for window in repairs.chunks(REPAIR_WINDOW) {
+        for (&(i, target), stripe) in window.iter().zip(&mut stripes) {
+            match target {
+                // The parity block alone is damaged; recompute it from the members
+                None => stripe.fill(0),
+                // A single member block is damaged; recompute it from the parity and the other members
+                Some(_) => {
+                    read_block(
+                        &mut parity_file,
+                        table.data_start + i * layout.block_size,
+                        stripe,
+                    )?;
+                }
+            }
+        }
+        for (member, name) in table.names.iter().enumerate() {
+            let mut file = None;
+            for (&(i, target), stripe) in window.iter().zip(&mut stripes) {
+                let n = layout.block_len(member, i);
+                if n == 0 || target == Some(member) {
+                    continue;
+                }
+                if file.is_none() {
+                    file = Some(File::open(base.join(name))?);
+                }
+                read_block(
+                    file.as_mut().unwrap(),
+                    i * layout.block_size,
+                    &mut block[..n],
+                )?;
+                xor(stripe, &block[..n]);
+            }
+        }
+        for (&(i, target), stripe) in window.iter().zip(&stripes) {
+            let target = match target {
+                Some(x) => x,
+                None => {
+                    if fix {
+                        parity_file
+                            .seek(SeekFrom::Start(table.data_start + i * layout.block_size))?;
+                        parity_file.write_all(stripe)?;
+                    }
+                    continue;
+                }
+            };
+            let n = layout.block_len(target, i);
+            if hash_of(&stripe[..n]) != table.member_hash(target, i) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "reconstructed block does not match its hash",
+                ));
+            }
+            if fix {
+                if target_file.as_ref().map(|x| x.0) != Some(target) {
+                    let file = fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(base.join(&table.names[target]))?;
+                    target_file = Some((target, file));
+                }
+                let file = &mut target_file.as_mut().unwrap().1;
+                file.seek(SeekFrom::Start(i * layout.block_size))?;
+                file.write_all(&stripe[..n])?;
+            }
+        }
+    }
+    drop(target_file);
+
+    if fix {
+        for (member, name) in table.names.iter().enumerate() {
+            if !damaged_members[member] {
+                continue;
+            }
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(base.join(name))?;
+            if file.metadata()?.len() != layout.lens[member] {
+                file.set_len(layout.lens[member])?;
+            }
+            file.sync_data()?;
+        }
+        parity_file.sync_data()?;
+    }
+    report.damaged = table
+        .names
+        .into_iter()
+        .zip(damaged_members)
+        .filter(|x| x.1)
+        .map(|x| PathBuf::from(x.0))
+        .collect();
+    Ok(report)
+}
+
+/// Read the block at `offset`, returning `false` if it's truncated.
+fn read_block(file: &mut File, offset: u64, block: &mut [u8]) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut read = 0;
+    while read < block.len() {
+        match file.read(&mut block[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    for x in &mut block[read..] {
+        *x = 0;
+    }
+    Ok(read == block.len())
+}
+
+struct Layout {
+    block_size: u64,
+    lens: Vec<u64>,
+}
+
+impl Layout {
+    fn blocks(&self, member: usize) -> u64 {
+        self.lens[member].div_ceil(self.block_size)
+    }
+
+    fn stripes(&self) -> u64 {
+        (0..self.lens.len())
+            .map(|x| self.blocks(x))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Length of the `stripe`th block of `member`, zero if absent.
+    fn block_len(&self, member: usize, stripe: u64) -> usize {
+        let start = stripe * self.block_size;
+        self.lens[member].saturating_sub(start).min(self.block_size) as usize
+    }
+}
+
+struct Table {
+    layout: Layout,
+    names: Vec<String>,
+    /// Index into `member_hashes` of each member's first block
+    starts: Vec<usize>,
+    member_hashes: Vec<Hash>,
+    parity_hashes: Vec<Hash>,
+    /// Offset of the first parity block
+    data_start: u64,
+}
+
+impl Table {
+    /// Read the table at the start of a parity file, leaving its parity blocks unread.
+    fn read<R: Read>(data: &mut R) -> io::Result<Self> {
+        let mut rest = Input {
+            inner: data,
+            pos: 0,
+        };
+        let rest = &mut rest;
+        if take(rest, 8)? != MAGIC {
+            return Err(malformed());
+        }
+        let block_size = u64::from(LittleEndian::read_u32(&take(rest, 4)?));
+        if block_size == 0 {
+            return Err(malformed());
+        }
+        let count = LittleEndian::read_u32(&take(rest, 4)?);
+        let mut names = Vec::new();
+        let mut lens = Vec::new();
+        for _ in 0..count {
+            let len = LittleEndian::read_u32(&take(rest, 4)?) as usize;
+            let name = String::from_utf8(take(rest, len)?).map_err(|_| malformed())?;
+            names.push(name);
+            lens.push(LittleEndian::read_u64(&take(rest, 8)?));
+        }
+        let layout = Layout { block_size, lens };
+        let mut starts = Vec::new();
+        let mut member_hashes = Vec::new();
+        for member in 0..names.len() {
+            starts.push(member_hashes.len());
+            for _ in 0..layout.blocks(member) {
+                member_hashes.push(read_hash(rest)?);
+            }
+        }
+        let parity_hashes = (0..layout.stripes())
+            .map(|_| read_hash(rest))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            layout,
+            names,
+            starts,
+            member_hashes,
+            parity_hashes,
+            data_start: rest.pos,
+        })
+    }
+
+    fn member_hash(&self, member: usize, stripe: u64) -> Hash {
+        self.member_hashes[self.starts[member] + stripe as usize]
+    }
+}
+
+fn xor(dest: &mut [u8], src: &[u8]) {
+    for (d, s) in dest.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+fn write_hash(out: &mut Vec<u8>, hash: &Hash) {
+    let mut kind = [0; 2];
+    LittleEndian::write_u16(&mut kind, hash.kind().id());
+    out.extend_from_slice(&kind);
+    out.extend_from_slice(hash.bytes());
+}
+
+fn read_hash<R: Read>(data: &mut Input<R>) -> io::Result<Hash> {
+    let kind = HashKind::from_id(LittleEndian::read_u16(&take(data, 2)?)).ok_or_else(malformed)?;
+    Hash::from_bytes(kind, &take(data, kind.len())?).map_err(|_| malformed())
+}
+
+/// A reader that tracks how much has been read from it.
+struct Input<R> {
+    inner: R,
+    pos: u64,
+}
+
+fn take<R: Read>(data: &mut Input<R>, n: usize) -> io::Result<Vec<u8>> {
+    // Read incrementally rather than allocating `n` bytes up front, since `n` may come from a corrupt file
+    let mut x = Vec::new();
+    (&mut data.inner).take(n as u64).read_to_end(&mut x)?;
+    if x.len() < n {
+        return Err(malformed());
+    }
+    data.pos += n as u64;
+    Ok(x)
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed parity file")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repair_damage() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-parity-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let a = (0..100u8).collect::<Vec<_>>();
+        let b = (0..37u8).map(|x| x * 3).collect::<Vec<_>>();
+        fs::write(dir.join("a"), &a).unwrap();
+        fs::write(dir.join("b"), &b).unwrap();
+        let members = [PathBuf::from("a"), PathBuf::from("b")];
+        let parity = dir.join("parity");
+        generate_with(&dir, &members, &parity, 16).unwrap();
+        assert_eq!(scan(&dir, &parity).unwrap(), Report::default());
+
+        let mut damaged = a.clone();
+        damaged[5] ^= 0xFF;
+        damaged[90] ^= 0x01;
+        fs::write(dir.join("a"), &damaged).unwrap();
+        fs::remove_file(dir.join("b")).unwrap();
+        // Stripe 0 has lost a block of each member, so can't be repaired
+        let report = scan(&dir, &parity).unwrap();
+        assert_eq!(report.damaged.len(), 2);
+        assert_eq!(report.damaged_blocks, 5);
+        assert!(!report.is_repairable());
+
+        fs::write(dir.join("b"), &b).unwrap();
+        let report = repair(&dir, &parity).unwrap();
+        assert_eq!(report.damaged, [PathBuf::from("a")]);
+        assert!(report.is_repairable());
+        assert_eq!(fs::read(dir.join("a")).unwrap(), a);
+        assert_eq!(scan(&dir, &parity).unwrap(), Report::default());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn many_stripes() {
+        let dir = std::env::temp_dir().join(format!(
+            "chasset-parity-stripes-{:016X}",
+            rand::random::<u64>()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let a = (0..(REPAIR_WINDOW as u32 * 2 + 3) * 16)
+            .map(|x| x as u8)
+            .collect::<Vec<_>>();
+        fs::write(dir.join("a"), &a).unwrap();
+        fs::write(dir.join("b"), b"short").unwrap();
+        let members = [PathBuf::from("a"), PathBuf::from("b")];
+        let parity = dir.join("parity");
+        generate_with(&dir, &members, &parity, 16).unwrap();
+
+        // Every stripe has lost a block, spanning several windows
+        fs::remove_file(dir.join("a")).unwrap();
+        let report = scan(&dir, &parity).unwrap();
+        assert_eq!(report.damaged, [PathBuf::from("a")]);
+        assert_eq!(report.damaged_blocks, REPAIR_WINDOW as u64 * 2 + 3);
+        assert!(report.is_repairable());
+        assert!(!dir.join("a").exists());
+        repair(&dir, &parity).unwrap();
+        assert_eq!(fs::read(dir.join("a")).unwrap(), a);
+        assert_eq!(scan(&dir, &parity).unwrap(), Report::default());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn many_members() {
+        let dir = std::env::temp_dir().join(format!(
+            "chasset-parity-many-{:016X}",
+            rand::random::<u64>()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let mut members = Vec::new();
+        for i in 0..MAX_OPEN_MEMBERS + 7 {
+            let name = PathBuf::from(i.to_string());
+            fs::write(dir.join(&name), vec![i as u8; i % 50]).unwrap();
+            members.push(name);
+        }
+        let parity = dir.join("parity");
+        generate_with(&dir, &members, &parity, 16).unwrap();
+        // Only the parity file and members remain
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), members.len() + 1);
+        assert_eq!(scan(&dir, &parity).unwrap(), Report::default());
+
+        // Damage a member of the second group
+        let target = dir.join(&members[MAX_OPEN_MEMBERS + 3]);
+        let original = fs::read(&target).unwrap();
+        fs::write(&target, b"garbage").unwrap();
+        let report = repair(&dir, &parity).unwrap();
+        assert_eq!(report.damaged, [members[MAX_OPEN_MEMBERS + 3].clone()]);
+        assert!(report.is_repairable());
+        assert_eq!(fs::read(&target).unwrap(), original);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+//! Persistent sets of assets to retain.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use data_encoding::BASE32_NOPAD;
+
+use crate::{Hash, HashKind};
+
+/// A persistent set of assets which garbage collection must retain, such as with `LooseFiles::collect_garbage`.
+///
+/// Pinning a manifest also retains everything reachable from it. Each pin is stored as an empty file named after the
+/// pinned hash, so pinning and unpinning are atomic and may safely be performed by multiple processes at once.
+pub struct Pins {
+    dir: PathBuf,
+}
+
+impl Pins {
+    /// Open the pins stored in `dir`, creating it if necessary.
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Add `hash` to the set. Has no effect if it's already present.
+    pub fn pin(&self, hash: &Hash) -> io::Result<()> {
+        let path = self.path_for(hash);
+        fs::create_dir_all(path.parent().unwrap())?;
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove `hash` from the set. Has no effect if it's not present.
+    pub fn unpin(&self, hash: &Hash) -> io::Result<()> {
+        match fs::remove_file(self.path_for(hash)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            x => x,
+        }
+    }
+
+    /// Determine whether `hash` is in the set.
+    pub fn is_pinned(&self, hash: &Hash) -> bool {
+        self.path_for(hash).exists()
+    }
+
+    /// Enumerate the set.
+    pub fn pins(&self) -> io::Result<Vec<Hash>> {
+        let mut result = Vec::new();
+        for kind_dir in fs::read_dir(&self.dir)? {
+            let kind_dir = kind_dir?;
+            let kind = match kind_dir
+                .file_name()
+                .to_str()
+                .and_then(|x| x.parse::<HashKind>().ok())
+            {
+                Some(x) => x,
+                None => continue,
+            };
+            for file in fs::read_dir(kind_dir.path())? {
+                let name = file?.file_name();
+                if let Some(hash) = name
+                    .to_str()
+                    .and_then(|x| BASE32_NOPAD.decode(x.as_bytes()).ok())
+                    .and_then(|x| Hash::from_bytes(kind, &x).ok())
+                {
+                    result.push(hash);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn path_for(&self, hash: &Hash) -> PathBuf {
+        self.dir
+            .join(hash.kind().name())
+            .join(BASE32_NOPAD.encode(hash.bytes()))
+    }
+}
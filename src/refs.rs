@@ -0,0 +1,216 @@
+//! Mutable names referring to assets.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand;
+
+use crate::Hash;
+
+/// How long to wait for another process to finish updating a reference
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A set of human-readable names, each referring to an asset by hash.
+///
+/// Content addressing alone can't express "the latest version" of anything, so applications typically keep a few
+/// well-known names pointing at, for example, a manifest describing the current asset set. Each reference is stored
+/// as a file in a single directory containing the human-readable hash, so references may be inspected and repaired by
+/// hand. Updates are atomic, even between multiple processes.
+///
+/// While a reference is updated, a hidden lock file is present alongside it. If a process is interrupted (such as by
+/// power loss) mid-update, the lock file may be left behind, causing further updates to that reference to time out
+/// until it's deleted.
+pub struct Refs {
+    dir: PathBuf,
+}
+
+impl Refs {
+    /// Open the references stored in `dir`, creating it if necessary.
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Get the asset referred to by `name`, if any.
+    pub fn get(&self, name: &str) -> io::Result<Option<Hash>> {
+        check_name(name)?;
+        read(&self.dir.join(name))
+    }
+
+    /// Point `name` at `hash`, regardless of where it pointed before.
+    pub fn set(&self, name: &str, hash: &Hash) -> io::Result<()> {
+        let _lock = Lock::acquire(&self.dir, name)?;
+        self.write(name, Some(hash))
+    }
+
+    /// Delete the reference `name`, if it exists.
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        let _lock = Lock::acquire(&self.dir, name)?;
+        self.write(name, None)
+    }
+
+    /// Point `name` at `new`, or delete it if `None`, only if it currently refers to `current`, or doesn't exist if
+    /// `None`. Returns whether the update was made.
+    ///
+    /// Allows multiple writers to safely update a reference derived from its previous value: read it with `get`,
+    /// compute the new value, then retry from the start if another writer got there first.
+    pub fn compare_and_swap(
+        &self,
+        name: &str,
+        current: Option<&Hash>,
+        new: Option<&Hash>,
+    ) -> io::Result<bool> {
+        let _lock = Lock::acquire(&self.dir, name)?;
+        if read(&self.dir.join(name))?.as_ref() != current {
+            return Ok(false);
+        }
+        self.write(name, new)?;
+        Ok(true)
+    }
+
+    /// Enumerate every reference and the asset it refers to.
+    ///
+    /// Unreadable or malformed references are skipped.
+    pub fn list(&self) -> impl Iterator<Item = (String, Hash)> {
+        let dir = self.dir.clone();
+        fs::read_dir(&self.dir)
+            .into_iter()
+            .flat_map(|x| x.into_iter())
+            .filter_map(move |entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                check_name(&name).ok()?;
+                let hash = read(&dir.join(&name)).ok()??;
+                Some((name, hash))
+            })
+    }
+
+    /// Replace the reference `name`, which must be locked.
+    fn write(&self, name: &str, hash: Option<&Hash>) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let hash = match hash {
+            Some(x) => x,
+            None => {
+                return match fs::remove_file(&path) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    x => x,
+                };
+            }
+        };
+        let temp = self
+            .dir
+            .join(format!(".{}.{:08X}", name, rand::random::<u64>()));
+        let result = (|| {
+            let mut file = fs::File::create(&temp)?;
+            writeln!(file, "{}", hash)?;
+            file.sync_data()?;
+            fs::rename(&temp, &path)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+}
+
+/// Exclusive permission to update a reference, held by the existence of a lock file.
+struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    fn acquire(dir: &Path, name: &str) -> io::Result<Self> {
+        check_name(name)?;
+        let path = dir.join(format!(".{}.lock", name));
+        let start = Instant::now();
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => {
+                    return Ok(Self { path });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if start.elapsed() > LOCK_TIMEOUT {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("timed out waiting for lock {}", path.display()),
+                        ));
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read(path: &Path) -> io::Result<Option<Hash>> {
+    let text = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    text.trim()
+        .parse()
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Ensure `name` can be used verbatim as a file name, and won't be confused with a lock or temporary file.
+pub(crate) fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c == ':' || c.is_control())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid reference name {:?}", name),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Hasher;
+
+    #[test]
+    fn compare_and_swap() {
+        let dir = std::env::temp_dir().join(format!("chasset-refs-{:016X}", rand::random::<u64>()));
+        let refs = Refs::open(dir.clone()).unwrap();
+        let a = Hasher::new().result();
+        let mut hasher = Hasher::new();
+        hasher.process(b"b");
+        let b = hasher.result();
+
+        assert_eq!(refs.get("latest").unwrap(), None);
+        assert!(refs.compare_and_swap("latest", None, Some(&a)).unwrap());
+        assert!(!refs.compare_and_swap("latest", None, Some(&b)).unwrap());
+        assert!(refs.compare_and_swap("latest", Some(&a), Some(&b)).unwrap());
+        assert_eq!(refs.get("latest").unwrap(), Some(b));
+        refs.set("other", &a).unwrap();
+        let mut listed = refs.list().collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(listed, vec![("latest".into(), b), ("other".into(), a)]);
+        refs.remove("other").unwrap();
+        assert_eq!(refs.get("other").unwrap(), None);
+        assert!(refs.get("../escape").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+//! Pruning of repositories that serve as caches.
+
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::loose_files::GcPlan;
+use crate::{ContentSet, LooseFiles};
+
+/// Limits on what a `LooseFiles` retains, applied by `LooseFiles::apply_retention`.
+///
+/// An asset was last used when it was most recently written or, where the filesystem records access times, read.
+/// Assets whose age exceeds `max_age` are deleted first, then the least recently used until no more than `max_bytes`
+/// remain.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Total size of assets to retain
+    pub max_bytes: Option<u64>,
+    /// Time since last use after which an asset is deleted
+    pub max_age: Option<Duration>,
+    /// Whether to retain assets that are pinned, referred to by a reference, or reachable from such an asset regardless
+    /// of limits, as `LooseFiles::collect_garbage` would. Retained assets still count towards `max_bytes`.
+    pub keep_pinned: bool,
+}
+
+impl RetentionPolicy {
+    /// Determine what applying this policy to `store` would delete, without deleting anything.
+    pub fn plan(&self, store: &LooseFiles) -> io::Result<GcPlan> {
+        let (roots, protected) = if self.keep_pinned {
+            store.trace(&[])?
+        } else {
+            (Vec::new(), ContentSet::default())
+        };
+        let now = SystemTime::now();
+        let mut plan = GcPlan {
+            roots,
+            ..GcPlan::default()
+        };
+        let mut total = 0;
+        let mut candidates = Vec::new();
+        for hash in store.list() {
+            let metadata = match fs::metadata(store.path(&hash)) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let written = metadata.modified()?;
+            let used = metadata.accessed().map_or(written, |x| x.max(written));
+            let age = now.duration_since(used).unwrap_or_default();
+            if protected.contains(&hash) {
+                plan.live += 1;
+                total += metadata.len();
+            } else if self.max_age.is_some_and(|x| age > x) {
+                plan.garbage.push(hash);
+                plan.bytes += metadata.len();
+            } else {
+                total += metadata.len();
+                candidates.push((used, hash, metadata.len()));
+            }
+        }
+        // Least recently used first
+        candidates.sort_unstable();
+        let mut candidates = candidates.into_iter();
+        if let Some(max) = self.max_bytes {
+            while total > max {
+                let (_, hash, len) = match candidates.next() {
+                    Some(x) => x,
+                    None => break,
+                };
+                plan.garbage.push(hash);
+                plan.bytes += len;
+                total -= len;
+            }
+        }
+        plan.live += candidates.len();
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn size_limit() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-retention-{:016X}", rand::random::<u64>()));
+        let store = LooseFiles::open(dir.clone()).unwrap();
+        let pinned = store.put(b"pinned").unwrap();
+        store.pins().unwrap().pin(&pinned).unwrap();
+        let old = store.put(b"old").unwrap();
+        let new = store.put(b"new").unwrap();
+        // Order uses explicitly, since both puts may fall within the same filesystem timestamp
+        let now = SystemTime::now();
+        for &(hash, age) in &[(old, 120), (new, 60)] {
+            let time = now - Duration::from_secs(age);
+            fs::File::open(store.path(&hash))
+                .unwrap()
+                .set_times(fs::FileTimes::new().set_accessed(time).set_modified(time))
+                .unwrap();
+        }
+
+        let policy = RetentionPolicy {
+            max_bytes: None,
+            max_age: Some(Duration::from_secs(90)),
+            keep_pinned: true,
+        };
+        assert_eq!(policy.plan(&store).unwrap().garbage, [old]);
+
+        let policy = RetentionPolicy {
+            max_bytes: Some(9),
+            max_age: None,
+            keep_pinned: true,
+        };
+        let plan = policy.plan(&store).unwrap();
+        assert_eq!(plan.garbage, [old]);
+        assert_eq!(plan.live, 2);
+        assert_eq!(store.apply_retention(&policy).unwrap(), 1);
+        assert!(store.contains(&pinned) && store.contains(&new) && !store.contains(&old));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
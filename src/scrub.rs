@@ -0,0 +1,297 @@
+//! Verification of a `LooseFiles`, either incrementally, so that bit rot in a large repository is found without long
+//! pauses, or exhaustively.
+
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Hash, HashKind, Hasher, LooseFiles};
+
+/// The outcome of a `LooseFiles::scrub`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ScrubReport {
+    /// Number of assets verified
+    pub checked: usize,
+    /// Total size of verified assets in bytes
+    pub bytes: u64,
+    /// Assets whose contents didn't match their hash, now moved into quarantine
+    pub quarantined: Vec<Hash>,
+    /// Whether this run reached the end of the repository, so that the next starts over from the beginning
+    pub pass_complete: bool,
+}
+
+/// Verify up to `limit` assets of `store` following the asset recorded in the file at `cursor`, moving corrupt files
+/// into the directory `quarantine`.
+pub(crate) fn run(
+    store: &LooseFiles,
+    cursor: &Path,
+    quarantine: &Path,
+    limit: usize,
+) -> io::Result<ScrubReport> {
+    let start =
+        match fs::read_to_string(cursor) {
+            Ok(x) => Some(x.trim().parse::<Hash>().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "malformed scrub cursor")
+            })?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+    // The `limit` least hashes after the cursor, found without holding the whole listing in memory
+    let mut next = BinaryHeap::with_capacity(limit + 1);
+    let mut remaining = 0usize;
+    for hash in store.list() {
+        if start.is_some_and(|x| hash <= x) {
+            continue;
+        }
+        remaining += 1;
+        next.push(hash);
+        if next.len() > limit {
+            next.pop();
+        }
+    }
+    let next = next.into_sorted_vec();
+
+    let mut report = ScrubReport {
+        pass_complete: remaining <= limit,
+        ..ScrubReport::default()
+    };
+    for hash in &next {
+        let intact = match verify(store, hash, &mut report.checked, &mut report.bytes)? {
+            Some(x) => x,
+            None => continue,
+        };
+        if !intact && store.quarantine(hash, quarantine)? {
+            report.quarantined.push(*hash);
+        }
+    }
+
+    match next.last() {
+        Some(last) if !report.pass_complete => {
+            let tmp = cursor.with_extension("tmp");
+            let mut file = File::create(&tmp)?;
+            writeln!(file, "{}", last)?;
+            file.sync_data()?;
+            fs::rename(&tmp, cursor)?;
+        }
+        _ => match fs::remove_file(cursor) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            x => x?,
+        },
+    }
+    Ok(report)
+}
+
+/// The outcome of a `LooseFiles::fsck`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FsckReport {
+    /// Number of assets verified
+    pub checked: usize,
+    /// Total size of verified assets in bytes
+    pub bytes: u64,
+    /// Assets whose contents didn't match their hash
+    pub corrupt: Vec<Hash>,
+    /// Whether `corrupt` assets were moved into quarantine
+    pub quarantined: bool,
+    /// Files and directories among the assets that don't belong there, left in place
+    pub stray: Vec<PathBuf>,
+}
+
+impl FsckReport {
+    /// Whether no problems were found.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.stray.is_empty()
+    }
+}
+
+/// Verify every asset of `store`, and find stray files among them. Corrupt files are moved into the directory
+/// `quarantine` if supplied.
+pub(crate) fn fsck(
+    store: &LooseFiles,
+    prefix: &Path,
+    quarantine: Option<&Path>,
+) -> io::Result<FsckReport> {
+    let mut report = FsckReport {
+        quarantined: quarantine.is_some(),
+        ..FsckReport::default()
+    };
+    let mut hashes = store.list().collect::<Vec<_>>();
+    hashes.sort_unstable();
+    for hash in &hashes {
+        let intact = match verify(store, hash, &mut report.checked, &mut report.bytes)? {
+            Some(x) => x,
+            None => continue,
+        };
+        if !intact && quarantine.map_or(Ok(true), |x| store.quarantine(hash, x))? {
+            report.corrupt.push(*hash);
+        }
+    }
+
+    // Anything within a hash kind's directory that `list` wouldn't yield
+    for kind_dir in fs::read_dir(prefix)? {
+        let kind_dir = kind_dir?;
+        let kind = match kind_dir.file_name().to_str().map(str::parse::<HashKind>) {
+            Some(Ok(x)) => x,
+            _ => continue,
+        };
+        for leaf_dir in fs::read_dir(kind_dir.path())? {
+            let leaf_dir = leaf_dir?;
+            let start = match leaf_dir.file_name().into_string() {
+                Ok(ref x) if leaf_dir.file_type()?.is_dir() && x.len() == 2 => x.clone(),
+                _ => {
+                    report.stray.push(leaf_dir.path());
+                    continue;
+                }
+            };
+            for file in fs::read_dir(leaf_dir.path())? {
+                let file = file?;
+                let valid = file.file_type()?.is_file()
+                    && file
+                        .file_name()
+                        .to_str()
+                        .is_some_and(|x| Hash::parse(kind, &(start.clone() + x)).is_ok());
+                if !valid {
+                    report.stray.push(file.path());
+                }
+            }
+        }
+    }
+    report.stray.sort_unstable();
+    Ok(report)
+}
+
+/// Check that the file holding `hash` matches it, tallying its size. Returns `None` if it no longer exists.
+fn verify(
+    store: &LooseFiles,
+    hash: &Hash,
+    checked: &mut usize,
+    bytes: &mut u64,
+) -> io::Result<Option<bool>> {
+    let mut file = match File::open(store.path(hash)) {
+        Ok(x) => x,
+        // Removed concurrently
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut hasher = Hasher::new();
+    *bytes += io::copy(&mut file, &mut hasher)?;
+    *checked += 1;
+    Ok(Some(hasher.result() == *hash))
+}
+
+/// A background thread that scrubs a `LooseFiles` at regular intervals until dropped.
+pub struct Scrubber {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    reports: mpsc::Receiver<io::Result<ScrubReport>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scrubber {
+    /// Verify up to `limit` assets of `store` every `interval`, beginning immediately.
+    pub fn spawn(store: Arc<LooseFiles>, limit: usize, interval: Duration) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let (send, reports) = mpsc::channel();
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || loop {
+                if send.send(store.scrub(limit)).is_err() {
+                    return;
+                }
+                let (ref stopped, ref cond) = *stop;
+                let guard = cond
+                    .wait_timeout_while(stopped.lock().unwrap(), interval, |x| !*x)
+                    .unwrap()
+                    .0;
+                if *guard {
+                    return;
+                }
+            })
+        };
+        Self {
+            stop,
+            reports,
+            thread: Some(thread),
+        }
+    }
+
+    /// Take the outcomes of runs completed since the last call, oldest first.
+    pub fn reports(&self) -> Vec<io::Result<ScrubReport>> {
+        self.reports.try_iter().collect()
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        let (ref stopped, ref cond) = *self.stop;
+        *stopped.lock().unwrap() = true;
+        cond.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn incremental() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-scrub-{:016X}", rand::random::<u64>()));
+        let store = LooseFiles::open(dir.clone()).unwrap();
+        let hashes = (0..5u8)
+            .map(|i| store.put(&[i]).unwrap())
+            .collect::<Vec<_>>();
+        let corrupt = hashes[3];
+        fs::write(store.path(&corrupt), b"rot").unwrap();
+
+        let first = store.scrub(3).unwrap();
+        assert_eq!(first.checked, 3);
+        assert!(!first.pass_complete);
+        let second = store.scrub(3).unwrap();
+        assert_eq!(second.checked, 2);
+        assert!(second.pass_complete);
+        let quarantined = [first.quarantined, second.quarantined].concat();
+        assert_eq!(quarantined, [corrupt]);
+        assert!(!store.contains(&corrupt));
+        assert!(dir.join("quarantine").join(corrupt.to_string()).exists());
+        assert_eq!(store.scrub(10).unwrap().checked, 4);
+
+        assert!(store.fsck(false).unwrap().is_clean());
+        fs::write(store.path(&hashes[0]), b"rot").unwrap();
+        let stray = store.path(&hashes[1]).with_file_name("stray");
+        fs::write(&stray, b"").unwrap();
+        let report = store.fsck(false).unwrap();
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.corrupt, [hashes[0]]);
+        assert_eq!(report.stray, [stray]);
+        assert!(store.contains(&hashes[0]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cached_quarantine() {
+        let dir = std::env::temp_dir().join(format!(
+            "chasset-scrub-cached-{:016X}",
+            rand::random::<u64>()
+        ));
+        let mut store = LooseFiles::open(dir.clone()).unwrap();
+        store.set_contains_cache(16);
+        let hash = store.put(b"data").unwrap();
+        assert!(store.contains(&hash));
+        fs::write(store.path(&hash), b"rot").unwrap();
+        assert_eq!(store.scrub(10).unwrap().quarantined, [hash]);
+        assert!(!store.contains(&hash));
+        // Restoring the asset must actually write it, rather than trusting a stale cache entry
+        assert!(store.put_unchecked(hash, &b"data"[..], 0.0).unwrap());
+        assert!(store.contains(&hash));
+        assert_eq!(&*store.get(&hash).unwrap(), b"data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
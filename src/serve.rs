@@ -0,0 +1,298 @@
+//! Tools for serving a repository over HTTP.
+
+use std::io::{self, Cursor, Read};
+
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+use crate::{Hash, HashKind, Store};
+
+/// An HTTP server exposing the assets of a `Store`.
+///
+/// Uses the same layout that `HttpStore` fetches from: the asset identified by a hash is served at `/{kind}/{base32}`,
+/// where `kind` is the name of the hash kind and `base32` is the unpadded base32 encoding of the hash. `GET` returns
+/// the asset, supporting single-range requests, and `HEAD` returns the same headers, including the asset's length,
+/// without a body. Since assets never change, responses carry the hash as their `ETag` and permit indefinite caching.
+///
+/// If enabled with `listing`, `GET /` returns the human-readable hash of every asset, one per line.
+pub struct Server<S> {
+    server: tiny_http::Server,
+    store: S,
+    listing: bool,
+}
+
+impl<S: Store> Server<S> {
+    /// Listen on `addr`, e.g. "0.0.0.0:8080", for requests for assets in `store`.
+    pub fn bind(addr: &str, store: S) -> io::Result<Self> {
+        let server = tiny_http::Server::http(addr).map_err(io::Error::other)?;
+        Ok(Self {
+            server,
+            store,
+            listing: false,
+        })
+    }
+
+    /// Whether `GET /` enumerates every asset. Defaults to `false`.
+    pub fn listing(&mut self, enabled: bool) -> &mut Self {
+        self.listing = enabled;
+        self
+    }
+
+    /// Serve requests one at a time, forever.
+    ///
+    /// For concurrency, call from multiple threads.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let request = self.server.recv()?;
+            // Failure to respond generally means the client went away, which shouldn't stop the server
+            let _ = self.handle(request);
+        }
+    }
+
+    /// Respond to a single `request`.
+    pub fn handle(&self, request: Request) -> io::Result<()> {
+        let head = match *request.method() {
+            Method::Get => false,
+            Method::Head => true,
+            _ => return request.respond(Response::empty(405)),
+        };
+        if request.url() == "/" {
+            if !self.listing || head {
+                return request.respond(Response::empty(if head { 200 } else { 404 }));
+            }
+            let mut body = String::new();
+            for hash in self.store.list() {
+                body.push_str(&hash.to_string());
+                body.push('\n');
+            }
+            return request.respond(
+                Response::from_string(body).with_header(header("Content-Type", "text/plain")),
+            );
+        }
+        let hash = match parse_path(request.url()) {
+            Some(x) => x,
+            None => return request.respond(Response::empty(404)),
+        };
+        let asset = match self.store.get(&hash) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return request.respond(Response::empty(404));
+            }
+            Err(_) => return request.respond(Response::empty(500)),
+        };
+        let etag = format!("\"{}\"", hash);
+        let mut headers = vec![
+            header("ETag", &etag),
+            header("Cache-Control", "public, max-age=31536000, immutable"),
+            header("Accept-Ranges", "bytes"),
+            header("Content-Type", "application/octet-stream"),
+        ];
+        if find_header(&request, "If-None-Match").is_some_and(|x| x.trim() == etag) {
+            return request.respond(Response::new(
+                StatusCode(304),
+                headers,
+                io::empty(),
+                Some(0),
+                None,
+            ));
+        }
+        let len = asset.len() as u64;
+        if head {
+            return request.respond(Response::new(
+                StatusCode(200),
+                headers,
+                io::empty(),
+                Some(len as usize),
+                None,
+            ));
+        }
+        let range = find_header(&request, "Range").map(|x| parse_range(x, len));
+        let (status, start, end) = match range {
+            None | Some(Range::Ignored) => (200, 0, len),
+            Some(Range::Satisfiable(start, end)) => {
+                headers.push(header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", start, end - 1, len),
+                ));
+                (206, start, end)
+            }
+            Some(Range::Unsatisfiable) => {
+                headers.push(header("Content-Range", &format!("bytes */{}", len)));
+                return request.respond(Response::new(
+                    StatusCode(416),
+                    headers,
+                    io::empty(),
+                    Some(0),
+                    None,
+                ));
+            }
+        };
+        let mut body = Cursor::new(asset);
+        body.set_position(start);
+        request.respond(Response::new(
+            StatusCode(status),
+            headers,
+            body.take(end - start),
+            Some((end - start) as usize),
+            None,
+        ))
+    }
+}
+
+/// Extract the hash from a request path of the form `/{kind}/{base32}`.
+fn parse_path(path: &str) -> Option<Hash> {
+    let mut parts = path.trim_start_matches('/').splitn(2, '/');
+    let kind = parts.next()?.parse::<HashKind>().ok()?;
+    Hash::parse(kind, parts.next()?).ok()
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Range {
+    /// Serve the entire asset
+    Ignored,
+    /// Serve from the first offset up to but excluding the second
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+/// Interpret the value of a `Range` header for an asset of `len` bytes.
+///
+/// Multiple ranges are rarely used and may legally be ignored, so they are.
+fn parse_range(value: &str, len: u64) -> Range {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(x) if !x.contains(',') => x.trim(),
+        _ => return Range::Ignored,
+    };
+    let dash = match spec.find('-') {
+        Some(x) => x,
+        None => return Range::Ignored,
+    };
+    let (first, last) = (&spec[..dash], &spec[dash + 1..]);
+    let (start, end) = if first.is_empty() {
+        // Suffix of `last` bytes
+        let n = match last.parse::<u64>() {
+            Ok(x) => x,
+            Err(_) => return Range::Ignored,
+        };
+        (len.saturating_sub(n), len)
+    } else {
+        let start = match first.parse::<u64>() {
+            Ok(x) => x,
+            Err(_) => return Range::Ignored,
+        };
+        let end = if last.is_empty() {
+            len
+        } else {
+            match last.parse::<u64>() {
+                Ok(x) if x >= start => x.saturating_add(1).min(len),
+                _ => return Range::Ignored,
+            }
+        };
+        (start, end)
+    };
+    if start >= end {
+        return Range::Unsatisfiable;
+    }
+    Range::Satisfiable(start, end)
+}
+
+fn find_header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|x| x.field.equiv(name))
+        .map(|x| x.value.as_str())
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn range() {
+        assert_eq!(parse_range("bytes=0-3", 10), Range::Satisfiable(0, 4));
+        assert_eq!(parse_range("bytes=5-", 10), Range::Satisfiable(5, 10));
+        assert_eq!(parse_range("bytes=-3", 10), Range::Satisfiable(7, 10));
+        assert_eq!(parse_range("bytes=8-20", 10), Range::Satisfiable(8, 10));
+        assert_eq!(
+            parse_range("bytes=0-18446744073709551615", 10),
+            Range::Satisfiable(0, 10)
+        );
+        assert_eq!(parse_range("bytes=10-", 10), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,3-4", 10), Range::Ignored);
+        assert_eq!(parse_range("items=0-1", 10), Range::Ignored);
+    }
+
+    /// Send a request with `method` for `path` and any extra header lines, returning the status line, headers, and
+    /// body of the response.
+    fn request(addr: &str, method: &str, path: &str, extra: &str) -> (String, String, Vec<u8>) {
+        use std::io::Write;
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+            method, path, extra
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|x| x == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        let mut lines = head.splitn(2, "\r\n");
+        let status = lines.next().unwrap().to_string();
+        let headers = lines.next().unwrap_or("").to_ascii_lowercase();
+        (status, headers, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn serve() {
+        let store = crate::MemoryStore::new();
+        let hash = store.put(b"hello, world");
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+        let server = Server::bind(&addr, store).unwrap();
+        let requests = 5;
+        let thread = std::thread::spawn(move || {
+            for _ in 0..requests {
+                server.handle(server.server.recv().unwrap()).unwrap();
+            }
+        });
+        let path = format!(
+            "/{}/{}",
+            hash.kind().name(),
+            data_encoding::BASE32_NOPAD.encode(hash.bytes())
+        );
+        let etag = format!("etag: \"{}\"", hash).to_ascii_lowercase();
+
+        let (status, headers, body) = request(&addr, "GET", &path, "");
+        assert!(status.contains(" 200 "), "{}", status);
+        assert!(headers.contains(&etag));
+        assert!(headers.contains("content-length: 12"));
+        assert_eq!(body, b"hello, world");
+
+        let (status, headers, body) = request(&addr, "HEAD", &path, "");
+        assert!(status.contains(" 200 "), "{}", status);
+        assert!(headers.contains(&etag));
+        assert!(headers.contains("content-length: 12"));
+        assert!(body.is_empty());
+
+        let (status, headers, body) = request(&addr, "GET", &path, "Range: bytes=7-\r\n");
+        assert!(status.contains(" 206 "), "{}", status);
+        assert!(headers.contains("content-range: bytes 7-11/12"));
+        assert_eq!(body, b"world");
+
+        let (status, _, _) = request(&addr, "HEAD", "/blake2b/AAAA", "");
+        assert!(status.contains(" 404 "), "{}", status);
+        let missing = format!("/blake2b/{}", data_encoding::BASE32_NOPAD.encode(&[0; 25]));
+        let (status, _, _) = request(&addr, "GET", &missing, "");
+        assert!(status.contains(" 404 "), "{}", status);
+        thread.join().unwrap();
+    }
+}
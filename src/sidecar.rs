@@ -0,0 +1,272 @@
+//! Small records associated with assets, stored beside them.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(feature = "bincode")]
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{ContentMap, Hash, HashKind};
+
+/// Record length denoting a removal
+const TOMBSTONE: u32 = u32::MAX;
+
+/// A table of small records, such as content types, source paths, or import times, each associated with a hash.
+///
+/// Records are appended to a log file, which is scanned into an in-memory index of record locations when opened, so
+/// writes are cheap and lookups take a single read. Superseded and removed records occupy space until `compact` is
+/// called. With the `bincode` feature, any serde-serializable type may be stored with `put` and `get`.
+///
+/// Each log entry is a little-endian `u16` hash kind ID, a `u8` hash length, the hash bytes, a `u32` record length,
+/// and the record itself. A length of `u32::MAX` denotes removal, with no record following.
+pub struct MetadataLog {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    /// Offset and length of the current record for each hash
+    index: ContentMap<(u64, u32)>,
+}
+
+impl MetadataLog {
+    /// Open the log at `path`, creating it if necessary.
+    ///
+    /// A trailing partial entry, as left by an interrupted write, is discarded.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut index = ContentMap::default();
+        let mut offset = 0;
+        while let Some((hash, len, next)) = parse_entry(&data, offset) {
+            if len == TOMBSTONE {
+                index.remove(&hash);
+            } else {
+                index.insert(hash, ((next - len as usize) as u64, len));
+            }
+            offset = next;
+        }
+        if offset != data.len() {
+            file.set_len(offset as u64)?;
+        }
+        Ok(Self {
+            path,
+            state: Mutex::new(State { file, index }),
+        })
+    }
+
+    /// Associate `record` with `hash`, replacing any existing record.
+    pub fn put_raw(&self, hash: &Hash, record: &[u8]) -> io::Result<()> {
+        if record.len() >= TOMBSTONE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "metadata record too large",
+            ));
+        }
+        let mut state = self.state.lock().unwrap();
+        let end = state.file.seek(SeekFrom::End(0))?;
+        let mut entry = encode_header(hash, record.len() as u32);
+        let offset = end + entry.len() as u64;
+        entry.extend_from_slice(record);
+        state.file.write_all(&entry)?;
+        state.index.insert(*hash, (offset, record.len() as u32));
+        Ok(())
+    }
+
+    /// Get the record associated with `hash`, if any.
+    pub fn get_raw(&self, hash: &Hash) -> io::Result<Option<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        let (offset, len) = match state.index.get(hash) {
+            Some(&x) => x,
+            None => return Ok(None),
+        };
+        let mut record = vec![0; len as usize];
+        state.file.seek(SeekFrom::Start(offset))?;
+        state.file.read_exact(&mut record)?;
+        Ok(Some(record))
+    }
+
+    /// Remove the record associated with `hash`, if any.
+    pub fn remove(&self, hash: &Hash) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.index.remove(hash).is_none() {
+            return Ok(());
+        }
+        state.file.write_all(&encode_header(hash, TOMBSTONE))
+    }
+
+    /// Determine whether a record is associated with `hash`.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.state.lock().unwrap().index.contains_key(hash)
+    }
+
+    /// Enumerate the hashes that have records.
+    pub fn hashes(&self) -> Vec<Hash> {
+        self.state.lock().unwrap().index.keys().cloned().collect()
+    }
+
+    /// Find every hash whose record satisfies `predicate`.
+    pub fn query_raw<F>(&self, mut predicate: F) -> io::Result<Vec<Hash>>
+    where
+        F: FnMut(&Hash, &[u8]) -> bool,
+    {
+        let mut result = Vec::new();
+        for hash in self.hashes() {
+            if let Some(record) = self.get_raw(&hash)? {
+                if predicate(&hash, &record) {
+                    result.push(hash);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Rewrite the log to contain only current records, reclaiming space.
+    pub fn compact(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let temp = temp_path(&self.path);
+        let mut out = File::create(&temp)?;
+        let mut index = ContentMap::default();
+        let mut offset = 0;
+        let entries = state
+            .index
+            .iter()
+            .map(|(&hash, &x)| (hash, x))
+            .collect::<Vec<_>>();
+        for (hash, (start, len)) in entries {
+            let mut entry = encode_header(&hash, len);
+            let header_len = entry.len();
+            entry.resize(header_len + len as usize, 0);
+            state.file.seek(SeekFrom::Start(start))?;
+            state.file.read_exact(&mut entry[header_len..])?;
+            out.write_all(&entry)?;
+            index.insert(hash, (offset + header_len as u64, len));
+            offset += entry.len() as u64;
+        }
+        out.sync_data()?;
+        drop(out);
+        fs::rename(&temp, &self.path)?;
+        state.file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        state.index = index;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl MetadataLog {
+    /// Associate `record` with `hash`, replacing any existing record.
+    pub fn put<T: Serialize>(&self, hash: &Hash, record: &T) -> io::Result<()> {
+        let data = bincode::serialize(record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.put_raw(hash, &data)
+    }
+
+    /// Get the record associated with `hash`, if any.
+    pub fn get<T: DeserializeOwned>(&self, hash: &Hash) -> io::Result<Option<T>> {
+        match self.get_raw(hash)? {
+            None => Ok(None),
+            Some(data) => bincode::deserialize(&data)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    /// Find every hash whose record satisfies `predicate`. Records that fail to deserialize are skipped.
+    pub fn query<T, F>(&self, mut predicate: F) -> io::Result<Vec<Hash>>
+    where
+        T: DeserializeOwned,
+        F: FnMut(&Hash, &T) -> bool,
+    {
+        self.query_raw(|hash, data| {
+            bincode::deserialize::<T>(data).is_ok_and(|x| predicate(hash, &x))
+        })
+    }
+}
+
+fn encode_header(hash: &Hash, len: u32) -> Vec<u8> {
+    let id = hash.kind().id();
+    let mut out = vec![id as u8, (id >> 8) as u8, hash.bytes().len() as u8];
+    out.extend_from_slice(hash.bytes());
+    let mut buf = [0; 4];
+    LittleEndian::write_u32(&mut buf, len);
+    out.extend_from_slice(&buf);
+    out
+}
+
+/// Parse the entry at `offset`, returning its hash, record length, and the offset of the following entry.
+///
+/// Entries of unknown hash kinds can't be represented and are skipped by returning the next entry instead.
+fn parse_entry(data: &[u8], mut offset: usize) -> Option<(Hash, u32, usize)> {
+    loop {
+        let header = data.get(offset..offset + 3)?;
+        let hash_len = header[2] as usize;
+        let hash_end = offset + 3 + hash_len;
+        let len = LittleEndian::read_u32(data.get(hash_end..hash_end + 4)?);
+        let next = if len == TOMBSTONE {
+            hash_end + 4
+        } else {
+            hash_end + 4 + len as usize
+        };
+        if next > data.len() {
+            return None;
+        }
+        let hash = HashKind::from_id(LittleEndian::read_u16(&header[0..2]))
+            .and_then(|kind| Hash::from_bytes(kind, &data[offset + 3..hash_end]).ok());
+        match hash {
+            Some(hash) => return Some((hash, len, next)),
+            None => offset = next,
+        }
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Hasher;
+
+    #[test]
+    fn reopen() {
+        let path =
+            std::env::temp_dir().join(format!("chasset-metadata-{:016X}", rand::random::<u64>()));
+        let a = Hasher::new().result();
+        let mut hasher = Hasher::new();
+        hasher.process(b"b");
+        let b = hasher.result();
+        {
+            let log = MetadataLog::open(path.clone()).unwrap();
+            log.put_raw(&a, b"old").unwrap();
+            log.put_raw(&a, b"text/plain").unwrap();
+            log.put_raw(&b, b"image/png").unwrap();
+            log.remove(&b).unwrap();
+        }
+        let log = MetadataLog::open(path.clone()).unwrap();
+        assert_eq!(log.get_raw(&a).unwrap().unwrap(), b"text/plain");
+        assert!(!log.contains(&b));
+        log.compact().unwrap();
+        assert_eq!(log.get_raw(&a).unwrap().unwrap(), b"text/plain");
+        assert_eq!(
+            log.query_raw(|_, x| x.starts_with(b"text/")).unwrap(),
+            vec![a]
+        );
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3 + 25 + 4 + 10);
+        fs::remove_file(&path).unwrap();
+    }
+}
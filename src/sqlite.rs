@@ -0,0 +1,217 @@
+//! Tools for a repository stored in a single SQLite database.
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{hash_of, not_found, Asset, Buffer, Hash, HashKind, Store, StoreWriter, WritableStore};
+
+/// A repository that stores every asset in one SQLite database file.
+///
+/// Useful on platforms where large numbers of small files perform poorly. Every write is transactional, so the
+/// database is never left with a partially written asset. Assets are read into memory in their entirety.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Open a repository stored in the database at `path`, creating it if necessary.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::from_connection(Connection::open(path).map_err(sql_error)?)
+    }
+
+    /// Use an existing database connection, creating the table of assets if necessary.
+    pub fn from_connection(conn: Connection) -> io::Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS assets (
+                kind INTEGER NOT NULL,
+                hash BLOB NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (kind, hash)
+            ) WITHOUT ROWID;",
+        )
+        .map_err(sql_error)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Access the asset identified by `hash`.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let data = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM assets WHERE kind = ?1 AND hash = ?2",
+                params![hash.kind().id(), hash.bytes()],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map_err(sql_error)?
+            .ok_or_else(not_found)?;
+        Ok(data.into())
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT 1 FROM assets WHERE kind = ?1 AND hash = ?2",
+                params![hash.kind().id(), hash.bytes()],
+                |_| Ok(()),
+            )
+            .optional()
+            .is_ok_and(|x| x.is_some())
+    }
+
+    /// Create a `Writer` for streaming data into the repository.
+    ///
+    /// Data is buffered in memory until it's stored.
+    pub fn make_writer(&self) -> Writer {
+        Writer {
+            conn: self.conn.clone(),
+            buffer: Buffer::default(),
+        }
+    }
+
+    /// Write `data` directly into the repository.
+    pub fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        let hash = hash_of(data);
+        insert(&self.conn.lock().unwrap(), &hash, data)?;
+        Ok(hash)
+    }
+
+    /// Write each of `assets` into the repository in a single transaction, so either all or none are stored.
+    pub fn put_all<'a, I>(&self, assets: I) -> io::Result<Vec<Hash>>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        let mut hashes = Vec::new();
+        for data in assets {
+            let hash = hash_of(data);
+            insert(&tx, &hash, data)?;
+            hashes.push(hash);
+        }
+        tx.commit().map_err(sql_error)?;
+        Ok(hashes)
+    }
+
+    /// Enumerate assets stored in the repository.
+    ///
+    /// This should only be used for diagnostic purposes. It almost never makes sense to access an asset you don't
+    /// already know the hash of.
+    pub fn list(&self) -> impl Iterator<Item = Hash> {
+        let conn = self.conn.lock().unwrap();
+        let mut hashes = Vec::new();
+        if let Ok(mut statement) = conn.prepare("SELECT kind, hash FROM assets") {
+            if let Ok(rows) = statement.query_map(params![], |row| {
+                Ok((row.get::<_, u16>(0)?, row.get::<_, Vec<u8>>(1)?))
+            }) {
+                hashes.extend(rows.filter_map(|row| {
+                    let (kind, bytes) = row.ok()?;
+                    Hash::from_bytes(HashKind::from_id(kind)?, &bytes).ok()
+                }));
+            }
+        }
+        hashes.into_iter()
+    }
+}
+
+impl Store for SqliteStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+}
+
+impl WritableStore for SqliteStore {
+    type Writer = Writer;
+
+    fn make_writer(&self) -> io::Result<Writer> {
+        Ok(self.make_writer())
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+/// A staging area for streaming data into a `SqliteStore`.
+///
+/// `store` must be called to commit data to the repository. Otherwise, it will be discarded when the `Writer` is
+/// dropped.
+pub struct Writer {
+    conn: Arc<Mutex<Connection>>,
+    buffer: Buffer,
+}
+
+impl Writer {
+    /// Commits the written data to the repository. The `bool` is true iff the data was not already there.
+    pub fn store(self) -> io::Result<(Hash, bool)> {
+        let (hash, data) = self.buffer.finish();
+        let inserted = insert(&self.conn.lock().unwrap(), &hash, &data)?;
+        Ok((hash, inserted))
+    }
+}
+
+impl StoreWriter for Writer {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        self.store()
+    }
+}
+
+impl io::Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Store `data` under `hash`, returning whether it was not already present.
+fn insert(conn: &Connection, hash: &Hash, data: &[u8]) -> io::Result<bool> {
+    let n = conn
+        .execute(
+            "INSERT OR IGNORE INTO assets (kind, hash, data) VALUES (?1, ?2, ?3)",
+            params![hash.kind().id(), hash.bytes(), data],
+        )
+        .map_err(sql_error)?;
+    Ok(n != 0)
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let store = SqliteStore::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let hash = store.put(b"hello").unwrap();
+        assert!(store.contains(&hash));
+        assert_eq!(&store.get(&hash).unwrap()[..], b"hello");
+        assert_eq!(store.list().collect::<Vec<_>>(), vec![hash]);
+        let mut writer = store.make_writer();
+        io::Write::write_all(&mut writer, b"hello").unwrap();
+        assert_eq!(writer.store().unwrap(), (hash, false));
+    }
+}
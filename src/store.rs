@@ -0,0 +1,1671 @@
+//! Interfaces common to all types of repository.
+
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::{hash_of, not_found, Asset, ContentSet, Hash, HashKind, Hasher, LooseFiles};
+
+/// A repository from which assets can be read.
+///
+/// Implemented by `LooseFiles` and, with the `carchive` feature, by `ArchiveSet` and `Archive`, allowing code that
+/// consumes assets to be written once for every type of repository. A store chosen at runtime can be held as a
+/// `BoxedStore`.
+pub trait Store {
+    /// Access the asset identified by `hash`.
+    ///
+    /// Fails with `io::ErrorKind::NotFound` if the repository does not contain the asset.
+    fn get(&self, hash: &Hash) -> io::Result<Asset>;
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    fn contains(&self, hash: &Hash) -> bool;
+
+    /// Access each asset identified by `hashes`, in the same order.
+    ///
+    /// The default implementation calls `get` for each hash; repositories override it to order their reads for
+    /// locality.
+    fn get_many(&self, hashes: &[Hash]) -> Vec<io::Result<Asset>> {
+        hashes.iter().map(|x| self.get(x)).collect()
+    }
+
+    /// Determine which of `hashes` exist in the repository, in the same order.
+    ///
+    /// The default implementation calls `contains` for each hash; repositories where lookups are slow override it to
+    /// check many at once.
+    fn contains_many(&self, hashes: &[Hash]) -> Vec<bool> {
+        hashes.iter().map(|x| self.contains(x)).collect()
+    }
+
+    /// Enumerate assets stored in the repository.
+    ///
+    /// This should only be used for diagnostic purposes. It almost never makes sense to access an asset you don't
+    /// already know the hash of.
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_>;
+
+    /// Enumerate assets stored in the repository along with their sizes in bytes.
+    ///
+    /// Assets removed during enumeration are skipped. The default implementation reads every asset; repositories that
+    /// record sizes override it.
+    fn list_sizes(&self) -> Box<dyn Iterator<Item = io::Result<(Hash, u64)>> + '_> {
+        Box::new(self.list().filter_map(move |hash| match self.get(&hash) {
+            Ok(x) => Some(Ok((hash, x.len() as u64))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => Some(Err(e)),
+        }))
+    }
+}
+
+/// A repository into which assets can be written.
+pub trait WritableStore: Store {
+    /// Type used to stream data into the repository.
+    type Writer: StoreWriter;
+
+    /// Create a writer for streaming data into the repository in constant memory.
+    fn make_writer(&self) -> io::Result<Self::Writer>;
+
+    /// Write `data` directly into the repository.
+    fn put(&self, mut data: &[u8]) -> io::Result<Hash> {
+        let mut writer = self.make_writer()?;
+        io::copy(&mut data, &mut writer)?;
+        writer.store().map(|(hash, _)| hash)
+    }
+}
+
+/// A staging area for streaming data into a `WritableStore`.
+///
+/// Data written is committed to the repository only once `store` is called. Writers dropped without being stored
+/// discard their data.
+pub trait StoreWriter: io::Write {
+    /// Commits the written data to the repository. The `bool` is true iff the data was not already there.
+    fn store(self) -> io::Result<(Hash, bool)>;
+}
+
+/// A `Store` chosen at runtime.
+pub type BoxedStore = Box<dyn Store + Send + Sync>;
+
+/// A `WritableStore` chosen at runtime.
+pub type BoxedWritableStore = Box<dyn DynWritableStore + Send + Sync>;
+
+/// Object-safe form of `WritableStore`, implemented for every `WritableStore` with a `Send` writer.
+///
+/// `dyn DynWritableStore` implements `WritableStore`, with writers erased to `BoxedWriter`.
+pub trait DynWritableStore: Store {
+    /// Like `WritableStore::make_writer`.
+    fn make_boxed_writer(&self) -> io::Result<BoxedWriter>;
+
+    /// Like `WritableStore::put`.
+    fn put_dyn(&self, data: &[u8]) -> io::Result<Hash>;
+}
+
+impl<T: WritableStore> DynWritableStore for T
+where
+    T::Writer: Send + 'static,
+{
+    fn make_boxed_writer(&self) -> io::Result<BoxedWriter> {
+        Ok(BoxedWriter(Box::new(self.make_writer()?)))
+    }
+
+    fn put_dyn(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+impl WritableStore for dyn DynWritableStore + Send + Sync {
+    type Writer = BoxedWriter;
+
+    fn make_writer(&self) -> io::Result<BoxedWriter> {
+        self.make_boxed_writer()
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put_dyn(data)
+    }
+}
+
+/// A `StoreWriter` of any type.
+pub struct BoxedWriter(Box<dyn ErasedWriter + Send>);
+
+trait ErasedWriter: io::Write {
+    fn store_boxed(self: Box<Self>) -> io::Result<(Hash, bool)>;
+}
+
+impl<T: StoreWriter> ErasedWriter for T {
+    fn store_boxed(self: Box<Self>) -> io::Result<(Hash, bool)> {
+        (*self).store()
+    }
+}
+
+impl StoreWriter for BoxedWriter {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        self.0.store_boxed()
+    }
+}
+
+impl io::Write for BoxedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+macro_rules! forward_store {
+    ($($ty:ty),*) => {$(
+        impl<S: Store + ?Sized> Store for $ty {
+            fn get(&self, hash: &Hash) -> io::Result<Asset> {
+                (**self).get(hash)
+            }
+
+            fn contains(&self, hash: &Hash) -> bool {
+                (**self).contains(hash)
+            }
+
+            fn get_many(&self, hashes: &[Hash]) -> Vec<io::Result<Asset>> {
+                (**self).get_many(hashes)
+            }
+
+            fn contains_many(&self, hashes: &[Hash]) -> Vec<bool> {
+                (**self).contains_many(hashes)
+            }
+
+            fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+                (**self).list()
+            }
+
+            fn list_sizes(&self) -> Box<dyn Iterator<Item = io::Result<(Hash, u64)>> + '_> {
+                (**self).list_sizes()
+            }
+        }
+
+        impl<S: WritableStore + ?Sized> WritableStore for $ty {
+            type Writer = S::Writer;
+
+            fn make_writer(&self) -> io::Result<S::Writer> {
+                (**self).make_writer()
+            }
+
+            fn put(&self, data: &[u8]) -> io::Result<Hash> {
+                (**self).put(data)
+            }
+        }
+    )*};
+}
+
+forward_store!(&S, Box<S>, Arc<S>);
+
+/// A store that reads from a fast `upper` layer, falling back to a slower `lower` layer.
+///
+/// Writes go to the upper layer. A typical arrangement is a `LooseFiles` of downloaded patches over an `ArchiveSet` of
+/// shipped content.
+///
+/// Used as an `Overlay`, the lower layer is never modified, and `additions` enumerates what has been written on top of
+/// it.
+pub struct Tiered<A, B> {
+    upper: A,
+    lower: B,
+    promote: Option<Promote<A>>,
+}
+
+/// Writes data read from the lower layer of a `Tiered` into its upper layer
+type Promote<A> = fn(&A, &[u8]) -> io::Result<Hash>;
+
+impl<A, B> Tiered<A, B> {
+    /// Layer `upper` over `lower`.
+    pub fn new(upper: A, lower: B) -> Self {
+        Self {
+            upper,
+            lower,
+            promote: None,
+        }
+    }
+
+    /// Copy assets found only in the lower layer into the upper layer as they're read.
+    ///
+    /// Failures to promote an asset are ignored, since it remains available from the lower layer.
+    pub fn promote(mut self) -> Self
+    where
+        A: WritableStore,
+    {
+        self.promote = Some(<A as WritableStore>::put);
+        self
+    }
+
+    /// The upper layer.
+    pub fn upper(&self) -> &A {
+        &self.upper
+    }
+
+    /// The lower layer.
+    pub fn lower(&self) -> &B {
+        &self.lower
+    }
+}
+
+impl<A: Store, B: Store> Tiered<A, B> {
+    /// Enumerate assets in the upper layer that are absent from the lower layer, e.g. to find user content that must be
+    /// uploaded or synchronized.
+    pub fn additions(&self) -> impl Iterator<Item = Hash> + '_ {
+        self.upper.list().filter(move |x| !self.lower.contains(x))
+    }
+}
+
+/// A writable `upper` store layered over a read-only `lower` one, in the manner of overlayfs.
+pub type Overlay<Upper, Lower> = Tiered<Upper, Lower>;
+
+impl<A: Store, B: Store> Store for Tiered<A, B> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        match self.upper.get(hash) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            x => {
+                return x;
+            }
+        }
+        let asset = self.lower.get(hash)?;
+        if let Some(promote) = self.promote {
+            let _ = promote(&self.upper, &asset);
+        }
+        Ok(asset)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.upper.contains(hash) || self.lower.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(
+            self.upper
+                .list()
+                .chain(self.lower.list().filter(move |x| !self.upper.contains(x))),
+        )
+    }
+}
+
+impl<A: WritableStore, B: Store> WritableStore for Tiered<A, B> {
+    type Writer = A::Writer;
+
+    fn make_writer(&self) -> io::Result<A::Writer> {
+        self.upper.make_writer()
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.upper.put(data)
+    }
+}
+
+/// A store that reads through a fast `local` store to a slow `remote` one, such as a network service.
+///
+/// Assets missing from the local store are fetched from the remote store and written locally before being returned, so
+/// each is fetched at most once. Concurrent misses for the same asset are coalesced into a single fetch.
+pub struct CachingStore<R, L> {
+    remote: R,
+    local: L,
+    /// Assets currently being fetched
+    pending: Mutex<ContentSet>,
+    fetched: Condvar,
+}
+
+impl<R, L> CachingStore<R, L> {
+    /// Cache assets from `remote` in `local`.
+    pub fn new(remote: R, local: L) -> Self {
+        Self {
+            remote,
+            local,
+            pending: Mutex::new(ContentSet::default()),
+            fetched: Condvar::new(),
+        }
+    }
+
+    /// The remote store.
+    pub fn remote(&self) -> &R {
+        &self.remote
+    }
+
+    /// The local store.
+    pub fn local(&self) -> &L {
+        &self.local
+    }
+}
+
+impl<R: Store, L: WritableStore> CachingStore<R, L> {
+    fn fetch(&self, hash: &Hash) -> io::Result<Asset> {
+        let asset = self.remote.get(hash)?;
+        // Verify before writing, so corrupt remote data never lands in the local store
+        if hash_of(&asset) != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data for {} does not match its hash", hash),
+            ));
+        }
+        self.local.put(&asset)?;
+        Ok(asset)
+    }
+}
+
+/// Marks a fetch as finished when dropped, even if it panicked, so that waiting threads don't block forever.
+struct Pending<'a> {
+    pending: &'a Mutex<ContentSet>,
+    fetched: &'a Condvar,
+    hash: Hash,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        // Tolerate poisoning, since this may run while unwinding
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.remove(&self.hash);
+        drop(pending);
+        self.fetched.notify_all();
+    }
+}
+
+impl<R: Store, L: WritableStore> Store for CachingStore<R, L> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        loop {
+            match self.local.get(hash) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                x => {
+                    return x;
+                }
+            }
+            let mut pending = self.pending.lock().unwrap();
+            if pending.contains(hash) {
+                // Another thread is fetching this asset; wait for it, then look again.
+                while pending.contains(hash) {
+                    pending = self.fetched.wait(pending).unwrap();
+                }
+                continue;
+            }
+            pending.insert(*hash);
+            drop(pending);
+            let _pending = Pending {
+                pending: &self.pending,
+                fetched: &self.fetched,
+                hash: *hash,
+            };
+            return self.fetch(hash);
+        }
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.local.contains(hash) || self.remote.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(
+            self.local
+                .list()
+                .chain(self.remote.list().filter(move |x| !self.local.contains(x))),
+        )
+    }
+}
+
+/// A read-only store combining any number of other stores, each with an explicit precedence.
+///
+/// Assets are read from the highest-precedence store that contains them, e.g. user content over base content over DLC.
+/// Stores of equal precedence are consulted in the order they were added.
+#[derive(Default)]
+pub struct UnionStore {
+    /// Sorted by descending precedence
+    layers: Vec<(i32, BoxedStore)>,
+}
+
+impl UnionStore {
+    /// Create a union of no stores.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `store` with the given `precedence`. Higher precedences take priority.
+    pub fn insert<S: Store + Send + Sync + 'static>(
+        &mut self,
+        precedence: i32,
+        store: S,
+    ) -> &mut Self {
+        let index = self
+            .layers
+            .iter()
+            .position(|&(x, _)| x < precedence)
+            .unwrap_or(self.layers.len());
+        self.layers.insert(index, (precedence, Box::new(store)));
+        self
+    }
+
+    /// Number of stores combined.
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Whether no stores are combined.
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+}
+
+impl Store for UnionStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        for (_, store) in &self.layers {
+            match store.get(hash) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                x => {
+                    return x;
+                }
+            }
+        }
+        Err(not_found())
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.layers.iter().any(|(_, x)| x.contains(hash))
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        let mut seen = ContentSet::default();
+        Box::new(
+            self.layers
+                .iter()
+                .flat_map(|(_, x)| x.list())
+                .filter(move |x| seen.insert(*x)),
+        )
+    }
+}
+
+/// A store that replicates every write to several underlying stores.
+///
+/// A write succeeds if at least a quorum of the underlying stores accept it; by default, all of them must. Reads are
+/// served by the first store, in order, that produces the asset.
+pub struct MirroredStore {
+    stores: Vec<BoxedWritableStore>,
+    quorum: usize,
+}
+
+impl MirroredStore {
+    /// Replicate writes to every one of `stores`.
+    pub fn new(stores: Vec<BoxedWritableStore>) -> Self {
+        Self {
+            quorum: stores.len(),
+            stores,
+        }
+    }
+
+    /// Consider writes successful once at least `n` stores have accepted them.
+    pub fn quorum(&mut self, n: usize) -> &mut Self {
+        self.quorum = n;
+        self
+    }
+
+    /// The underlying stores.
+    pub fn stores(&self) -> &[BoxedWritableStore] {
+        &self.stores
+    }
+}
+
+impl Store for MirroredStore {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let mut error = None;
+        for store in &self.stores {
+            match store.get(hash) {
+                Ok(x) => {
+                    return Ok(x);
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::NotFound || error.is_none() {
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(error.unwrap_or_else(not_found))
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.stores.iter().any(|x| x.contains(hash))
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        let mut seen = ContentSet::default();
+        Box::new(
+            self.stores
+                .iter()
+                .flat_map(|x| x.list())
+                .filter(move |x| seen.insert(*x)),
+        )
+    }
+}
+
+impl WritableStore for MirroredStore {
+    type Writer = MirroredWriter;
+
+    fn make_writer(&self) -> io::Result<MirroredWriter> {
+        let mut writer = MirroredWriter {
+            writers: Vec::with_capacity(self.stores.len()),
+            quorum: self.quorum,
+            total: self.stores.len(),
+            error: None,
+        };
+        for store in &self.stores {
+            match store.make_writer() {
+                Ok(x) => writer.writers.push(x),
+                Err(e) => writer.error = Some(e),
+            }
+        }
+        writer.check()?;
+        Ok(writer)
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        let mut hash = None;
+        let mut successes = 0;
+        let mut error = None;
+        for store in &self.stores {
+            match store.put(data) {
+                Ok(x) => {
+                    hash = Some(x);
+                    successes += 1;
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        quorum_error(successes, self.quorum, self.stores.len(), error)?;
+        hash.ok_or_else(|| io::Error::other("no stores to write to"))
+    }
+}
+
+/// A staging area for streaming data into a `MirroredStore`.
+pub struct MirroredWriter {
+    writers: Vec<BoxedWriter>,
+    quorum: usize,
+    total: usize,
+    /// Most recent failure of an underlying writer
+    error: Option<io::Error>,
+}
+
+impl MirroredWriter {
+    /// Fail if too few underlying writers remain to reach a quorum.
+    fn check(&mut self) -> io::Result<()> {
+        quorum_error(
+            self.writers.len(),
+            self.quorum,
+            self.total,
+            self.error.take(),
+        )
+    }
+}
+
+impl StoreWriter for MirroredWriter {
+    fn store(mut self) -> io::Result<(Hash, bool)> {
+        let mut result = None;
+        let mut successes = 0;
+        for writer in self.writers.drain(..) {
+            match writer.store() {
+                Ok((hash, new)) => {
+                    let (_, any_new) = result.get_or_insert((hash, false));
+                    *any_new |= new;
+                    successes += 1;
+                }
+                Err(e) => self.error = Some(e),
+            }
+        }
+        quorum_error(successes, self.quorum, self.total, self.error.take())?;
+        result.ok_or_else(|| io::Error::other("no stores to write to"))
+    }
+}
+
+impl io::Write for MirroredWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut i = 0;
+        while i < self.writers.len() {
+            match self.writers[i].write_all(buf) {
+                Ok(()) => i += 1,
+                Err(e) => {
+                    self.writers.swap_remove(i);
+                    self.error = Some(e);
+                }
+            }
+        }
+        self.check()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.writers.len() {
+            match self.writers[i].flush() {
+                Ok(()) => i += 1,
+                Err(e) => {
+                    self.writers.swap_remove(i);
+                    self.error = Some(e);
+                }
+            }
+        }
+        self.check()
+    }
+}
+
+fn quorum_error(
+    successes: usize,
+    quorum: usize,
+    total: usize,
+    error: Option<io::Error>,
+) -> io::Result<()> {
+    if successes >= quorum {
+        return Ok(());
+    }
+    Err(match error {
+        None => io::Error::other(format!("only {} of {} stores succeeded", successes, total)),
+        Some(e) => io::Error::new(
+            e.kind(),
+            format!("only {} of {} stores succeeded: {}", successes, total, e),
+        ),
+    })
+}
+
+/// A store that contains nothing and discards everything written to it.
+///
+/// Writes are still hashed, making this useful for measuring the cost of a pipeline exclusive of storage.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NullStore;
+
+impl Store for NullStore {
+    fn get(&self, _: &Hash) -> io::Result<Asset> {
+        Err(not_found())
+    }
+
+    fn contains(&self, _: &Hash) -> bool {
+        false
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(iter::empty())
+    }
+}
+
+impl WritableStore for NullStore {
+    type Writer = NullWriter;
+
+    fn make_writer(&self) -> io::Result<NullWriter> {
+        Ok(NullWriter(Hasher::new()))
+    }
+}
+
+/// Hashes data written to a `NullStore`.
+#[derive(Debug, Default)]
+pub struct NullWriter(Hasher);
+
+impl StoreWriter for NullWriter {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        Ok((self.0.result(), true))
+    }
+}
+
+impl io::Write for NullWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.process(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A wrapper that counts the operations performed on a store.
+#[derive(Debug, Default)]
+pub struct CountingStore<S> {
+    inner: S,
+    counters: Arc<Counters>,
+}
+
+/// Totals of the operations performed on a `CountingStore`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Counts {
+    /// Calls to `get`
+    pub gets: u64,
+    /// Calls to `get` that failed
+    pub get_failures: u64,
+    /// Total size of assets returned by `get`
+    pub get_bytes: u64,
+    /// Calls to `contains`
+    pub contains: u64,
+    /// Calls to `list`
+    pub lists: u64,
+    /// Calls to `put` and `StoreWriter::store`
+    pub puts: u64,
+    /// Calls to `put` and `StoreWriter::store` that failed
+    pub put_failures: u64,
+    /// Total size of data written
+    pub put_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    gets: AtomicU64,
+    get_failures: AtomicU64,
+    get_bytes: AtomicU64,
+    contains: AtomicU64,
+    lists: AtomicU64,
+    puts: AtomicU64,
+    put_failures: AtomicU64,
+    put_bytes: AtomicU64,
+}
+
+impl Counters {
+    fn put<T>(&self, bytes: u64, result: &io::Result<T>) {
+        self.puts.fetch_add(1, Ordering::Relaxed);
+        match *result {
+            Ok(_) => self.put_bytes.fetch_add(bytes, Ordering::Relaxed),
+            Err(_) => self.put_failures.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+impl<S> CountingStore<S> {
+    /// Count operations performed on `inner`.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            counters: Arc::default(),
+        }
+    }
+
+    /// The wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Totals of the operations performed so far.
+    pub fn counts(&self) -> Counts {
+        let c = &*self.counters;
+        Counts {
+            gets: c.gets.load(Ordering::Relaxed),
+            get_failures: c.get_failures.load(Ordering::Relaxed),
+            get_bytes: c.get_bytes.load(Ordering::Relaxed),
+            contains: c.contains.load(Ordering::Relaxed),
+            lists: c.lists.load(Ordering::Relaxed),
+            puts: c.puts.load(Ordering::Relaxed),
+            put_failures: c.put_failures.load(Ordering::Relaxed),
+            put_bytes: c.put_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset all counts to zero.
+    pub fn reset(&self) {
+        let c = &*self.counters;
+        for x in &[
+            &c.gets,
+            &c.get_failures,
+            &c.get_bytes,
+            &c.contains,
+            &c.lists,
+            &c.puts,
+            &c.put_failures,
+            &c.put_bytes,
+        ] {
+            x.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: Store> Store for CountingStore<S> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let c = &self.counters;
+        c.gets.fetch_add(1, Ordering::Relaxed);
+        let result = self.inner.get(hash);
+        match result {
+            Ok(ref x) => c.get_bytes.fetch_add(x.len() as u64, Ordering::Relaxed),
+            Err(_) => c.get_failures.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.counters.contains.fetch_add(1, Ordering::Relaxed);
+        self.inner.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        self.counters.lists.fetch_add(1, Ordering::Relaxed);
+        self.inner.list()
+    }
+}
+
+impl<S: WritableStore> WritableStore for CountingStore<S> {
+    type Writer = CountingWriter<S::Writer>;
+
+    fn make_writer(&self) -> io::Result<Self::Writer> {
+        Ok(CountingWriter {
+            inner: self.inner.make_writer()?,
+            counters: self.counters.clone(),
+            len: 0,
+        })
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        let result = self.inner.put(data);
+        self.counters.put(data.len() as u64, &result);
+        result
+    }
+}
+
+/// A `StoreWriter` for a `CountingStore`.
+#[derive(Debug)]
+pub struct CountingWriter<W> {
+    inner: W,
+    counters: Arc<Counters>,
+    len: u64,
+}
+
+impl<W: StoreWriter> StoreWriter for CountingWriter<W> {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        let result = self.inner.store();
+        self.counters.put(self.len, &result);
+        result
+    }
+}
+
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Callbacks invoked on the operations performed on an `ObservedStore`, e.g. for telemetry, audit logging, or
+/// triggering replication.
+///
+/// Every method does nothing by default. Callbacks run synchronously on the thread performing the operation, so
+/// expensive work should be handed off elsewhere.
+pub trait StoreObserver {
+    /// The asset identified by `hash`, `size` bytes long, was read.
+    fn get(&self, hash: &Hash, size: u64) {
+        let _ = (hash, size);
+    }
+
+    /// The asset identified by `hash` was requested, but not found.
+    fn miss(&self, hash: &Hash) {
+        let _ = hash;
+    }
+
+    /// `size` bytes identified by `hash` were written. `new` is true iff the data was not already present.
+    fn put(&self, hash: &Hash, size: u64, new: bool) {
+        let _ = (hash, size, new);
+    }
+
+    /// The asset identified by `hash` was removed.
+    fn remove(&self, hash: &Hash) {
+        let _ = hash;
+    }
+}
+
+/// A wrapper that reports the operations performed on a store to a `StoreObserver`.
+///
+/// Failures other than misses are not reported. Removals are reported only when made through `ObservedStore::remove`.
+pub struct ObservedStore<S> {
+    inner: S,
+    observer: Arc<dyn StoreObserver + Send + Sync>,
+}
+
+impl<S> ObservedStore<S> {
+    /// Report operations performed on `inner` to `observer`.
+    pub fn new(inner: S, observer: Arc<dyn StoreObserver + Send + Sync>) -> Self {
+        Self { inner, observer }
+    }
+
+    /// The wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl ObservedStore<LooseFiles> {
+    /// Delete the asset identified by `hash` from the repository.
+    pub fn remove(&self, hash: &Hash) -> io::Result<()> {
+        self.inner.remove(hash)?;
+        self.observer.remove(hash);
+        Ok(())
+    }
+}
+
+impl<S: Store> Store for ObservedStore<S> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let result = self.inner.get(hash);
+        match result {
+            Ok(ref x) => self.observer.get(hash, x.len() as u64),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => self.observer.miss(hash),
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.inner.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        self.inner.list()
+    }
+}
+
+impl<S: WritableStore> WritableStore for ObservedStore<S> {
+    type Writer = ObservedWriter<S::Writer>;
+
+    fn make_writer(&self) -> io::Result<Self::Writer> {
+        Ok(ObservedWriter {
+            inner: self.inner.make_writer()?,
+            observer: self.observer.clone(),
+            len: 0,
+        })
+    }
+}
+
+/// A `StoreWriter` for an `ObservedStore`.
+pub struct ObservedWriter<W> {
+    inner: W,
+    observer: Arc<dyn StoreObserver + Send + Sync>,
+    len: u64,
+}
+
+impl<W: StoreWriter> StoreWriter for ObservedWriter<W> {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        let (hash, new) = self.inner.store()?;
+        self.observer.put(&hash, self.len, new);
+        Ok((hash, new))
+    }
+}
+
+impl<W: io::Write> io::Write for ObservedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A `StoreWriter` that reports the total number of bytes written after each write, e.g. to display progress.
+pub struct ProgressWriter<W, F> {
+    inner: W,
+    progress: F,
+    len: u64,
+}
+
+impl<W, F: FnMut(u64)> ProgressWriter<W, F> {
+    /// Write into `inner`, calling `progress` with the total bytes written so far after each write.
+    pub fn new(inner: W, progress: F) -> Self {
+        Self {
+            inner,
+            progress,
+            len: 0,
+        }
+    }
+}
+
+impl<W: StoreWriter, F: FnMut(u64)> StoreWriter for ProgressWriter<W, F> {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        self.inner.store()
+    }
+}
+
+impl<W: io::Write, F: FnMut(u64)> io::Write for ProgressWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        (self.progress)(self.len);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Inspects assets as they're written to a `ValidatedStore`, rejecting those that violate some policy.
+pub trait Validator {
+    /// Begin inspecting a new asset.
+    fn begin(&self) -> Box<dyn Validation + Send>;
+}
+
+/// The inspection of a single asset by a `Validator`.
+///
+/// An error returned by any method rejects the asset, and is passed on to the writer.
+pub trait Validation {
+    /// Inspect the next part of the asset's data.
+    fn update(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Conclude inspection of the complete asset, identified by `hash` and `size` bytes long.
+    fn finish(self: Box<Self>, hash: &Hash, size: u64) -> io::Result<()>;
+}
+
+/// A `Validator` that rejects assets larger than a certain number of bytes.
+#[derive(Debug, Copy, Clone)]
+pub struct MaxSize(pub u64);
+
+impl Validator for MaxSize {
+    fn begin(&self) -> Box<dyn Validation + Send> {
+        Box::new(MaxSizeValidation {
+            max: self.0,
+            len: 0,
+        })
+    }
+}
+
+struct MaxSizeValidation {
+    max: u64,
+    len: u64,
+}
+
+impl Validation for MaxSizeValidation {
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        self.len += data.len() as u64;
+        if self.len > self.max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("asset exceeds {} bytes", self.max),
+            ));
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, _: &Hash, _: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A wrapper that subjects every asset written to a store to a set of `Validator`s.
+///
+/// Assets are only published into the wrapped store once every validator has accepted them.
+pub struct ValidatedStore<S> {
+    inner: S,
+    validators: Vec<Arc<dyn Validator + Send + Sync>>,
+}
+
+impl<S> ValidatedStore<S> {
+    /// Validate assets written to `inner`, initially with no validators.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            validators: Vec::new(),
+        }
+    }
+
+    /// Subject subsequently written assets to `validator`, in addition to any previously registered.
+    pub fn register(&mut self, validator: Arc<dyn Validator + Send + Sync>) {
+        self.validators.push(validator);
+    }
+
+    /// The wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Store> Store for ValidatedStore<S> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.inner.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.inner.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        self.inner.list()
+    }
+}
+
+impl<S: WritableStore> WritableStore for ValidatedStore<S> {
+    type Writer = ValidatedWriter<S::Writer>;
+
+    fn make_writer(&self) -> io::Result<Self::Writer> {
+        Ok(ValidatedWriter {
+            inner: self.inner.make_writer()?,
+            validations: self.validators.iter().map(|x| x.begin()).collect(),
+            hasher: Hasher::new(),
+            len: 0,
+        })
+    }
+}
+
+/// A `StoreWriter` for a `ValidatedStore`.
+///
+/// Data is buffered by the wrapped store's writer, and discarded if rejected.
+pub struct ValidatedWriter<W> {
+    inner: W,
+    validations: Vec<Box<dyn Validation + Send>>,
+    hasher: Hasher,
+    len: u64,
+}
+
+impl<W: StoreWriter> StoreWriter for ValidatedWriter<W> {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        let hash = self.hasher.result();
+        for validation in self.validations {
+            validation.finish(&hash, self.len)?;
+        }
+        self.inner.store()
+    }
+}
+
+impl<W: io::Write> io::Write for ValidatedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        for validation in &mut self.validations {
+            validation.update(&buf[..n])?;
+        }
+        self.hasher.process(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The ways a repository may be laid out on disk.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Layout {
+    /// A directory of loose files, opened as a `LooseFiles`
+    LooseFiles,
+    /// A directory of archives, opened as an `ArchiveSet`
+    Archives,
+    /// A single archive file, opened as an `Archive`
+    Archive,
+}
+
+impl Layout {
+    /// Open the repository at `path` as this layout.
+    ///
+    /// Archive layouts require the `carchive` feature.
+    pub fn open(self, path: &Path) -> io::Result<BoxedStore> {
+        match self {
+            Layout::LooseFiles => Ok(Box::new(LooseFiles::open(path.into())?)),
+            #[cfg(feature = "carchive")]
+            Layout::Archives => Ok(Box::new(crate::ArchiveSet::open(path)?)),
+            #[cfg(feature = "carchive")]
+            Layout::Archive => Ok(Box::new(crate::Archive::open(path)?)),
+            #[cfg(not(feature = "carchive"))]
+            Layout::Archives | Layout::Archive => {
+                Err(io::Error::other("archive support is not enabled"))
+            }
+        }
+    }
+}
+
+/// Files a `LooseFiles` repository may keep alongside its assets
+const LOOSE_FILES_FILES: &[&str] = &["lock", "audit", "metadata", "scrub"];
+
+/// Directories a `LooseFiles` repository may keep alongside its assets
+const LOOSE_FILES_DIRS: &[&str] = &[
+    "temp",
+    "pins",
+    "refs",
+    "journal",
+    "tags",
+    "quarantine",
+    "suspended",
+];
+
+/// Determine how the existing repository at `path` is laid out.
+///
+/// A file is taken to be a single archive. A directory containing loose file hash directories or any other file or
+/// directory `LooseFiles` keeps alongside them, or containing nothing at all, is taken to be loose files. Any other
+/// directory is taken to contain archives.
+pub fn detect(path: &Path) -> io::Result<Layout> {
+    if !fs::metadata(path)?.is_dir() {
+        return Ok(Layout::Archive);
+    }
+    let mut empty = true;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !entry.file_type()?.is_dir() {
+            if LOOSE_FILES_FILES.contains(&&*name) {
+                return Ok(Layout::LooseFiles);
+            }
+            empty = false;
+            continue;
+        }
+        if LOOSE_FILES_DIRS.contains(&&*name) || name.parse::<HashKind>().is_ok() {
+            return Ok(Layout::LooseFiles);
+        }
+    }
+    Ok(if empty {
+        Layout::LooseFiles
+    } else {
+        Layout::Archives
+    })
+}
+
+/// Open the existing repository at `path`, whatever its layout.
+///
+/// See `detect` for how the layout is determined.
+pub fn open(path: &Path) -> io::Result<BoxedStore> {
+    detect(path)?.open(path)
+}
+
+/// Call `get` for each of `hashes` in increasing order of `key`, returning the results in the order of `hashes`.
+///
+/// With the `rayon` feature enabled, calls are made concurrently.
+pub(crate) fn get_ordered<K, O, F>(hashes: &[Hash], key: K, get: F) -> Vec<io::Result<Asset>>
+where
+    K: Fn(&Hash) -> O,
+    O: Ord,
+    F: Fn(&Hash) -> io::Result<Asset> + Sync,
+{
+    let mut order = (0..hashes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| key(&hashes[i]));
+    #[cfg(feature = "rayon")]
+    let fetched = {
+        use rayon::prelude::*;
+        order
+            .par_iter()
+            .map(|&i| (i, get(&hashes[i])))
+            .collect::<Vec<_>>()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let fetched = order
+        .iter()
+        .map(|&i| (i, get(&hashes[i])))
+        .collect::<Vec<_>>();
+    let mut result = hashes.iter().map(|_| None).collect::<Vec<_>>();
+    for (i, x) in fetched {
+        result[i] = Some(x);
+    }
+    result.into_iter().map(Option::unwrap).collect()
+}
+
+/// Find the unique asset of `store` whose human-readable hash begins with `prefix`, e.g. to expand an abbreviation
+/// typed by a user.
+///
+/// The hash kind may be omitted from `prefix`, and its base32 digits are case-insensitive. Enumerates `store` unless
+/// `prefix` is a complete hash. Fails with `io::ErrorKind::NotFound` if no asset matches, or
+/// `io::ErrorKind::InvalidInput` if several do.
+pub fn resolve<S: Store + ?Sized>(store: &S, prefix: &str) -> io::Result<Hash> {
+    if let Ok(hash) = prefix.parse() {
+        return Ok(hash);
+    }
+    let (kind, digits) = match prefix.find(':') {
+        Some(i) => (Some(&prefix[..i]), prefix[i + 1..].to_ascii_uppercase()),
+        None => (None, prefix.to_ascii_uppercase()),
+    };
+    let mut found = None;
+    for hash in store.list() {
+        if kind.is_some_and(|x| x != hash.kind().name())
+            || !data_encoding::BASE32_NOPAD
+                .encode(hash.bytes())
+                .starts_with(&digits)
+        {
+            continue;
+        }
+        match found {
+            None => found = Some(hash),
+            Some(x) if x == hash => {}
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is ambiguous", prefix),
+                ))
+            }
+        }
+    }
+    found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no asset matches {:?}", prefix),
+        )
+    })
+}
+
+/// Stream the asset identified by `expected` from `reader` into `store`, publishing it only if it matches.
+///
+/// Returns `true` iff the asset was not already present. If it is, nothing is read. Fails with
+/// `io::ErrorKind::InvalidData`, leaving `store` unchanged, if the data doesn't match `expected`.
+pub fn store_verified<S, R>(store: &S, expected: &Hash, reader: R) -> io::Result<bool>
+where
+    S: WritableStore + ?Sized,
+    R: io::Read,
+{
+    verify_into(store, expected, None, reader)
+}
+
+/// Like `store_verified`, but for an asset known to be `size` bytes long.
+///
+/// Fails as soon as more data is read than `size` allows, rather than reading to the end.
+pub fn store_verified_sized<S, R>(
+    store: &S,
+    expected: &Hash,
+    size: u64,
+    reader: R,
+) -> io::Result<bool>
+where
+    S: WritableStore + ?Sized,
+    R: io::Read,
+{
+    verify_into(store, expected, Some(size), reader)
+}
+
+fn verify_into<S, R>(
+    store: &S,
+    expected: &Hash,
+    size: Option<u64>,
+    mut reader: R,
+) -> io::Result<bool>
+where
+    S: WritableStore + ?Sized,
+    R: io::Read,
+{
+    let mismatch = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("data for {} does not match its hash", expected),
+        )
+    };
+    if expected.kind() != HashKind::default() {
+        // Can't be produced by `Hasher`
+        return Err(mismatch());
+    }
+    if store.contains(expected) {
+        return Ok(false);
+    }
+    let mut writer = store.make_writer()?;
+    let mut hasher = Hasher::new();
+    let mut len = 0u64;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        len += n as u64;
+        if size.is_some_and(|x| len > x) {
+            return Err(mismatch());
+        }
+        hasher.process(&buf[..n]);
+        io::Write::write_all(&mut writer, &buf[..n])?;
+    }
+    if size.is_some_and(|x| len != x) || hasher.result() != *expected {
+        return Err(mismatch());
+    }
+    Ok(writer.store()?.1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MemoryStore;
+
+    #[test]
+    fn tiered() {
+        let upper = MemoryStore::new();
+        let lower = MemoryStore::new();
+        let a = upper.put(b"a");
+        let b = lower.put(b"b");
+        lower.put(b"a");
+        let store = Tiered::new(upper, lower).promote();
+        assert_eq!(store.additions().collect::<Vec<_>>(), vec![]);
+        assert_eq!(&Store::get(&store, &b).unwrap()[..], b"b");
+        assert!(store.upper().contains(&b));
+        let c = store.put(b"c").unwrap();
+        assert_eq!(store.additions().collect::<Vec<_>>(), vec![c]);
+        let mut list = Store::list(&store).collect::<Vec<_>>();
+        list.sort();
+        let mut expected = vec![a, b, c];
+        expected.sort();
+        assert_eq!(list, expected);
+    }
+
+    #[test]
+    fn union() {
+        let base = MemoryStore::new();
+        let a = base.put(b"a");
+        let user = MemoryStore::new();
+        let b = user.put(b"b");
+        user.put(b"a");
+        let mut store = UnionStore::new();
+        store.insert(0, base).insert(10, user);
+        assert!(Store::contains(&store, &a));
+        assert_eq!(&Store::get(&store, &b).unwrap()[..], b"b");
+        assert_eq!(Store::list(&store).count(), 2);
+    }
+
+    #[test]
+    fn boxed() {
+        let store: BoxedWritableStore = Box::new(MemoryStore::new());
+        let hash = store.put(b"a").unwrap();
+        let mut writer = store.make_writer().unwrap();
+        io::Write::write_all(&mut writer, b"a").unwrap();
+        assert_eq!(writer.store().unwrap(), (hash, false));
+        assert!(store.contains(&hash));
+    }
+
+    #[test]
+    fn mirrored() {
+        let a = Arc::new(MemoryStore::new());
+        let b = Arc::new(MemoryStore::new());
+        let store = MirroredStore::new(vec![Box::new(a.clone()), Box::new(b.clone())]);
+        let hash = store.put(b"data").unwrap();
+        assert!(a.contains(&hash) && b.contains(&hash));
+        let mut writer = store.make_writer().unwrap();
+        io::Write::write_all(&mut writer, b"more").unwrap();
+        let (hash, new) = writer.store().unwrap();
+        assert!(new && a.contains(&hash) && b.contains(&hash));
+    }
+
+    #[test]
+    fn counting() {
+        let store = CountingStore::new(NullStore);
+        let hash = store.put(b"data").unwrap();
+        assert!(store.get(&hash).is_err());
+        assert!(!store.contains(&hash));
+        let mut writer = store.make_writer().unwrap();
+        io::Write::write_all(&mut writer, b"data").unwrap();
+        assert_eq!(writer.store().unwrap(), (hash, true));
+        assert_eq!(
+            store.counts(),
+            Counts {
+                gets: 1,
+                get_failures: 1,
+                contains: 1,
+                puts: 2,
+                put_bytes: 8,
+                ..Counts::default()
+            }
+        );
+        store.reset();
+        assert_eq!(store.counts(), Counts::default());
+    }
+
+    #[test]
+    fn observed() {
+        #[derive(Default)]
+        struct Log(Mutex<Vec<String>>);
+        impl StoreObserver for Log {
+            fn get(&self, _: &Hash, size: u64) {
+                self.0.lock().unwrap().push(format!("get {}", size));
+            }
+            fn miss(&self, _: &Hash) {
+                self.0.lock().unwrap().push("miss".into());
+            }
+            fn put(&self, _: &Hash, size: u64, new: bool) {
+                self.0.lock().unwrap().push(format!("put {} {}", size, new));
+            }
+        }
+
+        let log = Arc::new(Log::default());
+        let store = ObservedStore::new(MemoryStore::new(), log.clone());
+        let hash = WritableStore::put(&store, b"data").unwrap();
+        WritableStore::put(&store, b"data").unwrap();
+        Store::get(&store, &hash).unwrap();
+        assert!(Store::get(&store, &Hash::Blake2b([0; 25])).is_err());
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            vec!["put 4 true", "put 4 false", "get 4", "miss"]
+        );
+    }
+
+    #[test]
+    fn verified() {
+        let store = MemoryStore::new();
+        let hash = NullStore.put(b"data").unwrap();
+        assert!(store_verified_sized(&store, &hash, 3, &b"data"[..]).is_err());
+        assert!(store_verified(&store, &hash, &b"date"[..]).is_err());
+        assert_eq!(store.list().count(), 0);
+        assert!(store_verified(&store, &hash, &b"data"[..]).unwrap());
+        assert!(!store_verified_sized(&store, &hash, 4, &b"data"[..]).unwrap());
+    }
+
+    #[test]
+    fn validated() {
+        let mut store = ValidatedStore::new(MemoryStore::new());
+        store.register(Arc::new(MaxSize(4)));
+        let hash = WritableStore::put(&store, b"data").unwrap();
+        assert!(store.inner().contains(&hash));
+        assert!(WritableStore::put(&store, b"large").is_err());
+        assert_eq!(store.inner().list().count(), 1);
+    }
+
+    #[test]
+    fn caching() {
+        let remote = MemoryStore::new();
+        let hash = remote.put(b"remote");
+        let store = CachingStore::new(remote, MemoryStore::new());
+        assert!(!store.local().contains(&hash));
+        assert_eq!(&Store::get(&store, &hash).unwrap()[..], b"remote");
+        assert!(store.local().contains(&hash));
+        assert_eq!(
+            Store::get(&store, &Hash::Blake2b([0; 25]))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        let local = store.local().put(b"local");
+        let mut list = Store::list(&store).collect::<Vec<_>>();
+        list.sort();
+        let mut expected = vec![hash, local];
+        expected.sort();
+        assert_eq!(list, expected);
+    }
+
+    #[test]
+    fn caching_rejects_corrupt() {
+        struct Corrupt;
+        impl Store for Corrupt {
+            fn get(&self, _: &Hash) -> io::Result<Asset> {
+                Ok(b"wrong"[..].into())
+            }
+            fn contains(&self, _: &Hash) -> bool {
+                true
+            }
+            fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+                Box::new(iter::empty())
+            }
+        }
+        let hash = MemoryStore::new().put(b"right");
+        let store = CachingStore::new(Corrupt, MemoryStore::new());
+        assert_eq!(
+            Store::get(&store, &hash).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(store.local().list().count(), 0);
+    }
+
+    #[test]
+    fn caching_survives_panic() {
+        struct Panicking;
+        impl Store for Panicking {
+            fn get(&self, _: &Hash) -> io::Result<Asset> {
+                panic!("remote failed");
+            }
+            fn contains(&self, _: &Hash) -> bool {
+                true
+            }
+            fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+                Box::new(iter::empty())
+            }
+        }
+        let hash = MemoryStore::new().put(b"data");
+        let store = CachingStore::new(Panicking, MemoryStore::new());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ = Store::get(&store, &hash);
+        }));
+        assert!(result.is_err());
+        assert!(store.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn detect_layout() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-detect-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(detect(&dir).unwrap(), Layout::LooseFiles);
+        fs::write(dir.join("ABCDEF"), b"archive").unwrap();
+        assert_eq!(detect(&dir).unwrap(), Layout::Archives);
+        assert_eq!(detect(&dir.join("ABCDEF")).unwrap(), Layout::Archive);
+        for name in LOOSE_FILES_FILES {
+            fs::write(dir.join(name), b"").unwrap();
+            assert_eq!(detect(&dir).unwrap(), Layout::LooseFiles, "{}", name);
+            fs::remove_file(dir.join(name)).unwrap();
+        }
+        for name in LOOSE_FILES_DIRS.iter().chain(&["blake2b"]) {
+            fs::create_dir(dir.join(name)).unwrap();
+            assert_eq!(detect(&dir).unwrap(), Layout::LooseFiles, "{}", name);
+            fs::remove_dir(dir.join(name)).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_prefix() {
+        let store = MemoryStore::new();
+        let hashes = (0..64u8).map(|i| store.put(&[i])).collect::<Vec<_>>();
+        let full = hashes[0].to_string();
+        let digits = &full[full.find(':').unwrap() + 1..];
+        let unique = (1..full.len())
+            .map(|n| &full[..n])
+            .find(|x| resolve(&store, x).is_ok())
+            .unwrap();
+        assert_eq!(resolve(&store, unique).unwrap(), hashes[0]);
+        assert_eq!(
+            resolve(&store, &digits[..12].to_ascii_lowercase()).unwrap(),
+            hashes[0]
+        );
+        assert_eq!(
+            resolve(&store, "blake2b:").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            resolve(&store, "unknown:A").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn list_sizes() {
+        let store = MemoryStore::new();
+        let a = store.put(b"a");
+        let bc = store.put(b"bc");
+        let mut sizes = Store::list_sizes(&store)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        sizes.sort();
+        let mut expected = vec![(a, 1), (bc, 2)];
+        expected.sort();
+        assert_eq!(sizes, expected);
+    }
+}
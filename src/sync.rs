@@ -0,0 +1,330 @@
+//! A have/want protocol for synchronizing repositories over any transport.
+//!
+//! A receiver opens an exchange by sending a `Message`: `Have` summarizing every asset it already has, to receive
+//! everything else, or `Want` listing exactly the assets it needs. The sender replies with a `bundle` of the requested
+//! assets that it has. Synchronization therefore takes a single round trip, and no asset is sent that the receiver
+//! has. `fetch` and `serve` implement each side over a pair of byte streams.
+//!
+//! `Have` carries a Golomb-coded set, costing about `p + 1.5` bits per asset rather than the size of a hash. Each asset
+//! the receiver lacks has a 2^-p chance of being mistaken for one it has and withheld; a subsequent exchange using a
+//! different salt catches such assets independently.
+//!
+//! Messages begin with the magic `CHSYNC01` and a tag byte. `Have` (tag 0) continues with `u8` p, then
+//! little-endian `u64` salt, range, count, and length of the following Golomb-Rice coded deltas. `Want` (tag 1)
+//! continues with a `u64` count of hashes, each a `u16` hash kind ID, `u16` length, and bytes.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{bundle, Hash, HashKind, Store, WritableStore};
+
+const MAGIC: &[u8; 8] = b"CHSYNC01";
+
+/// Value of `p` used by `fetch`, giving a one-in-a-million chance of withholding each missing asset.
+pub const DEFAULT_P: u8 = 20;
+
+/// A compact, probabilistic representation of a set of hashes.
+///
+/// Never reports that a member is absent, but reports a non-member as present with probability 2^-p.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GolombSet {
+    p: u8,
+    salt: u64,
+    range: u64,
+    /// Sorted
+    values: Vec<u64>,
+}
+
+impl GolombSet {
+    /// Summarize `hashes`, with a false positive rate of 2^-`p` under `salt`.
+    ///
+    /// # Panics
+    ///
+    /// If `p` is not between 1 and 32.
+    pub fn new<I: IntoIterator<Item = Hash>>(hashes: I, p: u8, salt: u64) -> Self {
+        assert!((1..=32).contains(&p), "p must be between 1 and 32");
+        let hashes = hashes.into_iter().collect::<Vec<_>>();
+        let mut set = Self {
+            p,
+            salt,
+            range: (hashes.len() as u64) << p,
+            values: Vec::new(),
+        };
+        set.values = hashes.iter().map(|x| set.value(x)).collect();
+        set.values.sort_unstable();
+        set.values.dedup();
+        set
+    }
+
+    /// Whether `hash` may be a member.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.range != 0 && self.values.binary_search(&self.value(hash)).is_ok()
+    }
+
+    /// The salt used to compute the set.
+    pub fn salt(&self) -> u64 {
+        self.salt
+    }
+
+    /// Map `hash` uniformly into `0..range`.
+    fn value(&self, hash: &Hash) -> u64 {
+        // Hashes are already uniformly distributed, so need only be made to vary with the salt
+        let mut x = LittleEndian::read_u64(&hash.bytes()[..8]) ^ self.salt;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+        ((u128::from(x) * u128::from(self.range)) >> 64) as u64
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut bits = BitWriter::default();
+        let mut last = 0;
+        for &value in &self.values {
+            let delta = value - last;
+            last = value;
+            for _ in 0..delta >> self.p {
+                bits.push(true);
+            }
+            bits.push(false);
+            for i in (0..self.p).rev() {
+                bits.push(delta >> i & 1 == 1);
+            }
+        }
+        out.push(self.p);
+        let mut header = [0; 32];
+        LittleEndian::write_u64(&mut header[0..8], self.salt);
+        LittleEndian::write_u64(&mut header[8..16], self.range);
+        LittleEndian::write_u64(&mut header[16..24], self.values.len() as u64);
+        LittleEndian::write_u64(&mut header[24..32], bits.bytes.len() as u64);
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&bits.bytes);
+    }
+
+    fn decode<R: Read>(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 33];
+        input.read_exact(&mut header)?;
+        let p = header[0];
+        if !(1..=32).contains(&p) {
+            return Err(malformed());
+        }
+        let salt = LittleEndian::read_u64(&header[1..9]);
+        let range = LittleEndian::read_u64(&header[9..17]);
+        let count = LittleEndian::read_u64(&header[17..25]);
+        let len = LittleEndian::read_u64(&header[25..33]);
+        let mut bytes = Vec::new();
+        if (&mut input).take(len).read_to_end(&mut bytes)? as u64 != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated message",
+            ));
+        }
+        let mut bits = BitReader {
+            bytes: &bytes,
+            pos: 0,
+        };
+        let mut values = Vec::new();
+        let mut last = 0u64;
+        for _ in 0..count {
+            let mut quotient = 0u64;
+            while bits.next().ok_or_else(malformed)? {
+                quotient += 1;
+            }
+            let mut delta = quotient.checked_shl(u32::from(p)).ok_or_else(malformed)?;
+            for i in (0..p).rev() {
+                if bits.next().ok_or_else(malformed)? {
+                    delta |= 1 << i;
+                }
+            }
+            last = last.checked_add(delta).ok_or_else(malformed)?;
+            values.push(last);
+        }
+        Ok(Self {
+            p,
+            salt,
+            range,
+            values,
+        })
+    }
+}
+
+/// A request from a receiver to a sender.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    /// The receiver has the assets in the set, and wants every other asset
+    Have(GolombSet),
+    /// The receiver wants exactly these assets
+    Want(Vec<Hash>),
+}
+
+impl Message {
+    /// Write this message to `out`.
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut data = MAGIC.to_vec();
+        match *self {
+            Message::Have(ref set) => {
+                data.push(0);
+                set.encode(&mut data);
+            }
+            Message::Want(ref hashes) => {
+                data.push(1);
+                let mut buf = [0; 8];
+                LittleEndian::write_u64(&mut buf, hashes.len() as u64);
+                data.extend_from_slice(&buf);
+                for hash in hashes {
+                    LittleEndian::write_u16(&mut buf[0..2], hash.kind().id());
+                    LittleEndian::write_u16(&mut buf[2..4], hash.bytes().len() as u16);
+                    data.extend_from_slice(&buf[0..4]);
+                    data.extend_from_slice(hash.bytes());
+                }
+            }
+        }
+        out.write_all(&data)?;
+        out.flush()
+    }
+
+    /// Read a message from `input`.
+    pub fn read<R: Read>(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 9];
+        input.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(malformed());
+        }
+        match header[8] {
+            0 => Ok(Message::Have(GolombSet::decode(input)?)),
+            1 => {
+                let mut buf = [0; 8];
+                input.read_exact(&mut buf)?;
+                let mut hashes = Vec::new();
+                for _ in 0..LittleEndian::read_u64(&buf) {
+                    input.read_exact(&mut buf[..4])?;
+                    let kind = HashKind::from_id(LittleEndian::read_u16(&buf[0..2]))
+                        .ok_or_else(malformed)?;
+                    let mut bytes = vec![0; LittleEndian::read_u16(&buf[2..4]) as usize];
+                    input.read_exact(&mut bytes)?;
+                    hashes.push(Hash::from_bytes(kind, &bytes).map_err(|_| malformed())?);
+                }
+                Ok(Message::Want(hashes))
+            }
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// Enumerate the assets of `store` that aren't in `set`.
+pub fn missing<'a, S: Store + ?Sized>(
+    store: &'a S,
+    set: &'a GolombSet,
+) -> impl Iterator<Item = Hash> + 'a {
+    store.list().filter(move |x| !set.contains(x))
+}
+
+/// Receive every asset from a sender that `store` lacks, returning the hashes received.
+///
+/// Requests are written to `output`, and the sender's reply read from `input`. Use a different `salt` for each
+/// exchange with the same sender.
+pub fn fetch<S, R, W>(store: &S, salt: u64, input: R, output: W) -> io::Result<Vec<Hash>>
+where
+    S: WritableStore + ?Sized,
+    R: Read,
+    W: Write,
+{
+    Message::Have(GolombSet::new(store.list(), DEFAULT_P, salt)).write(output)?;
+    bundle::read(input, store)
+}
+
+/// Answer one request read from `input` with assets from `store`, written to `output`.
+pub fn serve<S, R, W>(store: &S, input: R, output: W) -> io::Result<()>
+where
+    S: Store + ?Sized,
+    R: Read,
+    W: Write,
+{
+    match Message::read(input)? {
+        Message::Have(set) => bundle::write(store, missing(store, &set), output),
+        Message::Want(hashes) => bundle::write(
+            store,
+            hashes.into_iter().filter(|x| store.contains(x)),
+            output,
+        ),
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn push(&mut self, bit: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn next(&mut self) -> Option<bool> {
+        let byte = *self.bytes.get(self.pos / 8)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed sync message")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MemoryStore;
+
+    #[test]
+    fn golomb_roundtrip() {
+        let store = MemoryStore::new();
+        let hashes = (0..1000u32)
+            .map(|i| store.put(&i.to_le_bytes()))
+            .collect::<Vec<_>>();
+        let set = GolombSet::new(hashes[..500].iter().cloned(), 10, 42);
+        let mut data = Vec::new();
+        Message::Have(set.clone()).write(&mut data).unwrap();
+        // Far smaller than the hashes themselves
+        assert!(data.len() < 500 * 2);
+        assert_eq!(
+            Message::read(&data[..]).unwrap(),
+            Message::Have(set.clone())
+        );
+        assert!(hashes[..500].iter().all(|x| set.contains(x)));
+        assert!(hashes[500..].iter().filter(|x| set.contains(x)).count() < 10);
+    }
+
+    #[test]
+    fn sync() {
+        let sender = MemoryStore::new();
+        let receiver = MemoryStore::new();
+        let shared = sender.put(b"shared");
+        receiver.put(b"shared");
+        let new = sender.put(b"new");
+
+        let mut request = Vec::new();
+        Message::Have(GolombSet::new(receiver.list(), DEFAULT_P, 0))
+            .write(&mut request)
+            .unwrap();
+        let mut reply = Vec::new();
+        serve(&sender, &request[..], &mut reply).unwrap();
+        assert_eq!(bundle::read(&reply[..], &receiver).unwrap(), [new]);
+        assert!(receiver.contains(&shared) && receiver.contains(&new));
+    }
+}
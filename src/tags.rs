@@ -0,0 +1,140 @@
+//! Persistent indexes finding assets by category.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use data_encoding::BASE32_NOPAD;
+
+use crate::{Hash, Pins};
+
+/// Longest tag that encodes to a file name within common filesystems' 255 byte limit.
+const MAX_TAG_LEN: usize = 255 * 5 / 8;
+
+/// A persistent map from arbitrary string tags to sets of assets.
+///
+/// Each tag is a directory named after its base32-encoded text, containing an empty file per member in the same layout
+/// as `Pins`, so tagging and untagging are atomic and may safely be performed by multiple processes at once. Tags do
+/// not retain assets during garbage collection; pin assets that must be kept.
+pub struct Tags {
+    dir: PathBuf,
+}
+
+impl Tags {
+    /// Open the tags stored in `dir`, creating it if necessary.
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Add `hash` to `tag`. Has no effect if it's already present.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if `tag` is empty or longer than 159 bytes.
+    pub fn tag(&self, tag: &str, hash: &Hash) -> io::Result<()> {
+        Pins::open(self.path_for(tag)?)?.pin(hash)
+    }
+
+    /// Remove `hash` from `tag`. Has no effect if it's not present.
+    pub fn untag(&self, tag: &str, hash: &Hash) -> io::Result<()> {
+        match self.members(tag)? {
+            Some(x) => x.unpin(hash),
+            None => Ok(()),
+        }
+    }
+
+    /// Determine whether `tag` includes `hash`.
+    pub fn has_tag(&self, tag: &str, hash: &Hash) -> io::Result<bool> {
+        Ok(self.members(tag)?.is_some_and(|x| x.is_pinned(hash)))
+    }
+
+    /// Enumerate the assets with `tag`.
+    pub fn get(&self, tag: &str) -> io::Result<Vec<Hash>> {
+        match self.members(tag)? {
+            Some(x) => x.pins(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Enumerate every tag with at least one asset.
+    pub fn tags(&self) -> io::Result<Vec<String>> {
+        let mut result = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let tag = match entry
+                .file_name()
+                .to_str()
+                .and_then(|x| BASE32_NOPAD.decode(x.as_bytes()).ok())
+                .and_then(|x| String::from_utf8(x).ok())
+            {
+                Some(x) => x,
+                None => continue,
+            };
+            if !Pins::open(entry.path())?.pins()?.is_empty() {
+                result.push(tag);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Enumerate the tags of `hash`.
+    ///
+    /// Takes time proportional to the number of tags.
+    pub fn tags_of(&self, hash: &Hash) -> io::Result<Vec<String>> {
+        let mut result = self.tags()?;
+        result.retain(|tag| self.has_tag(tag, hash).unwrap_or(false));
+        Ok(result)
+    }
+
+    /// The members of `tag`, if it's ever been used.
+    fn members(&self, tag: &str) -> io::Result<Option<Pins>> {
+        let path = self.path_for(tag)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        Pins::open(path).map(Some)
+    }
+
+    fn path_for(&self, tag: &str) -> io::Result<PathBuf> {
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid tag {:?}", tag),
+            ));
+        }
+        Ok(self.dir.join(BASE32_NOPAD.encode(tag.as_bytes())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Hasher;
+
+    #[test]
+    fn tag() {
+        let dir = std::env::temp_dir().join(format!("chasset-tags-{:016X}", rand::random::<u64>()));
+        let tags = Tags::open(dir.clone()).unwrap();
+        let a = Hasher::new().result();
+        let mut hasher = Hasher::new();
+        hasher.process(b"b");
+        let b = hasher.result();
+
+        tags.tag("textures/stone", &a).unwrap();
+        tags.tag("textures/stone", &b).unwrap();
+        tags.tag("Level 1", &a).unwrap();
+        let mut stone = tags.get("textures/stone").unwrap();
+        stone.sort();
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(stone, expected);
+        let mut of_a = tags.tags_of(&a).unwrap();
+        of_a.sort();
+        assert_eq!(of_a, ["Level 1", "textures/stone"]);
+        tags.untag("Level 1", &a).unwrap();
+        assert!(!tags.has_tag("Level 1", &a).unwrap());
+        assert_eq!(tags.tags().unwrap(), ["textures/stone"]);
+        assert!(tags.get("unused").unwrap().is_empty());
+        assert!(tags.tag("", &a).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
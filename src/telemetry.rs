@@ -0,0 +1,121 @@
+//! Reporting of repository activity through the `metrics` facade.
+//!
+//! With the `metrics` feature enabled, hashing and memory mapping are always reported, as are store operations on
+//! `LooseFiles` and `ArchiveSet`, labeled `loose_files` and `archive_set` respectively. Reads through `get_many` that
+//! are served by io_uring aren't counted. Operations on other stores, such as remote or layered ones, are reported by
+//! wrapping them with `instrument`; wrapping a `LooseFiles` or `ArchiveSet` would count its operations twice.
+//!
+//! | Metric                       | Type      | Labels  | Meaning                                 |
+//! |------------------------------|-----------|---------|-----------------------------------------|
+//! | `chasset_gets_total`         | counter   | `store` | Assets read                             |
+//! | `chasset_misses_total`       | counter   | `store` | Assets requested but not found          |
+//! | `chasset_get_size_bytes`     | histogram | `store` | Sizes of assets read                    |
+//! | `chasset_puts_total`         | counter   | `store` | Assets written, including duplicates    |
+//! | `chasset_put_new_total`      | counter   | `store` | Assets written that weren't present     |
+//! | `chasset_put_size_bytes`     | histogram | `store` | Sizes of assets written                 |
+//! | `chasset_removes_total`      | counter   | `store` | Assets removed                          |
+//! | `chasset_hashed_bytes_total` | counter   |         | Data hashed, e.g. for hash throughput   |
+//! | `chasset_mmaps_total`        | counter   |         | Memory mappings made                    |
+//! | `chasset_mmap_bytes_total`   | counter   |         | Total size of memory mappings made      |
+
+use std::io;
+use std::sync::Arc;
+
+use metrics::{counter, describe_counter, describe_histogram, histogram};
+
+use crate::{Asset, Hash, ObservedStore, StoreObserver};
+
+/// A `StoreObserver` that reports operations through the `metrics` facade, labeled with the name of the store.
+#[derive(Debug, Copy, Clone)]
+pub struct MetricsObserver {
+    store: &'static str,
+}
+
+impl MetricsObserver {
+    /// Report operations labeled with `store`.
+    pub fn new(store: &'static str) -> Self {
+        Self { store }
+    }
+}
+
+impl StoreObserver for MetricsObserver {
+    fn get(&self, _: &Hash, size: u64) {
+        got(self.store, size);
+    }
+
+    fn miss(&self, _: &Hash) {
+        missed(self.store);
+    }
+
+    fn put(&self, _: &Hash, size: u64, new: bool) {
+        stored(self.store, size, new);
+    }
+
+    fn remove(&self, _: &Hash) {
+        removed(self.store);
+    }
+}
+
+/// Report operations performed on `store` through the `metrics` facade, labeled with `name`.
+pub fn instrument<S>(store: S, name: &'static str) -> ObservedStore<S> {
+    ObservedStore::new(store, Arc::new(MetricsObserver::new(name)))
+}
+
+/// Register descriptions of every metric with the installed recorder.
+pub fn describe() {
+    describe_counter!("chasset_gets_total", "Assets read");
+    describe_counter!("chasset_misses_total", "Assets requested but not found");
+    describe_histogram!("chasset_get_size_bytes", "Sizes of assets read");
+    describe_counter!("chasset_puts_total", "Assets written, including duplicates");
+    describe_counter!(
+        "chasset_put_new_total",
+        "Assets written that weren't present"
+    );
+    describe_histogram!("chasset_put_size_bytes", "Sizes of assets written");
+    describe_counter!("chasset_removes_total", "Assets removed");
+    describe_counter!("chasset_hashed_bytes_total", "Data hashed");
+    describe_counter!("chasset_mmaps_total", "Memory mappings made");
+    describe_counter!(
+        "chasset_mmap_bytes_total",
+        "Total size of memory mappings made"
+    );
+}
+
+fn got(store: &'static str, size: u64) {
+    counter!("chasset_gets_total", "store" => store).increment(1);
+    histogram!("chasset_get_size_bytes", "store" => store).record(size as f64);
+}
+
+fn missed(store: &'static str) {
+    counter!("chasset_misses_total", "store" => store).increment(1);
+}
+
+pub(crate) fn stored(store: &'static str, size: u64, new: bool) {
+    counter!("chasset_puts_total", "store" => store).increment(1);
+    if new {
+        counter!("chasset_put_new_total", "store" => store).increment(1);
+    }
+    histogram!("chasset_put_size_bytes", "store" => store).record(size as f64);
+}
+
+pub(crate) fn removed(store: &'static str) {
+    counter!("chasset_removes_total", "store" => store).increment(1);
+}
+
+/// Report the outcome of a read from `store`.
+pub(crate) fn read(store: &'static str, result: &io::Result<Asset>) {
+    match *result {
+        Ok(ref asset) => got(store, asset.len() as u64),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => missed(store),
+        Err(_) => {}
+    }
+}
+
+pub(crate) fn hashed(len: usize) {
+    counter!("chasset_hashed_bytes_total").increment(len as u64);
+}
+
+pub(crate) fn mapped(len: usize) {
+    counter!("chasset_mmaps_total").increment(1);
+    counter!("chasset_mmap_bytes_total").increment(len as u64);
+}
@@ -0,0 +1,460 @@
+//! Configurable chains of reversible transformations applied to asset data at rest.
+//!
+//! A `Pipeline` applies an ordered chain of `Transform`s, such as compression, encryption, and padding, to each asset
+//! written through a `TransformStore`, and reverses them on read. The chain is recorded with each asset, so changing a
+//! store's pipeline leaves previously written assets readable so long as their transforms remain available, e.g. via
+//! `Pipeline::also_read`.
+//!
+//! Each transformed asset is stored as the magic `CHXFORM1`, a 1-byte count of transforms, and for each transform in
+//! the order applied, a 1-byte length and that many bytes of its UTF-8 name, followed by the transformed data.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::str;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::index::Index;
+use crate::{hash_of, Asset, Buffer, Hash, Store, StoreWriter, WritableStore};
+
+const MAGIC: &[u8; 8] = b"CHXFORM1";
+
+/// A reversible transformation of asset data.
+pub trait Transform: Send + Sync {
+    /// Identifies this transform, including any parameters needed to reverse it such as a key fingerprint, in the
+    /// records of transformed assets. At most 255 bytes.
+    fn name(&self) -> String;
+
+    /// Transform `data`, the data or an intermediate form of the asset identified by `hash`.
+    fn apply(&self, hash: &Hash, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Undo `apply`.
+    fn reverse(&self, hash: &Hash, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// An ordered chain of `Transform`s.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    chain: Vec<Arc<dyn Transform>>,
+    extra: Vec<Arc<dyn Transform>>,
+}
+
+impl Pipeline {
+    /// Create a pipeline that leaves data unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply `transform` after those already in the chain.
+    pub fn then<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.chain.push(Arc::new(transform));
+        self
+    }
+
+    /// Make `transform` available for reversing previously written assets without applying it to new ones.
+    pub fn also_read<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.extra.push(Arc::new(transform));
+        self
+    }
+
+    /// Transform `data`, identified by `hash`, and prefix it with a record of the chain.
+    pub fn apply(&self, hash: &Hash, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stored = MAGIC.to_vec();
+        stored.push(self.chain.len() as u8);
+        let mut data = data.to_vec();
+        for transform in &self.chain {
+            let name = transform.name();
+            if name.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "transform name too long",
+                ));
+            }
+            stored.push(name.len() as u8);
+            stored.extend_from_slice(name.as_bytes());
+            data = transform.apply(hash, &data)?;
+        }
+        stored.extend_from_slice(&data);
+        Ok(stored)
+    }
+
+    /// Undo `apply`, using whichever transforms are recorded in `stored`.
+    pub fn reverse(&self, hash: &Hash, stored: &[u8]) -> io::Result<Vec<u8>> {
+        if stored.len() < MAGIC.len() + 1 || &stored[..MAGIC.len()] != MAGIC {
+            return Err(malformed());
+        }
+        let count = stored[MAGIC.len()];
+        let mut rest = &stored[MAGIC.len() + 1..];
+        let mut names = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = *rest.first().ok_or_else(malformed)? as usize;
+            if rest.len() < 1 + len {
+                return Err(malformed());
+            }
+            names.push(str::from_utf8(&rest[1..1 + len]).map_err(|_| malformed())?);
+            rest = &rest[1 + len..];
+        }
+        let mut data = rest.to_vec();
+        for name in names.into_iter().rev() {
+            let transform = self
+                .chain
+                .iter()
+                .chain(&self.extra)
+                .find(|x| x.name() == name)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("transform {:?} is not configured", name),
+                    )
+                })?;
+            data = transform.reverse(hash, &data)?;
+        }
+        Ok(data)
+    }
+}
+
+/// Pads data with zeroes to a multiple of `block` bytes, obscuring its exact length.
+///
+/// Padded data is prefixed with its 8-byte little-endian original length.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Pad {
+    /// Length in bytes that padded data is a multiple of
+    pub block: usize,
+}
+
+impl Transform for Pad {
+    fn name(&self) -> String {
+        "pad".into()
+    }
+
+    fn apply(&self, _: &Hash, data: &[u8]) -> io::Result<Vec<u8>> {
+        let block = self.block.max(1);
+        let len = 8 + data.len();
+        let mut result = Vec::with_capacity(len + block - 1);
+        let mut header = [0; 8];
+        LittleEndian::write_u64(&mut header, data.len() as u64);
+        result.extend_from_slice(&header);
+        result.extend_from_slice(data);
+        result.resize(len.div_ceil(block) * block, 0);
+        Ok(result)
+    }
+
+    fn reverse(&self, _: &Hash, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < 8 {
+            return Err(malformed());
+        }
+        let len = LittleEndian::read_u64(data);
+        if len > data.len() as u64 - 8 {
+            return Err(malformed());
+        }
+        Ok(data[8..8 + len as usize].to_vec())
+    }
+}
+
+/// Compresses data with a zstd `Dictionary`.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone)]
+pub struct Compress {
+    /// Dictionary to compress with
+    pub dictionary: crate::Dictionary,
+    /// Compression level, from 1 to 22
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Transform for Compress {
+    fn name(&self) -> String {
+        format!("zstd/{}", self.dictionary.hash())
+    }
+
+    fn apply(&self, _: &Hash, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.dictionary.compress(data, self.level)
+    }
+
+    fn reverse(&self, _: &Hash, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.dictionary.decompress(data)
+    }
+}
+
+/// Encrypts data with XChaCha20-Poly1305 under a random nonce, authenticated with the asset's hash.
+///
+/// Encrypted data is prefixed with its 24-byte nonce.
+#[cfg(feature = "chacha20poly1305")]
+#[derive(Debug, Clone)]
+pub struct Encrypt {
+    /// Key to encrypt with
+    pub key: crate::encryption::Key,
+}
+
+#[cfg(feature = "chacha20poly1305")]
+impl Transform for Encrypt {
+    fn name(&self) -> String {
+        format!("xchacha20poly1305/{}", self.key.fingerprint())
+    }
+
+    fn apply(&self, hash: &Hash, data: &[u8]) -> io::Result<Vec<u8>> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        use chacha20poly1305::XNonce;
+
+        use crate::encryption::NONCE_LEN;
+
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let mut result = nonce.to_vec();
+        result.extend(
+            self.key
+                .cipher()
+                .encrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: data,
+                        aad: hash.bytes(),
+                    },
+                )
+                .map_err(|_| io::Error::other("failed to encrypt asset"))?,
+        );
+        Ok(result)
+    }
+
+    fn reverse(&self, hash: &Hash, data: &[u8]) -> io::Result<Vec<u8>> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        use chacha20poly1305::XNonce;
+
+        use crate::encryption::NONCE_LEN;
+
+        if data.len() < NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated encrypted asset",
+            ));
+        }
+        self.key
+            .cipher()
+            .decrypt(
+                XNonce::from_slice(&data[..NONCE_LEN]),
+                Payload {
+                    msg: &data[NONCE_LEN..],
+                    aad: hash.bytes(),
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt asset"))
+    }
+}
+
+/// A store that transforms asset data with a `Pipeline` before writing it into another store, and reverses the
+/// transformation on read.
+///
+/// Assets are addressed by the hash of their original data, recorded in a local index mapping it to the hash of the
+/// transformed data in the inner store. Assets absent from the index are read from the inner store unchanged, so a
+/// pipeline may be introduced to an existing repository. The index uses the same format as that of an
+/// `EncryptedStore`.
+pub struct TransformStore<S> {
+    shared: Arc<Shared<S>>,
+}
+
+struct Shared<S> {
+    inner: S,
+    pipeline: Pipeline,
+    index: Index,
+}
+
+impl<S> TransformStore<S> {
+    /// Transform assets stored in `inner` with `pipeline`, addressing them via the index at `index`, which is created
+    /// if necessary.
+    pub fn open(inner: S, pipeline: Pipeline, index: &Path) -> io::Result<Self> {
+        Ok(Self {
+            shared: Arc::new(Shared {
+                inner,
+                pipeline,
+                index: Index::open(index)?,
+            }),
+        })
+    }
+
+    /// The store holding transformed data.
+    pub fn inner(&self) -> &S {
+        &self.shared.inner
+    }
+}
+
+impl<S: Store> TransformStore<S> {
+    /// Access the asset identified by `hash`, reversing its transformation.
+    pub fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        let shared = &*self.shared;
+        let stored = match shared.index.get(hash) {
+            Some(x) => x,
+            None => return shared.inner.get(hash),
+        };
+        let data = shared.pipeline.reverse(hash, &shared.inner.get(&stored)?)?;
+        if hash_of(&data) != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("data for {} does not match its hash", hash),
+            ));
+        }
+        Ok(data.into())
+    }
+
+    /// Determine whether the asset identified by `hash` exists in the repository.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        let stored = self.shared.index.get(hash).unwrap_or(*hash);
+        self.shared.inner.contains(&stored)
+    }
+
+    /// Enumerate assets stored in the repository.
+    ///
+    /// Transformed assets are listed first, followed by every asset of the inner store that isn't the transformed
+    /// form of another.
+    ///
+    /// This should only be used for diagnostic purposes. It almost never makes sense to access an asset you don't
+    /// already know the hash of.
+    pub fn list(&self) -> impl Iterator<Item = Hash> + '_ {
+        let transformed = self.shared.index.keys();
+        let stored = transformed
+            .iter()
+            .filter_map(|x| self.shared.index.get(x))
+            .collect::<crate::ContentSet>();
+        transformed.into_iter().chain(
+            self.shared
+                .inner
+                .list()
+                .filter(move |x| !stored.contains(x)),
+        )
+    }
+}
+
+impl<S: WritableStore> TransformStore<S> {
+    /// Create a `Writer` for streaming data into the repository.
+    ///
+    /// Data is buffered in memory until it's stored.
+    pub fn make_writer(&self) -> Writer<S> {
+        Writer {
+            shared: self.shared.clone(),
+            buffer: Buffer::default(),
+        }
+    }
+
+    /// Transform and write `data` into the repository.
+    pub fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.shared.put(hash_of(data), data).map(|(hash, _)| hash)
+    }
+}
+
+impl<S: WritableStore> Shared<S> {
+    fn put(&self, hash: Hash, data: &[u8]) -> io::Result<(Hash, bool)> {
+        if self.index.contains(&hash) {
+            return Ok((hash, false));
+        }
+        let stored = self.pipeline.apply(&hash, data)?;
+        let mut writer = self.inner.make_writer()?;
+        writer.write_all(&stored)?;
+        let (stored, _) = writer.store()?;
+        Ok((hash, self.index.insert(hash, stored)?))
+    }
+}
+
+impl<S: Store> Store for TransformStore<S> {
+    fn get(&self, hash: &Hash) -> io::Result<Asset> {
+        self.get(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.contains(hash)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = Hash> + '_> {
+        Box::new(self.list())
+    }
+}
+
+impl<S: WritableStore> WritableStore for TransformStore<S> {
+    type Writer = Writer<S>;
+
+    fn make_writer(&self) -> io::Result<Writer<S>> {
+        Ok(self.make_writer())
+    }
+
+    fn put(&self, data: &[u8]) -> io::Result<Hash> {
+        self.put(data)
+    }
+}
+
+/// A staging area for streaming data into a `TransformStore`.
+///
+/// `store` must be called to commit data to the repository. Otherwise, it will be discarded when the `Writer` is
+/// dropped.
+pub struct Writer<S> {
+    shared: Arc<Shared<S>>,
+    buffer: Buffer,
+}
+
+impl<S: WritableStore> StoreWriter for Writer<S> {
+    fn store(self) -> io::Result<(Hash, bool)> {
+        let (hash, data) = self.buffer.finish();
+        self.shared.put(hash, &data)
+    }
+}
+
+impl<S> io::Write for Writer<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed transformed asset")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    use crate::MemoryStore;
+
+    #[test]
+    fn mixed() {
+        let index =
+            std::env::temp_dir().join(format!("chasset-xindex-{:016X}", rand::random::<u64>()));
+        let inner = MemoryStore::new();
+        let legacy = inner.put(b"legacy");
+        let padded = {
+            let store =
+                TransformStore::open(&inner, Pipeline::new().then(Pad { block: 64 }), &index)
+                    .unwrap();
+            let hash = store.put(b"padded").unwrap();
+            assert_eq!(&store.get(&hash).unwrap()[..], b"padded");
+            assert!(!inner.contains(&hash));
+            hash
+        };
+
+        // Padding is no longer applied, but remains reversible
+        let store =
+            TransformStore::open(&inner, Pipeline::new().also_read(Pad { block: 64 }), &index)
+                .unwrap();
+        let plain = store.put(b"plain").unwrap();
+        for &(hash, data) in &[
+            (legacy, &b"legacy"[..]),
+            (padded, b"padded"),
+            (plain, b"plain"),
+        ] {
+            assert_eq!(&store.get(&hash).unwrap()[..], data);
+        }
+        let mut listed = store.list().collect::<Vec<_>>();
+        listed.sort();
+        let mut expected = vec![legacy, padded, plain];
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        let store = TransformStore::open(&inner, Pipeline::new(), &index).unwrap();
+        assert_eq!(
+            store.get(&padded).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(&index).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+//! Conversion between directory trees and manifests.
+//!
+//! Each file becomes a blob entry and each directory a tree entry. On Unix, permission bits are recorded as the
+//! `MODE` metadata key in octal, and symbolic links are stored as blobs holding their target, marked by `TYPE` set to
+//! `SYMLINK`. Other special files, such as sockets, are skipped.
+//!
+//! Exporting reverses the process, verifying each asset against its hash before it's written. Assets of a `LooseFiles`
+//! repository may be linked into the destination rather than copied, sparing both time and space.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, FileType};
+use std::io;
+use std::path::Path;
+
+use crate::manifest::{Entry, EntryKind, Manifest};
+use crate::{Asset, Hash, Hasher, LooseFiles, Store, StoreWriter, WritableStore};
+
+/// Metadata key of an entry's permission bits, in octal.
+pub const MODE: &str = "mode";
+/// Metadata key distinguishing special entries.
+pub const TYPE: &str = "type";
+/// Value of `TYPE` for a symbolic link, whose content is its target.
+pub const SYMLINK: &str = "symlink";
+
+/// How `export_linked` populates files from a repository.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Link {
+    /// Hard link each file to the repository's copy.
+    ///
+    /// Exported files then share permissions with, and must never be modified lest they corrupt, the repository, so
+    /// recorded modes are not applied to them.
+    Hard,
+    /// Clone each file from the repository's copy, sharing storage until either is modified, on filesystems that
+    /// support it. Falls back to copying elsewhere.
+    Reflink,
+}
+
+/// Store every file under the directory `path` in `store`, returning the hash of a manifest describing the tree.
+///
+/// With the `rayon` feature enabled, the entries of each directory are read and hashed concurrently.
+pub fn import_tree<S: WritableStore + Sync + ?Sized>(store: &S, path: &Path) -> io::Result<Hash> {
+    import_tree_with(store, path, |_, _| {})
+}
+
+/// Like `import_tree`, but call `visit` with the path relative to `path` and the entry of each file as it's stored,
+/// e.g. to report progress.
+///
+/// With the `rayon` feature enabled, `visit` may be called from multiple threads at once, in no particular order.
+pub fn import_tree_with<S, F>(store: &S, path: &Path, visit: F) -> io::Result<Hash>
+where
+    S: WritableStore + Sync + ?Sized,
+    F: Fn(&Path, &Entry) + Sync,
+{
+    Ok(import_dir(store, path, Path::new(""), &visit)?.hash)
+}
+
+type Visit<'a> = &'a (dyn Fn(&Path, &Entry) + Sync);
+
+fn import_dir<S: WritableStore + Sync + ?Sized>(
+    store: &S,
+    path: &Path,
+    relative: &Path,
+    visit: Visit<'_>,
+) -> io::Result<Entry> {
+    let mut children = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("non-UTF-8 file name in {}", path.display()),
+            )
+        })?;
+        children.push((name, entry.path(), entry.file_type()?));
+    }
+
+    #[cfg(feature = "rayon")]
+    let children = {
+        use rayon::prelude::*;
+        children
+            .into_par_iter()
+            .map(|(name, path, ty)| {
+                let entry = import_entry(store, &path, &relative.join(&name), ty, visit)?;
+                Ok((name, entry))
+            })
+            .collect::<io::Result<Vec<_>>>()?
+    };
+    #[cfg(not(feature = "rayon"))]
+    let children = children
+        .into_iter()
+        .map(|(name, path, ty)| {
+            let entry = import_entry(store, &path, &relative.join(&name), ty, visit)?;
+            Ok((name, entry))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let manifest = Manifest {
+        entries: children
+            .into_iter()
+            .filter_map(|(name, entry)| Some((name, entry?)))
+            .collect(),
+    };
+    let data = manifest.encode();
+    Ok(Entry {
+        hash: store.put(&data)?,
+        kind: EntryKind::Tree,
+        size: data.len() as u64,
+        metadata: mode(&fs::metadata(path)?),
+    })
+}
+
+fn import_entry<S: WritableStore + Sync + ?Sized>(
+    store: &S,
+    path: &Path,
+    relative: &Path,
+    ty: FileType,
+    visit: Visit<'_>,
+) -> io::Result<Option<Entry>> {
+    if ty.is_dir() {
+        return import_dir(store, path, relative, visit).map(Some);
+    }
+    if ty.is_symlink() {
+        let target = link_target(path)?;
+        let mut entry = Entry::blob(store.put(&target)?, target.len() as u64);
+        entry.metadata.insert(TYPE.into(), SYMLINK.into());
+        visit(relative, &entry);
+        return Ok(Some(entry));
+    }
+    if !ty.is_file() {
+        return Ok(None);
+    }
+    let mut file = File::open(path)?;
+    let mut writer = store.make_writer()?;
+    let size = io::copy(&mut file, &mut writer)?;
+    let (hash, _) = writer.store()?;
+    let entry = Entry {
+        hash,
+        kind: EntryKind::Blob,
+        size,
+        metadata: mode(&file.metadata()?),
+    };
+    visit(relative, &entry);
+    Ok(Some(entry))
+}
+
+/// Recreate the tree described by the manifest `root` of `store` under the directory `dest` by copying.
+///
+/// `dest` is created if necessary, and must not already contain any of the tree's paths. Fails with
+/// `io::ErrorKind::InvalidData` if an asset doesn't match its hash or an entry's name isn't a single path component.
+pub fn export_tree<S: Store + ?Sized>(store: &S, root: &Hash, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    export_dir(store, None, root, dest)
+}
+
+/// Recreate the tree described by the manifest `root` of `store` under the directory `dest`, populating files with
+/// links according to `link`.
+///
+/// As `export_tree` otherwise.
+pub fn export_linked(store: &LooseFiles, root: &Hash, dest: &Path, link: Link) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    export_dir(store, Some((store, link)), root, dest)
+}
+
+fn export_dir<S: Store + ?Sized>(
+    store: &S,
+    link: Option<(&LooseFiles, Link)>,
+    hash: &Hash,
+    dir: &Path,
+) -> io::Result<()> {
+    let manifest = Manifest::decode(&get_verified(store, hash)?)?;
+    for (name, entry) in &manifest.entries {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid entry name {:?}", name),
+            ));
+        }
+        let path = dir.join(name);
+        match entry.kind {
+            EntryKind::Tree => {
+                fs::create_dir(&path)?;
+                export_dir(store, link, &entry.hash, &path)?;
+                set_mode(&path, entry)?;
+            }
+            EntryKind::Blob if entry.metadata.get(TYPE).map(|x| &x[..]) == Some(SYMLINK) => {
+                make_link(&get_verified(store, &entry.hash)?, &path)?;
+            }
+            EntryKind::Blob => {
+                if export_blob(store, link, &entry.hash, &path)? {
+                    set_mode(&path, entry)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the asset `hash` of `store` to a new file at `dest` by copying.
+///
+/// Fails with `io::ErrorKind::InvalidData` if the asset doesn't match its hash.
+pub fn export_asset<S: Store + ?Sized>(store: &S, hash: &Hash, dest: &Path) -> io::Result<()> {
+    export_blob(store, None, hash, dest).map(|_| ())
+}
+
+/// Populate a new file at `dest` with the asset `hash` of `store` according to `link`.
+///
+/// As `export_asset` otherwise.
+pub fn export_asset_linked(
+    store: &LooseFiles,
+    hash: &Hash,
+    dest: &Path,
+    link: Link,
+) -> io::Result<()> {
+    export_blob(store, Some((store, link)), hash, dest).map(|_| ())
+}
+
+/// Returns whether `path` is a distinct file, rather than a hard link whose mode mustn't be changed.
+fn export_blob<S: Store + ?Sized>(
+    store: &S,
+    link: Option<(&LooseFiles, Link)>,
+    hash: &Hash,
+    path: &Path,
+) -> io::Result<bool> {
+    let data = get_verified(store, hash)?;
+    match link {
+        Some((files, Link::Hard)) => {
+            fs::hard_link(files.path(hash), path)?;
+            return Ok(false);
+        }
+        Some((files, Link::Reflink)) if reflink(&files.path(hash), path).is_ok() => {}
+        _ => {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)?;
+            io::Write::write_all(&mut file, &data)?;
+        }
+    }
+    Ok(true)
+}
+
+fn get_verified<S: Store + ?Sized>(store: &S, hash: &Hash) -> io::Result<Asset> {
+    let data = store.get(hash)?;
+    let mut hasher = Hasher::new();
+    hasher.process(&data);
+    if hasher.result() != *hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("asset {} is corrupt", hash),
+        ));
+    }
+    Ok(data)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, entry: &Entry) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = match entry.metadata.get(MODE) {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let mode = u32::from_str_radix(mode, 8)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed mode"))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_: &Path, _: &Entry) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::raw::{c_int, c_ulong};
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+    const FICLONE: c_ulong = 0x4004_9409;
+
+    let src = File::open(src)?;
+    let out = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)?;
+    if unsafe { ioctl(out.as_raw_fd(), FICLONE, src.as_raw_fd()) } != 0 {
+        let e = io::Error::last_os_error();
+        drop(out);
+        fs::remove_file(dest)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::Error::other(
+        "reflinks are unsupported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn make_link(target: &[u8], path: &Path) -> io::Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(OsStr::from_bytes(target), path)
+}
+
+#[cfg(windows)]
+fn make_link(target: &[u8], path: &Path) -> io::Result<()> {
+    let target = std::str::from_utf8(target)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "non-UTF-8 link target"))?;
+    std::os::windows::fs::symlink_file(target, path)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn make_link(_: &[u8], _: &Path) -> io::Result<()> {
+    Err(io::Error::other(
+        "symbolic links are unsupported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> BTreeMap<String, String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut result = BTreeMap::new();
+    result.insert(
+        MODE.into(),
+        format!("{:o}", metadata.permissions().mode() & 0o7777),
+    );
+    result
+}
+
+#[cfg(not(unix))]
+fn mode(_: &fs::Metadata) -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+#[cfg(unix)]
+fn link_target(path: &Path) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(fs::read_link(path)?.into_os_string().into_vec())
+}
+
+#[cfg(not(unix))]
+fn link_target(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read_link(path)?
+        .into_os_string()
+        .into_string()
+        .map(String::into_bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "non-UTF-8 link target"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{manifest, MemoryStore};
+
+    #[test]
+    fn import() {
+        let dir = std::env::temp_dir().join(format!("chasset-tree-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), b"alpha").unwrap();
+        fs::write(dir.join("sub/b"), b"beta").unwrap();
+        let store = MemoryStore::new();
+        let root = import_tree(&store, &dir).unwrap();
+        let entries = manifest::walk(&store, &root)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let paths = entries.iter().map(|x| &x.0[..]).collect::<Vec<_>>();
+        assert_eq!(paths, ["a", "sub", "sub/b"]);
+        assert_eq!(&*store.get(&entries[2].1.hash).unwrap(), b"beta");
+        let visited = std::sync::Mutex::new(Vec::new());
+        let again = import_tree_with(&store, &dir, |path, _| {
+            visited.lock().unwrap().push(path.to_owned())
+        })
+        .unwrap();
+        assert_eq!(again, root);
+        let mut visited = visited.into_inner().unwrap();
+        visited.sort();
+        assert_eq!(visited, [Path::new("a"), Path::new("sub/b")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export() {
+        let dir = std::env::temp_dir().join(format!("chasset-tree-{:016X}", rand::random::<u64>()));
+        fs::create_dir_all(dir.join("src/sub")).unwrap();
+        fs::write(dir.join("src/a"), b"alpha").unwrap();
+        fs::write(dir.join("src/sub/b"), b"beta").unwrap();
+        let store = LooseFiles::open(dir.join("store")).unwrap();
+        let root = import_tree(&store, &dir.join("src")).unwrap();
+
+        export_tree(&store, &root, &dir.join("copy")).unwrap();
+        assert_eq!(fs::read(dir.join("copy/sub/b")).unwrap(), b"beta");
+        assert_eq!(import_tree(&store, &dir.join("copy")).unwrap(), root);
+
+        export_linked(&store, &root, &dir.join("linked"), Link::Hard).unwrap();
+        assert_eq!(fs::read(dir.join("linked/a")).unwrap(), b"alpha");
+        assert!(export_tree(&store, &root, &dir.join("linked")).is_err());
+
+        let gamma = store.put(b"gamma").unwrap();
+        export_asset_linked(&store, &gamma, &dir.join("gamma"), Link::Reflink).unwrap();
+        assert_eq!(fs::read(dir.join("gamma")).unwrap(), b"gamma");
+        assert!(export_asset(&store, &gamma, &dir.join("gamma")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! Batched file IO through io_uring, for issuing many small operations at once.
+//!
+//! Each function returns `None` if io_uring is unavailable, e.g. on an old kernel or under a seccomp filter, or if the
+//! ring fails partway through, in which case the caller should fall back to ordinary syscalls.
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, types, IoUring};
+
+/// Number of operations in flight at once.
+const DEPTH: usize = 64;
+
+/// A read of `len` bytes starting at `offset` in `file`.
+pub(crate) struct Read<'a> {
+    pub file: &'a File,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// Perform every read in `reads`, returning the data read by each in the same order.
+pub(crate) fn read_many(reads: &[Read<'_>]) -> Option<Vec<io::Result<Vec<u8>>>> {
+    let mut ring = IoUring::new(DEPTH as u32).ok()?;
+    let mut bufs = reads
+        .iter()
+        .map(|x| vec![0; x.len as usize])
+        .collect::<Vec<_>>();
+    let mut results = Vec::with_capacity(reads.len());
+    let mut failed = false;
+    for (chunk, bufs) in reads.chunks(DEPTH).zip(bufs.chunks_mut(DEPTH)) {
+        let entries = chunk.iter().zip(bufs.iter_mut()).map(|(read, buf)| {
+            opcode::Read::new(types::Fd(read.file.as_raw_fd()), buf.as_mut_ptr(), read.len)
+                .offset(read.offset)
+        });
+        let entries = entries.map(|x| x.build()).collect::<Vec<_>>();
+        match run(&mut ring, entries) {
+            Some(done) => results.extend(done),
+            None => {
+                failed = true;
+                break;
+            }
+        }
+    }
+    if failed {
+        // The kernel may still write into any of the buffers
+        mem::forget(ring);
+        mem::forget(bufs);
+        return None;
+    }
+    Some(
+        results
+            .into_iter()
+            .zip(bufs)
+            .zip(reads)
+            .map(|((result, mut buf), read)| {
+                let n = result?;
+                if n < buf.len() {
+                    // Short reads are rare; finish them synchronously
+                    read_exact_at(read.file, &mut buf[n..], read.offset + n as u64)?;
+                }
+                Ok(buf)
+            })
+            .collect(),
+    )
+}
+
+/// Flush the data of every file in `files` to disk.
+pub(crate) fn sync_many(files: &[&File]) -> Option<io::Result<()>> {
+    let mut ring = IoUring::new(DEPTH as u32).ok()?;
+    for chunk in files.chunks(DEPTH) {
+        let entries = chunk
+            .iter()
+            .map(|file| {
+                opcode::Fsync::new(types::Fd(file.as_raw_fd()))
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let done = match run(&mut ring, entries) {
+            Some(x) => x,
+            None => {
+                mem::forget(ring);
+                return None;
+            }
+        };
+        for result in done {
+            if let Err(e) = result {
+                return Some(Err(e));
+            }
+        }
+    }
+    Some(Ok(()))
+}
+
+/// Submit up to `DEPTH` operations and wait for all of them to complete, returning the result of each in order.
+///
+/// Returns `None` if the ring fails while operations are in flight. The caller must then leak the ring and every buffer
+/// the operations refer to, since the kernel might still write to them.
+fn run(
+    ring: &mut IoUring,
+    entries: Vec<io_uring::squeue::Entry>,
+) -> Option<Vec<io::Result<usize>>> {
+    let count = entries.len();
+    let mut results = (0..count).map(|_| None).collect::<Vec<_>>();
+    for (i, entry) in entries.into_iter().enumerate() {
+        // The ring has room for `DEPTH` entries, and the buffers outlive the operations
+        unsafe {
+            ring.submission()
+                .push(&entry.user_data(i as u64))
+                .expect("submission queue is large enough");
+        }
+    }
+    let mut remaining = count;
+    while remaining > 0 {
+        match ring.submit_and_wait(remaining) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        }
+        for completion in ring.completion() {
+            let result = completion.result();
+            results[completion.user_data() as usize] = Some(if result < 0 {
+                Err(io::Error::from_raw_os_error(-result))
+            } else {
+                Ok(result as usize)
+            });
+            remaining -= 1;
+        }
+    }
+    Some(results.into_iter().map(Option::unwrap).collect())
+}
+
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match file.read_at(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Automatic ingestion of files as they change.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher as _};
+
+use crate::{Hash, StoreWriter, WritableStore};
+
+/// Watches a directory tree, storing the contents of each file whenever it's created or modified.
+///
+/// Iterate to receive the path and hash of each new version in the order they were stored. Saving a file commonly
+/// produces several filesystem events; a file is only reported again once its contents differ from those last stored.
+/// Files that vanish before they can be read are ignored. Watching stops when the `Watcher` is dropped.
+pub struct Watcher {
+    _watcher: RecommendedWatcher,
+    events: mpsc::Receiver<io::Result<(PathBuf, Hash)>>,
+}
+
+impl Watcher {
+    /// Begin storing files under `dir` into `store`.
+    ///
+    /// Files already present are only stored once they change.
+    pub fn new<S>(store: S, dir: &Path) -> io::Result<Self>
+    where
+        S: WritableStore + Send + 'static,
+    {
+        let (send, events) = mpsc::channel();
+        let mut latest = HashMap::new();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let _ = send.send(Err(notify_error(e)));
+                        return;
+                    }
+                };
+                match event.kind {
+                    EventKind::Create(_)
+                    | EventKind::Modify(ModifyKind::Data(_))
+                    | EventKind::Modify(ModifyKind::Any) => {}
+                    EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                        // Paths are the source and then the destination
+                        if let Some(path) = event.paths.last() {
+                            ingest(&store, &mut latest, &send, path);
+                        }
+                        return;
+                    }
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {}
+                    EventKind::Remove(_) => {
+                        for path in &event.paths {
+                            latest.remove(path);
+                        }
+                        return;
+                    }
+                    _ => return,
+                }
+                for path in &event.paths {
+                    ingest(&store, &mut latest, &send, path);
+                }
+            })
+            .map_err(notify_error)?;
+        watcher
+            .watch(dir, RecursiveMode::Recursive)
+            .map_err(notify_error)?;
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Get the next version stored, if one is already available.
+    pub fn try_next(&self) -> Option<io::Result<(PathBuf, Hash)>> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = io::Result<(PathBuf, Hash)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.events.recv().ok()
+    }
+}
+
+fn ingest<S: WritableStore>(
+    store: &S,
+    latest: &mut HashMap<PathBuf, Hash>,
+    send: &mpsc::Sender<io::Result<(PathBuf, Hash)>>,
+    path: &Path,
+) {
+    let result = store_file(store, path);
+    let hash = match result {
+        Ok(Some(x)) => x,
+        Ok(None) => return,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            let _ = send.send(Err(e));
+            return;
+        }
+    };
+    if latest.insert(path.to_owned(), hash) == Some(hash) {
+        return;
+    }
+    let _ = send.send(Ok((path.to_owned(), hash)));
+}
+
+fn store_file<S: WritableStore>(store: &S, path: &Path) -> io::Result<Option<Hash>> {
+    let mut file = File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return Ok(None);
+    }
+    let mut writer = store.make_writer()?;
+    io::copy(&mut file, &mut writer)?;
+    Ok(Some(writer.store()?.0))
+}
+
+fn notify_error(e: notify::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    use crate::{hash_of, LooseFiles};
+
+    /// Wait up to ten seconds for `path` to be stored with the contents identified by `hash`.
+    ///
+    /// Intermediate versions, e.g. an empty file seen between its creation and the write, are skipped.
+    fn wait_for(watcher: &Watcher, path: &Path, hash: Hash) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(x) = watcher.try_next() {
+                let (stored, stored_hash) = x.unwrap();
+                assert_eq!(stored, path);
+                if stored_hash == hash {
+                    return;
+                }
+                continue;
+            }
+            assert!(Instant::now() < deadline, "timed out waiting for an event");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn watch() {
+        let dir =
+            std::env::temp_dir().join(format!("chasset-watch-{:016X}", rand::random::<u64>()));
+        let source = dir.join("source");
+        std::fs::create_dir_all(source.join("nested")).unwrap();
+        let watcher = Watcher::new(LooseFiles::open(dir.join("repo")).unwrap(), &source).unwrap();
+        let store = LooseFiles::open(dir.join("repo")).unwrap();
+
+        let path = source.join("nested").join("a");
+        std::fs::write(&path, b"first").unwrap();
+        wait_for(&watcher, &path, hash_of(b"first"));
+        assert!(store.contains(&hash_of(b"first")));
+
+        std::fs::write(&path, b"second").unwrap();
+        wait_for(&watcher, &path, hash_of(b"second"));
+        assert!(store.contains(&hash_of(b"second")));
+
+        drop(watcher);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}